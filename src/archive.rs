@@ -0,0 +1,615 @@
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use snowboard::{headers, Response, DEFAULT_HTTP_VERSION};
+use spdlog::prelude::*;
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::config::{Config, EntryErrorPolicy};
+use crate::crc32::Crc32;
+use crate::format;
+use crate::ExplorerEntry;
+
+/// Bytes buffered on their way to the connection.
+const BUFFER_LEN: usize = 64 * 1024;
+
+const TAR_BLOCK: usize = 512;
+
+/// Largest value of a tar header's octal fields of 12 bytes.
+const TAR_MAX: u64 = 0o77_777_777_777;
+
+/// Name of the extended headers carrying what a tar header can't.
+const PAX_NAME: &str = "././@PaxHeader";
+
+/// Sizes, offsets and counts from which zip needs its 64-bit records.
+const ZIP64_SIZE: u64 = 0xffff_ffff;
+const ZIP64_COUNT: usize = 0xffff;
+
+/// Formats a directory can be downloaded as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "tar" => Ok(Self::Tar),
+            "tar.gz" | "tgz" => Ok(Self::TarGz),
+            "zip" => Ok(Self::Zip),
+            _ => Err(format!("Unknown archive format: {}", value)),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    /// The format of an archive file going by its name.
+    pub(crate) fn of(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(".tgz") {
+            return Some(Self::TarGz);
+        }
+        [Self::TarGz, Self::Tar, Self::Zip]
+            .into_iter()
+            .find(|format| {
+                name.strip_suffix(format.extension())
+                    .is_some_and(|stem| stem.ends_with('.'))
+            })
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Tar => "tar",
+            Self::TarGz => "tar.gz",
+            Self::Zip => "zip",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Tar => "application/x-tar",
+            Self::TarGz => "application/gzip",
+            Self::Zip => "application/zip",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+    #[error("Archive of {0} holds more than {1} files")]
+    TooManyFiles(String, usize),
+    #[error("Archive of {0} holds more than {1} bytes")]
+    TooLarge(String, u64),
+    #[error("Failed to read {0}: {1}")]
+    Io(String, #[source] io::Error),
+}
+
+/// A directory or file of an archive, by its path below the archived
+/// directory.
+struct Member {
+    path: String,
+    mtime: SystemTime,
+    /// `None` for directories.
+    size: Option<u64>,
+}
+
+/// Whether members are read, or only counted to measure the archive.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pass {
+    Measure,
+    Send,
+}
+
+/// A directory tree sent as an archive while its files are read, with
+/// no copy of it made anywhere.
+pub struct Archive {
+    format: ArchiveFormat,
+    full_path: PathBuf,
+    /// Directory the members are put in, and the name of the download.
+    name: String,
+    members: Vec<Member>,
+}
+
+impl Archive {
+    /// Takes the entries of a flat recursive listing of `full_path`,
+    /// keeping the files that can be read, up to the configured limits.
+    pub fn new(
+        config: &Config,
+        full_path: PathBuf,
+        format: ArchiveFormat,
+        entries: Vec<ExplorerEntry>,
+    ) -> Result<Self, ArchiveError> {
+        let display = || full_path.to_string_lossy().into_owned();
+        let mut members = Vec::with_capacity(entries.len());
+        let mut files = 0;
+        let mut total = 0;
+
+        for entry in entries {
+            let path = entry.path().to_string();
+            if entry.size().is_none() {
+                members.push(Member {
+                    path,
+                    mtime: entry.mtime(),
+                    size: None,
+                });
+                continue;
+            }
+
+            let file_path = full_path.join(&path);
+            let metadata = match readable(&file_path) {
+                Ok(Some(metadata)) => metadata,
+                Ok(None) => continue,
+                Err(err) => {
+                    let file_path = file_path.to_string_lossy().into_owned();
+                    match config.entry_errors {
+                        EntryErrorPolicy::Skip => {
+                            warn!("Failed to read {}: {}", file_path, err);
+                            continue;
+                        }
+                        EntryErrorPolicy::Fail => return Err(ArchiveError::Io(file_path, err)),
+                    }
+                }
+            };
+
+            files += 1;
+            total += metadata.len();
+            if files > config.max_archive_files {
+                return Err(ArchiveError::TooManyFiles(
+                    display(),
+                    config.max_archive_files,
+                ));
+            }
+            if total > config.max_archive_size {
+                return Err(ArchiveError::TooLarge(display(), config.max_archive_size));
+            }
+
+            members.push(Member {
+                path,
+                mtime: metadata.modified().unwrap_or(entry.mtime()),
+                size: Some(metadata.len()),
+            });
+        }
+
+        // Directories come before what they hold.
+        members.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let name = full_path
+            .file_name()
+            .map_or("root".into(), |name| name.to_string_lossy().into_owned());
+        Ok(Self {
+            format,
+            full_path,
+            name,
+            members,
+        })
+    }
+
+    /// Status line and headers, with the exact length of the archive
+    /// unless it is compressed.
+    pub fn head(&self) -> Response {
+        let filename = format!("{}.{}", self.name, self.format.extension());
+        let mut headers = headers! {
+            "Content-Type" => self.format.content_type(),
+            "Content-Disposition" => content_disposition(&filename),
+        };
+
+        if let Some(len) = self.len() {
+            headers.insert("Content-Length", len.to_string());
+        }
+
+        Response::new(DEFAULT_HTTP_VERSION, 200, "OK", Vec::new(), Some(headers))
+    }
+
+    /// Length of the archive, not known for compressed ones until they
+    /// are sent.
+    fn len(&self) -> Option<u64> {
+        let mut counted = Counted::new(io::sink());
+        match self.format {
+            ArchiveFormat::Tar => self.tar(&mut counted, Pass::Measure).ok()?,
+            ArchiveFormat::Zip => self.zip(&mut counted, Pass::Measure).ok()?,
+            ArchiveFormat::TarGz => return None,
+        }
+        Some(counted.written)
+    }
+
+    /// Writes the archive to `writer`, reading each file as it goes.
+    pub fn send<W: Write>(self, config: &Config, writer: &mut W) -> io::Result<()> {
+        let mut buffered = BufWriter::with_capacity(BUFFER_LEN, writer);
+        match self.format {
+            ArchiveFormat::Tar => self.tar(&mut Counted::new(&mut buffered), Pass::Send)?,
+            ArchiveFormat::Zip => self.zip(&mut Counted::new(&mut buffered), Pass::Send)?,
+            ArchiveFormat::TarGz => {
+                let gzip = config.compression.gzip_writer(&mut buffered);
+                let mut counted = Counted::new(gzip);
+                self.tar(&mut counted, Pass::Send)?;
+                counted.inner.finish()?;
+            }
+        }
+        buffered.flush()
+    }
+
+    /// The member's path in the archive, directories ending in a slash.
+    fn member_path(&self, member: &Member) -> String {
+        let slash = if member.size.is_none() { "/" } else { "" };
+        format!("{}/{}{}", self.name, member.path, slash)
+    }
+
+    /// A ustar archive, with pax extended headers for longer paths and
+    /// larger files than ustar holds.
+    fn tar<W: Write>(&self, out: &mut Counted<W>, pass: Pass) -> io::Result<()> {
+        for member in &self.members {
+            let path = self.member_path(member);
+            let size = member.size.unwrap_or(0);
+            let mtime = member
+                .mtime
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |mtime| mtime.as_secs());
+            let (mode, kind) = match member.size {
+                Some(_) => (0o644, b'0'),
+                None => (0o755, b'5'),
+            };
+
+            let split = split_ustar(&path);
+            let mut records = String::new();
+            if split.is_none() {
+                records.push_str(&pax_record("path", &path));
+            }
+            if size > TAR_MAX {
+                records.push_str(&pax_record("size", &size.to_string()));
+            }
+            if !records.is_empty() {
+                let header = tar_header(PAX_NAME, "", records.len() as u64, mtime, 0o644, b'x');
+                out.write_all(&header)?;
+                out.write_all(records.as_bytes())?;
+                out.write_all(&[0; TAR_BLOCK][..tar_padding(records.len() as u64)])?;
+            }
+
+            // Names too long for ustar are cut, the pax path stands.
+            let (prefix, name) = split.unwrap_or(("", truncate(&path, 100)));
+            out.write_all(&tar_header(name, prefix, size, mtime, mode, kind))?;
+            if member.size.is_some() {
+                self.copy(member, out, pass, false)?;
+                out.write_all(&[0; TAR_BLOCK][..tar_padding(size)])?;
+            }
+        }
+        out.write_all(&[0; 2 * TAR_BLOCK])
+    }
+
+    /// A zip archive of stored files, whose checksums follow their data
+    /// as they're only known once it's sent.
+    fn zip<W: Write>(&self, out: &mut Counted<W>, pass: Pass) -> io::Result<()> {
+        let mut central = Vec::new();
+
+        for member in &self.members {
+            let offset = out.written;
+            let path = self.member_path(member);
+            let size = member.size.unwrap_or(0);
+            let zip64 = size >= ZIP64_SIZE;
+            let (time, date) = dos_time(member.mtime);
+            let mtime = member
+                .mtime
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |mtime| mtime.as_secs().min(u32::MAX as u64) as u32);
+
+            // UTF-8 names, and sizes after the data of files.
+            let flags = match member.size {
+                Some(_) => 0x0808,
+                None => 0x0800,
+            };
+            let needed = if zip64 { 45 } else { 20 };
+            let mut timestamp = Record::new();
+            timestamp.u16(0x5455).u16(5).u8(1).u32(mtime);
+
+            let mut local = Record::new();
+            local
+                .u32(0x0403_4b50)
+                .u16(needed)
+                .u16(flags)
+                .u16(0)
+                .u16(time)
+                .u16(date)
+                .u32(0);
+            let mut extra = timestamp.clone();
+            if zip64 {
+                local.u32(ZIP64_SIZE as u32).u32(ZIP64_SIZE as u32);
+                extra.u16(0x0001).u16(16).u64(0).u64(0);
+            } else {
+                local.u32(0).u32(0);
+            }
+            local
+                .u16(path.len() as u16)
+                .u16(extra.0.len() as u16)
+                .bytes(path.as_bytes())
+                .bytes(&extra.0);
+            out.write_all(&local.0)?;
+
+            let mut crc = 0;
+            if member.size.is_some() {
+                crc = self.copy(member, out, pass, true)?;
+                let mut descriptor = Record::new();
+                descriptor.u32(0x0807_4b50).u32(crc);
+                if zip64 {
+                    descriptor.u64(size).u64(size);
+                } else {
+                    descriptor.u32(size as u32).u32(size as u32);
+                }
+                out.write_all(&descriptor.0)?;
+            }
+
+            let mut extra = timestamp;
+            let mut wide = Record::new();
+            if zip64 {
+                wide.u64(size).u64(size);
+            }
+            if offset >= ZIP64_SIZE {
+                wide.u64(offset);
+            }
+            if !wide.0.is_empty() {
+                extra.u16(0x0001).u16(wide.0.len() as u16).bytes(&wide.0);
+            }
+            let external = match member.size {
+                Some(_) => 0o100_644 << 16,
+                None => (0o40_755 << 16) | 0x10,
+            };
+            let mut entry = Record::new();
+            entry
+                .u32(0x0201_4b50)
+                .u16(0x0300 | needed)
+                .u16(needed)
+                .u16(flags)
+                .u16(0)
+                .u16(time)
+                .u16(date)
+                .u32(crc)
+                .u32(size.min(ZIP64_SIZE) as u32)
+                .u32(size.min(ZIP64_SIZE) as u32)
+                .u16(path.len() as u16)
+                .u16(extra.0.len() as u16)
+                .u16(0)
+                .u16(0)
+                .u16(0)
+                .u32(external)
+                .u32(offset.min(ZIP64_SIZE) as u32)
+                .bytes(path.as_bytes())
+                .bytes(&extra.0);
+            central.extend(entry.0);
+        }
+
+        let offset = out.written;
+        let size = central.len() as u64;
+        let count = self.members.len();
+        out.write_all(&central)?;
+
+        let mut end = Record::new();
+        if count >= ZIP64_COUNT || offset >= ZIP64_SIZE || size >= ZIP64_SIZE {
+            let at = out.written;
+            end.u32(0x0606_4b50)
+                .u64(44)
+                .u16(0x0300 | 45)
+                .u16(45)
+                .u32(0)
+                .u32(0)
+                .u64(count as u64)
+                .u64(count as u64)
+                .u64(size)
+                .u64(offset);
+            end.u32(0x0706_4b50).u32(0).u64(at).u32(1);
+        }
+        let count = count.min(ZIP64_COUNT) as u16;
+        end.u32(0x0605_4b50)
+            .u16(0)
+            .u16(0)
+            .u16(count)
+            .u16(count)
+            .u32(size.min(ZIP64_SIZE) as u32)
+            .u32(offset.min(ZIP64_SIZE) as u32)
+            .u16(0);
+        out.write_all(&end.0)
+    }
+
+    /// Sends as many bytes of a file as it had when the archive was
+    /// made, cut or filled with zeros if it changed or went away since,
+    /// and returns their CRC-32 if `crc` is set. The archive keeps the
+    /// length announced for it either way.
+    fn copy<W: Write>(
+        &self,
+        member: &Member,
+        out: &mut Counted<W>,
+        pass: Pass,
+        crc: bool,
+    ) -> io::Result<u32> {
+        let size = member.size.unwrap_or(0);
+        if pass == Pass::Measure {
+            out.written += size;
+            return Ok(0);
+        }
+
+        let file_path = self.full_path.join(&member.path);
+        let file: Box<dyn Read> = match File::open(&file_path) {
+            Ok(file) => Box::new(file.take(size)),
+            Err(err) => {
+                warn!("Failed to read {}: {}", file_path.display(), err);
+                Box::new(io::empty())
+            }
+        };
+        let mut reader = file.chain(io::repeat(0)).take(size);
+        let mut checksum = Crc32::new();
+        let mut buffer = vec![0; BUFFER_LEN.min(size as usize)];
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if crc {
+                checksum.update(&buffer[..read]);
+            }
+            out.write_all(&buffer[..read])?;
+        }
+        Ok(checksum.finish())
+    }
+}
+
+/// The metadata of a regular file that can be opened, `None` for other
+/// kinds of files.
+fn readable(path: &Path) -> io::Result<Option<Metadata>> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    File::open(path)?;
+    Ok(Some(metadata))
+}
+
+/// Bytes written through to `inner`, for the offsets zip records.
+struct Counted<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Counted<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, written: 0 }
+    }
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Little-endian fields of a zip record.
+#[derive(Clone)]
+struct Record(Vec<u8>);
+
+impl Record {
+    fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+}
+
+fn tar_header(name: &str, prefix: &str, size: u64, mtime: u64, mode: u32, kind: u8) -> [u8; 512] {
+    let mut header = [0; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size.min(TAR_MAX));
+    octal(&mut header[136..148], mtime.min(TAR_MAX));
+    header[156] = kind;
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is summed with its own field as spaces.
+    header[148..156].fill(b' ');
+    let checksum = header.iter().map(|&byte| byte as u64).sum::<u64>();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+/// Fills a field with `value` in octal digits and a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+fn tar_padding(len: u64) -> usize {
+    (TAR_BLOCK - (len % TAR_BLOCK as u64) as usize) % TAR_BLOCK
+}
+
+/// Splits a path into ustar's prefix and name at a slash, if it fits.
+fn split_ustar(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+    path.trim_end_matches('/')
+        .match_indices('/')
+        .map(|(at, _)| (&path[..at], &path[at + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+}
+
+/// A pax record, whose length counts its own digits.
+fn pax_record(key: &str, value: &str) -> String {
+    let text = format!(" {}={}\n", key, value);
+    let mut len = text.len();
+    while len != text.len() + len.to_string().len() {
+        len = text.len() + len.to_string().len();
+    }
+    format!("{}{}", len, text)
+}
+
+/// The longest start of `text` within `len` bytes.
+fn truncate(text: &str, len: usize) -> &str {
+    let mut end = len.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// MS-DOS time and date of `mtime` in UTC, within the years they hold.
+fn dos_time(mtime: SystemTime) -> (u16, u16) {
+    let mtime = DateTime::<Utc>::from(mtime);
+    match mtime.year() {
+        ..1980 => (0, (1 << 5) | 1),
+        2108.. => (0xbf7d, 0xff9f),
+        year => (
+            ((mtime.hour() << 11) | (mtime.minute() << 5) | (mtime.second() / 2)) as u16,
+            (((year - 1980) as u32) << 9 | (mtime.month() << 5) | mtime.day()) as u16,
+        ),
+    }
+}
+
+/// Names the download, in ASCII for old clients and in full for the
+/// others.
+fn content_disposition(filename: &str) -> String {
+    let ascii = filename
+        .chars()
+        .map(|char| match char {
+            ' '..='~' if char != '"' && char != '\\' => char,
+            _ => '_',
+        })
+        .collect::<String>();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii,
+        format::escape_uri(filename)
+    )
+}
@@ -0,0 +1,815 @@
+use chrono::NaiveDate;
+use spdlog::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, Metadata};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::archive::ArchiveFormat;
+use crate::compress::Inflate;
+use crate::explorer::ExplorerError;
+use crate::file::{Download, Source};
+use crate::{ExplorerEntry, Filter, Layout, ListOptions};
+
+const TAR_BLOCK: u64 = 512;
+
+/// Longest pax or GNU extended header read.
+const TAR_EXTENDED_MAX: u64 = 1 << 20;
+
+/// Most bytes a zip's end record and its comment take.
+const ZIP_TAIL: u64 = 22 + 0xffff;
+
+const ZIP_LOCAL: u32 = 0x0403_4b50;
+const ZIP_CENTRAL: u32 = 0x0201_4b50;
+const ZIP_END: u32 = 0x0605_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+
+#[derive(Debug, Error)]
+pub enum BundleError {
+    #[error("Failed to read {0}: {1}")]
+    Io(String, #[source] io::Error),
+    #[error("Archive {0} is corrupt: {1}")]
+    Corrupt(String, &'static str),
+    #[error("Archive {0} has more than {1} members")]
+    TooManyMembers(String, usize),
+    #[error("Can't extract {0} from {1}: {2}")]
+    Unsupported(String, String, &'static str),
+}
+
+/// An archive member as its header tells it, before it is put in the
+/// tree.
+struct Header {
+    path: String,
+    mtime: Option<SystemTime>,
+    /// Size and place of files, `None` for directories.
+    file: Option<(u64, Data)>,
+}
+
+/// Where the bytes of a member are.
+#[derive(Clone, Copy)]
+enum Data {
+    /// In a tar file from this offset.
+    Tar(u64),
+    /// In the decompressed stream of a `.tar.gz` from this offset.
+    TarGz(u64),
+    /// In a zip file after the local header at `header`.
+    Zip {
+        header: u64,
+        method: u16,
+        encrypted: bool,
+    },
+}
+
+struct Member {
+    name: String,
+    mtime: SystemTime,
+    file: Option<(u64, Data)>,
+    /// Members of a directory, by their index.
+    children: Vec<usize>,
+}
+
+impl Member {
+    fn entry(&self) -> ExplorerEntry {
+        match self.file {
+            Some((size, _)) => ExplorerEntry::File {
+                mtime: self.mtime,
+                name: self.name.clone(),
+                path: None,
+                size,
+                hash: None,
+            },
+            None => ExplorerEntry::Directory {
+                mtime: self.mtime,
+                name: self.name.clone(),
+                path: None,
+                children: None,
+                child_count: None,
+            },
+        }
+    }
+}
+
+/// The members of a tar or zip file on disk, read from their headers
+/// to browse the archive as a tree of directories.
+pub(crate) struct Bundle {
+    /// Directories and files, the top directory first.
+    members: Vec<Member>,
+    /// Indexes of members by their path below the top directory.
+    paths: HashMap<String, usize>,
+}
+
+impl Bundle {
+    /// Reads the headers of the archive at `full_path`, giving up past
+    /// `limit` members.
+    fn read(
+        full_path: &Path,
+        format: ArchiveFormat,
+        metadata: &Metadata,
+        limit: usize,
+    ) -> Result<Self, BundleError> {
+        let file = File::open(full_path).map_err(|err| io_error(full_path, err))?;
+        let headers = match format {
+            // Seeking past the end doesn't fail, so a cut archive is
+            // told by its length.
+            ArchiveFormat::Tar => tar(
+                &mut BufReader::new(&file),
+                |reader, len| {
+                    let end = reader.stream_position()?.checked_add(len);
+                    match (end, i64::try_from(len)) {
+                        (Some(end), Ok(len)) if end <= metadata.len() => reader.seek_relative(len),
+                        (Some(_), Ok(_)) => Err(io::ErrorKind::UnexpectedEof.into()),
+                        _ => Err(io::ErrorKind::InvalidData.into()),
+                    }
+                },
+                Data::Tar,
+                full_path,
+                limit,
+            ),
+            ArchiveFormat::TarGz => tar(
+                &mut Inflate::gzip(&file),
+                |reader, len| {
+                    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+                    match skipped == len {
+                        true => Ok(()),
+                        false => Err(io::ErrorKind::UnexpectedEof.into()),
+                    }
+                },
+                Data::TarGz,
+                full_path,
+                limit,
+            ),
+            ArchiveFormat::Zip => zip(&file, metadata.len(), full_path, limit),
+        }?;
+
+        // Directories the archive leaves out take its own mtime.
+        let mtime = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let mut bundle = Self {
+            members: vec![Member {
+                name: String::new(),
+                mtime,
+                file: None,
+                children: Vec::new(),
+            }],
+            paths: HashMap::new(),
+        };
+        for header in headers {
+            bundle.add(header, mtime);
+        }
+        Ok(bundle)
+    }
+
+    /// Puts a member in the tree with the directories above it. Paths
+    /// climbing out of the archive are left out, and later members
+    /// replace earlier ones of the same path, as extracting does.
+    fn add(&mut self, header: Header, mtime: SystemTime) {
+        let mut segments = Vec::new();
+        for segment in header.path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return,
+                _ => segments.push(segment),
+            }
+        }
+
+        let mut parent = 0;
+        let mut path = String::new();
+        for (at, segment) in segments.iter().enumerate() {
+            if at > 0 {
+                path.push('/');
+            }
+            path.push_str(segment);
+            let last = at + 1 == segments.len();
+
+            if let Some(&found) = self.paths.get(&path) {
+                let member = &mut self.members[found];
+                // Nothing goes below a file, nor replaces a directory
+                // holding members.
+                match (last, &member.file) {
+                    (false, Some(_)) => return,
+                    (false, None) => parent = found,
+                    (true, None) if !member.children.is_empty() && header.file.is_some() => {}
+                    (true, _) => {
+                        member.mtime = header.mtime.unwrap_or(mtime);
+                        member.file = header.file;
+                    }
+                }
+                continue;
+            }
+
+            let index = self.members.len();
+            self.members.push(Member {
+                name: segment.to_string(),
+                mtime: header.mtime.filter(|_| last).unwrap_or(mtime),
+                file: header.file.filter(|_| last),
+                children: Vec::new(),
+            });
+            self.members[parent].children.push(index);
+            self.paths.insert(path.clone(), index);
+            parent = index;
+        }
+    }
+
+    /// The member at `path` below the top directory, which is the empty
+    /// path.
+    pub(crate) fn find(&self, path: &str) -> Option<usize> {
+        let mut segments = Vec::new();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop()?;
+                }
+                _ => segments.push(segment),
+            }
+        }
+        match segments.is_empty() {
+            true => Some(0),
+            false => self.paths.get(&segments.join("/")).copied(),
+        }
+    }
+
+    pub(crate) fn is_file(&self, index: usize) -> bool {
+        self.members[index].file.is_some()
+    }
+
+    /// Lists the directory at `index` as `walk::list` lists one on
+    /// disk, down to `options.depth` levels.
+    pub(crate) fn list(
+        &self,
+        index: usize,
+        options: &ListOptions,
+        max_entries: Option<usize>,
+    ) -> Result<Vec<ExplorerEntry>, ExplorerError> {
+        let prefix = (options.layout == Layout::Flat && options.depth > 1).then_some("");
+        self.directory(index, prefix, options.depth, options, max_entries)
+    }
+
+    fn directory(
+        &self,
+        index: usize,
+        prefix: Option<&str>,
+        depth: usize,
+        options: &ListOptions,
+        max_entries: Option<usize>,
+    ) -> Result<Vec<ExplorerEntry>, ExplorerError> {
+        let tree = options.layout == Layout::Tree;
+        let mut entries = Vec::new();
+        let mut subdirs = Vec::new();
+        let mut count = 0;
+
+        for &child in &self.members[index].children {
+            let member = &self.members[child];
+            let is_dir = member.file.is_none();
+            let path = prefix.map(|prefix| format!("{}{}", prefix, member.name));
+            if is_dir && !tree && depth > 1 {
+                if let Some(path) = &path {
+                    subdirs.push((child, format!("{}/", path)));
+                }
+            }
+
+            // Trees show every directory, as walks do.
+            let filter = match tree && is_dir {
+                true => &Filter::default(),
+                false => &options.filter,
+            };
+            let size = member.file.map_or(0, |(size, _)| size);
+            if !filter.accepts_name(&member.name)
+                || !filter.accepts_attributes(is_dir, false, size, member.mtime)
+            {
+                continue;
+            }
+            count += 1;
+            if let Some(max) = max_entries.filter(|&max| count > max) {
+                return Err(ExplorerError::TooManyEntries(max));
+            }
+
+            let mut entry = member.entry();
+            if let Some(path) = path {
+                entry = entry.with_path(path);
+            }
+            if tree && is_dir {
+                entry = match depth {
+                    1 => entry.with_contents(None, Some(member.children.len())),
+                    _ => {
+                        let mut children =
+                            self.directory(child, None, depth - 1, options, max_entries)?;
+                        children.sort_by(|a, b| options.sort.compare(a, b));
+                        entry.with_contents(Some(children), None)
+                    }
+                };
+            }
+            entries.push(entry);
+        }
+
+        for (child, prefix) in subdirs {
+            let nested = self.directory(child, Some(&prefix), depth - 1, options, max_entries)?;
+            entries.extend(nested);
+        }
+        Ok(entries)
+    }
+
+    /// The file member at `index`, to be read out of the archive at
+    /// `full_path` as it is sent.
+    pub(crate) fn open(&self, full_path: &Path, index: usize) -> Result<Download, BundleError> {
+        let member = &self.members[index];
+        let Some((size, data)) = member.file else {
+            return Err(io_error(full_path, io::ErrorKind::IsADirectory.into()));
+        };
+        let unsupported = |reason| {
+            let archive = full_path.to_string_lossy().into_owned();
+            BundleError::Unsupported(member.name.clone(), archive, reason)
+        };
+
+        let file = File::open(full_path).map_err(|err| io_error(full_path, err))?;
+        let source = match data {
+            Data::Tar(start) => Source::Plain(start),
+            Data::TarGz(skip) => Source::Inflated {
+                start: 0,
+                gzip: true,
+                skip,
+            },
+            Data::Zip {
+                encrypted: true, ..
+            } => return Err(unsupported("encrypted")),
+            Data::Zip { header, method, .. } => {
+                let mut local = [0; 30];
+                file.read_exact_at(&mut local, header)
+                    .map_err(|err| io_error(full_path, err))?;
+                if u32_at(&local, 0) != ZIP_LOCAL {
+                    return Err(corrupt(full_path, "Bad local header"));
+                }
+                let start = header + 30 + u16_at(&local, 26) as u64 + u16_at(&local, 28) as u64;
+                match method {
+                    0 => Source::Plain(start),
+                    8 => Source::Inflated {
+                        start,
+                        gzip: false,
+                        skip: 0,
+                    },
+                    _ => return Err(unsupported("compression method")),
+                }
+            }
+        };
+        Ok(Download::member(
+            file,
+            source,
+            size,
+            member.mtime,
+            &member.name,
+        ))
+    }
+}
+
+/// Archives read so far, least recently used first out. An archive is
+/// read again once its file is replaced or changes size or mtime.
+pub struct BundleCache {
+    /// Most archives kept.
+    capacity: usize,
+    state: Mutex<Cached>,
+}
+
+/// What tells versions of an archive file apart: its device, inode,
+/// size and mtime.
+type Stamp = (u64, u64, u64, Option<SystemTime>);
+
+#[derive(Default)]
+struct Cached {
+    bundles: HashMap<PathBuf, Slot>,
+    /// Paths of cached archives by last use.
+    order: BTreeMap<u64, PathBuf>,
+    /// Counts uses, so none is ever repeated.
+    clock: u64,
+}
+
+struct Slot {
+    stamp: Stamp,
+    used: u64,
+    bundle: Arc<Bundle>,
+}
+
+impl BundleCache {
+    /// Keeps up to `capacity` archives.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(Cached::default()),
+        }
+    }
+
+    /// The archive of `format` at `full_path`, read unless it is cached
+    /// and unchanged. Archives of more than `limit` members aren't read.
+    pub(crate) fn get(
+        &self,
+        full_path: &Path,
+        format: ArchiveFormat,
+        metadata: &Metadata,
+        limit: usize,
+    ) -> Result<Arc<Bundle>, BundleError> {
+        let stamp = (
+            metadata.dev(),
+            metadata.ino(),
+            metadata.len(),
+            metadata.modified().ok(),
+        );
+
+        {
+            let mut state = self.lock();
+            let state = &mut *state;
+            if let Some(slot) = state.bundles.get_mut(full_path) {
+                if slot.stamp == stamp {
+                    state.clock += 1;
+                    state.order.remove(&slot.used);
+                    state.order.insert(state.clock, full_path.to_path_buf());
+                    slot.used = state.clock;
+                    return Ok(slot.bundle.clone());
+                }
+            }
+        }
+
+        // Read without the lock, so that cached archives are served
+        // meanwhile.
+        let start_time = Instant::now();
+        let bundle = Arc::new(Bundle::read(full_path, format, metadata, limit)?);
+        let elapsed = start_time.elapsed().as_micros() as f64 / 1000.0;
+        debug!(
+            "Read {} members of {} in {}ms",
+            bundle.members.len() - 1,
+            full_path.display(),
+            elapsed
+        );
+
+        let mut state = self.lock();
+        let state = &mut *state;
+        state.clock += 1;
+        let slot = Slot {
+            stamp,
+            used: state.clock,
+            bundle: bundle.clone(),
+        };
+        if let Some(replaced) = state.bundles.insert(full_path.to_path_buf(), slot) {
+            state.order.remove(&replaced.used);
+        }
+        state.order.insert(state.clock, full_path.to_path_buf());
+        while state.bundles.len() > self.capacity {
+            let Some((_, path)) = state.order.pop_first() else {
+                break;
+            };
+            state.bundles.remove(&path);
+        }
+
+        Ok(bundle)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cached> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Headers of a tar stream, passing over member data with `skip` and
+/// placing it with `data`. Reads pax and GNU extended headers, and
+/// leaves out links and special files.
+fn tar<R: Read>(
+    reader: &mut R,
+    skip: impl Fn(&mut R, u64) -> io::Result<()>,
+    data: fn(u64) -> Data,
+    full_path: &Path,
+    limit: usize,
+) -> Result<Vec<Header>, BundleError> {
+    let io = |err| io_error(full_path, err);
+    let mut headers = Vec::new();
+    let mut block = [0; TAR_BLOCK as usize];
+    let mut at = 0;
+    // Set by extended headers for the member after them.
+    let mut long_path = None;
+    let mut pax = Pax::default();
+
+    loop {
+        if !read_block(reader, &mut block).map_err(io)? || block.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        at += TAR_BLOCK;
+        if !tar_checksum(&block) {
+            return Err(corrupt(full_path, "Bad header checksum"));
+        }
+        let size = tar_number(&block[124..136]).ok_or_else(|| corrupt(full_path, "Bad size"))?;
+
+        let kind = block[156];
+        if matches!(kind, b'x' | b'L') {
+            if size > TAR_EXTENDED_MAX {
+                return Err(corrupt(full_path, "Extended header too long"));
+            }
+            let mut extended = vec![0; padded(size) as usize];
+            reader.read_exact(&mut extended).map_err(io)?;
+            at += extended.len() as u64;
+            extended.truncate(size as usize);
+            match kind {
+                b'L' => long_path = Some(text(&extended)),
+                _ => pax = Pax::parse(&extended).ok_or_else(|| corrupt(full_path, "Bad pax"))?,
+            }
+            continue;
+        }
+
+        let size = pax.size.take().unwrap_or(size);
+        let path = pax
+            .path
+            .take()
+            .or(long_path.take())
+            .unwrap_or_else(|| ustar_path(&block));
+        let mtime = pax.mtime.take().or_else(|| {
+            let mtime = tar_number(&block[136..148])?;
+            UNIX_EPOCH.checked_add(Duration::from_secs(mtime))
+        });
+
+        // Old archives mark directories with a slash alone.
+        let file = match kind {
+            b'0' | b'\0' | b'7' if !path.ends_with('/') => Some(Some((size, data(at)))),
+            b'0' | b'\0' | b'7' | b'5' => Some(None),
+            _ => None,
+        };
+        if let Some(file) = file {
+            if headers.len() == limit {
+                return Err(BundleError::TooManyMembers(display(full_path), limit));
+            }
+            headers.push(Header { path, mtime, file });
+        }
+
+        skip(reader, padded(size)).map_err(io)?;
+        at += padded(size);
+    }
+    Ok(headers)
+}
+
+/// Fills `block`, or returns `false` at the end of the input.
+fn read_block<R: Read>(reader: &mut R, block: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+/// Records of a pax extended header that matter to a listing.
+#[derive(Default)]
+struct Pax {
+    path: Option<String>,
+    size: Option<u64>,
+    mtime: Option<SystemTime>,
+}
+
+impl Pax {
+    /// Reads records of the form `<len> <key>=<value>\n`.
+    fn parse(mut records: &[u8]) -> Option<Self> {
+        let mut pax = Self::default();
+        while !records.is_empty() {
+            let space = records.iter().position(|&byte| byte == b' ')?;
+            let len = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+            let record = records.get(space + 1..len)?.strip_suffix(b"\n")?;
+            records = &records[len..];
+
+            let at = record.iter().position(|&byte| byte == b'=')?;
+            let value = &record[at + 1..];
+            match &record[..at] {
+                b"path" => pax.path = Some(text(value)),
+                b"size" => pax.size = Some(std::str::from_utf8(value).ok()?.parse().ok()?),
+                b"mtime" => {
+                    // Seconds may have a fraction, and times before
+                    // 1970 are left to the header.
+                    let value = std::str::from_utf8(value).ok()?;
+                    let seconds = value.split('.').next()?;
+                    pax.mtime = seconds
+                        .parse()
+                        .ok()
+                        .and_then(|seconds| UNIX_EPOCH.checked_add(Duration::from_secs(seconds)));
+                }
+                _ => {}
+            }
+        }
+        Some(pax)
+    }
+}
+
+/// The path of a ustar header, joined to its prefix in POSIX ones.
+fn ustar_path(block: &[u8]) -> String {
+    let name = text(&block[..100]);
+    let prefix = match &block[257..263] {
+        b"ustar\0" => text(&block[345..500]),
+        _ => String::new(),
+    };
+    match prefix.is_empty() {
+        true => name,
+        false => format!("{}/{}", prefix, name),
+    }
+}
+
+/// A numeric field in octal digits, or in base 256 as GNU tar writes
+/// large values.
+fn tar_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold((field[0] & 0x7f) as u64, |value, &byte| {
+                value.checked_mul(256).map(|value| value | byte as u64)
+            });
+    }
+    let digits = text(field);
+    let digits = digits.trim_matches([' ', '\0']);
+    match digits.is_empty() {
+        true => Some(0),
+        false => u64::from_str_radix(digits, 8).ok(),
+    }
+}
+
+/// Whether a header matches its checksum, summed with the field itself
+/// as spaces. Some old archives summed signed bytes.
+fn tar_checksum(block: &[u8]) -> bool {
+    let Some(checksum) = tar_number(&block[148..156]) else {
+        return false;
+    };
+    let spaces = 8 * b' ' as u64;
+    let field = &block[148..156];
+    let unsigned = block.iter().map(|&byte| byte as u64).sum::<u64>()
+        - field.iter().map(|&byte| byte as u64).sum::<u64>()
+        + spaces;
+    let signed = block.iter().map(|&byte| byte as i8 as i64).sum::<i64>()
+        - field.iter().map(|&byte| byte as i8 as i64).sum::<i64>()
+        + spaces as i64;
+    checksum == unsigned || checksum as i64 == signed
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(TAR_BLOCK) * TAR_BLOCK
+}
+
+/// Headers of a zip's central directory, zip64 included.
+fn zip(file: &File, len: u64, full_path: &Path, limit: usize) -> Result<Vec<Header>, BundleError> {
+    let io = |err| io_error(full_path, err);
+
+    let tail_len = len.min(ZIP_TAIL);
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact_at(&mut tail, len - tail_len).map_err(io)?;
+    // The end record is the last one whose comment reaches the end.
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&at| {
+            u32_at(&tail, at) == ZIP_END && at + 22 + u16_at(&tail, at + 20) as usize <= tail.len()
+        })
+        .ok_or_else(|| corrupt(full_path, "No end of central directory"))?;
+
+    let mut count = u16_at(&tail, end + 10) as u64;
+    let mut size = u32_at(&tail, end + 12) as u64;
+    let mut offset = u32_at(&tail, end + 16) as u64;
+    let locator = end
+        .checked_sub(20)
+        .filter(|&at| u32_at(&tail, at) == ZIP64_LOCATOR);
+    if let Some(locator) = locator {
+        let mut record = [0; 56];
+        file.read_exact_at(&mut record, u64_at(&tail, locator + 8))
+            .map_err(io)?;
+        if u32_at(&record, 0) != ZIP64_END {
+            return Err(corrupt(full_path, "Bad zip64 end of central directory"));
+        }
+        count = u64_at(&record, 32);
+        size = u64_at(&record, 40);
+        offset = u64_at(&record, 48);
+    }
+    if count > limit as u64 {
+        return Err(BundleError::TooManyMembers(display(full_path), limit));
+    }
+    if offset.checked_add(size).is_none_or(|end| end > len) {
+        return Err(corrupt(full_path, "Central directory out of bounds"));
+    }
+
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(offset)).map_err(io)?;
+    let mut reader = reader.take(size);
+    let mut headers = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut fixed = [0; 46];
+        reader.read_exact(&mut fixed).map_err(io)?;
+        if u32_at(&fixed, 0) != ZIP_CENTRAL {
+            return Err(corrupt(full_path, "Bad central directory entry"));
+        }
+        let mut name = vec![0; u16_at(&fixed, 28) as usize];
+        let mut extra = vec![0; u16_at(&fixed, 30) as usize];
+        let mut comment = vec![0; u16_at(&fixed, 32) as usize];
+        for field in [&mut name, &mut extra, &mut comment] {
+            reader.read_exact(field).map_err(io)?;
+        }
+
+        let flags = u16_at(&fixed, 8);
+        let method = u16_at(&fixed, 10);
+        let mut mtime = dos_time(u16_at(&fixed, 14), u16_at(&fixed, 12));
+        let mut file_size = u32_at(&fixed, 24) as u64;
+        let mut header = u32_at(&fixed, 42) as u64;
+
+        let mut fields = extra.as_slice();
+        while fields.len() >= 4 {
+            let id = u16_at(fields, 0);
+            let data = fields.get(4..4 + u16_at(fields, 2) as usize).unwrap_or(&[]);
+            fields = fields.get(4 + data.len()..).unwrap_or(&[]);
+            match id {
+                // Only the values too large for their own fields are
+                // here, in order.
+                0x0001 => {
+                    let mut wide = data.chunks_exact(8).map(|value| u64_at(value, 0));
+                    if file_size == 0xffff_ffff {
+                        file_size = wide.next().unwrap_or(file_size);
+                    }
+                    if u32_at(&fixed, 20) == 0xffff_ffff {
+                        wide.next();
+                    }
+                    if header == 0xffff_ffff {
+                        header = wide.next().unwrap_or(header);
+                    }
+                }
+                0x5455 if data.len() >= 5 && data[0] & 1 != 0 => {
+                    mtime = UNIX_EPOCH.checked_add(Duration::from_secs(u32_at(data, 1) as u64));
+                }
+                _ => {}
+            }
+        }
+
+        // Unix symlinks hold their target, and aren't listed.
+        let unix = u16_at(&fixed, 4) >> 8 == 3;
+        if unix && (u32_at(&fixed, 38) >> 16) & 0o170_000 == 0o120_000 {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&name).replace('\\', "/");
+        let file = (!path.ends_with('/')).then_some((
+            file_size,
+            Data::Zip {
+                header,
+                method,
+                encrypted: flags & 1 != 0,
+            },
+        ));
+        headers.push(Header { path, mtime, file });
+    }
+    Ok(headers)
+}
+
+/// An MS-DOS date and time, taken for UTC as zip doesn't say.
+fn dos_time(date: u16, time: u16) -> Option<SystemTime> {
+    let date = NaiveDate::from_ymd_opt(
+        1980 + (date >> 9) as i32,
+        (date >> 5 & 15) as u32,
+        (date & 31) as u32,
+    )?;
+    let time = date.and_hms_opt(
+        (time >> 11) as u32,
+        (time >> 5 & 63) as u32,
+        (time & 31) as u32 * 2,
+    )?;
+    Some(time.and_utc().into())
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// Text up to the first NUL.
+fn text(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn corrupt(full_path: &Path, reason: &'static str) -> BundleError {
+    BundleError::Corrupt(display(full_path), reason)
+}
+
+/// Data that doesn't decode or ends early makes the archive corrupt,
+/// other errors are the file's.
+fn io_error(full_path: &Path, err: io::Error) -> BundleError {
+    match err.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            corrupt(full_path, "Data ends early or doesn't decode")
+        }
+        _ => BundleError::Io(display(full_path), err),
+    }
+}
@@ -0,0 +1,401 @@
+use spdlog::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::SystemTime;
+
+use crate::inotify::{self, Event, Inotify};
+use crate::{Listing, Variants};
+
+/// Rendered listings of single directories, least recently used
+/// first out. Directories are watched with inotify, or checked for a
+/// new mtime once the kernel's watch limit is reached; the mtime misses
+/// files rewritten in place.
+pub struct ListingCache {
+    shared: Arc<Shared>,
+}
+
+/// What `watch` saw of a directory before it was read, to tell
+/// whether a listing made from it may be cached.
+pub struct Ticket {
+    dir: PathBuf,
+    generation: u64,
+}
+
+struct Shared {
+    /// Most bytes of listing bodies kept, compressed copies included.
+    capacity: usize,
+    inotify: Option<Inotify>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    dirs: HashMap<PathBuf, Watched>,
+    /// Paths of each inotify watch. A directory reached through
+    /// several paths has a single watch.
+    watches: HashMap<i32, Vec<PathBuf>>,
+    /// Keys of cached listings by last use.
+    order: BTreeMap<u64, (PathBuf, String)>,
+    size: usize,
+    /// Set once inotify events can't be read, to check mtimes instead.
+    blind: bool,
+    /// Counts uses and generations, so neither is ever repeated.
+    clock: u64,
+}
+
+struct Watched {
+    watch: Watch,
+    /// Changes whenever the directory does.
+    generation: u64,
+    listings: HashMap<String, Slot>,
+}
+
+enum Watch {
+    Inotify(i32),
+    Mtime(Option<SystemTime>),
+}
+
+struct Slot {
+    used: u64,
+    /// Bytes of the body and of the compressed copies made so far.
+    size: usize,
+    listing: Listing,
+}
+
+impl ListingCache {
+    /// Keeps up to `capacity` bytes of listings.
+    pub fn new(capacity: usize) -> Self {
+        let inotify = match Inotify::new() {
+            Ok(inotify) => Some(inotify),
+            Err(err) => {
+                warn!("Failed to start inotify, checking mtimes instead: {}", err);
+                None
+            }
+        };
+
+        let shared = Arc::new(Shared {
+            capacity,
+            inotify,
+            state: Mutex::new(State::default()),
+        });
+
+        if shared.inotify.is_some() {
+            let events = shared.clone();
+            let spawned = thread::Builder::new()
+                .name("rindex-inotify".to_string())
+                .spawn(move || events.read_events());
+            if let Err(err) = spawned {
+                warn!("Failed to watch the cache: {}", err);
+            }
+        }
+
+        Self { shared }
+    }
+
+    /// The listing cached for `dir` under `key`, if the directory is
+    /// unchanged since.
+    pub fn get(&self, dir: &Path, key: &str) -> Option<Listing> {
+        let mut state = self.shared.lock();
+        let watched = state.dirs.get(dir)?;
+
+        if let Watch::Mtime(mtime) = watched.watch {
+            if mtime.is_none() || mtime != modified(dir) {
+                self.shared.forget(&mut state, dir);
+                return None;
+            }
+        }
+
+        let state = &mut *state;
+        state.clock += 1;
+        let slot = state.dirs.get_mut(dir)?.listings.get_mut(key)?;
+        let key = state.order.remove(&slot.used)?;
+        state.order.insert(state.clock, key);
+        slot.used = state.clock;
+        Some(slot.listing.clone())
+    }
+
+    /// Watches `dir` ahead of reading it.
+    pub fn watch(&self, dir: &Path) -> Ticket {
+        let mut state = self.shared.lock();
+
+        if let Some(watched) = state.dirs.get(dir) {
+            return Ticket {
+                dir: dir.to_path_buf(),
+                generation: watched.generation,
+            };
+        }
+
+        let added = match (&self.shared.inotify, state.blind) {
+            (Some(inotify), false) => inotify.add_watch(dir),
+            _ => Err(io::ErrorKind::Unsupported.into()),
+        };
+        let watch = match added {
+            Ok(wd) => {
+                state.watches.entry(wd).or_default().push(dir.to_path_buf());
+                Watch::Inotify(wd)
+            }
+            Err(err) => {
+                debug!("Checking mtimes of {}: {}", dir.display(), err);
+                Watch::Mtime(modified(dir))
+            }
+        };
+
+        state.clock += 1;
+        let generation = state.clock;
+        state.dirs.insert(
+            dir.to_path_buf(),
+            Watched {
+                watch,
+                generation,
+                listings: HashMap::new(),
+            },
+        );
+
+        Ticket {
+            dir: dir.to_path_buf(),
+            generation,
+        }
+    }
+
+    /// Caches `listing` unless the directory changed since `ticket`.
+    /// Compressed copies made of it later count too.
+    pub fn insert(&self, ticket: Ticket, key: String, listing: &Listing) {
+        let mut state = self.shared.lock();
+        let state = &mut *state;
+
+        let Some(watched) = state.dirs.get_mut(&ticket.dir) else {
+            return;
+        };
+        if watched.generation != ticket.generation {
+            return;
+        }
+
+        let len = listing.body.len();
+        if len > self.shared.capacity {
+            if watched.listings.is_empty() {
+                self.shared.forget(state, &ticket.dir);
+            }
+            return;
+        }
+
+        state.clock += 1;
+        let slot = Slot {
+            used: state.clock,
+            size: len,
+            listing: listing.clone(),
+        };
+        if let Some(replaced) = watched.listings.insert(key.clone(), slot) {
+            state.order.remove(&replaced.used);
+            state.size -= replaced.size;
+        }
+        state
+            .order
+            .insert(state.clock, (ticket.dir.clone(), key.clone()));
+        state.size += len;
+        self.shared.shrink(state);
+
+        let shared = Arc::downgrade(&self.shared);
+        let variants = Arc::downgrade(&listing.variants);
+        listing.variants.on_add(move |len| {
+            if let Some(shared) = shared.upgrade() {
+                shared.grow(&ticket.dir, &key, &variants, len);
+            }
+        });
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Counts a compressed copy of the listing cached under `key`, if
+    /// `variants` are still its own, making room for it.
+    fn grow(&self, dir: &Path, key: &str, variants: &Weak<Variants>, len: usize) {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let slot = state
+            .dirs
+            .get_mut(dir)
+            .and_then(|watched| watched.listings.get_mut(key));
+        let Some(slot) = slot else {
+            return;
+        };
+        if !ptr::eq(Arc::as_ptr(&slot.listing.variants), variants.as_ptr()) {
+            return;
+        }
+        slot.size += len;
+        state.size += len;
+        self.shrink(state);
+    }
+
+    /// Evicts the least recently used listings until they fit.
+    fn shrink(&self, state: &mut State) {
+        while state.size > self.capacity {
+            let Some((_, (dir, key))) = state.order.pop_first() else {
+                break;
+            };
+            self.evict(state, &dir, &key);
+        }
+    }
+
+    /// Drops one listing, and the watch once its directory has none.
+    fn evict(&self, state: &mut State, dir: &Path, key: &str) {
+        let Some(watched) = state.dirs.get_mut(dir) else {
+            return;
+        };
+        if let Some(slot) = watched.listings.remove(key) {
+            state.size -= slot.size;
+        }
+        if watched.listings.is_empty() {
+            self.forget(state, dir);
+        }
+    }
+
+    /// Drops every listing of `dir` and stops watching it.
+    fn forget(&self, state: &mut State, dir: &Path) {
+        let Some(watched) = state.dirs.remove(dir) else {
+            return;
+        };
+        for slot in watched.listings.into_values() {
+            state.order.remove(&slot.used);
+            state.size -= slot.size;
+        }
+
+        let Watch::Inotify(wd) = watched.watch else {
+            return;
+        };
+        let Some(paths) = state.watches.get_mut(&wd) else {
+            return;
+        };
+        paths.retain(|path| path != dir);
+        if paths.is_empty() {
+            state.watches.remove(&wd);
+            if let Some(inotify) = &self.inotify {
+                inotify.rm_watch(wd);
+            }
+        }
+    }
+
+    /// Forgets directories as their events arrive, for as long as the
+    /// server runs.
+    fn read_events(&self) {
+        let Some(inotify) = &self.inotify else {
+            return;
+        };
+        let mut buffer = vec![0; inotify::BUFFER_LEN];
+
+        loop {
+            let events = match inotify.read(&mut buffer) {
+                Ok(events) => events,
+                Err(err) => {
+                    warn!("Failed to read inotify events, checking mtimes: {}", err);
+                    let mut state = self.lock();
+                    state.blind = true;
+                    self.clear(&mut state);
+                    return;
+                }
+            };
+
+            let mut state = self.lock();
+            for event in events {
+                match event {
+                    Event::Changed(wd) => {
+                        for dir in state.watches.get(&wd).cloned().unwrap_or_default() {
+                            self.forget(&mut state, &dir);
+                        }
+                    }
+                    Event::Overflow => {
+                        info!("Inotify queue overflowed, clearing the cache");
+                        self.clear(&mut state);
+                    }
+                }
+            }
+        }
+    }
+
+    fn clear(&self, state: &mut State) {
+        let dirs = state.dirs.keys().cloned().collect::<Vec<_>>();
+        for dir in dirs {
+            self.forget(state, &dir);
+        }
+    }
+}
+
+fn modified(dir: &Path) -> Option<SystemTime> {
+    fs::metadata(dir)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use super::ListingCache;
+    use crate::{Encoding, Format, Listing, Page};
+
+    fn listing(body: &str) -> Listing {
+        Listing {
+            body: body.to_string(),
+            format: Format::Json,
+            page: Page {
+                total: 0,
+                next: None,
+            },
+            complete: None,
+            etag: String::new(),
+            modified: None,
+            variants: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn compressed_copies_count_against_the_capacity() {
+        let dir = std::env::temp_dir().join(format!("rindex-variants-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for sub in ["a", "b"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let cache = ListingCache::new(250);
+        let size = || cache.shared.lock().size;
+
+        let a = listing(&"a".repeat(100));
+        cache.insert(cache.watch(&dir.join("a")), "a".to_string(), &a);
+        let b = listing(&"b".repeat(100));
+        cache.insert(cache.watch(&dir.join("b")), "b".to_string(), &b);
+        assert_eq!(size(), 200);
+
+        // A copy made twice is counted once.
+        let cached = cache.get(&dir.join("b"), "b").unwrap();
+        cached
+            .variants
+            .get_or_compress(Encoding::Gzip, || vec![0; 20]);
+        cached
+            .variants
+            .get_or_compress(Encoding::Gzip, || vec![0; 20]);
+        assert_eq!(size(), 220);
+
+        // Room for another copy is made by evicting the oldest listing.
+        let cached = cache.get(&dir.join("b"), "b").unwrap();
+        cached
+            .variants
+            .get_or_compress(Encoding::Brotli, || vec![0; 40]);
+        assert_eq!(size(), 160);
+        assert!(cache.get(&dir.join("a"), "a").is_none());
+
+        // Copies of listings no longer cached aren't counted.
+        a.variants.get_or_compress(Encoding::Zstd, || vec![0; 40]);
+        cache.insert(cache.watch(&dir.join("b")), "b".to_string(), &listing("b"));
+        b.variants.get_or_compress(Encoding::Zstd, || vec![0; 40]);
+        assert_eq!(size(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
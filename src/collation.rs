@@ -0,0 +1,305 @@
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// How entry names are compared. Every collation falls back to
+/// byte order so that the result is total.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Collation {
+    #[default]
+    Bytes,
+    /// Version sort, as in `ls -v`.
+    Natural,
+    /// ASCII and Unicode case folding.
+    Nocase,
+    /// Case and accent insensitive first, then unaccented before
+    /// accented letters, then lowercase before uppercase. Accents are known for Latin, Greek
+    /// without polytonic marks and Cyrillic `ё`, and combining marks are
+    /// dropped from any script.
+    Unicode,
+}
+
+impl FromStr for Collation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bytes" => Ok(Self::Bytes),
+            "natural" => Ok(Self::Natural),
+            "nocase" => Ok(Self::Nocase),
+            "unicode" => Ok(Self::Unicode),
+            _ => Err(format!("Unknown collation: {}", value)),
+        }
+    }
+}
+
+impl Collation {
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            Self::Bytes => Ordering::Equal,
+            Self::Natural => version_compare(a.as_bytes(), b.as_bytes()),
+            Self::Nocase => lowercase(a).cmp(lowercase(b)),
+            Self::Unicode => {
+                let (mut base_a, mut base_b) = (String::new(), String::new());
+                a.chars().for_each(|char| fold(char, &mut base_a));
+                b.chars().for_each(|char| fold(char, &mut base_b));
+                let uppercase =
+                    |text: &str| text.chars().map(char::is_uppercase).collect::<Vec<_>>();
+                base_a
+                    .cmp(&base_b)
+                    .then_with(|| accents(a).cmp(&accents(b)))
+                    .then_with(|| uppercase(a).cmp(&uppercase(b)))
+            }
+        }
+        .then_with(|| a.cmp(b))
+    }
+}
+
+fn lowercase(text: &str) -> impl Iterator<Item = char> + '_ {
+    text.chars().flat_map(fold_case)
+}
+
+/// Lowercase of `char`, with final sigma folded as Unicode's case
+/// folding does.
+fn fold_case(char: char) -> impl Iterator<Item = char> {
+    char.to_lowercase()
+        .map(|char| if char == 'ς' { 'σ' } else { char })
+}
+
+/// Base letters of Latin Extended-A, by the last code point of each run.
+const LATIN_EXTENDED_A: [(char, &str); 22] = [
+    ('\u{105}', "a"),
+    ('\u{10d}', "c"),
+    ('\u{111}', "d"),
+    ('\u{11b}', "e"),
+    ('\u{123}', "g"),
+    ('\u{127}', "h"),
+    ('\u{131}', "i"),
+    ('\u{133}', "ij"),
+    ('\u{135}', "j"),
+    ('\u{138}', "k"),
+    ('\u{142}', "l"),
+    ('\u{14b}', "n"),
+    ('\u{151}', "o"),
+    ('\u{153}', "oe"),
+    ('\u{159}', "r"),
+    ('\u{161}', "s"),
+    ('\u{167}', "t"),
+    ('\u{173}', "u"),
+    ('\u{175}', "w"),
+    ('\u{178}', "y"),
+    ('\u{17e}', "z"),
+    ('\u{17f}', "s"),
+];
+
+/// Base letters of Latin Extended Additional, by the last code point
+/// of each run.
+const LATIN_EXTENDED_ADDITIONAL: [(char, &str); 41] = [
+    ('\u{1e01}', "a"),
+    ('\u{1e07}', "b"),
+    ('\u{1e09}', "c"),
+    ('\u{1e13}', "d"),
+    ('\u{1e1d}', "e"),
+    ('\u{1e1f}', "f"),
+    ('\u{1e21}', "g"),
+    ('\u{1e2b}', "h"),
+    ('\u{1e2f}', "i"),
+    ('\u{1e35}', "k"),
+    ('\u{1e3d}', "l"),
+    ('\u{1e43}', "m"),
+    ('\u{1e4b}', "n"),
+    ('\u{1e53}', "o"),
+    ('\u{1e57}', "p"),
+    ('\u{1e5f}', "r"),
+    ('\u{1e69}', "s"),
+    ('\u{1e71}', "t"),
+    ('\u{1e7b}', "u"),
+    ('\u{1e7f}', "v"),
+    ('\u{1e89}', "w"),
+    ('\u{1e8d}', "x"),
+    ('\u{1e8f}', "y"),
+    ('\u{1e95}', "z"),
+    ('\u{1e96}', "h"),
+    ('\u{1e97}', "t"),
+    ('\u{1e98}', "w"),
+    ('\u{1e99}', "y"),
+    ('\u{1e9a}', "a"),
+    ('\u{1e9d}', "s"),
+    ('\u{1e9e}', "ss"),
+    ('\u{1e9f}', "d"),
+    ('\u{1eb7}', "a"),
+    ('\u{1ec7}', "e"),
+    ('\u{1ecb}', "i"),
+    ('\u{1ee3}', "o"),
+    ('\u{1ef1}', "u"),
+    ('\u{1ef9}', "y"),
+    ('\u{1efb}', "ll"),
+    ('\u{1efd}', "v"),
+    ('\u{1eff}', "y"),
+];
+
+/// Which characters of `text` fold to other letters than their
+/// lowercase, such as accented ones.
+fn accents(text: &str) -> Vec<bool> {
+    let mut base = String::new();
+    text.chars()
+        .map(|char| {
+            base.clear();
+            fold(char, &mut base);
+            !base.chars().eq(fold_case(char))
+        })
+        .collect()
+}
+
+/// Appends the lowercase base letters of `char`, dropping accents
+/// and combining marks.
+fn fold(char: char, base: &mut String) {
+    let folded = match char {
+        'À'..='Å' | 'à'..='å' => "a",
+        'Æ' | 'æ' => "ae",
+        'Ç' | 'ç' => "c",
+        'È'..='Ë' | 'è'..='ë' => "e",
+        'Ì'..='Ï' | 'ì'..='ï' => "i",
+        'Ð' | 'ð' => "d",
+        'Ñ' | 'ñ' => "n",
+        'Ò'..='Ö' | 'Ø' | 'ò'..='ö' | 'ø' => "o",
+        'Ù'..='Ü' | 'ù'..='ü' => "u",
+        'Ý' | 'ý' | 'ÿ' => "y",
+        'Þ' | 'þ' => "th",
+        'ß' => "ss",
+        '\u{100}'..='\u{17f}' => LATIN_EXTENDED_A
+            .iter()
+            .find(|&&(last, _)| char <= last)
+            .map_or("", |&(_, letters)| letters),
+        '\u{1e00}'..='\u{1eff}' => LATIN_EXTENDED_ADDITIONAL
+            .iter()
+            .find(|&&(last, _)| char <= last)
+            .map_or("", |&(_, letters)| letters),
+        'Ά' | 'ά' => "α",
+        'Έ' | 'έ' => "ε",
+        'Ή' | 'ή' => "η",
+        'Ί' | 'Ϊ' | 'ί' | 'ϊ' | 'ΐ' => "ι",
+        'Ό' | 'ό' => "ο",
+        'Ύ' | 'Ϋ' | 'ύ' | 'ϋ' | 'ΰ' => "υ",
+        'Ώ' | 'ώ' => "ω",
+        'Ё' | 'ё' => "е",
+        '\u{300}'..='\u{36f}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{20d0}'..='\u{20ff}'
+        | '\u{fe20}'..='\u{fe2f}' => "",
+        _ => {
+            base.extend(fold_case(char));
+            return;
+        }
+    };
+    base.push_str(folded);
+}
+
+/// GNU `filevercmp`: hidden names first, then a version comparison
+/// that ignores trailing suffixes like `.tar.gz` unless they differ.
+fn version_compare(a: &[u8], b: &[u8]) -> Ordering {
+    match (a.first(), b.first()) {
+        (None, None) => return Ordering::Equal,
+        (None, _) => return Ordering::Less,
+        (_, None) => return Ordering::Greater,
+        (Some(b'.'), Some(b'.')) => {
+            let rank = |name: &[u8]| match name {
+                b"." => 0,
+                b".." => 1,
+                _ => 2,
+            };
+            match rank(a).cmp(&rank(b)) {
+                Ordering::Equal if rank(a) == 2 => {}
+                ordering => return ordering,
+            }
+        }
+        (Some(b'.'), _) => return Ordering::Less,
+        (_, Some(b'.')) => return Ordering::Greater,
+        _ => {}
+    }
+
+    let (a_prefix, b_prefix) = (suffix_start(a), suffix_start(b));
+    let ordering = verrevcmp(&a[..a_prefix], &b[..b_prefix]);
+
+    if ordering.is_ne() || (a_prefix == a.len() && b_prefix == b.len()) {
+        ordering
+    } else {
+        verrevcmp(a, b)
+    }
+}
+
+/// Start of the trailing run of suffixes matching `(\.[A-Za-z~][A-Za-z0-9~]*)*`.
+fn suffix_start(name: &[u8]) -> usize {
+    let (mut index, mut prefix) = (0, 0);
+
+    while index < name.len() {
+        index += 1;
+        prefix = index;
+        while index + 1 < name.len()
+            && name[index] == b'.'
+            && (name[index + 1].is_ascii_alphabetic() || name[index + 1] == b'~')
+        {
+            index += 2;
+            while index < name.len() && (name[index].is_ascii_alphanumeric() || name[index] == b'~')
+            {
+                index += 1;
+            }
+        }
+    }
+
+    prefix
+}
+
+/// Weight of a non-digit byte: `~` before the end of the string,
+/// letters before everything else.
+fn order(name: &[u8], index: usize) -> i32 {
+    match name.get(index) {
+        None => -1,
+        Some(&byte) if byte.is_ascii_digit() => 0,
+        Some(&byte) if byte.is_ascii_alphabetic() => byte as i32,
+        Some(b'~') => -2,
+        Some(&byte) => byte as i32 + 256,
+    }
+}
+
+fn verrevcmp(a: &[u8], b: &[u8]) -> Ordering {
+    let is_digit = |name: &[u8], index: usize| name.get(index).is_some_and(u8::is_ascii_digit);
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !is_digit(a, i)) || (j < b.len() && !is_digit(b, j)) {
+            let (order_a, order_b) = (order(a, i), order(b, j));
+            if order_a != order_b {
+                return order_a.cmp(&order_b);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while is_digit(a, i) && is_digit(b, j) {
+            first_diff = first_diff.then(a[i].cmp(&b[j]));
+            i += 1;
+            j += 1;
+        }
+
+        if is_digit(a, i) {
+            return Ordering::Greater;
+        }
+        if is_digit(b, j) {
+            return Ordering::Less;
+        }
+        if first_diff.is_ne() {
+            return first_diff;
+        }
+    }
+
+    Ordering::Equal
+}
@@ -0,0 +1,168 @@
+use std::io::{self, Read};
+
+/// Bytes read from the input at a time.
+const INPUT_LEN: usize = 32 * 1024;
+
+/// Bits packed least significant first, as deflate, brotli and zstd
+/// all read them.
+pub(super) struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    /// Appends the low `count` bits of `value`, at most 32.
+    pub fn write(&mut self, value: u64, count: u32) {
+        let mask = (1 << count) - 1;
+        self.buffer |= (value & mask) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Takes the bytes completed so far, leaving the bits of the one
+    /// being filled.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+
+    /// Pads with zeros to the next byte.
+    pub fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+
+    /// Pads and returns the bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+
+    /// Ends a stream read backwards, as zstd does, with a set bit that
+    /// marks where its last byte starts.
+    pub fn close(mut self) -> Vec<u8> {
+        self.write(1, 1);
+        self.finish()
+    }
+}
+
+/// Bits read least significant first from `inner`, as deflate packs
+/// them.
+pub(super) struct BitReader<R> {
+    inner: R,
+    input: Box<[u8]>,
+    /// Unread part of `input`.
+    start: usize,
+    end: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            input: vec![0; INPUT_LEN].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    /// Tops up the buffer to at least `count` bits, or fewer at the end
+    /// of the input. Returns whether there are `count`.
+    fn fill(&mut self, count: u32) -> io::Result<bool> {
+        while self.count < count {
+            if self.start == self.end {
+                self.start = 0;
+                self.end = loop {
+                    match self.inner.read(&mut self.input) {
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        read => break read?,
+                    }
+                };
+                if self.end == 0 {
+                    return Ok(false);
+                }
+            }
+            while self.count <= 56 && self.start < self.end {
+                self.buffer |= (self.input[self.start] as u64) << self.count;
+                self.start += 1;
+                self.count += 8;
+            }
+        }
+        Ok(true)
+    }
+
+    /// The next `count` bits, at most 32, without taking them. `None`
+    /// if the input ends before.
+    pub fn peek(&mut self, count: u32) -> io::Result<Option<u32>> {
+        let filled = self.fill(count)?;
+        Ok(filled.then_some((self.buffer & ((1 << count) - 1)) as u32))
+    }
+
+    /// Takes `count` bits after a `peek` that saw them.
+    pub fn consume(&mut self, count: u32) {
+        self.buffer >>= count;
+        self.count -= count;
+    }
+
+    /// Takes the next `count` bits, at most 32.
+    pub fn read(&mut self, count: u32) -> io::Result<u32> {
+        let value = self
+            .peek(count)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        self.consume(count);
+        Ok(value)
+    }
+
+    /// Skips to the next byte.
+    pub fn align(&mut self) {
+        self.consume(self.count % 8);
+    }
+
+    /// Fills `bytes` from a byte boundary.
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        let mut at = 0;
+        while at < bytes.len() && self.count >= 8 {
+            bytes[at] = self.read(8)? as u8;
+            at += 1;
+        }
+        while at < bytes.len() {
+            if self.start == self.end && !self.fill(8)? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            // Whole bytes left in the buffer go first.
+            if self.count > 0 {
+                bytes[at] = self.read(8)? as u8;
+                at += 1;
+                continue;
+            }
+            let take = (bytes.len() - at).min(self.end - self.start);
+            bytes[at..at + take].copy_from_slice(&self.input[self.start..self.start + take]);
+            self.start += take;
+            at += take;
+        }
+        Ok(())
+    }
+
+    /// Whether the input ends at the next byte boundary.
+    pub fn at_end(&mut self) -> io::Result<bool> {
+        self.align();
+        Ok(!self.fill(8)?)
+    }
+}
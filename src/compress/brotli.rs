@@ -0,0 +1,312 @@
+use super::bits::BitWriter;
+use super::huffman;
+use super::lz77::{Command, Matcher};
+
+/// Base two logarithm of the window.
+const WINDOW_BITS: u32 = 22;
+
+/// Input bytes per meta-block, each with codes of its own.
+const BLOCK_LEN: usize = 1 << 20;
+
+const MAX_MATCH: usize = 64 * 1024;
+
+const LITERALS: usize = 256;
+const COMMANDS: usize = 704;
+/// Sixteen short codes, then 48 with extra bits.
+const DISTANCES: usize = 64;
+
+const INSERT_BASE: [u32; 24] = [
+    0, 1, 2, 3, 4, 5, 6, 8, 10, 14, 18, 26, 34, 50, 66, 98, 130, 194, 322, 578, 1090, 2114, 6210,
+    22594,
+];
+const INSERT_EXTRA: [u8; 24] = [
+    0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 12, 14, 24,
+];
+const COPY_BASE: [u32; 24] = [
+    2, 3, 4, 5, 6, 7, 8, 9, 10, 12, 14, 18, 22, 30, 38, 54, 70, 102, 134, 198, 326, 582, 1094, 2118,
+];
+const COPY_EXTRA: [u8; 24] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 24,
+];
+
+/// First command code of each cell of insert and copy codes, by their
+/// top two bits, for commands that send their distance.
+const CELLS: [[usize; 3]; 3] = [[128, 192, 384], [256, 320, 512], [448, 576, 640]];
+
+/// Order in which the lengths of the code length code are sent.
+const CODE_LENGTH_ORDER: [usize; 18] =
+    [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Fixed code for the lengths of the code length code.
+const LENGTH_LENGTH_CODES: [u8; 6] = [0, 7, 3, 2, 1, 15];
+const LENGTH_LENGTH_BITS: [u8; 6] = [2, 4, 3, 2, 2, 4];
+
+/// Code length that repeats of nonzero lengths start from.
+const INITIAL_REPEAT: u8 = 8;
+
+/// A brotli stream holding `data`, with `effort` from 0 to 9.
+pub(super) fn compress(data: &[u8], effort: u32) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write(1, 1);
+    writer.write(WINDOW_BITS as u64 - 17, 3);
+
+    if data.is_empty() {
+        writer.write(0b11, 2);
+        return writer.finish();
+    }
+
+    let mut matcher = Matcher::new(data, (1 << WINDOW_BITS) - 16, MAX_MATCH, effort);
+    let mut start = 0;
+    while start < data.len() {
+        let end = (start + BLOCK_LEN).min(data.len());
+        let commands = matcher.commands(start, end);
+        meta_block(&mut writer, &data[start..end], &commands, end == data.len());
+        start = end;
+    }
+    writer.finish()
+}
+
+/// A meta-block with one block type and one prefix code of each kind.
+fn meta_block(writer: &mut BitWriter, data: &[u8], commands: &[Command], last: bool) {
+    let mut literal_freqs = vec![0; LITERALS];
+    let mut command_freqs = vec![0; COMMANDS];
+    let mut distance_freqs = vec![0; DISTANCES];
+    let mut symbols = Vec::with_capacity(commands.len());
+    let mut pos = 0;
+    for command in commands {
+        for &byte in &data[pos..pos + command.literals] {
+            literal_freqs[byte as usize] += 1;
+        }
+        pos += command.literals + command.length;
+
+        let symbol = Symbols::new(command);
+        command_freqs[symbol.command] += 1;
+        if let Some((distance, _, _)) = symbol.distance {
+            distance_freqs[distance] += 1;
+        }
+        symbols.push(symbol);
+    }
+
+    let len = data.len() - 1;
+    let nibbles = (usize::BITS - len.leading_zeros()).div_ceil(4).max(4);
+    writer.write(last as u64, 1);
+    if last {
+        writer.write(0, 1);
+    }
+    writer.write(nibbles as u64 - 4, 2);
+    writer.write(len as u64, nibbles * 4);
+    if !last {
+        writer.write(0, 1);
+    }
+    // One block type of each kind, no postfix or direct distances,
+    // literal context mode 0, and one literal and one distance tree.
+    writer.write(0, 3);
+    writer.write(0, 6);
+    writer.write(0, 2);
+    writer.write(0, 2);
+
+    let literals = Code::new(writer, &literal_freqs);
+    let commands_code = Code::new(writer, &command_freqs);
+    let distances = Code::new(writer, &distance_freqs);
+
+    let mut pos = 0;
+    for (command, symbol) in commands.iter().zip(&symbols) {
+        commands_code.write(writer, symbol.command);
+        writer.write(symbol.insert.0 as u64, symbol.insert.1 as u32);
+        writer.write(symbol.copy.0 as u64, symbol.copy.1 as u32);
+        for &byte in &data[pos..pos + command.literals] {
+            literals.write(writer, byte as usize);
+        }
+        pos += command.literals + command.length;
+        if let Some((code, extra, bits)) = symbol.distance {
+            distances.write(writer, code);
+            writer.write(extra as u64, bits as u32);
+        }
+    }
+}
+
+/// A command's symbols and extra bits.
+struct Symbols {
+    command: usize,
+    insert: (u32, u8),
+    copy: (u32, u8),
+    distance: Option<(usize, u32, u8)>,
+}
+
+impl Symbols {
+    fn new(command: &Command) -> Self {
+        let insert = code(&INSERT_BASE, command.literals);
+        // A trailing run of literals ends the meta-block before its copy.
+        let copy = code(&COPY_BASE, command.length.max(2));
+        let cell = CELLS[insert >> 3][copy >> 3];
+
+        let distance = (command.length > 0).then(|| {
+            let value = command.distance as u32 + 3;
+            let bits = 31 - value.leading_zeros() - 1;
+            let prefix = (value >> bits) & 1;
+            let code = 16 + 2 * (bits as usize - 1) + prefix as usize;
+            (code, value - ((2 + prefix) << bits), bits as u8)
+        });
+
+        Self {
+            command: cell + ((insert & 7) << 3) + (copy & 7),
+            insert: (
+                (command.literals as u32 - INSERT_BASE[insert]),
+                INSERT_EXTRA[insert],
+            ),
+            copy: (
+                (command.length.max(2) as u32 - COPY_BASE[copy]),
+                COPY_EXTRA[copy],
+            ),
+            distance,
+        }
+    }
+}
+
+fn code(bases: &[u32], value: usize) -> usize {
+    bases.partition_point(|&base| base as usize <= value) - 1
+}
+
+/// A prefix code, sent as it is made.
+struct Code {
+    lengths: Vec<u8>,
+    codes: Vec<u16>,
+}
+
+impl Code {
+    fn new(writer: &mut BitWriter, freqs: &[u32]) -> Self {
+        let lengths = huffman::lengths(freqs, 15);
+        let used = (0..freqs.len())
+            .filter(|&symbol| lengths[symbol] > 0)
+            .collect::<Vec<_>>();
+        let alphabet_bits = usize::BITS - (freqs.len() - 1).leading_zeros();
+
+        // Up to two symbols go as a simple code, one of them in no bits.
+        if used.len() <= 2 {
+            writer.write(1, 2);
+            writer.write(used.len().max(1) as u64 - 1, 2);
+            for &symbol in used.iter().chain(used.is_empty().then_some(&0)) {
+                writer.write(symbol as u64, alphabet_bits);
+            }
+            let mut lengths = lengths;
+            if used.len() == 1 {
+                lengths[used[0]] = 0;
+            }
+            let codes = huffman::codes(&lengths);
+            return Self { lengths, codes };
+        }
+
+        let (tree, extra) = tree(&lengths);
+        let mut length_freqs = [0; 18];
+        for &symbol in &tree {
+            length_freqs[symbol as usize] += 1;
+        }
+        let mut length_lengths = huffman::lengths(&length_freqs, 5);
+        let length_codes = length_lengths.iter().filter(|&&len| len > 0).count();
+
+        let mut stored = CODE_LENGTH_ORDER.len();
+        if length_codes > 1 {
+            stored = CODE_LENGTH_ORDER
+                .iter()
+                .rposition(|&symbol| length_lengths[symbol] > 0)
+                .map_or(0, |at| at + 1);
+        }
+        let skip = match CODE_LENGTH_ORDER.map(|symbol| length_lengths[symbol]) {
+            [0, 0, 0, ..] => 3,
+            [0, 0, ..] => 2,
+            _ => 0,
+        };
+        writer.write(skip as u64, 2);
+        for &symbol in &CODE_LENGTH_ORDER[skip..stored] {
+            let len = length_lengths[symbol] as usize;
+            writer.write(
+                LENGTH_LENGTH_CODES[len] as u64,
+                LENGTH_LENGTH_BITS[len] as u32,
+            );
+        }
+
+        // A lone code length symbol takes no bits.
+        if length_codes == 1 {
+            length_lengths.iter_mut().for_each(|len| *len = 0);
+        }
+        let length_symbols = huffman::codes(&length_lengths);
+        for (&symbol, &extra) in tree.iter().zip(&extra) {
+            let symbol = symbol as usize;
+            writer.write(length_symbols[symbol] as u64, length_lengths[symbol] as u32);
+            match symbol {
+                16 => writer.write(extra as u64, 2),
+                17 => writer.write(extra as u64, 3),
+                _ => {}
+            }
+        }
+
+        let codes = huffman::codes(&lengths);
+        Self { lengths, codes }
+    }
+
+    fn write(&self, writer: &mut BitWriter, symbol: usize) {
+        writer.write(self.codes[symbol] as u64, self.lengths[symbol] as u32);
+    }
+}
+
+/// Code lengths as code length symbols and their extra bits, up to the
+/// last nonzero one. Runs are repeat codes whose counts build on each
+/// other, most significant digit first.
+fn tree(lengths: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let end = lengths
+        .iter()
+        .rposition(|&len| len > 0)
+        .map_or(0, |at| at + 1);
+    let mut tree = Vec::new();
+    let mut extra = Vec::new();
+    let mut previous = INITIAL_REPEAT;
+
+    let mut at = 0;
+    while at < end {
+        let len = lengths[at];
+        let count = lengths[at..end]
+            .iter()
+            .take_while(|&&next| next == len)
+            .count();
+        let mut left = count;
+
+        let (repeat, bits, single) = match len {
+            0 => (17, 3, 11),
+            _ => (16, 2, 7),
+        };
+        if len != 0 && len != previous {
+            tree.push(len);
+            extra.push(0);
+            left -= 1;
+        }
+        if left == single {
+            tree.push(len);
+            extra.push(0);
+            left -= 1;
+        }
+        if left < 3 {
+            tree.extend(std::iter::repeat_n(len, left));
+            extra.extend(std::iter::repeat_n(0, left));
+        } else {
+            let start = tree.len();
+            left -= 3;
+            loop {
+                tree.push(repeat);
+                extra.push((left & ((1 << bits) - 1)) as u8);
+                left >>= bits;
+                if left == 0 {
+                    break;
+                }
+                left -= 1;
+            }
+            tree[start..].reverse();
+            extra[start..].reverse();
+        }
+
+        if len != 0 {
+            previous = len;
+        }
+        at += count;
+    }
+    (tree, extra)
+}
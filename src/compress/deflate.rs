@@ -0,0 +1,289 @@
+use std::io::{self, Write};
+
+use super::bits::BitWriter;
+use super::huffman;
+use super::lz77::{Command, Matcher};
+use crate::crc32::Crc32;
+
+/// Input bytes per block, each with codes of its own.
+const BLOCK_LEN: usize = 64 * 1024;
+
+pub(super) const WINDOW: usize = 32 * 1024;
+const MAX_MATCH: usize = 258;
+
+pub(super) const END_OF_BLOCK: usize = 256;
+
+pub(super) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(super) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+pub(super) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(super) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which the lengths of the code length code are sent.
+pub(super) const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Input compressed at a time by a `GzipWriter`, in whole blocks.
+const CHUNK_LEN: usize = 4 * BLOCK_LEN;
+
+/// A gzip member holding `data`, with `effort` from 0 to 9.
+pub(super) fn gzip(data: &[u8], effort: u32) -> Vec<u8> {
+    let mut writer = GzipWriter::new(Vec::new(), effort);
+    writer.chunk(data);
+    writer.finish().unwrap_or_default()
+}
+
+/// A gzip member written as its data comes in, compressing a chunk at
+/// a time with the window before it to match against.
+pub(crate) struct GzipWriter<W: Write> {
+    inner: W,
+    effort: u32,
+    /// The window, then input not yet compressed.
+    data: Vec<u8>,
+    window: usize,
+    bits: BitWriter,
+    crc: Crc32,
+    len: u32,
+}
+
+impl<W: Write> GzipWriter<W> {
+    /// Starts a member over `inner`, with `effort` from 0 to 9.
+    pub fn new(inner: W, effort: u32) -> Self {
+        let flags = match effort {
+            9 => 2,
+            0 | 1 => 4,
+            _ => 0,
+        };
+        let mut bits = BitWriter::new();
+        for byte in [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, flags, 3] {
+            bits.write(byte, 8);
+        }
+
+        Self {
+            inner,
+            effort,
+            data: Vec::with_capacity(WINDOW + CHUNK_LEN),
+            window: 0,
+            bits,
+            crc: Crc32::new(),
+            len: 0,
+        }
+    }
+
+    /// Compresses what is left as the last block and writes the trailer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.compress(true);
+        let mut bytes = self.bits.finish();
+        bytes.extend(self.crc.finish().to_le_bytes());
+        bytes.extend(self.len.to_le_bytes());
+        self.inner.write_all(&bytes)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Takes in `data` without writing any of it out.
+    fn chunk(&mut self, data: &[u8]) {
+        self.crc.update(data);
+        // Lengths are kept modulo 2^32.
+        self.len = self.len.wrapping_add(data.len() as u32);
+        self.data.extend_from_slice(data);
+    }
+
+    /// Compresses the input taken in so far, then keeps the end of it
+    /// as the window of what comes next.
+    fn compress(&mut self, last: bool) {
+        let mut matcher = Matcher::new(&self.data, WINDOW, MAX_MATCH, self.effort);
+        let mut start = self.window;
+        loop {
+            let end = (start + BLOCK_LEN).min(self.data.len());
+            let commands = matcher.commands(start, end);
+            let last = last && end == self.data.len();
+            block(&mut self.bits, &self.data[start..end], &commands, last);
+            start = end;
+            if start == self.data.len() {
+                break;
+            }
+        }
+
+        self.data.drain(..self.data.len().saturating_sub(WINDOW));
+        self.window = self.data.len();
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = buf.len().min(self.window + CHUNK_LEN - self.data.len());
+        self.chunk(&buf[..take]);
+        if self.data.len() == self.window + CHUNK_LEN {
+            self.compress(false);
+            self.inner.write_all(&self.bits.take())?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A block with dynamic codes.
+fn block(writer: &mut BitWriter, data: &[u8], commands: &[Command], last: bool) {
+    let mut literal_freqs = [0; 286];
+    let mut distance_freqs = [0; 30];
+    let mut pos = 0;
+    for command in commands {
+        for &byte in &data[pos..pos + command.literals] {
+            literal_freqs[byte as usize] += 1;
+        }
+        pos += command.literals + command.length;
+        if command.length > 0 {
+            literal_freqs[257 + code(&LENGTH_BASE, command.length)] += 1;
+            distance_freqs[code(&DISTANCE_BASE, command.distance)] += 1;
+        }
+    }
+    literal_freqs[END_OF_BLOCK] += 1;
+    // Codes with a single symbol aren't complete, so they get a second.
+    for freqs in [&mut literal_freqs[..], &mut distance_freqs[..]] {
+        for symbol in 0..2 {
+            if freqs.iter().filter(|&&freq| freq > 0).count() < 2 {
+                freqs[symbol] = freqs[symbol].max(1);
+            }
+        }
+    }
+
+    let literal_lengths = huffman::lengths(&literal_freqs, 15);
+    let distance_lengths = huffman::lengths(&distance_freqs, 15);
+    let literal_codes = huffman::codes(&literal_lengths);
+    let distance_codes = huffman::codes(&distance_lengths);
+
+    let literal_count = 257.max(used(&literal_lengths));
+    let distance_count = 1.max(used(&distance_lengths));
+    let mut lengths = literal_lengths[..literal_count].to_vec();
+    lengths.extend(&distance_lengths[..distance_count]);
+    let runs = runs(&lengths);
+
+    let mut length_freqs = [0; 19];
+    for &(symbol, _) in &runs {
+        length_freqs[symbol as usize] += 1;
+    }
+    let length_lengths = huffman::lengths(&length_freqs, 7);
+    let length_codes = huffman::codes(&length_lengths);
+    let length_count = 4.max(
+        CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| length_lengths[symbol] > 0)
+            .map_or(0, |at| at + 1),
+    );
+
+    writer.write(last as u64, 1);
+    writer.write(2, 2);
+    writer.write(literal_count as u64 - 257, 5);
+    writer.write(distance_count as u64 - 1, 5);
+    writer.write(length_count as u64 - 4, 4);
+    for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+        writer.write(length_lengths[symbol] as u64, 3);
+    }
+    for &(symbol, extra) in &runs {
+        let symbol = symbol as usize;
+        writer.write(length_codes[symbol] as u64, length_lengths[symbol] as u32);
+        match symbol {
+            16 => writer.write(extra as u64, 2),
+            17 => writer.write(extra as u64, 3),
+            18 => writer.write(extra as u64, 7),
+            _ => {}
+        }
+    }
+
+    let literal = |writer: &mut BitWriter, symbol: usize| {
+        writer.write(literal_codes[symbol] as u64, literal_lengths[symbol] as u32);
+    };
+    let mut pos = 0;
+    for command in commands {
+        for &byte in &data[pos..pos + command.literals] {
+            literal(writer, byte as usize);
+        }
+        pos += command.literals + command.length;
+        if command.length == 0 {
+            continue;
+        }
+
+        let length = code(&LENGTH_BASE, command.length);
+        literal(writer, 257 + length);
+        writer.write(
+            (command.length - LENGTH_BASE[length] as usize) as u64,
+            LENGTH_EXTRA[length] as u32,
+        );
+        let distance = code(&DISTANCE_BASE, command.distance);
+        writer.write(
+            distance_codes[distance] as u64,
+            distance_lengths[distance] as u32,
+        );
+        writer.write(
+            (command.distance - DISTANCE_BASE[distance] as usize) as u64,
+            DISTANCE_EXTRA[distance] as u32,
+        );
+    }
+    literal(writer, END_OF_BLOCK);
+}
+
+/// The code whose base is the largest not above `value`.
+fn code(bases: &[u16], value: usize) -> usize {
+    bases.partition_point(|&base| base as usize <= value) - 1
+}
+
+/// Symbols up to the last one with a code.
+fn used(lengths: &[u8]) -> usize {
+    lengths
+        .iter()
+        .rposition(|&len| len > 0)
+        .map_or(0, |at| at + 1)
+}
+
+/// Code lengths run-length coded as code length symbols and their
+/// extra bits.
+fn runs(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs = Vec::new();
+    let mut at = 0;
+    while at < lengths.len() {
+        let len = lengths[at];
+        let count = lengths[at..]
+            .iter()
+            .take_while(|&&next| next == len)
+            .count();
+        let mut left = count;
+
+        if len == 0 {
+            while left >= 11 {
+                let take = left.min(138);
+                runs.push((18, (take - 11) as u8));
+                left -= take;
+            }
+            if left >= 3 {
+                runs.push((17, (left - 3) as u8));
+                left = 0;
+            }
+        } else {
+            runs.push((len, 0));
+            left -= 1;
+            while left >= 3 {
+                let take = left.min(6);
+                runs.push((16, (take - 3) as u8));
+                left -= take;
+            }
+        }
+        runs.extend(std::iter::repeat_n((len, 0), left));
+        at += count;
+    }
+    runs
+}
@@ -0,0 +1,88 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Huffman code lengths for `freqs`, none longer than `limit`. Unused
+/// symbols get 0, and a lone used symbol gets 1.
+pub(super) fn lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    loop {
+        let lengths = build(&freqs);
+        if lengths.iter().all(|&len| len <= limit) {
+            return lengths;
+        }
+        // Flatter counts give a shallower tree, down to a balanced one.
+        for freq in freqs.iter_mut().filter(|freq| **freq > 0) {
+            *freq = freq.div_ceil(2);
+        }
+    }
+}
+
+fn build(freqs: &[u32]) -> Vec<u8> {
+    let mut lengths = vec![0; freqs.len()];
+    let mut heap = BinaryHeap::new();
+    // Leaves come first, then joined nodes, as (left, right) children.
+    let mut nodes = Vec::new();
+
+    for (symbol, &freq) in freqs.iter().enumerate() {
+        if freq > 0 {
+            heap.push(Reverse((freq as u64, nodes.len())));
+            nodes.push((symbol, usize::MAX));
+        }
+    }
+    match heap.len() {
+        0 => return lengths,
+        1 => {
+            lengths[nodes[0].0] = 1;
+            return lengths;
+        }
+        _ => {}
+    }
+
+    let leaves = nodes.len();
+    while let (Some(Reverse((a, left))), Some(Reverse((b, right)))) = (heap.pop(), heap.pop()) {
+        heap.push(Reverse((a + b, nodes.len())));
+        nodes.push((left, right));
+    }
+
+    let mut stack = vec![(nodes.len() - 1, 0)];
+    while let Some((node, depth)) = stack.pop() {
+        match node < leaves {
+            true => lengths[nodes[node].0] = depth,
+            false => {
+                let (left, right) = nodes[node];
+                stack.push((left, depth + 1));
+                stack.push((right, depth + 1));
+            }
+        }
+    }
+    lengths
+}
+
+/// Canonical codes for `lengths`, shorter codes and then lower symbols
+/// first, bit-reversed to be written least significant bit first.
+pub(super) fn codes(lengths: &[u8]) -> Vec<u16> {
+    let max = lengths.iter().copied().max().unwrap_or(0) as usize;
+    let mut counts = vec![0u16; max + 1];
+    for &len in lengths.iter().filter(|&&len| len > 0) {
+        counts[len as usize] += 1;
+    }
+
+    let mut next = vec![0u16; max + 1];
+    let mut code = 0;
+    for len in 1..=max {
+        code = (code + counts[len - 1]) << 1;
+        next[len] = code;
+    }
+
+    lengths
+        .iter()
+        .map(|&len| match len {
+            0 => 0,
+            _ => {
+                let code = next[len as usize];
+                next[len as usize] += 1;
+                code.reverse_bits() >> (16 - len)
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,382 @@
+use std::io::{self, Read};
+
+use super::bits::BitReader;
+use super::deflate::{
+    CODE_LENGTH_ORDER, DISTANCE_BASE, DISTANCE_EXTRA, END_OF_BLOCK, LENGTH_BASE, LENGTH_EXTRA,
+    WINDOW,
+};
+use crate::crc32::Crc32;
+
+/// Bits of the next code looked up at once. Longer codes are read a
+/// bit at a time.
+const FAST_BITS: u32 = 9;
+
+/// Output decoded at a time, at most.
+const OUTPUT_LEN: usize = 32 * 1024;
+
+/// A deflate stream, raw or in gzip members, decoded as it is read.
+pub(crate) struct Inflate<R> {
+    bits: BitReader<R>,
+    gzip: bool,
+    state: State,
+    /// Whether the block being decoded is the last of its stream.
+    last: bool,
+    /// The window of output that matches copy from, then the output
+    /// not read yet.
+    output: Vec<u8>,
+    unread: usize,
+    /// Set once a gzip member was read, after which anything but
+    /// another one is taken for padding.
+    member: bool,
+    crc: Crc32,
+    len: u32,
+}
+
+enum State {
+    /// A gzip member or the end of the input.
+    Member,
+    Block,
+    /// Bytes of a stored block still to copy.
+    Stored(usize),
+    Codes(Box<(Code, Code)>),
+    /// The end of a stream, and the check of a gzip member.
+    Trailer,
+    Done,
+}
+
+impl<R: Read> Inflate<R> {
+    /// A raw deflate stream, as zip stores its members.
+    pub fn raw(inner: R) -> Self {
+        Self::new(inner, false, State::Block)
+    }
+
+    /// Gzip members one after another, as `.gz` files hold them.
+    pub fn gzip(inner: R) -> Self {
+        Self::new(inner, true, State::Member)
+    }
+
+    fn new(inner: R, gzip: bool, state: State) -> Self {
+        Self {
+            bits: BitReader::new(inner),
+            gzip,
+            state,
+            last: false,
+            output: Vec::with_capacity(2 * WINDOW + OUTPUT_LEN),
+            unread: 0,
+            member: false,
+            crc: Crc32::new(),
+            len: 0,
+        }
+    }
+
+    /// Moves the stream on, decoding some output unless a header or
+    /// trailer was next.
+    fn step(&mut self) -> io::Result<()> {
+        // Of what was read, only the window is kept.
+        if self.output.len() >= 2 * WINDOW {
+            self.output.drain(..self.output.len() - WINDOW);
+            self.unread = self.output.len();
+        }
+        let start = self.output.len();
+
+        self.state = match std::mem::replace(&mut self.state, State::Done) {
+            State::Member => self.member()?,
+            State::Block => self.block()?,
+            State::Stored(left) => {
+                let take = left.min(OUTPUT_LEN);
+                self.output.resize(start + take, 0);
+                self.bits.read_bytes(&mut self.output[start..])?;
+                match left - take {
+                    0 => self.next_block(),
+                    left => State::Stored(left),
+                }
+            }
+            State::Codes(codes) => self.codes(codes)?,
+            State::Trailer => self.trailer()?,
+            State::Done => State::Done,
+        };
+
+        if self.gzip {
+            let output = &self.output[start..];
+            self.crc.update(output);
+            self.len = self.len.wrapping_add(output.len() as u32);
+        }
+        Ok(())
+    }
+
+    fn next_block(&self) -> State {
+        match self.last {
+            true => State::Trailer,
+            false => State::Block,
+        }
+    }
+
+    /// Reads the header of a gzip member, if another one follows.
+    fn member(&mut self) -> io::Result<State> {
+        if self.bits.at_end()? {
+            return Ok(State::Done);
+        }
+        let mut header = [0; 10];
+        match self.bits.read_bytes(&mut header) {
+            Ok(()) if header[..3] == [0x1f, 0x8b, 8] => {}
+            _ if self.member => return Ok(State::Done),
+            Ok(()) => return Err(invalid("Not a gzip stream")),
+            Err(err) => return Err(err),
+        }
+
+        let flags = header[3];
+        if flags & 4 != 0 {
+            let mut len = [0; 2];
+            self.bits.read_bytes(&mut len)?;
+            self.bits
+                .read_bytes(&mut vec![0; u16::from_le_bytes(len) as usize])?;
+        }
+        // The name and the comment end with a NUL.
+        for flag in [8, 16] {
+            if flags & flag != 0 {
+                while self.bits.read(8)? != 0 {}
+            }
+        }
+        if flags & 2 != 0 {
+            self.bits.read_bytes(&mut [0; 2])?;
+        }
+
+        self.member = true;
+        self.crc = Crc32::new();
+        self.len = 0;
+        Ok(State::Block)
+    }
+
+    fn block(&mut self) -> io::Result<State> {
+        self.last = self.bits.read(1)? == 1;
+        match self.bits.read(2)? {
+            0 => {
+                self.bits.align();
+                let mut header = [0; 4];
+                self.bits.read_bytes(&mut header)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(invalid("Stored block length doesn't match its complement"));
+                }
+                Ok(State::Stored(len as usize))
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let codes = (Code::new(&lengths)?, Code::new(&[5; 30])?);
+                Ok(State::Codes(Box::new(codes)))
+            }
+            2 => Ok(State::Codes(Box::new(self.dynamic()?))),
+            _ => Err(invalid("Reserved block type")),
+        }
+    }
+
+    /// Reads the codes of a block with dynamic codes.
+    fn dynamic(&mut self) -> io::Result<(Code, Code)> {
+        let literal_count = self.bits.read(5)? as usize + 257;
+        let distance_count = self.bits.read(5)? as usize + 1;
+        let length_count = self.bits.read(4)? as usize + 4;
+
+        let mut length_lengths = [0; 19];
+        for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+            length_lengths[symbol] = self.bits.read(3)? as u8;
+        }
+        let length_code = Code::new(&length_lengths)?;
+
+        let count = literal_count + distance_count;
+        let mut lengths = Vec::with_capacity(count);
+        while lengths.len() < count {
+            let (len, repeat) = match length_code.decode(&mut self.bits)? {
+                16 => match lengths.last() {
+                    Some(&len) => (len, 3 + self.bits.read(2)?),
+                    None => return Err(invalid("Repeat of a code length before any")),
+                },
+                17 => (0, 3 + self.bits.read(3)?),
+                18 => (0, 11 + self.bits.read(7)?),
+                len => (len as u8, 1),
+            };
+            lengths.extend(std::iter::repeat_n(len, repeat as usize));
+        }
+        if lengths.len() > count {
+            return Err(invalid("Code lengths run past their count"));
+        }
+        if lengths[END_OF_BLOCK] == 0 {
+            return Err(invalid("Block without an end"));
+        }
+
+        Ok((
+            Code::new(&lengths[..literal_count])?,
+            Code::new(&lengths[literal_count..])?,
+        ))
+    }
+
+    /// Decodes symbols until the block ends or enough output is made.
+    fn codes(&mut self, codes: Box<(Code, Code)>) -> io::Result<State> {
+        let (literals, distances) = &*codes;
+        let end = self.output.len() + OUTPUT_LEN;
+
+        while self.output.len() < end {
+            let symbol = literals.decode(&mut self.bits)?;
+            if symbol < END_OF_BLOCK {
+                self.output.push(symbol as u8);
+                continue;
+            }
+            if symbol == END_OF_BLOCK {
+                return Ok(self.next_block());
+            }
+
+            let length = symbol - 257;
+            if length >= LENGTH_BASE.len() {
+                return Err(invalid("Invalid length code"));
+            }
+            let length = LENGTH_BASE[length] as usize
+                + self.bits.read(LENGTH_EXTRA[length] as u32)? as usize;
+            let distance = distances.decode(&mut self.bits)?;
+            if distance >= DISTANCE_BASE.len() {
+                return Err(invalid("Invalid distance code"));
+            }
+            let distance = DISTANCE_BASE[distance] as usize
+                + self.bits.read(DISTANCE_EXTRA[distance] as u32)? as usize;
+            if distance > self.output.len() {
+                return Err(invalid("Distance before the start of the stream"));
+            }
+
+            // Overlapping copies repeat what they've just copied.
+            let from = self.output.len() - distance;
+            if distance >= length {
+                self.output.extend_from_within(from..from + length);
+            } else {
+                for at in from..from + length {
+                    self.output.push(self.output[at]);
+                }
+            }
+        }
+        Ok(State::Codes(codes))
+    }
+
+    /// Checks a gzip member against its trailer.
+    fn trailer(&mut self) -> io::Result<State> {
+        if !self.gzip {
+            return Ok(State::Done);
+        }
+        self.bits.align();
+        let mut trailer = [0; 8];
+        self.bits.read_bytes(&mut trailer)?;
+        let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+        if crc != self.crc.finish() || len != self.len {
+            return Err(invalid("Gzip member doesn't match its checksum"));
+        }
+        Ok(State::Member)
+    }
+}
+
+impl<R: Read> Read for Inflate<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.unread == self.output.len() {
+            if buf.is_empty() || matches!(self.state, State::Done) {
+                return Ok(0);
+            }
+            self.step()?;
+        }
+        let take = buf.len().min(self.output.len() - self.unread);
+        buf[..take].copy_from_slice(&self.output[self.unread..self.unread + take]);
+        self.unread += take;
+        Ok(take)
+    }
+}
+
+/// A canonical prefix code, as deflate sends them by their lengths.
+struct Code {
+    /// Symbol and length of each code of up to `FAST_BITS` bits, by
+    /// those bits as read. Zero where longer codes start.
+    fast: Vec<u16>,
+    /// Codes of each length.
+    counts: [u16; 16],
+    /// Symbols in the order of their codes.
+    symbols: Vec<u16>,
+}
+
+impl Code {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // More codes than their lengths leave room for can't be told
+        // apart. Fewer are allowed, such as a lone distance code.
+        let mut left = 1_i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(invalid("Over-subscribed code"));
+            }
+        }
+
+        let mut offsets = [0; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; counts.iter().sum::<u16>() as usize];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len > 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        // Codes are sent from their top bit, so the table is indexed by
+        // their reverse, with every value of the bits after them.
+        let mut fast = vec![0; 1 << FAST_BITS];
+        let (mut code, mut index) = (0_u32, 0);
+        for len in 1..=FAST_BITS {
+            for _ in 0..counts[len as usize] {
+                let entry = symbols[index] << 4 | len as u16;
+                let reversed = code.reverse_bits() >> (32 - len);
+                for at in (reversed as usize..fast.len()).step_by(1 << len) {
+                    fast[at] = entry;
+                }
+                code += 1;
+                index += 1;
+            }
+            code <<= 1;
+        }
+
+        Ok(Self {
+            fast,
+            counts,
+            symbols,
+        })
+    }
+
+    fn decode<R: Read>(&self, bits: &mut BitReader<R>) -> io::Result<usize> {
+        if let Some(peeked) = bits.peek(FAST_BITS)? {
+            let entry = self.fast[peeked as usize];
+            if entry & 15 != 0 {
+                bits.consume(entry as u32 & 15);
+                return Ok(entry as usize >> 4);
+            }
+        }
+
+        // Longer codes, and those at the very end of the input.
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= bits.read(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("Invalid code"))
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
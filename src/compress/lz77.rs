@@ -0,0 +1,154 @@
+/// Bits of the hash of the next four bytes.
+const HASH_BITS: u32 = 16;
+
+/// Shortest match worth a command.
+const MIN_MATCH: usize = 4;
+
+/// Candidates tried per position, by effort.
+const CHAIN: [usize; 10] = [4, 8, 16, 32, 64, 128, 256, 512, 1024, 4096];
+
+/// Effort from which a match is put off when the next byte starts a
+/// longer one.
+const LAZY_EFFORT: u32 = 4;
+
+/// Literals followed by a copy of `length` bytes from `distance`
+/// back. The last command of a block may have no copy.
+pub(super) struct Command {
+    pub literals: usize,
+    pub length: usize,
+    pub distance: usize,
+}
+
+/// Finds repeats through hash chains over the whole input, so blocks
+/// can refer back to the ones before them.
+pub(super) struct Matcher<'a> {
+    data: &'a [u8],
+    window: usize,
+    max_match: usize,
+    chain: usize,
+    lazy: bool,
+    /// Latest position of each hash, plus one.
+    head: Vec<u32>,
+    /// Previous position with the same hash, plus one, by position
+    /// within the window.
+    prev: Vec<u32>,
+    /// Positions before this one are in the chains.
+    inserted: usize,
+}
+
+impl<'a> Matcher<'a> {
+    /// Matches up to `max_match` bytes long and `window` bytes back,
+    /// looking harder with `effort` from 0 to 9.
+    pub fn new(data: &'a [u8], window: usize, max_match: usize, effort: u32) -> Self {
+        let window = window.min(data.len().next_power_of_two()).max(1);
+        let effort = effort.min(9);
+        Self {
+            data,
+            window,
+            max_match,
+            chain: CHAIN[effort as usize],
+            lazy: effort >= LAZY_EFFORT,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; window.next_power_of_two()],
+            inserted: 0,
+        }
+    }
+
+    /// Commands producing `data[start..end]`.
+    pub fn commands(&mut self, start: usize, end: usize) -> Vec<Command> {
+        let mut commands = Vec::new();
+        let mut anchor = start;
+        let mut pos = start;
+
+        while pos + MIN_MATCH <= end {
+            let Some((mut length, mut distance)) = self.find(pos, end) else {
+                pos += 1;
+                continue;
+            };
+            if self.lazy && length < self.max_match {
+                if let Some((next, next_distance)) = self.find(pos + 1, end) {
+                    if next > length {
+                        pos += 1;
+                        (length, distance) = (next, next_distance);
+                    }
+                }
+            }
+
+            commands.push(Command {
+                literals: pos - anchor,
+                length,
+                distance,
+            });
+            pos += length;
+            anchor = pos;
+        }
+
+        if end > anchor {
+            commands.push(Command {
+                literals: end - anchor,
+                length: 0,
+                distance: 0,
+            });
+        }
+        commands
+    }
+
+    /// The longest match at `pos` ending by `end`.
+    fn find(&mut self, pos: usize, end: usize) -> Option<(usize, usize)> {
+        if pos + MIN_MATCH > end {
+            return None;
+        }
+        self.insert_until(pos);
+
+        let data = self.data;
+        let limit = (end - pos).min(self.max_match);
+        let mask = self.prev.len() - 1;
+        let mut best = (0, 0);
+        let mut candidate = self.head[hash(data, pos)] as usize;
+
+        for _ in 0..self.chain {
+            if candidate == 0 || pos - (candidate - 1) > self.window {
+                break;
+            }
+            let from = candidate - 1;
+            if data[from + best.0] == data[pos + best.0] {
+                let length = data[from..from + limit]
+                    .iter()
+                    .zip(&data[pos..pos + limit])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, pos - from);
+                    if length == limit {
+                        break;
+                    }
+                }
+            }
+
+            let next = self.prev[from & mask] as usize;
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+
+        (best.0 >= MIN_MATCH).then_some(best)
+    }
+
+    fn insert_until(&mut self, pos: usize) {
+        let mask = self.prev.len() - 1;
+        let last = self.data.len().saturating_sub(MIN_MATCH - 1);
+        while self.inserted < pos.min(last) {
+            let at = self.inserted;
+            let hash = hash(self.data, at);
+            self.prev[at & mask] = self.head[hash];
+            self.head[hash] = at as u32 + 1;
+            self.inserted += 1;
+        }
+    }
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let word = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+    (word.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
@@ -0,0 +1,167 @@
+mod bits;
+mod brotli;
+mod deflate;
+mod huffman;
+mod inflate;
+mod lz77;
+#[cfg(test)]
+mod tests;
+mod zstd;
+
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use thiserror::Error;
+
+use crate::request;
+
+pub(crate) use deflate::GzipWriter;
+pub(crate) use inflate::Inflate;
+
+/// A `Content-Encoding` the service can send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    /// Preferred first when the client weighs several the same.
+    const ALL: [Self; 3] = [Self::Brotli, Self::Zstd, Self::Gzip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Levels from fastest to smallest.
+    fn levels(self) -> (u32, u32) {
+        match self {
+            Self::Gzip => (1, 9),
+            Self::Brotli => (0, 11),
+            Self::Zstd => (1, 19),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Level {level} of {encoding} is outside {min} to {max}")]
+pub struct LevelError {
+    encoding: Encoding,
+    level: u32,
+    min: u32,
+    max: u32,
+}
+
+/// When and how hard listing bodies are compressed.
+pub struct Compression {
+    /// Smallest body worth compressing, in bytes.
+    threshold: usize,
+    gzip: u32,
+    brotli: u32,
+    zstd: u32,
+}
+
+impl Compression {
+    /// Takes each encoding's own levels: 1 to 9 for gzip, 0 to 11 for
+    /// brotli and 1 to 19 for zstd.
+    pub fn new(threshold: usize, gzip: u32, brotli: u32, zstd: u32) -> Result<Self, LevelError> {
+        for (encoding, level) in [
+            (Encoding::Gzip, gzip),
+            (Encoding::Brotli, brotli),
+            (Encoding::Zstd, zstd),
+        ] {
+            let (min, max) = encoding.levels();
+            if !(min..=max).contains(&level) {
+                return Err(LevelError {
+                    encoding,
+                    level,
+                    min,
+                    max,
+                });
+            }
+        }
+
+        Ok(Self {
+            threshold,
+            gzip,
+            brotli,
+            zstd,
+        })
+    }
+
+    /// The encoding for a body of `len` bytes going by the client's
+    /// `Accept-Encoding`, or `None` to send it as it is.
+    pub fn negotiate(&self, accept: Option<&str>, len: usize) -> Option<Encoding> {
+        if len < self.threshold {
+            return None;
+        }
+        let offers = Encoding::ALL.map(Encoding::name);
+        let chosen = request::negotiate(accept?, &offers)?;
+        Encoding::ALL
+            .into_iter()
+            .find(|encoding| encoding.name() == chosen)
+    }
+
+    pub fn compress(&self, body: &[u8], encoding: Encoding) -> Vec<u8> {
+        // Levels are spread over the matcher's efforts, 0 to 9.
+        match encoding {
+            Encoding::Gzip => deflate::gzip(body, self.gzip),
+            Encoding::Brotli => brotli::compress(body, self.brotli * 9 / 11),
+            Encoding::Zstd => zstd::compress(body, (self.zstd - 1) / 2),
+        }
+    }
+
+    /// A gzip stream over `inner` at the configured level.
+    pub(crate) fn gzip_writer<W: Write>(&self, inner: W) -> GzipWriter<W> {
+        GzipWriter::new(inner, self.gzip)
+    }
+}
+
+/// Compressed copies of a listing body, made on first request. Clones
+/// of a listing share them, so a cached listing keeps its own.
+#[derive(Default)]
+pub struct Variants {
+    bodies: Mutex<Vec<(Encoding, Arc<[u8]>)>>,
+    /// Told the length of each copy made, by the cache holding them.
+    on_add: OnceLock<Box<dyn Fn(usize) + Send + Sync>>,
+}
+
+impl Variants {
+    /// The body in `encoding`, compressing it with `compress` unless it
+    /// was before.
+    pub fn get_or_compress(
+        &self,
+        encoding: Encoding,
+        compress: impl FnOnce() -> Vec<u8>,
+    ) -> Arc<[u8]> {
+        let mut bodies = self.bodies.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, body)) = bodies.iter().find(|(known, _)| *known == encoding) {
+            return body.clone();
+        }
+        let body = Arc::<[u8]>::from(compress());
+        bodies.push((encoding, body.clone()));
+        drop(bodies);
+
+        if let Some(on_add) = self.on_add.get() {
+            on_add(body.len());
+        }
+        body
+    }
+
+    /// Calls `on_add` with the length of each copy made from now on,
+    /// unless something else was told before.
+    pub(crate) fn on_add(&self, on_add: impl Fn(usize) + Send + Sync + 'static) {
+        let _ = self.on_add.set(Box::new(on_add));
+    }
+}
@@ -0,0 +1,163 @@
+//! Round trips through each encoding at each level, read back by the
+//! gzip decoder bundles use and by test-only brotli and zstd decoders.
+
+mod brotli;
+mod zstd;
+
+use std::io::Read;
+
+use super::{Compression, Encoding, Inflate};
+
+/// A canonical prefix code, read a bit at a time.
+struct Prefix {
+    /// Codes of each length, from 1 up.
+    counts: Vec<usize>,
+    /// Symbols by code.
+    symbols: Vec<usize>,
+    /// The symbol of a code of one symbol, which takes no bits.
+    lone: Option<usize>,
+}
+
+impl Prefix {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = vec![0; 16];
+        for &len in lengths.iter().filter(|&&len| len > 0) {
+            counts[len as usize] += 1;
+        }
+        let mut symbols = Vec::new();
+        for len in 1..16 {
+            symbols.extend((0..lengths.len()).filter(|&symbol| lengths[symbol] == len));
+        }
+        Self {
+            counts,
+            symbols,
+            lone: None,
+        }
+    }
+
+    fn with_lone(mut self, symbol: usize) -> Self {
+        self.lone = Some(symbol);
+        self
+    }
+
+    fn decode(&self, read: &mut impl FnMut(u32) -> u32) -> usize {
+        if let Some(symbol) = self.lone {
+            return symbol;
+        }
+        // First code and index of the codes of each length in turn.
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= read(1) as usize;
+            if code < first + count {
+                return self.symbols[index + code - first];
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        panic!("Code outside the tree");
+    }
+}
+
+fn random(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Inputs worth a round trip, by name.
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    // A repeat too far back for any window, brotli's and zstd's 4 MiB
+    // included, with a run in between that matches quickly.
+    let mut far = random(32 * 1024);
+    far.resize((4 << 20) + 64 * 1024, 0);
+    far.extend_from_within(..32 * 1024);
+
+    let mut text = Vec::new();
+    for line in 0..2_000 {
+        text.extend(
+            format!(
+                "{{\"name\":\"file-{}.iso\",\"size\":{}}},",
+                line % 977,
+                line
+            )
+            .bytes(),
+        );
+    }
+
+    vec![
+        ("empty", Vec::new()),
+        ("one byte", vec![b'a']),
+        ("incompressible", random(100_000)),
+        ("text", text),
+        ("beyond the window", far),
+    ]
+}
+
+fn decompress(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut output = Vec::new();
+            Inflate::gzip(data).read_to_end(&mut output).unwrap();
+            output
+        }
+        Encoding::Brotli => brotli::decompress(data),
+        Encoding::Zstd => zstd::decompress(data),
+    }
+}
+
+/// Compresses each input at each level of `encoding` and reads it
+/// back.
+fn round_trips(encoding: Encoding) {
+    let inputs = inputs();
+    let (min, max) = encoding.levels();
+    for level in min..=max {
+        let compression = match encoding {
+            Encoding::Gzip => Compression::new(0, level, 0, 1),
+            Encoding::Brotli => Compression::new(0, 1, level, 1),
+            Encoding::Zstd => Compression::new(0, 1, 0, level),
+        }
+        .unwrap();
+
+        for (name, input) in &inputs {
+            let compressed = compression.compress(input, encoding);
+            let output = decompress(encoding, &compressed);
+            assert!(output == *input, "Level {}: {}", level, name);
+        }
+    }
+}
+
+#[test]
+fn gzip_round_trips() {
+    round_trips(Encoding::Gzip);
+}
+
+#[test]
+fn brotli_round_trips() {
+    round_trips(Encoding::Brotli);
+}
+
+#[test]
+fn zstd_round_trips() {
+    round_trips(Encoding::Zstd);
+}
+
+#[test]
+fn incompressible_input_barely_grows() {
+    let input = random(100_000);
+    let compression = Compression::new(0, 9, 11, 19).unwrap();
+    for encoding in Encoding::ALL {
+        let compressed = compression.compress(&input, encoding);
+        assert!(
+            compressed.len() < input.len() + input.len() / 500,
+            "{}",
+            encoding
+        );
+    }
+}
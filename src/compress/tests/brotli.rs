@@ -0,0 +1,277 @@
+//! A brotli decoder for what the encoder sends: one block type of each
+//! kind, no context modeling and no static dictionary.
+
+use super::Prefix;
+use crate::compress::bits::BitReader;
+
+const INSERT_BASE: [u32; 24] = [
+    0, 1, 2, 3, 4, 5, 6, 8, 10, 14, 18, 26, 34, 50, 66, 98, 130, 194, 322, 578, 1090, 2114, 6210,
+    22594,
+];
+const INSERT_EXTRA: [u32; 24] = [
+    0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 12, 14, 24,
+];
+const COPY_BASE: [u32; 24] = [
+    2, 3, 4, 5, 6, 7, 8, 9, 10, 12, 14, 18, 22, 30, 38, 54, 70, 102, 134, 198, 326, 582, 1094, 2118,
+];
+const COPY_EXTRA: [u32; 24] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 24,
+];
+
+/// Insert and copy code ranges of each cell of 64 commands, as RFC
+/// 7932 section 5 lists them.
+const CELLS: [(u32, u32); 11] = [
+    (0, 0),
+    (0, 8),
+    (0, 0),
+    (0, 8),
+    (8, 0),
+    (8, 8),
+    (0, 16),
+    (16, 0),
+    (8, 16),
+    (16, 8),
+    (16, 16),
+];
+
+const CODE_LENGTH_ORDER: [usize; 18] =
+    [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Distance codes 0 to 15, as an index into the last distances and a
+/// change to it.
+const SHORT_DISTANCES: [(usize, i64); 16] = [
+    (0, 0),
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (0, -1),
+    (0, 1),
+    (0, -2),
+    (0, 2),
+    (0, -3),
+    (0, 3),
+    (1, -1),
+    (1, 1),
+    (1, -2),
+    (1, 2),
+    (1, -3),
+    (1, 3),
+];
+
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitReader::new(data);
+    let mut read = |count| bits.read(count).unwrap();
+    let window_bits = match read(1) {
+        0 => 16,
+        _ => match read(3) {
+            0 => panic!("Unexpected window size"),
+            bits => 17 + bits,
+        },
+    };
+    let max_distance = (1 << window_bits) - 16;
+
+    let mut output = Vec::new();
+    // Last distances, most recent first.
+    let mut distances = [4, 11, 15, 16];
+    loop {
+        let last = read(1) == 1;
+        if last && read(1) == 1 {
+            break;
+        }
+        let nibbles = match read(2) {
+            3 => panic!("Unexpected metadata"),
+            nibbles => nibbles + 4,
+        };
+        let len = read(nibbles * 4) as usize + 1;
+        if !last {
+            assert_eq!(read(1), 0, "Uncompressed meta-block");
+        }
+
+        // One block type of each kind, no postfix or direct distances
+        // and one literal and one distance tree.
+        for _ in 0..3 {
+            assert_eq!(read(1), 0, "Several block types");
+        }
+        assert_eq!(read(6), 0, "Postfix or direct distances");
+        read(2);
+        for _ in 0..2 {
+            assert_eq!(read(1), 0, "Context map");
+        }
+
+        let literals = prefix(&mut read, 256);
+        let commands = prefix(&mut read, 704);
+        let distance_codes = prefix(&mut read, 64);
+
+        let end = output.len() + len;
+        while output.len() < end {
+            let command = commands.decode(&mut read) as u32;
+            let (insert_base, copy_base) = CELLS[command as usize >> 6];
+            let insert = (insert_base + (command >> 3 & 7)) as usize;
+            let copy = (copy_base + (command & 7)) as usize;
+            let insert = INSERT_BASE[insert] + read(INSERT_EXTRA[insert]);
+            let copy = COPY_BASE[copy] + read(COPY_EXTRA[copy]);
+
+            for _ in 0..insert {
+                output.push(literals.decode(&mut read) as u8);
+            }
+            assert!(output.len() <= end, "Literals past the meta-block");
+            if output.len() == end {
+                break;
+            }
+
+            let distance = match command {
+                0..128 => distances[0],
+                _ => match distance_codes.decode(&mut read) {
+                    code @ 0..16 => {
+                        let (last, change) = SHORT_DISTANCES[code];
+                        let distance = distances[last] as i64 + change;
+                        assert!(distance > 0, "Distance below 1");
+                        let distance = distance as usize;
+                        if code > 0 {
+                            distances = [distance, distances[0], distances[1], distances[2]];
+                        }
+                        distance
+                    }
+                    code => {
+                        let bits = 1 + (code as u32 - 16) / 2;
+                        let offset = ((2 + (code - 16) % 2) << bits) - 4;
+                        let distance = offset + read(bits) as usize + 1;
+                        distances = [distance, distances[0], distances[1], distances[2]];
+                        distance
+                    }
+                },
+            };
+            assert!(
+                distance <= output.len().min(max_distance),
+                "Distance {} outside the window",
+                distance
+            );
+            for _ in 0..copy {
+                output.push(output[output.len() - distance]);
+            }
+            assert!(output.len() <= end, "Copy past the meta-block");
+        }
+
+        if last {
+            break;
+        }
+    }
+    assert!(bits.at_end().unwrap(), "Trailing bytes");
+    output
+}
+
+/// A prefix code over `alphabet` symbols, simple or complex.
+fn prefix(read: &mut impl FnMut(u32) -> u32, alphabet: usize) -> Prefix {
+    let alphabet_bits = usize::BITS - (alphabet - 1).leading_zeros();
+    let skip = read(2) as usize;
+
+    let mut lengths = vec![0; alphabet];
+    if skip == 1 {
+        let count = read(2) as usize + 1;
+        let symbols = (0..count)
+            .map(|_| read(alphabet_bits) as usize)
+            .collect::<Vec<_>>();
+        let mut sorted = symbols.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), count, "Repeated symbols");
+        match count {
+            1 => return Prefix::new(&lengths).with_lone(symbols[0]),
+            2 => symbols.iter().for_each(|&symbol| lengths[symbol] = 1),
+            3 => {
+                lengths[symbols[0]] = 1;
+                lengths[symbols[1]] = 2;
+                lengths[symbols[2]] = 2;
+            }
+            _ => {
+                let tree = match read(1) {
+                    0 => [2, 2, 2, 2],
+                    _ => [1, 2, 3, 3],
+                };
+                for (symbol, len) in symbols.into_iter().zip(tree) {
+                    lengths[symbol] = len;
+                }
+            }
+        }
+        return Prefix::new(&lengths);
+    }
+
+    // Lengths of the code length code, in their own fixed code.
+    let mut length_lengths = [0; 18];
+    let mut space = 0;
+    let mut used = 0;
+    for &symbol in &CODE_LENGTH_ORDER[skip..] {
+        let len = match read(2) {
+            0 => 0,
+            1 => 4,
+            2 => 3,
+            _ => match read(1) {
+                0 => 2,
+                _ => match read(1) {
+                    0 => 1,
+                    _ => 5,
+                },
+            },
+        };
+        length_lengths[symbol] = len;
+        if len > 0 {
+            space += 32 >> len;
+            used += 1;
+            if space >= 32 {
+                break;
+            }
+        }
+    }
+    assert!(used == 1 || space == 32, "Incomplete code length code");
+    let length_code = match used {
+        1 => {
+            let lone = length_lengths.iter().position(|&len| len > 0).unwrap();
+            Prefix::new(&[0; 18]).with_lone(lone)
+        }
+        _ => Prefix::new(&length_lengths),
+    };
+
+    let mut symbol = 0;
+    let mut space = 0;
+    let mut previous = 8;
+    // The kind and count of the repeat before, as repeats build on it.
+    let mut repeat = (0, 0);
+    while symbol < alphabet && space < 1 << 15 {
+        let code = length_code.decode(read);
+        if code < 16 {
+            lengths[symbol] = code as u8;
+            symbol += 1;
+            if code > 0 {
+                previous = code as u8;
+                space += (1 << 15) >> code;
+            }
+            repeat = (0, 0);
+            continue;
+        }
+
+        let (len, bits) = match code {
+            16 => (previous, 2),
+            _ => (0, 3),
+        };
+        let count = match repeat {
+            (kind, count) if kind == code && count > 0 => {
+                ((count - 2) << bits) + read(bits) as usize + 3 - count
+            }
+            _ => read(bits) as usize + 3,
+        };
+        repeat = match repeat {
+            (kind, total) if kind == code && total > 0 => (code, total + count),
+            _ => (code, count),
+        };
+        assert!(symbol + count <= alphabet, "Repeat past the alphabet");
+        for _ in 0..count {
+            lengths[symbol] = len;
+            symbol += 1;
+            if len > 0 {
+                space += (1 << 15) >> len;
+            }
+        }
+    }
+    assert_eq!(space, 1 << 15, "Incomplete code");
+    Prefix::new(&lengths)
+}
@@ -0,0 +1,495 @@
+//! A zstd decoder for single frames without dictionaries or checksums,
+//! whose blocks describe their own tables.
+
+const MAGIC: u32 = 0xfd2f_b528;
+
+const LITERAL_LENGTH_BASE: [u32; 36] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64,
+    128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536,
+];
+const LITERAL_LENGTH_BITS: [u32; 36] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16,
+];
+const MATCH_LENGTH_BASE: [u32; 53] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    28, 29, 30, 31, 32, 33, 34, 35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027,
+    2051, 4099, 8195, 16387, 32771, 65539,
+];
+const MATCH_LENGTH_BITS: [u32; 53] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+];
+
+/// Default distributions, as RFC 8878 section 3.1.1.3.2.2 has them.
+const LITERAL_LENGTH_NORM: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const MATCH_LENGTH_NORM: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OFFSET_NORM: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut input = Input(data);
+    assert_eq!(u32::from_le_bytes(input.array()), MAGIC);
+
+    let [descriptor] = input.array();
+    let single_segment = descriptor & 0x20 != 0;
+    assert_eq!(descriptor & 0x1f, 0, "Checksum, dictionary or reserved bit");
+    let window = match single_segment {
+        true => usize::MAX,
+        false => {
+            let [byte] = input.array();
+            let base = 1usize << (10 + (byte >> 3));
+            base + base / 8 * (byte & 7) as usize
+        }
+    };
+    let size = match (descriptor >> 6, single_segment) {
+        (0, false) => None,
+        (0, true) => Some(input.take(1)[0] as u64),
+        (1, _) => Some(u16::from_le_bytes(input.array()) as u64 + 256),
+        (2, _) => Some(u32::from_le_bytes(input.array()) as u64),
+        _ => Some(u64::from_le_bytes(input.array())),
+    };
+
+    let mut output = Vec::new();
+    let mut offsets = [1, 4, 8];
+    loop {
+        let [low, middle, high] = input.array();
+        let header = u32::from_le_bytes([low, middle, high, 0]);
+        let len = (header >> 3) as usize;
+        match header >> 1 & 3 {
+            0 => output.extend(input.take(len)),
+            1 => {
+                let [byte] = input.array();
+                output.extend(std::iter::repeat_n(byte, len));
+            }
+            2 => {
+                let block = input.take(len);
+                assert!(len < 128 * 1024, "Block too large");
+                decode_block(block, &mut output, &mut offsets, window);
+            }
+            _ => panic!("Reserved block type"),
+        }
+        if header & 1 == 1 {
+            break;
+        }
+    }
+    assert!(input.0.is_empty(), "Trailing bytes");
+    if let Some(size) = size {
+        assert_eq!(output.len() as u64, size, "Content size");
+    }
+    output
+}
+
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        taken
+    }
+
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        self.take(N).try_into().unwrap()
+    }
+}
+
+fn decode_block(block: &[u8], output: &mut Vec<u8>, offsets: &mut [usize; 3], window: usize) {
+    let mut input = Input(block);
+    let literals = literals(&mut input);
+
+    let count = match input.array() {
+        [0] => 0,
+        [byte @ 1..128] => byte as usize,
+        [255] => u16::from_le_bytes(input.array()) as usize + 0x7f00,
+        [byte] => ((byte as usize - 128) << 8) + input.array::<1>()[0] as usize,
+    };
+    if count == 0 {
+        assert!(input.0.is_empty(), "Bytes after the literals");
+        output.extend(literals);
+        return;
+    }
+
+    let [modes] = input.array();
+    assert_eq!(modes & 3, 0, "Reserved bits");
+    let literal_lengths = table(&mut input, modes >> 6, &LITERAL_LENGTH_NORM, 6, 9);
+    let offset_codes = table(&mut input, modes >> 4 & 3, &OFFSET_NORM, 5, 8);
+    let match_lengths = table(&mut input, modes >> 2 & 3, &MATCH_LENGTH_NORM, 6, 9);
+
+    let mut bits = Backward::new(input.0);
+    let mut literal_length = bits.read(literal_lengths.log) as usize;
+    let mut offset_code = bits.read(offset_codes.log) as usize;
+    let mut match_length = bits.read(match_lengths.log) as usize;
+
+    let mut literals = &literals[..];
+    for left in (0..count).rev() {
+        let (offset_symbol, _, _) = offset_codes.states[offset_code];
+        let (match_symbol, _, _) = match_lengths.states[match_length];
+        let (literal_symbol, _, _) = literal_lengths.states[literal_length];
+        let (offset_symbol, match_symbol, literal_symbol) = (
+            offset_symbol as u32,
+            match_symbol as usize,
+            literal_symbol as usize,
+        );
+
+        assert!(offset_symbol <= 31, "Offset code");
+        let offset_value = (1 << offset_symbol) + bits.read(offset_symbol) as usize;
+        let copy = MATCH_LENGTH_BASE[match_symbol] as usize
+            + bits.read(MATCH_LENGTH_BITS[match_symbol]) as usize;
+        let insert = LITERAL_LENGTH_BASE[literal_symbol] as usize
+            + bits.read(LITERAL_LENGTH_BITS[literal_symbol]) as usize;
+
+        let offset = match (offset_value, insert) {
+            (4.., _) => offset_value - 3,
+            (repeat, 0) if repeat < 3 => offsets[repeat],
+            (3, 0) => offsets[0] - 1,
+            (repeat, _) => offsets[repeat - 1],
+        };
+        *offsets = match (offset_value, insert) {
+            (4.., _) | (2.., 0) | (3, _) => [offset, offsets[0], offsets[1]],
+            (1, 0) | (2, _) => [offset, offsets[0], offsets[2]],
+            _ => *offsets,
+        };
+
+        let (inserted, rest) = literals.split_at(insert);
+        output.extend(inserted);
+        literals = rest;
+        assert!(
+            offset > 0 && offset <= output.len().min(window),
+            "Offset {} outside the window",
+            offset
+        );
+        for _ in 0..copy {
+            output.push(output[output.len() - offset]);
+        }
+
+        if left > 0 {
+            literal_length = literal_lengths.next(literal_length, &mut bits);
+            match_length = match_lengths.next(match_length, &mut bits);
+            offset_code = offset_codes.next(offset_code, &mut bits);
+        }
+    }
+    assert_eq!(bits.left, 0, "Sequence bits left over");
+    output.extend(literals);
+}
+
+fn literals(input: &mut Input) -> Vec<u8> {
+    let [first] = input.array();
+    let kind = first & 3;
+    let format = first >> 2 & 3;
+
+    if kind < 2 {
+        let len = match format {
+            0 | 2 => (first >> 3) as usize,
+            1 => (u16::from_le_bytes([first, input.array::<1>()[0]]) >> 4) as usize,
+            _ => {
+                let [middle, high] = input.array();
+                (u32::from_le_bytes([first, middle, high, 0]) >> 4) as usize
+            }
+        };
+        return match kind {
+            0 => input.take(len).to_vec(),
+            _ => vec![input.array::<1>()[0]; len],
+        };
+    }
+
+    assert_eq!(kind, 2, "Literals with the previous tree");
+    let (header_len, size_bits) = match format {
+        0 | 1 => (3, 10),
+        2 => (4, 14),
+        _ => (5, 18),
+    };
+    let mut header = [0; 8];
+    header[0] = first;
+    header[1..header_len].copy_from_slice(input.take(header_len - 1));
+    let header = u64::from_le_bytes(header);
+    let mask = (1 << size_bits) - 1;
+    let len = (header >> 4 & mask) as usize;
+    let compressed = (header >> (4 + size_bits) & mask) as usize;
+
+    let mut body = Input(input.take(compressed));
+    let tree = Tree::new(&mut body);
+    if format == 0 {
+        return tree.decode(body.0, len);
+    }
+
+    let sizes = [(); 3].map(|_| u16::from_le_bytes(body.array()) as usize);
+    let segment = len.div_ceil(4);
+    let mut literals = Vec::with_capacity(len);
+    for stream in 0..4 {
+        let stream_len = match sizes.get(stream) {
+            Some(&size) => size,
+            None => body.0.len(),
+        };
+        let regenerated = segment.min(len - literals.len());
+        literals.extend(tree.decode(body.take(stream_len), regenerated));
+    }
+    literals
+}
+
+/// Bits read backwards from the end of a stream, from the set bit that
+/// ends it, most significant first. Reads past its start give zeros.
+struct Backward<'a> {
+    bytes: &'a [u8],
+    /// Bits not read yet.
+    left: usize,
+    /// Set once a read went past the start.
+    overrun: bool,
+}
+
+impl<'a> Backward<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        let last = *bytes.last().expect("Empty stream");
+        assert_ne!(last, 0, "No end mark");
+        let left = bytes.len() * 8 - last.leading_zeros() as usize - 1;
+        Self {
+            bytes,
+            left,
+            overrun: false,
+        }
+    }
+
+    fn read(&mut self, count: u32) -> u32 {
+        let mut value = 0;
+        for _ in 0..count {
+            let bit = match self.left {
+                0 => {
+                    self.overrun = true;
+                    0
+                }
+                left => {
+                    self.left -= 1;
+                    self.bytes[(left - 1) / 8] >> ((left - 1) % 8) & 1
+                }
+            };
+            value = value << 1 | bit as u32;
+        }
+        value
+    }
+}
+
+/// A literal Huffman tree, from its weights.
+struct Tree {
+    /// Symbol and length of each code of `max_bits`.
+    table: Vec<(u8, u32)>,
+    max_bits: u32,
+}
+
+impl Tree {
+    fn new(input: &mut Input) -> Self {
+        let [header] = input.array();
+        let mut weights = match header {
+            0..128 => {
+                let mut input = Input(input.take(header as usize));
+                let table = describe(&mut input, 6);
+                fse_weights(&table, input.0)
+            }
+            _ => {
+                let count = header as usize - 127;
+                let bytes = input.take(count.div_ceil(2));
+                (0..count)
+                    .map(|at| match at % 2 {
+                        0 => bytes[at / 2] >> 4,
+                        _ => bytes[at / 2] & 15,
+                    })
+                    .collect()
+            }
+        };
+
+        let total = weights
+            .iter()
+            .filter(|&&weight| weight > 0)
+            .map(|&weight| 1u32 << (weight - 1))
+            .sum::<u32>();
+        let max_bits = 32 - total.leading_zeros();
+        let rest = (1 << max_bits) - total;
+        assert!(rest.is_power_of_two(), "Weights that don't add up");
+        weights.push(rest.trailing_zeros() as u8 + 1);
+        assert!(max_bits <= 11, "Code too long");
+
+        let mut table = Vec::with_capacity(1 << max_bits);
+        for weight in 1..=max_bits as u8 {
+            for (symbol, _) in weights.iter().enumerate().filter(|(_, &w)| w == weight) {
+                let entry = (symbol as u8, max_bits + 1 - weight as u32);
+                table.extend(std::iter::repeat_n(entry, 1 << (weight - 1)));
+            }
+        }
+        assert_eq!(table.len(), 1 << max_bits);
+        Self { table, max_bits }
+    }
+
+    fn decode(&self, stream: &[u8], len: usize) -> Vec<u8> {
+        let mut bits = Backward::new(stream);
+        let mut literals = Vec::with_capacity(len);
+        // Peek at a full code, giving back what it didn't use.
+        while literals.len() < len {
+            let left = bits.left;
+            let (symbol, code_len) = self.table[bits.read(self.max_bits) as usize];
+            assert!(left >= code_len as usize, "Literals past the stream");
+            bits.left = left;
+            bits.read(code_len);
+            literals.push(symbol);
+        }
+        assert_eq!(bits.left, 0, "Literal bits left over");
+        literals
+    }
+}
+
+/// Huffman weights sent through a table in two states taking turns,
+/// until one of them runs out of bits.
+fn fse_weights(table: &Table, stream: &[u8]) -> Vec<u8> {
+    let mut bits = Backward::new(stream);
+    let mut states = [(); 2].map(|_| bits.read(table.log) as usize);
+    let mut weights = Vec::new();
+    for turn in [0, 1].into_iter().cycle() {
+        weights.push(table.states[states[turn]].0 as u8);
+        states[turn] = table.next(states[turn], &mut bits);
+        if bits.overrun {
+            weights.push(table.states[states[turn ^ 1]].0 as u8);
+            break;
+        }
+    }
+    weights
+}
+
+/// A finite state entropy decoding table.
+struct Table {
+    log: u32,
+    /// Symbol, bits to read and base of the next state, by state.
+    states: Vec<(u16, u32, usize)>,
+}
+
+impl Table {
+    fn new(norm: &[i16], log: u32) -> Self {
+        let size = 1 << log;
+        let mut symbols = vec![0u16; size];
+        let mut next = vec![0u32; norm.len()];
+        let mut high = size;
+        for (symbol, &count) in norm.iter().enumerate() {
+            if count == -1 {
+                high -= 1;
+                symbols[high] = symbol as u16;
+                next[symbol] = 1;
+            } else {
+                next[symbol] = count.max(0) as u32;
+            }
+        }
+
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut position = 0;
+        for (symbol, &count) in norm.iter().enumerate() {
+            for _ in 0..count.max(0) {
+                symbols[position] = symbol as u16;
+                position = (position + step) & (size - 1);
+                while position >= high {
+                    position = (position + step) & (size - 1);
+                }
+            }
+        }
+        assert_eq!(position, 0, "Spread that doesn't come round");
+
+        let states = symbols
+            .into_iter()
+            .map(|symbol| {
+                let state = next[symbol as usize];
+                next[symbol as usize] += 1;
+                let bits = log - (31 - state.leading_zeros());
+                (symbol, bits, ((state << bits) as usize) - size)
+            })
+            .collect();
+        Self { log, states }
+    }
+
+    fn next(&self, state: usize, bits: &mut Backward) -> usize {
+        let (_, count, base) = self.states[state];
+        base + bits.read(count) as usize
+    }
+}
+
+/// The table of one part of the sequences, in `mode`.
+fn table(input: &mut Input, mode: u8, predefined: &[i16], log: u32, max_log: u32) -> Table {
+    match mode {
+        0 => Table::new(predefined, log),
+        1 => {
+            let [symbol] = input.array();
+            let mut norm = vec![0; symbol as usize + 1];
+            norm[symbol as usize] = 1;
+            Table::new(&norm, 0)
+        }
+        2 => {
+            let table = describe(input, max_log);
+            assert!(table.log <= max_log, "Table too large");
+            table
+        }
+        _ => panic!("Repeated table"),
+    }
+}
+
+/// Reads the counts of a table, as RFC 8878 section 4.1.1 has them.
+fn describe(input: &mut Input, max_log: u32) -> Table {
+    let bytes = input.0;
+    let mut at = 0;
+    let mut read = |count: u32, peek: bool| {
+        let mut value = 0;
+        for bit in 0..count {
+            let pos = at + bit as usize;
+            let byte = bytes.get(pos / 8).copied().unwrap_or(0);
+            value |= ((byte >> (pos % 8) & 1) as u32) << bit;
+        }
+        if !peek {
+            at += count as usize;
+        }
+        value
+    };
+
+    let log = read(4, false) + 5;
+    assert!(log <= max_log, "Table too large");
+    let mut remaining = (1 << log) + 1;
+    let mut threshold = 1 << log;
+    let mut bits = log + 1;
+    let mut norm = Vec::new();
+    let mut after_zero = false;
+
+    while remaining > 1 {
+        if after_zero {
+            loop {
+                let repeat = read(2, false);
+                norm.extend(std::iter::repeat_n(0, repeat as usize));
+                if repeat < 3 {
+                    break;
+                }
+            }
+        }
+
+        let max = 2 * threshold - 1 - remaining;
+        let value = match read(bits - 1, true) {
+            low if low < max => {
+                read(bits - 1, false);
+                low
+            }
+            _ => match read(bits, false) {
+                value if value >= threshold => value - max,
+                value => value,
+            },
+        };
+        let count = value as i32 - 1;
+        remaining -= count.unsigned_abs();
+        norm.push(count as i16);
+        after_zero = count == 0;
+        while remaining < threshold {
+            bits -= 1;
+            threshold >>= 1;
+        }
+    }
+    assert_eq!(remaining, 1, "Counts that don't add up");
+
+    input.take(at.div_ceil(8));
+    Table::new(&norm, log)
+}
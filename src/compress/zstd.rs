@@ -0,0 +1,655 @@
+use super::bits::BitWriter;
+use super::huffman;
+use super::lz77::{Command, Matcher};
+
+const MAGIC: u32 = 0xfd2f_b528;
+
+/// Largest block, compressed or not.
+const BLOCK_LEN: usize = 128 * 1024;
+
+/// Base two logarithms of the smallest and largest window.
+const MIN_WINDOW_LOG: u32 = 10;
+const MAX_WINDOW_LOG: u32 = 22;
+
+const MAX_MATCH: usize = 64 * 1024;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_COMPRESSED: u32 = 2;
+
+const LITERALS_RAW: u32 = 0;
+const LITERALS_RLE: u32 = 1;
+const LITERALS_HUFFMAN: u32 = 2;
+
+/// Longest literal code.
+const HUFFMAN_LIMIT: u8 = 11;
+/// Literal weights may be sent directly, four bits each, for the
+/// first 129 bytes.
+const DIRECT_WEIGHTS: usize = 128;
+
+/// Table logs of the codes a block describes itself.
+const MIN_TABLE_LOG: u32 = 5;
+const LITERAL_LENGTH_LOG: u32 = 9;
+const MATCH_LENGTH_LOG: u32 = 9;
+const OFFSET_LOG: u32 = 8;
+const WEIGHT_LOG: u32 = 6;
+
+const MODE_PREDEFINED: u8 = 0;
+const MODE_COMPRESSED: u8 = 2;
+
+const LITERAL_LENGTH_BASE: [u32; 36] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64,
+    128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536,
+];
+const LITERAL_LENGTH_BITS: [u8; 36] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16,
+];
+const MATCH_LENGTH_BASE: [u32; 53] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    28, 29, 30, 31, 32, 33, 34, 35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027,
+    2051, 4099, 8195, 16387, 32771, 65539,
+];
+const MATCH_LENGTH_BITS: [u8; 53] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+];
+
+/// The predefined distributions of each code, with their table logs.
+/// Codes of -1 are less likely than any other.
+const LITERAL_LENGTH_NORM: (&[i16], u32) = (
+    &[
+        4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1,
+        1, 1, -1, -1, -1, -1,
+    ],
+    6,
+);
+const MATCH_LENGTH_NORM: (&[i16], u32) = (
+    &[
+        1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+    ],
+    6,
+);
+const OFFSET_NORM: (&[i16], u32) = (
+    &[
+        1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+    ],
+    5,
+);
+
+/// A zstd frame holding `data`, with `effort` from 0 to 9.
+pub(super) fn compress(data: &[u8], effort: u32) -> Vec<u8> {
+    let window_log = (usize::BITS - data.len().saturating_sub(1).leading_zeros())
+        .clamp(MIN_WINDOW_LOG, MAX_WINDOW_LOG);
+
+    let mut out = MAGIC.to_le_bytes().to_vec();
+    let size = data.len() as u64;
+    let (flag, size_bytes) = match size {
+        0..=255 => (0, Vec::new()),
+        256..=65791 => (1, (size as u16 - 256).to_le_bytes().to_vec()),
+        65792..=0xffff_ffff => (2, (size as u32).to_le_bytes().to_vec()),
+        _ => (3, size.to_le_bytes().to_vec()),
+    };
+    out.push(flag << 6);
+    out.push(((window_log - MIN_WINDOW_LOG) << 3) as u8);
+    out.extend(size_bytes);
+
+    if data.is_empty() {
+        block_header(&mut out, true, BLOCK_RAW, 0);
+        return out;
+    }
+
+    let mut matcher = Matcher::new(data, 1 << window_log, MAX_MATCH, effort);
+    let mut start = 0;
+    while start < data.len() {
+        let end = (start + BLOCK_LEN).min(data.len());
+        let last = end == data.len();
+        let commands = matcher.commands(start, end);
+        match block(&data[start..end], &commands) {
+            Some(block) if block.len() < end - start => {
+                block_header(&mut out, last, BLOCK_COMPRESSED, block.len());
+                out.extend(block);
+            }
+            _ => {
+                block_header(&mut out, last, BLOCK_RAW, end - start);
+                out.extend(&data[start..end]);
+            }
+        }
+        start = end;
+    }
+    out
+}
+
+fn block_header(out: &mut Vec<u8>, last: bool, kind: u32, len: usize) {
+    let header = last as u32 | kind << 1 | (len as u32) << 3;
+    out.extend(&header.to_le_bytes()[..3]);
+}
+
+/// A compressed block, or `None` if it couldn't be coded.
+fn block(data: &[u8], commands: &[Command]) -> Option<Vec<u8>> {
+    let mut literals = Vec::new();
+    let mut pos = 0;
+    for command in commands {
+        literals.extend(&data[pos..pos + command.literals]);
+        pos += command.literals + command.length;
+    }
+
+    let mut out = literals_section(&literals);
+    // The last literals follow the last sequence on their own.
+    let sequences = commands
+        .iter()
+        .filter(|command| command.length > 0)
+        .map(Sequence::new)
+        .collect::<Vec<_>>();
+
+    let count = sequences.len();
+    match count {
+        0..=127 => out.push(count as u8),
+        128..=0x7eff => out.extend([(count >> 8) as u8 + 128, count as u8]),
+        _ => {
+            let rest = (count - 0x7f00) as u16;
+            out.push(0xff);
+            out.extend(rest.to_le_bytes());
+        }
+    }
+    if count == 0 {
+        return Some(out);
+    }
+
+    let literal_lengths = Coding::new(
+        sequences.iter().map(|sequence| sequence.literal_length.0),
+        LITERAL_LENGTH_NORM,
+        LITERAL_LENGTH_LOG,
+    );
+    let offsets = Coding::new(
+        sequences.iter().map(|sequence| sequence.offset.0),
+        OFFSET_NORM,
+        OFFSET_LOG,
+    );
+    let match_lengths = Coding::new(
+        sequences.iter().map(|sequence| sequence.match_length.0),
+        MATCH_LENGTH_NORM,
+        MATCH_LENGTH_LOG,
+    );
+    out.push(literal_lengths.mode << 6 | offsets.mode << 4 | match_lengths.mode << 2);
+    for coding in [&literal_lengths, &offsets, &match_lengths] {
+        out.extend(&coding.description);
+    }
+    out.extend(encode(
+        &sequences,
+        &literal_lengths.table,
+        &offsets.table,
+        &match_lengths.table,
+    ));
+
+    (out.len() < BLOCK_LEN).then_some(out)
+}
+
+/// Literals as Huffman coded streams, one byte repeated, or as they
+/// are.
+fn literals_section(literals: &[u8]) -> Vec<u8> {
+    let len = literals.len();
+    if len > 0 && literals.iter().all(|&byte| byte == literals[0]) {
+        let mut out = size_header(LITERALS_RLE, len);
+        out.push(literals[0]);
+        return out;
+    }
+    if let Some(out) = huffman_literals(literals) {
+        if out.len() < len + size_header(LITERALS_RAW, len).len() {
+            return out;
+        }
+    }
+    let mut out = size_header(LITERALS_RAW, len);
+    out.extend(literals);
+    out
+}
+
+/// Header of raw or repeated literals.
+fn size_header(kind: u32, len: usize) -> Vec<u8> {
+    let len = len as u32;
+    match len {
+        0..=31 => vec![(kind | len << 3) as u8],
+        32..=4095 => (kind | 1 << 2 | len << 4).to_le_bytes()[..2].to_vec(),
+        _ => (kind | 3 << 2 | len << 4).to_le_bytes()[..3].to_vec(),
+    }
+}
+
+fn huffman_literals(literals: &[u8]) -> Option<Vec<u8>> {
+    let mut freqs = [0; 256];
+    for &byte in literals {
+        freqs[byte as usize] += 1;
+    }
+    let max_symbol = freqs.iter().rposition(|&freq| freq > 0)?;
+
+    let lengths = huffman::lengths(&freqs[..=max_symbol], HUFFMAN_LIMIT);
+    let max_bits = lengths.iter().copied().max()?;
+    let weights = lengths
+        .iter()
+        .map(|&len| match len {
+            0 => 0,
+            _ => max_bits + 1 - len,
+        })
+        .collect::<Vec<_>>();
+    let codes = codes(&weights, max_bits);
+
+    // The weight of the last symbol follows from the others.
+    let weights = &weights[..max_symbol];
+    let direct = (weights.len() <= DIRECT_WEIGHTS).then(|| {
+        let mut tree = vec![127 + weights.len() as u8];
+        for pair in weights.chunks(2) {
+            tree.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+        }
+        tree
+    });
+    let tree = [direct, compressed_weights(weights)]
+        .into_iter()
+        .flatten()
+        .min_by_key(Vec::len)?;
+
+    let stream = |literals: &[u8]| {
+        let mut writer = BitWriter::new();
+        for &byte in literals.iter().rev() {
+            let symbol = byte as usize;
+            writer.write(codes[symbol] as u64, lengths[symbol] as u32);
+        }
+        writer.close()
+    };
+
+    let len = literals.len();
+    let mut body = tree;
+    let (four, format) = match len {
+        0..=1023 => (false, 0),
+        1024..=16383 => (true, 2),
+        _ => (true, 3),
+    };
+    if four {
+        let segment = len.div_ceil(4);
+        let streams = literals.chunks(segment).map(stream).collect::<Vec<_>>();
+        if streams.len() != 4 {
+            return None;
+        }
+        for stream in &streams[..3] {
+            body.extend(u16::try_from(stream.len()).ok()?.to_le_bytes());
+        }
+        streams.iter().for_each(|stream| body.extend(stream));
+    } else {
+        body.extend(stream(literals));
+    }
+
+    let (len, compressed) = (len as u64, body.len() as u64);
+    let mut out = match format {
+        0 if compressed < 1024 => {
+            let header = LITERALS_HUFFMAN as u64 | len << 4 | compressed << 14;
+            header.to_le_bytes()[..3].to_vec()
+        }
+        2 if compressed < 16384 => {
+            let header = LITERALS_HUFFMAN as u64 | 2 << 2 | len << 4 | compressed << 18;
+            header.to_le_bytes()[..4].to_vec()
+        }
+        3 if compressed < 1 << 18 => {
+            let header = LITERALS_HUFFMAN as u64 | 3 << 2 | len << 4 | compressed << 22;
+            header.to_le_bytes()[..5].to_vec()
+        }
+        _ => return None,
+    };
+    out.extend(body);
+    Some(out)
+}
+
+/// Literal weights coded with a table of their own, in two states
+/// taking turns, or `None` if they can't be.
+fn compressed_weights(weights: &[u8]) -> Option<Vec<u8>> {
+    let mut counts = [0; HUFFMAN_LIMIT as usize + 1];
+    for &weight in weights {
+        counts[weight as usize] += 1;
+    }
+    if weights.len() < 3 || counts.iter().filter(|&&count| count > 0).count() < 2 {
+        return None;
+    }
+
+    let norm = normalize(&counts, WEIGHT_LOG);
+    let table = Fse::new(&norm, WEIGHT_LOG);
+    let mut writer = BitWriter::new();
+    describe(&mut writer, &norm, WEIGHT_LOG);
+    let mut out = writer.finish();
+
+    let mut writer = BitWriter::new();
+    let last = weights.len() - 1;
+    let mut states = [0; 2];
+    for at in [last, last - 1] {
+        states[at % 2] = table.init(weights[at]);
+    }
+    for at in (0..last - 1).rev() {
+        table.encode(&mut writer, &mut states[at % 2], weights[at]);
+    }
+    table.flush(&mut writer, states[1]);
+    table.flush(&mut writer, states[0]);
+    out.extend(writer.close());
+
+    let len = u8::try_from(out.len()).ok().filter(|&len| len < 128)?;
+    out.insert(0, len);
+    Some(out)
+}
+
+/// Huffman codes as zstd assigns them: by weight, lightest first, then
+/// by symbol, each read most significant bit first.
+fn codes(weights: &[u8], max_bits: u8) -> Vec<u16> {
+    let mut start = vec![0u32; max_bits as usize + 2];
+    for &weight in weights.iter().filter(|&&weight| weight > 0) {
+        start[weight as usize + 1] += 1 << (weight - 1);
+    }
+    for weight in 1..start.len() {
+        start[weight] += start[weight - 1];
+    }
+
+    weights
+        .iter()
+        .map(|&weight| match weight {
+            0 => 0,
+            _ => {
+                let at = &mut start[weight as usize];
+                let code = *at >> (weight - 1);
+                *at += 1 << (weight - 1);
+                code as u16
+            }
+        })
+        .collect()
+}
+
+/// Codes and extra bits of a match.
+struct Sequence {
+    literal_length: (u8, u32),
+    match_length: (u8, u32),
+    offset: (u8, u32),
+}
+
+impl Sequence {
+    fn new(command: &Command) -> Self {
+        let code = |bases: &[u32], value: usize| {
+            (bases.partition_point(|&base| base as usize <= value) - 1) as u8
+        };
+        let literal_length = code(&LITERAL_LENGTH_BASE, command.literals);
+        let match_length = code(&MATCH_LENGTH_BASE, command.length);
+        // Offsets of 1 to 3 stand for repeated ones.
+        let offset = command.distance as u32 + 3;
+
+        Self {
+            literal_length: (
+                literal_length,
+                command.literals as u32 - LITERAL_LENGTH_BASE[literal_length as usize],
+            ),
+            match_length: (
+                match_length,
+                command.length as u32 - MATCH_LENGTH_BASE[match_length as usize],
+            ),
+            offset: (31 - offset.leading_zeros() as u8, offset),
+        }
+    }
+}
+
+/// How a block codes one part of its sequences: with the predefined
+/// table, or with one of its own sent ahead of them.
+struct Coding {
+    mode: u8,
+    description: Vec<u8>,
+    table: Fse,
+}
+
+impl Coding {
+    /// Whichever table makes `codes` smaller, its own description
+    /// included.
+    fn new(codes: impl Iterator<Item = u8>, predefined: (&[i16], u32), max_log: u32) -> Self {
+        let mut counts = vec![0; predefined.0.len()];
+        let mut total = 0usize;
+        for code in codes {
+            counts[code as usize] += 1;
+            total += 1;
+        }
+
+        let max_symbol = counts.iter().rposition(|&count| count > 0).unwrap_or(0);
+        let distinct = counts.iter().filter(|&&count| count > 0).count();
+        if distinct > 1 {
+            let log = (usize::BITS - total.leading_zeros())
+                .saturating_sub(2)
+                .max(usize::BITS - max_symbol.leading_zeros() + 1)
+                .clamp(MIN_TABLE_LOG, max_log);
+            let norm = normalize(&counts, log);
+            let mut writer = BitWriter::new();
+            describe(&mut writer, &norm, log);
+            let description = writer.finish();
+
+            let own = description.len() as f64 * 8.0 + cost(&counts, &norm, log);
+            if own < cost(&counts, predefined.0, predefined.1) {
+                return Self {
+                    mode: MODE_COMPRESSED,
+                    description,
+                    table: Fse::new(&norm, log),
+                };
+            }
+        }
+
+        Self {
+            mode: MODE_PREDEFINED,
+            description: Vec::new(),
+            table: Fse::new(predefined.0, predefined.1),
+        }
+    }
+}
+
+/// Roughly the bits `counts` take with a table of `norm`.
+fn cost(counts: &[u32], norm: &[i16], log: u32) -> f64 {
+    counts
+        .iter()
+        .zip(norm)
+        .map(|(&count, &norm)| count as f64 * (log as f64 - (norm.max(1) as f64).log2()))
+        .sum()
+}
+
+/// `counts` scaled to add up to a table of `1 << log` states, every
+/// used symbol keeping at least one.
+fn normalize(counts: &[u32], log: u32) -> Vec<i16> {
+    let size = 1i64 << log;
+    let total = counts.iter().map(|&count| count as i64).sum::<i64>();
+    let mut norm = counts
+        .iter()
+        .map(|&count| match count {
+            0 => 0,
+            _ => (count as i64 * size / total).max(1),
+        })
+        .collect::<Vec<_>>();
+
+    // Rounding goes to the most common symbols.
+    let mut sum = norm.iter().sum::<i64>();
+    if sum < size {
+        let at = (0..norm.len()).max_by_key(|&at| counts[at]).unwrap();
+        norm[at] += size - sum;
+    }
+    while sum > size {
+        let at = (0..norm.len()).max_by_key(|&at| norm[at]).unwrap();
+        let change = (sum - size).min(norm[at] - 1);
+        norm[at] -= change;
+        sum -= change;
+    }
+    norm.into_iter().map(|norm| norm as i16).collect()
+}
+
+/// Writes the counts of a table as zstd reads them: each in as few
+/// bits as the counts left allow, and runs of unused symbols as
+/// repeat flags.
+fn describe(writer: &mut BitWriter, norm: &[i16], log: u32) {
+    writer.write((log - MIN_TABLE_LOG) as u64, 4);
+
+    let mut remaining = (1 << log) + 1;
+    let mut threshold = 1 << log;
+    let mut bits = log + 1;
+    let mut symbol = 0;
+    let mut after_zero = false;
+
+    while symbol < norm.len() && remaining > 1 {
+        if after_zero {
+            let mut start = symbol;
+            while symbol < norm.len() && norm[symbol] == 0 {
+                symbol += 1;
+            }
+            while symbol >= start + 24 {
+                start += 24;
+                writer.write(0xffff, 16);
+            }
+            while symbol >= start + 3 {
+                start += 3;
+                writer.write(3, 2);
+            }
+            writer.write((symbol - start) as u64, 2);
+        }
+
+        let count = norm[symbol] as i32;
+        symbol += 1;
+        let max = 2 * threshold - 1 - remaining;
+        remaining -= count.abs();
+        let mut value = count + 1;
+        if value >= threshold {
+            value += max;
+        }
+        writer.write(value as u64, bits - (value < max) as u32);
+        after_zero = value == 1;
+        while remaining < threshold {
+            bits -= 1;
+            threshold >>= 1;
+        }
+    }
+}
+
+/// The sequences bitstream, written last to first for the decoder to
+/// read backwards.
+fn encode(
+    sequences: &[Sequence],
+    literal_lengths: &Fse,
+    offsets: &Fse,
+    match_lengths: &Fse,
+) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    let extras = |writer: &mut BitWriter, sequence: &Sequence| {
+        let (code, extra) = sequence.literal_length;
+        writer.write(extra as u64, LITERAL_LENGTH_BITS[code as usize] as u32);
+        let (code, extra) = sequence.match_length;
+        writer.write(extra as u64, MATCH_LENGTH_BITS[code as usize] as u32);
+        let (code, offset) = sequence.offset;
+        writer.write(offset as u64, code as u32);
+    };
+
+    let (last, rest) = sequences.split_last().unwrap();
+    let mut match_length = match_lengths.init(last.match_length.0);
+    let mut offset = offsets.init(last.offset.0);
+    let mut literal_length = literal_lengths.init(last.literal_length.0);
+    extras(&mut writer, last);
+
+    for sequence in rest.iter().rev() {
+        offsets.encode(&mut writer, &mut offset, sequence.offset.0);
+        match_lengths.encode(&mut writer, &mut match_length, sequence.match_length.0);
+        literal_lengths.encode(&mut writer, &mut literal_length, sequence.literal_length.0);
+        extras(&mut writer, sequence);
+    }
+
+    match_lengths.flush(&mut writer, match_length);
+    offsets.flush(&mut writer, offset);
+    literal_lengths.flush(&mut writer, literal_length);
+    writer.close()
+}
+
+/// A finite state entropy coding table.
+struct Fse {
+    log: u32,
+    /// Next states, grouped by symbol.
+    states: Vec<u16>,
+    /// Per symbol, the bits to send from a state, less the state, in
+    /// the high half, and where its next states start.
+    transforms: Vec<(u32, i32)>,
+}
+
+impl Fse {
+    fn new(norm: &[i16], log: u32) -> Self {
+        let size = 1 << log;
+        let mask = size - 1;
+        let mut symbols = vec![0; size];
+
+        let mut high = size - 1;
+        let mut cumulative = vec![0; norm.len() + 1];
+        for (symbol, &count) in norm.iter().enumerate() {
+            cumulative[symbol + 1] = cumulative[symbol]
+                + match count {
+                    -1 => {
+                        symbols[high] = symbol;
+                        high -= 1;
+                        1
+                    }
+                    _ => count as usize,
+                };
+        }
+
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut position = 0;
+        for (symbol, &count) in norm.iter().enumerate() {
+            for _ in 0..count.max(0) {
+                symbols[position] = symbol;
+                position = (position + step) & mask;
+                while position > high {
+                    position = (position + step) & mask;
+                }
+            }
+        }
+
+        let mut states = vec![0; size];
+        for (state, &symbol) in symbols.iter().enumerate() {
+            states[cumulative[symbol]] = (size + state) as u16;
+            cumulative[symbol] += 1;
+        }
+
+        let mut total = 0;
+        let transforms = norm
+            .iter()
+            .map(|&count| match count {
+                0 => (((log + 1) << 16) - size as u32, 0),
+                -1 | 1 => {
+                    total += 1;
+                    ((log << 16) - size as u32, total - 2)
+                }
+                _ => {
+                    let count = count as u32;
+                    let max_bits = log - (31 - (count - 1).leading_zeros());
+                    let transform = ((max_bits << 16) - (count << max_bits), total - count as i32);
+                    total += count as i32;
+                    transform
+                }
+            })
+            .collect();
+
+        Self {
+            log,
+            states,
+            transforms,
+        }
+    }
+
+    /// The state after the last symbol, which sends no bits.
+    fn init(&self, symbol: u8) -> u32 {
+        let (delta_bits, delta_state) = self.transforms[symbol as usize];
+        let bits = (delta_bits + (1 << 15)) >> 16;
+        let value = (bits << 16) - delta_bits;
+        self.next(value >> bits, delta_state)
+    }
+
+    fn encode(&self, writer: &mut BitWriter, state: &mut u32, symbol: u8) {
+        let (delta_bits, delta_state) = self.transforms[symbol as usize];
+        let bits = (*state + delta_bits) >> 16;
+        writer.write(*state as u64, bits);
+        *state = self.next(*state >> bits, delta_state);
+    }
+
+    fn flush(&self, writer: &mut BitWriter, state: u32) {
+        writer.write(state as u64, self.log);
+    }
+
+    fn next(&self, index: u32, delta_state: i32) -> u32 {
+        self.states[(index as i32 + delta_state) as usize] as u32
+    }
+}
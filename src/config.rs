@@ -0,0 +1,166 @@
+use chrono::FixedOffset;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::format::{self, Format};
+use crate::{BundleCache, Collation, Compression, HashCache, Index, ListingCache, Root};
+
+/// What to do with a directory entry that can't be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryErrorPolicy {
+    /// Leave the entry out of the listing and log it.
+    Skip,
+    /// Fail the whole listing.
+    Fail,
+}
+
+impl FromStr for EntryErrorPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "skip" => Ok(Self::Skip),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!("Unknown entry error policy: {}", value)),
+        }
+    }
+}
+
+/// A `Cache-Control` value for request paths starting with `prefix`,
+/// given as `<prefix>=<value>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheControl {
+    pub prefix: String,
+    pub value: String,
+}
+
+impl FromStr for CacheControl {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('=') {
+            Some((prefix, value)) if prefix.starts_with('/') && !value.is_empty() => Ok(Self {
+                prefix: prefix.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(format!("Expected <prefix>=<value>: {}", value)),
+        }
+    }
+}
+
+/// How files are handed to a web server in front, which sends them
+/// once rindex has checked the path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandoffHeader {
+    /// nginx's `X-Accel-Redirect`, naming an internal location.
+    AccelRedirect,
+    /// `X-Sendfile` of Apache and lighttpd, naming a filesystem path.
+    Sendfile,
+}
+
+impl HandoffHeader {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::AccelRedirect => "X-Accel-Redirect",
+            Self::Sendfile => "X-Sendfile",
+        }
+    }
+}
+
+/// Files answered with `header` pointing below `prefix`, given as
+/// `accel-redirect=<prefix>` or `sendfile=<prefix>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handoff {
+    pub header: HandoffHeader,
+    pub prefix: String,
+}
+
+impl Handoff {
+    /// The header value for `path`, relative to the root. URIs of
+    /// internal locations are percent-encoded, while filesystem paths
+    /// go as they are and can't hold control characters.
+    pub fn target(&self, path: &str) -> Option<String> {
+        let path = match self.header {
+            HandoffHeader::AccelRedirect => format::escape_uri(path),
+            HandoffHeader::Sendfile if path.contains(char::is_control) => return None,
+            HandoffHeader::Sendfile => path.to_string(),
+        };
+        Some(format!("{}/{}", self.prefix.trim_end_matches('/'), path))
+    }
+}
+
+impl FromStr for Handoff {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (header, prefix) = value
+            .split_once('=')
+            .filter(|(_, prefix)| prefix.starts_with('/'))
+            .ok_or_else(|| format!("Expected <header>=<prefix>: {}", value))?;
+        let header = match header {
+            "accel-redirect" => HandoffHeader::AccelRedirect,
+            "sendfile" => HandoffHeader::Sendfile,
+            _ => return Err(format!("Unknown handoff header: {}", header)),
+        };
+        Ok(Self {
+            header,
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+pub struct Config {
+    pub root: Root,
+    pub entry_errors: EntryErrorPolicy,
+    pub max_entries: Option<usize>,
+    /// Deepest `depth` a recursive listing may ask for.
+    pub max_depth: usize,
+    /// Most entries a recursive listing may read in total.
+    pub max_walk: usize,
+    /// Longest a search may walk before answering with what it found.
+    pub search_timeout: Duration,
+    /// Searches and recursive listings read this instead of the
+    /// filesystem.
+    pub index: Option<Index>,
+    /// Time between refreshes of the index.
+    pub index_refresh: Duration,
+    /// Rendered listings of single directories, unless disabled.
+    pub cache: Option<ListingCache>,
+    pub cache_control: Vec<CacheControl>,
+    pub compression: Compression,
+    /// Whether files are sent instead of refused as not directories.
+    pub serve_files: bool,
+    /// Hands checked files to the web server in front instead.
+    pub handoff: Option<Handoff>,
+    /// Most bytes of files an archive download may hold.
+    pub max_archive_size: u64,
+    /// Most files an archive download may hold.
+    pub max_archive_files: usize,
+    /// Archives on disk that requests may continue into, keeping the
+    /// last ones read, unless disabled.
+    pub bundles: Option<BundleCache>,
+    /// Digests of files computed so far, unless disabled.
+    pub hashes: Option<HashCache>,
+    pub format: Format,
+    pub exact_size: bool,
+    pub localtime: bool,
+    pub utc_offset: FixedOffset,
+    pub collation: Collation,
+}
+
+impl Config {
+    /// Whether file requests are answered, by rindex or the server in
+    /// front.
+    pub fn serves_files(&self) -> bool {
+        self.serve_files || self.handoff.is_some()
+    }
+
+    /// The `Cache-Control` value with the longest prefix of `path`.
+    pub fn cache_control(&self, path: &str) -> Option<&str> {
+        self.cache_control
+            .iter()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+            .map(|rule| rule.value.as_str())
+    }
+}
@@ -0,0 +1,83 @@
+/// CRC-32 (IEEE 802.3) lookup tables, for the reflected polynomial.
+/// The first is the classic byte table; each next one advances a byte
+/// further, so eight bytes are folded in at once.
+const TABLES: [[u32; 256]; 8] = {
+    let mut tables = [[0; 256]; 8];
+    let mut index = 0;
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = match value & 1 {
+                0 => value >> 1,
+                _ => value >> 1 ^ 0xedb8_8320,
+            };
+            bit += 1;
+        }
+        tables[0][index] = value;
+        index += 1;
+    }
+
+    let mut table = 1;
+    while table < 8 {
+        let mut index = 0;
+        while index < 256 {
+            let previous = tables[table - 1][index];
+            tables[table][index] = previous >> 8 ^ tables[0][(previous & 0xff) as usize];
+            index += 1;
+        }
+        table += 1;
+    }
+    tables
+};
+
+/// Running CRC-32, as used by gzip and zip.
+#[derive(Clone, Copy)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            let low = self.0 ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            self.0 = TABLES[7][(low & 0xff) as usize]
+                ^ TABLES[6][(low >> 8 & 0xff) as usize]
+                ^ TABLES[5][(low >> 16 & 0xff) as usize]
+                ^ TABLES[4][(low >> 24) as usize]
+                ^ TABLES[3][chunk[4] as usize]
+                ^ TABLES[2][chunk[5] as usize]
+                ^ TABLES[1][chunk[6] as usize]
+                ^ TABLES[0][chunk[7] as usize];
+        }
+        for &byte in chunks.remainder() {
+            self.0 = TABLES[0][((self.0 ^ byte as u32) & 0xff) as usize] ^ self.0 >> 8;
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Crc32;
+
+    #[test]
+    fn check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+
+        // Split across the eight-byte folds.
+        let mut crc = Crc32::new();
+        crc.update(b"12");
+        crc.update(b"3456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+        assert_eq!(Crc32::new().finish(), 0);
+    }
+}
@@ -0,0 +1,232 @@
+use super::sha256::IV;
+
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+
+/// Order of the message words in each next round.
+const PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+/// BLAKE3 with its default 32-byte output, unkeyed. Input is hashed in
+/// 1 KiB chunks, which are merged into a binary tree as they come.
+pub(super) struct Blake3 {
+    chunk: Chunk,
+    /// Chaining values of complete subtrees, the largest first.
+    stack: Vec<[u32; 8]>,
+}
+
+impl Blake3 {
+    pub fn new() -> Self {
+        Self {
+            chunk: Chunk::new(0),
+            stack: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // A full chunk is only known not to be the root once more
+            // input follows.
+            if self.chunk.len() == CHUNK_LEN {
+                let mut value = self.chunk.output().chaining_value();
+                let mut total = self.chunk.counter + 1;
+                while total & 1 == 0 {
+                    let Some(left) = self.stack.pop() else {
+                        break;
+                    };
+                    value = parent(left, value).chaining_value();
+                    total >>= 1;
+                }
+                self.stack.push(value);
+                self.chunk = Chunk::new(self.chunk.counter + 1);
+            }
+
+            let take = (CHUNK_LEN - self.chunk.len()).min(data.len());
+            self.chunk.update(&data[..take]);
+            data = &data[take..];
+        }
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        let mut output = self.chunk.output();
+        for &left in self.stack.iter().rev() {
+            output = parent(left, output.chaining_value());
+        }
+
+        let words = compress(
+            &output.value,
+            &output.block,
+            output.counter,
+            output.len,
+            output.flags | ROOT,
+        );
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+/// A chunk being hashed, a block at a time.
+struct Chunk {
+    value: [u32; 8],
+    counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    /// Blocks compressed so far.
+    blocks: usize,
+}
+
+impl Chunk {
+    fn new(counter: u64) -> Self {
+        Self {
+            value: IV,
+            counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.blocks * BLOCK_LEN + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        match self.blocks {
+            0 => CHUNK_START,
+            _ => 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // Like chunks, the last block is held back for its flags.
+            if self.block_len == BLOCK_LEN {
+                let words = compress(
+                    &self.value,
+                    &words(&self.block),
+                    self.counter,
+                    BLOCK_LEN as u32,
+                    self.start_flag(),
+                );
+                self.value.copy_from_slice(&words[..8]);
+                self.blocks += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+
+            let take = (BLOCK_LEN - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        Output {
+            value: self.value,
+            block: words(&self.block),
+            counter: self.counter,
+            len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+/// The last compression of a node, kept back until it is known whether
+/// the node is the root.
+struct Output {
+    value: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        let words = compress(&self.value, &self.block, self.counter, self.len, self.flags);
+        words[..8].try_into().unwrap()
+    }
+}
+
+fn parent(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block = [0; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+    Output {
+        value: IV,
+        block,
+        counter: 0,
+        len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+fn words(block: &[u8; BLOCK_LEN]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    words
+}
+
+fn compress(value: &[u32; 8], block: &[u32; 16], counter: u64, len: u32, flags: u32) -> [u32; 16] {
+    let mut state = [0; 16];
+    state[..8].copy_from_slice(value);
+    state[8..12].copy_from_slice(&IV[..4]);
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+    state[14] = len;
+    state[15] = flags;
+
+    let mut message = *block;
+    for round in 0..7 {
+        for (at, [a, b, c, d]) in [
+            [0, 4, 8, 12],
+            [1, 5, 9, 13],
+            [2, 6, 10, 14],
+            [3, 7, 11, 15],
+            [0, 5, 10, 15],
+            [1, 6, 11, 12],
+            [2, 7, 8, 13],
+            [3, 4, 9, 14],
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            mix(
+                &mut state,
+                [a, b, c, d],
+                message[2 * at],
+                message[2 * at + 1],
+            );
+        }
+        if round < 6 {
+            message = PERMUTATION.map(|from| message[from]);
+        }
+    }
+
+    for at in 0..8 {
+        state[at] ^= state[at + 8];
+        state[at + 8] ^= value[at];
+    }
+    state
+}
+
+fn mix(state: &mut [u32; 16], [a, b, c, d]: [usize; 4], x: u32, y: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(x);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(y);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
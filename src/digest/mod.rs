@@ -0,0 +1,415 @@
+mod blake3;
+mod md5;
+mod sha1;
+mod sha256;
+#[cfg(test)]
+mod tests;
+
+use spdlog::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Instant, SystemTime};
+
+use crate::Root;
+
+/// Bytes of a file read at a time while hashing it.
+const READ_LEN: usize = 256 * 1024;
+
+/// Largest checksum file read for a listing.
+const SUMS_MAX: u64 = 16 << 20;
+
+/// A digest of file contents the service can compute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Blake3,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "md5" => Ok(Self::Md5),
+            "sha1" => Ok(Self::Sha1),
+            "sha256" => Ok(Self::Sha256),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(format!("Unknown hash algorithm: {}", value)),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Bytes of a digest.
+    fn len(self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha256 | Self::Blake3 => 32,
+        }
+    }
+
+    /// Name of the checksum file listing digests of a directory's files,
+    /// as `md5sum`, `sha1sum`, `sha256sum` and `b3sum` write them.
+    fn sums_name(self) -> &'static str {
+        match self {
+            Self::Md5 => "MD5SUMS",
+            Self::Sha1 => "SHA1SUMS",
+            Self::Sha256 => "SHA256SUMS",
+            Self::Blake3 => "B3SUMS",
+        }
+    }
+
+    /// Tag of the algorithm in BSD-style checksum lines.
+    fn tag(self) -> &'static str {
+        match self {
+            Self::Md5 => "MD5",
+            Self::Sha1 => "SHA1",
+            Self::Sha256 => "SHA256",
+            Self::Blake3 => "BLAKE3",
+        }
+    }
+}
+
+/// Input cut into the 64-byte blocks of MD5 and the SHAs, counting its
+/// length for the padding.
+struct Blocks {
+    buffer: [u8; 64],
+    buffered: usize,
+    len: u64,
+}
+
+impl Blocks {
+    fn new() -> Self {
+        Self {
+            buffer: [0; 64],
+            buffered: 0,
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8], mut compress: impl FnMut(&[u8; 64])) {
+        self.len = self.len.wrapping_add(data.len() as u64);
+
+        if self.buffered > 0 {
+            let take = (64 - self.buffered).min(data.len());
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 64 {
+                return;
+            }
+            compress(&self.buffer);
+            self.buffered = 0;
+        }
+
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            compress(block.try_into().unwrap());
+        }
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    /// Pads the input with its length in bits, which MD5 writes little
+    /// endian and the SHAs big endian.
+    fn finish(mut self, big_endian: bool, mut compress: impl FnMut(&[u8; 64])) {
+        let bits = self.len.wrapping_mul(8);
+        let mut padding = vec![0x80];
+        padding.resize(1 + (119 - self.buffered) % 64, 0);
+        padding.extend(match big_endian {
+            true => bits.to_be_bytes(),
+            false => bits.to_le_bytes(),
+        });
+        self.update(&padding, &mut compress);
+    }
+}
+
+enum Hasher {
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(sha256::Sha256),
+    Blake3(Box<blake3::Blake3>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Self::Md5(md5::Md5::new()),
+            HashAlgorithm::Sha1 => Self::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Sha256 => Self::Sha256(sha256::Sha256::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Blake3::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => hasher.update(data),
+        }
+    }
+
+    /// The digest in lowercase hex.
+    fn finish(self) -> String {
+        let digest = match self {
+            Self::Md5(hasher) => hasher.finish().to_vec(),
+            Self::Sha1(hasher) => hasher.finish().to_vec(),
+            Self::Sha256(hasher) => hasher.finish().to_vec(),
+            Self::Blake3(hasher) => hasher.finish().to_vec(),
+        };
+        digest.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+    }
+}
+
+/// Hashes all of `reader` with `algorithm`, a buffer at a time.
+fn digest<R: Read>(mut reader: R, algorithm: HashAlgorithm) -> io::Result<String> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; READ_LEN];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(hasher.finish()),
+            Ok(len) => hasher.update(&buffer[..len]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The digest of the file at `full_path`, from `cache` if it holds one
+/// for this version of the file. A digest is only cached if the file
+/// didn't change while it was read.
+pub(crate) fn file(
+    full_path: &Path,
+    algorithm: HashAlgorithm,
+    cache: Option<&HashCache>,
+) -> io::Result<String> {
+    let file = File::open(full_path)?;
+    let before = file.metadata()?;
+    if let Some(digest) = cache.and_then(|cache| cache.get(&before, algorithm)) {
+        return Ok(digest);
+    }
+
+    let start_time = Instant::now();
+    let digest = digest(&file, algorithm)?;
+    let elapsed = start_time.elapsed().as_micros() as f64 / 1000.0;
+    debug!(
+        "Hashed {} bytes of {} with {} in {}ms",
+        before.len(),
+        full_path.display(),
+        algorithm.name(),
+        elapsed
+    );
+
+    if let Some(cache) = cache {
+        if stamp(&file.metadata()?) == stamp(&before) {
+            cache.insert(&before, algorithm, &digest);
+        }
+    }
+    Ok(digest)
+}
+
+/// What tells versions of a file apart: its device, inode, size and
+/// mtime.
+type Stamp = (u64, u64, u64, Option<SystemTime>);
+
+fn stamp(metadata: &Metadata) -> Stamp {
+    (
+        metadata.dev(),
+        metadata.ino(),
+        metadata.len(),
+        metadata.modified().ok(),
+    )
+}
+
+/// Digests of files computed so far, least recently used first out.
+/// A file gets new ones once it is replaced or changes size or mtime.
+pub struct HashCache {
+    /// Most digests kept.
+    capacity: usize,
+    state: Mutex<Cached>,
+}
+
+#[derive(Default)]
+struct Cached {
+    digests: HashMap<(Stamp, HashAlgorithm), Slot>,
+    /// Keys of cached digests by last use.
+    order: BTreeMap<u64, (Stamp, HashAlgorithm)>,
+    /// Counts uses, so none is ever repeated.
+    clock: u64,
+}
+
+struct Slot {
+    used: u64,
+    digest: String,
+}
+
+impl HashCache {
+    /// Keeps up to `capacity` digests.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(Cached::default()),
+        }
+    }
+
+    /// The digest of the file with `metadata`, if it was computed for
+    /// this version of it.
+    pub(crate) fn get(&self, metadata: &Metadata, algorithm: HashAlgorithm) -> Option<String> {
+        let key = (stamp(metadata), algorithm);
+        let mut state = self.lock();
+        let state = &mut *state;
+
+        let slot = state.digests.get_mut(&key)?;
+        state.clock += 1;
+        state.order.remove(&slot.used);
+        state.order.insert(state.clock, key);
+        slot.used = state.clock;
+        Some(slot.digest.clone())
+    }
+
+    fn insert(&self, metadata: &Metadata, algorithm: HashAlgorithm, digest: &str) {
+        let key = (stamp(metadata), algorithm);
+        let mut state = self.lock();
+        let state = &mut *state;
+
+        state.clock += 1;
+        let slot = Slot {
+            used: state.clock,
+            digest: digest.to_string(),
+        };
+        if let Some(replaced) = state.digests.insert(key, slot) {
+            state.order.remove(&replaced.used);
+        }
+        state.order.insert(state.clock, key);
+        while state.digests.len() > self.capacity {
+            let Some((_, key)) = state.order.pop_first() else {
+                break;
+            };
+            state.digests.remove(&key);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cached> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Digests of a directory's files as its checksum file lists them,
+/// by name.
+pub(crate) struct Sums(HashMap<String, String>);
+
+impl Sums {
+    /// Reads the checksum file of `algorithm` in `dir`, if there is
+    /// one the root permits. Lines are taken in the format of
+    /// `sha256sum` and the like or in their BSD format with `--tag`;
+    /// others are skipped.
+    pub fn read(dir: &Path, algorithm: HashAlgorithm, root: &Root) -> Option<Self> {
+        let path = dir.join(algorithm.sums_name());
+        let file_type = fs::symlink_metadata(&path).ok()?.file_type();
+        if let Err(err) = root.permits(&path, file_type) {
+            info!("{}", err);
+            return None;
+        }
+
+        let mut text = String::new();
+        let read = File::open(&path).and_then(|file| file.take(SUMS_MAX).read_to_string(&mut text));
+        if let Err(err) = read {
+            warn!("Failed to read {}: {}", path.display(), err);
+            return None;
+        }
+
+        let sums = text
+            .lines()
+            .filter_map(|line| Self::parse(line, algorithm))
+            .collect();
+        Some(Self(sums))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    /// The name and digest of a checksum line. Names holding a newline
+    /// or a backslash are escaped, and the line starts with a backslash.
+    fn parse(line: &str, algorithm: HashAlgorithm) -> Option<(String, String)> {
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+
+        let (name, digest) = match line.strip_prefix(algorithm.tag()) {
+            Some(tagged) => tagged.strip_prefix(" (")?.rsplit_once(") = ")?,
+            None => {
+                let (digest, name) = line.split_once(' ')?;
+                // Binary mode marks the name with a star.
+                let name = name.strip_prefix([' ', '*'])?;
+                (name, digest)
+            }
+        };
+
+        let valid = digest.len() == 2 * algorithm.len()
+            && digest.bytes().all(|byte| byte.is_ascii_hexdigit());
+        if !valid {
+            return None;
+        }
+
+        let name = name.strip_prefix("./").unwrap_or(name);
+        let name = match escaped {
+            true => unescape(name)?,
+            false => name.to_string(),
+        };
+        Some((name, digest.to_ascii_lowercase()))
+    }
+}
+
+/// A checksum line for `name`, as `sha256sum` and the like write it.
+pub(crate) fn line(digest: &str, name: &str) -> String {
+    if !name.contains(['\\', '\n', '\r']) {
+        return format!("{}  {}\n", digest, name);
+    }
+    let name = name
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\\{}  {}\n", digest, name)
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => match chars.next()? {
+                'n' => unescaped.push('\n'),
+                'r' => unescaped.push('\r'),
+                '\\' => unescaped.push('\\'),
+                _ => return None,
+            },
+            _ => unescaped.push(char),
+        }
+    }
+    Some(unescaped)
+}
@@ -0,0 +1,160 @@
+//! Published test vectors, with lengths either side of where the
+//! padding takes a second block and, for BLAKE3, of its 1024-byte
+//! chunks.
+
+use super::{HashAlgorithm, Hasher};
+
+/// The NIST vector of 56 bytes, which leaves no room for the length in
+/// its last block.
+const NIST_56: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+
+fn hex(algorithm: HashAlgorithm, data: &[u8]) -> String {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// The digest of `data` fed a piece of each of `pieces` bytes in turn.
+fn hex_in_pieces(algorithm: HashAlgorithm, data: &[u8], pieces: &[usize]) -> String {
+    let mut hasher = Hasher::new(algorithm);
+    let mut rest = data;
+    for &len in pieces.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (piece, after) = rest.split_at(len.min(rest.len()));
+        hasher.update(piece);
+        rest = after;
+    }
+    hasher.finish()
+}
+
+/// Checks `algorithm` against `(input, digest)` pairs, whole and fed
+/// in pieces across block boundaries.
+fn check(algorithm: HashAlgorithm, vectors: &[(&[u8], &str)]) {
+    for (input, digest) in vectors {
+        assert_eq!(hex(algorithm, input), *digest, "{} bytes", input.len());
+        for pieces in [&[1][..], &[63, 1, 64], &[65, 1000]] {
+            assert_eq!(
+                hex_in_pieces(algorithm, input, pieces),
+                *digest,
+                "{} bytes in {:?}",
+                input.len(),
+                pieces
+            );
+        }
+    }
+}
+
+fn a(len: usize) -> Vec<u8> {
+    vec![b'a'; len]
+}
+
+#[test]
+fn md5_vectors() {
+    check(
+        HashAlgorithm::Md5,
+        &[
+            (b"", "d41d8cd98f00b204e9800998ecf8427e"),
+            (b"abc", "900150983cd24fb0d6963f7d28e17f72"),
+            (NIST_56, "8215ef0796a20bcaaae116d3876c664a"),
+            (&a(55), "ef1772b6dff9a122358552954ad0df65"),
+            (&a(56), "3b0c8ac703f828b04c6c197006d17218"),
+            (&a(63), "b06521f39153d618550606be297466d5"),
+            (&a(64), "014842d480b571495a4a0363793f7367"),
+            (&a(65), "c743a45e0d2e6a95cb859adae0248435"),
+            (&a(1_000_000), "7707d6ae4e027c70eea2a935c2296f21"),
+        ],
+    );
+}
+
+#[test]
+fn sha1_vectors() {
+    check(
+        HashAlgorithm::Sha1,
+        &[
+            (b"", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            (b"abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (NIST_56, "84983e441c3bd26ebaae4aa1f95129e5e54670f1"),
+            (&a(55), "c1c8bbdc22796e28c0e15163d20899b65621d65a"),
+            (&a(56), "c2db330f6083854c99d4b5bfb6e8f29f201be699"),
+            (&a(63), "03f09f5b158a7a8cdad920bddc29b81c18a551f5"),
+            (&a(64), "0098ba824b5c16427bd7a1122a5a442a25ec644d"),
+            (&a(65), "11655326c708d70319be2610e8a57d9a5b959d3b"),
+            (&a(1_000_000), "34aa973cd4c4daa4f61eeb2bdbad27316534016f"),
+        ],
+    );
+}
+
+#[test]
+fn sha256_vectors() {
+    check(
+        HashAlgorithm::Sha256,
+        &[
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                NIST_56,
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+            (
+                &a(55),
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                &a(56),
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                &a(63),
+                "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34",
+            ),
+            (
+                &a(64),
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+            (
+                &a(65),
+                "635361c48bb9eab14198e76ea8ab7f1a41685d6ad62aa9146d301d4f17eb0ae0",
+            ),
+            (
+                &a(1_000_000),
+                "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+            ),
+        ],
+    );
+}
+
+/// The official BLAKE3 vectors, whose input of each length repeats the
+/// bytes 0 to 250.
+#[test]
+fn blake3_vectors() {
+    let input = |len: usize| (0..len).map(|at| (at % 251) as u8).collect::<Vec<_>>();
+    check(
+        HashAlgorithm::Blake3,
+        &[
+            (
+                &input(0),
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                &input(1024),
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                &input(2049),
+                "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030",
+            ),
+            (
+                &input(102_400),
+                "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085",
+            ),
+        ],
+    );
+}
@@ -1,23 +1,48 @@
 use anyhow::Result;
-use serde::Serialize;
-use std::{cmp::Ordering, fs, fs::DirEntry};
+use serde::{Serialize, Serializer};
+use std::time::SystemTime;
+use std::{cmp::Ordering, fs, fs::DirEntry, io};
 use thiserror::Error;
 
+use crate::filter::Filter;
+use crate::root::{ResolveError, Root};
+
 #[derive(Serialize, PartialEq, Eq)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum ExplorerEntry {
     Directory {
-        mtime: String,
+        #[serde(serialize_with = "serialize_mtime")]
+        mtime: SystemTime,
         name: String,
+        /// Path relative to the listed directory, in recursive listings.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        /// Sorted entries of the directory, in tree listings.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        children: Option<Vec<ExplorerEntry>>,
+        /// Number of entries of a directory collapsed at the depth limit
+        /// of a tree listing.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        child_count: Option<usize>,
     },
     File {
-        mtime: String,
+        #[serde(serialize_with = "serialize_mtime")]
+        mtime: SystemTime,
         name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
         size: u64,
+        /// Digest asked for with `hash`, where one is known.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hash: Option<String>,
     },
 }
 
+fn serialize_mtime<S: Serializer>(mtime: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&httpdate::fmt_http_date(*mtime))
+}
+
 impl Ord for ExplorerEntry {
     fn cmp(&self, other: &Self) -> Ordering {
         match (self, other) {
@@ -43,36 +68,133 @@ pub enum ExplorerError {
     MissingSymlinkTarget(String),
     #[error("Not supported on this platform")]
     UnsupportMetadata,
+    #[error(transparent)]
+    Hidden(#[from] ResolveError),
+    #[error("Failed to read {0}: {1}")]
+    Io(String, #[source] io::Error),
+    #[error("Directory has more than {0} entries")]
+    TooManyEntries(usize),
+    #[error("Recursive listing reads more than {0} entries")]
+    WalkTooLarge(usize),
+    #[error("Streamed listing was abandoned")]
+    Abandoned,
+    #[error("Listing timed out")]
+    TimedOut,
 }
 
 impl ExplorerEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Directory { name, .. } | Self::File { name, .. } => name,
+        }
+    }
+
+    /// The relative path in recursive listings, the name otherwise.
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Directory { path, name, .. } | Self::File { path, name, .. } => {
+                path.as_deref().unwrap_or(name)
+            }
+        }
+    }
+
+    pub(crate) fn with_path(mut self, relative: String) -> Self {
+        match &mut self {
+            Self::Directory { path, .. } | Self::File { path, .. } => *path = Some(relative),
+        }
+        self
+    }
+
+    /// Fills in the contents of a directory in a tree listing, either
+    /// its entries or, when collapsed, their count.
+    pub(crate) fn with_contents(
+        mut self,
+        entries: Option<Vec<ExplorerEntry>>,
+        count: Option<usize>,
+    ) -> Self {
+        if let Self::Directory {
+            children,
+            child_count,
+            ..
+        } = &mut self
+        {
+            *children = entries;
+            *child_count = count;
+        }
+        self
+    }
+
+    pub(crate) fn with_hash(mut self, digest: Option<String>) -> Self {
+        if let Self::File { hash, .. } = &mut self {
+            *hash = digest;
+        }
+        self
+    }
+
+    pub fn mtime(&self) -> SystemTime {
+        match self {
+            Self::Directory { mtime, .. } | Self::File { mtime, .. } => *mtime,
+        }
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Self::Directory { .. } => None,
+            Self::File { size, .. } => Some(*size),
+        }
+    }
+
+    /// Stats `file`, returning `None` if it doesn't pass `filter`.
     #[inline]
-    pub fn new(file: &DirEntry) -> Result<Self, ExplorerError> {
+    pub fn new(
+        file: &DirEntry,
+        root: &Root,
+        filter: &Filter,
+    ) -> Result<Option<Self>, ExplorerError> {
         let path = file.path();
 
-        let metadata = fs::metadata(&path).map_err(|_| {
+        let file_type = file.file_type().ok();
+        if let Some(file_type) = file_type {
+            root.permits(&path, file_type)?;
+        }
+
+        let metadata = fs::metadata(&path).map_err(|err| {
             let path = path.to_string_lossy().into_owned();
-            ExplorerError::MissingSymlinkTarget(path)
+            match err.kind() {
+                io::ErrorKind::NotFound => ExplorerError::MissingSymlinkTarget(path),
+                _ => ExplorerError::Io(path, err),
+            }
         })?;
 
         let name = file.file_name().to_string_lossy().to_string();
 
-        let modified = metadata
+        let mtime = metadata
             .modified()
             .map_err(|_| ExplorerError::UnsupportMetadata)?;
-        
-        let mtime = httpdate::fmt_http_date(modified);
+
+        let symlink = file_type.is_some_and(|file_type| file_type.is_symlink());
+        if !filter.accepts_attributes(metadata.is_dir(), symlink, metadata.len(), mtime) {
+            return Ok(None);
+        }
 
         let explorer_entry = if metadata.is_dir() {
-            Self::Directory { name, mtime }
+            Self::Directory {
+                name,
+                path: None,
+                mtime,
+                children: None,
+                child_count: None,
+            }
         } else {
             Self::File {
                 name,
+                path: None,
                 size: metadata.len(),
                 mtime,
+                hash: None,
             }
         };
 
-        Ok(explorer_entry)
+        Ok(Some(explorer_entry))
     }
 }
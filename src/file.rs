@@ -0,0 +1,357 @@
+use httpdate::HttpDate;
+use snowboard::{headers, Response, DEFAULT_HTTP_VERSION};
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::compress::Inflate;
+
+/// Most ranges a request may ask for before it gets the whole file.
+const MAX_RANGES: usize = 64;
+
+/// Multipart bodies made so far, to tell their boundaries apart.
+static BOUNDARIES: AtomicU64 = AtomicU64::new(0);
+
+/// Where the bytes of a download are read from.
+pub(crate) enum Source {
+    /// The file as it is, from `start`.
+    Plain(u64),
+    /// What inflating the file from `start` gives, past the first
+    /// `skip` bytes.
+    Inflated { start: u64, gzip: bool, skip: u64 },
+}
+
+/// A file sent as it is read, for deployments without a web server in
+/// front.
+pub struct Download {
+    file: File,
+    source: Source,
+    len: u64,
+    modified: Option<SystemTime>,
+    content_type: &'static str,
+    /// Parts to send, or none for the whole file.
+    ranges: Vec<Range<u64>>,
+    boundary: String,
+}
+
+impl Download {
+    pub fn open(full_path: &Path, metadata: &Metadata) -> io::Result<Self> {
+        Ok(Self {
+            file: File::open(full_path)?,
+            source: Source::Plain(0),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            content_type: content_type(full_path),
+            ranges: Vec::new(),
+            boundary: String::new(),
+        })
+    }
+
+    /// A member of an archive named `name`, of `len` bytes read out of
+    /// `file` as `source` says.
+    pub(crate) fn member(
+        file: File,
+        source: Source,
+        len: u64,
+        modified: SystemTime,
+        name: &str,
+    ) -> Self {
+        Self {
+            file,
+            source,
+            len,
+            modified: Some(modified),
+            content_type: content_type(Path::new(name)),
+            ranges: Vec::new(),
+            boundary: String::new(),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether ranges can be read without inflating what comes before
+    /// them.
+    pub fn is_seekable(&self) -> bool {
+        matches!(self.source, Source::Plain(_))
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// A validator from the size and mtime, as nginx makes them.
+    pub fn etag(&self) -> String {
+        let mtime = self
+            .modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |mtime| mtime.as_secs());
+        format!("\"{:x}-{:x}\"", mtime, self.len)
+    }
+
+    /// Whether an `If-Range` validator names this version of the file,
+    /// so that its ranges may be sent.
+    pub fn matches(&self, validator: &str) -> bool {
+        let validator = validator.trim();
+        if validator.starts_with('"') {
+            return validator == self.etag();
+        }
+        match (httpdate::parse_http_date(validator), self.modified) {
+            (Ok(date), Some(modified)) => SystemTime::from(HttpDate::from(modified)) == date,
+            _ => false,
+        }
+    }
+
+    /// Sends only `ranges` of the file, in one part or several.
+    pub fn select(&mut self, ranges: Vec<Range<u64>>) {
+        if ranges.len() > 1 {
+            let count = BOUNDARIES.fetch_add(1, Ordering::Relaxed);
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.subsec_nanos());
+            self.boundary = format!("{:08x}{:08x}", nanos, count);
+        }
+        self.ranges = ranges;
+    }
+
+    /// Status line and headers, sent before the file is read.
+    pub fn head(&self) -> Response {
+        let accept_ranges = match self.is_seekable() {
+            true => "bytes",
+            false => "none",
+        };
+        let mut headers = headers! {
+            "Accept-Ranges" => accept_ranges,
+            "ETag" => self.etag(),
+        };
+        if let Some(modified) = self.modified {
+            headers.insert("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+
+        let (content_type, len) = match self.ranges.as_slice() {
+            [] => (self.content_type.to_string(), self.len),
+            [range] => {
+                headers.insert("Content-Range", self.content_range(range));
+                (self.content_type.to_string(), range.end - range.start)
+            }
+            ranges => {
+                let parts = ranges
+                    .iter()
+                    .map(|range| self.part_head(range).len() as u64 + range.end - range.start)
+                    .sum::<u64>();
+                (
+                    format!("multipart/byteranges; boundary={}", self.boundary),
+                    parts + self.closing().len() as u64,
+                )
+            }
+        };
+        headers.insert("Content-Type", content_type);
+        headers.insert("Content-Length", len.to_string());
+
+        if self.ranges.is_empty() {
+            Response::new(DEFAULT_HTTP_VERSION, 200, "OK", Vec::new(), Some(headers))
+        } else {
+            Response::partial_content(Vec::new(), Some(headers), DEFAULT_HTTP_VERSION)
+        }
+    }
+
+    /// Copies the file or its ranges to `writer`, straight from the
+    /// file. Only the lengths announced in the head are sent, in case
+    /// the file grew since.
+    pub fn send<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        let mut reader = Reader {
+            file: &self.file,
+            source: &self.source,
+            inflated: None,
+        };
+        match self.ranges.as_slice() {
+            [] => reader.copy(&(0..self.len), writer)?,
+            [range] => reader.copy(range, writer)?,
+            ranges => {
+                for range in ranges {
+                    writer.write_all(self.part_head(range).as_bytes())?;
+                    reader.copy(range, writer)?;
+                }
+                writer.write_all(self.closing().as_bytes())?;
+            }
+        }
+        writer.flush()
+    }
+
+    fn content_range(&self, range: &Range<u64>) -> String {
+        format!("bytes {}-{}/{}", range.start, range.end - 1, self.len)
+    }
+
+    /// Delimiter and headers before a part of a multipart body.
+    fn part_head(&self, range: &Range<u64>) -> String {
+        format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            self.boundary,
+            self.content_type,
+            self.content_range(range)
+        )
+    }
+
+    fn closing(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+}
+
+/// Reads the ranges of a download in order. Inflated ones can't seek,
+/// so their decoder carries on from one range to the next.
+struct Reader<'a> {
+    file: &'a File,
+    source: &'a Source,
+    /// The decoder and how much of its output was read.
+    inflated: Option<(Inflate<&'a File>, u64)>,
+}
+
+impl Reader<'_> {
+    fn copy<W: Write>(&mut self, range: &Range<u64>, writer: &mut W) -> io::Result<()> {
+        let len = range.end - range.start;
+        let mut file = self.file;
+        let copied = match *self.source {
+            Source::Plain(start) => {
+                file.seek(SeekFrom::Start(start + range.start))?;
+                io::copy(&mut file.take(len), writer)?
+            }
+            Source::Inflated { start, gzip, skip } => {
+                let (decoder, at) = match &mut self.inflated {
+                    Some(inflated) => inflated,
+                    None => {
+                        file.seek(SeekFrom::Start(start))?;
+                        let decoder = match gzip {
+                            true => Inflate::gzip(file),
+                            false => Inflate::raw(file),
+                        };
+                        self.inflated.insert((decoder, 0))
+                    }
+                };
+                let from = skip + range.start;
+                let skipped = io::copy(&mut decoder.take(from - *at), &mut io::sink())?;
+                *at += skipped;
+                let copied = match *at == from {
+                    true => io::copy(&mut decoder.take(len), writer)?,
+                    false => 0,
+                };
+                *at += copied;
+                copied
+            }
+        };
+        if copied < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(())
+    }
+}
+
+/// Byte ranges of a `Range` header over `len` bytes, sorted and with
+/// overlapping or adjacent ones merged. Empty if none of them is
+/// satisfiable, and `None` if the header is to be ignored: in another
+/// unit, malformed, or asking for too many parts.
+pub fn ranges(header: &str, len: u64) -> Option<Vec<Range<u64>>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    let specs = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty());
+    for (index, spec) in specs.enumerate() {
+        if index == MAX_RANGES {
+            return None;
+        }
+        let range = match spec.split_once('-')? {
+            ("", suffix) => len.saturating_sub(number(suffix)?)..len,
+            (first, "") => number(first)?..len,
+            (first, last) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if last < first {
+                    return None;
+                }
+                first..last.saturating_add(1).min(len)
+            }
+        };
+        // Ranges starting past the end can't be sent.
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Some(merged)
+}
+
+/// A position in a range, digits only.
+fn number(text: &str) -> Option<u64> {
+    if text.is_empty() || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+/// The media type of a file going by its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "txt" | "log" | "md" | "asc" | "sig" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "text/xml; charset=utf-8",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "flac" => "audio/flac",
+        "wav" => "audio/wav",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "bz2" => "application/x-bzip2",
+        "xz" => "application/x-xz",
+        "zst" => "application/zstd",
+        "7z" => "application/x-7z-compressed",
+        "tar" => "application/x-tar",
+        "iso" => "application/x-iso9660-image",
+        "deb" => "application/vnd.debian.binary-package",
+        "rpm" => "application/x-rpm",
+        _ => "application/octet-stream",
+    }
+}
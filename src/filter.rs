@@ -0,0 +1,114 @@
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use chrono::DateTime;
+
+use crate::pattern::Pattern;
+use crate::search::Query;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl FromStr for EntryKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "file" => Ok(Self::File),
+            "dir" => Ok(Self::Dir),
+            "symlink" => Ok(Self::Symlink),
+            _ => Err(format!("Unknown entry type: {}", value)),
+        }
+    }
+}
+
+/// What a listing entry has to pass. Name patterns are checked
+/// before the entry is stat'ed, everything else against its metadata.
+#[derive(Default)]
+pub struct Filter {
+    /// Keep only entries matching one of these, unless empty.
+    pub include: Vec<Pattern>,
+    /// Drop entries matching any of these.
+    pub exclude: Vec<Pattern>,
+    /// Keep only entries matching this search.
+    pub query: Option<Query>,
+    pub kind: Option<EntryKind>,
+    /// Size bounds, inclusive. Directories have no size and never
+    /// pass them.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Modified at or after this time.
+    pub modified_since: Option<SystemTime>,
+    /// Modified strictly before this time.
+    pub modified_before: Option<SystemTime>,
+}
+
+impl Filter {
+    pub fn accepts_name(&self, name: &str) -> bool {
+        let included =
+            self.include.is_empty() || self.include.iter().any(|pattern| pattern.is_match(name));
+        included
+            && !self.exclude.iter().any(|pattern| pattern.is_match(name))
+            && self
+                .query
+                .as_ref()
+                .is_none_or(|query| query.score(name).is_some())
+    }
+
+    /// `symlink` tells whether the entry itself is a symlink; the rest
+    /// describes its target.
+    pub fn accepts_attributes(
+        &self,
+        is_dir: bool,
+        symlink: bool,
+        size: u64,
+        mtime: SystemTime,
+    ) -> bool {
+        let kind = match self.kind {
+            Some(EntryKind::File) => !is_dir,
+            Some(EntryKind::Dir) => is_dir,
+            Some(EntryKind::Symlink) => symlink,
+            None => true,
+        };
+
+        let sized = self.min_size.is_none() && self.max_size.is_none();
+        let size = sized
+            || !is_dir
+                && self.min_size.is_none_or(|min| size >= min)
+                && self.max_size.is_none_or(|max| size <= max);
+
+        let modified = self.modified_since.is_none_or(|since| mtime >= since)
+            && self.modified_before.is_none_or(|before| mtime < before);
+
+        kind && size && modified
+    }
+}
+
+/// Parses a byte count with an optional binary suffix, like `1G`.
+pub fn parse_size(value: &str) -> Option<u64> {
+    let digits = value.trim_end_matches(|char: char| char.is_ascii_alphabetic());
+    let shift = match &value[digits.len()..] {
+        "" | "B" | "b" => 0,
+        suffix => match suffix.trim_end_matches(['B', 'b', 'i']) {
+            "K" | "k" => 10,
+            "M" | "m" => 20,
+            "G" | "g" => 30,
+            "T" | "t" => 40,
+            _ => return None,
+        },
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Parses an HTTP-date or an RFC 3339 timestamp.
+pub fn parse_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value).ok().or_else(|| {
+        DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(SystemTime::from)
+    })
+}
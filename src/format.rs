@@ -0,0 +1,232 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::page::Page;
+use crate::{ExplorerEntry, ListOptions};
+
+/// Width of the name column in the HTML listing, as in nginx.
+const NAME_LEN: usize = 50;
+
+/// Output formats of nginx's `autoindex_format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Xml,
+    Json,
+    Jsonp,
+    /// Newline-delimited JSON, streamed as the directory is read.
+    Ndjson,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "html" => Ok(Self::Html),
+            "xml" => Ok(Self::Xml),
+            "json" => Ok(Self::Json),
+            "jsonp" => Ok(Self::Jsonp),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(format!("Unknown format: {}", value)),
+        }
+    }
+}
+
+impl Format {
+    pub const ALL: [Format; 5] = [Self::Html, Self::Xml, Self::Json, Self::Jsonp, Self::Ndjson];
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Html => "text/html",
+            Self::Xml => "text/xml",
+            Self::Json => "application/json",
+            Self::Jsonp => "application/javascript",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.content_type() == content_type)
+    }
+}
+
+/// JSON listing together with its page metadata.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    total: usize,
+    next: Option<&'a str>,
+    entries: &'a [ExplorerEntry],
+}
+
+/// Renders a sorted listing of the directory at `uri`.
+pub fn render(
+    uri: &str,
+    entries: &[ExplorerEntry],
+    page: &Page,
+    options: &ListOptions,
+) -> Result<String, sonic_rs::Error> {
+    let json = || {
+        if options.envelope {
+            let next = page.next.as_deref();
+            let total = page.total;
+            sonic_rs::to_string(&Envelope {
+                total,
+                next,
+                entries,
+            })
+        } else {
+            sonic_rs::to_string(entries)
+        }
+    };
+
+    match (options.format, options.callback.as_deref()) {
+        (Format::Html, _) => Ok(render_html(uri, entries, options)),
+        (Format::Xml, _) => Ok(render_xml(entries)),
+        (Format::Jsonp, Some(callback)) => Ok(format!("{}({});", callback, json()?)),
+        (Format::Json | Format::Jsonp, _) => json(),
+        (Format::Ndjson, _) => entries
+            .iter()
+            .map(|entry| sonic_rs::to_string(entry).map(|line| line + "\n"))
+            .collect(),
+    }
+}
+
+/// Whether `callback` is safe to use as a JSONP function name.
+pub fn is_valid_callback(callback: &str) -> bool {
+    !callback.is_empty()
+        && callback
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'.')
+}
+
+fn render_html(uri: &str, entries: &[ExplorerEntry], options: &ListOptions) -> String {
+    let mut html = String::with_capacity(256 + entries.len() * 128);
+    let uri = escape_html(uri);
+
+    let _ = write!(
+        html,
+        "<html>\r\n<head><title>Index of {uri}</title></head>\r\n<body>\r\n\
+         <h1>Index of {uri}</h1><hr><pre><a href=\"../\">../</a>\r\n"
+    );
+
+    for entry in entries {
+        let is_dir = matches!(entry, ExplorerEntry::Directory { .. });
+        let name = entry.path();
+        let slash = if is_dir { "/" } else { "" };
+
+        let _ = write!(html, "<a href=\"{}{}\">", escape_uri(name), slash);
+
+        let len = name.chars().count();
+        if len > NAME_LEN {
+            let shown = name.chars().take(NAME_LEN - 3).collect::<String>();
+            let _ = write!(html, "{}..&gt;</a>", escape_html(&shown));
+        } else {
+            let slash = if len < NAME_LEN { slash } else { "" };
+            let padding = " ".repeat(NAME_LEN - len - slash.len());
+            let _ = write!(html, "{}{}</a>{}", escape_html(name), slash, padding);
+        }
+
+        let mtime = DateTime::<Utc>::from(entry.mtime()).with_timezone(&options.utc_offset);
+        let _ = write!(html, " {} ", mtime.format("%d-%b-%Y %H:%M"));
+
+        let _ = match (entry.size(), options.exact_size) {
+            (Some(size), true) => write!(html, "{:>19}\r\n", size),
+            (Some(size), false) => write!(html, "{}\r\n", human_size(size)),
+            (None, true) => write!(html, "{:>19}\r\n", "-"),
+            (None, false) => write!(html, "{:>7}\r\n", "-"),
+        };
+    }
+
+    html.push_str("</pre><hr></body>\r\n</html>\r\n");
+    html
+}
+
+/// Rounds a size to the nearest K, M or G the way nginx does,
+/// right-aligned in seven columns.
+fn human_size(size: u64) -> String {
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * KIB;
+    const GIB: u64 = 1024 * MIB;
+
+    let scaled = |unit: u64| size / unit + u64::from(size % unit >= unit / 2);
+
+    match size {
+        size if size >= GIB => format!("{:>6}G", scaled(GIB)),
+        size if size >= MIB => format!("{:>6}M", scaled(MIB)),
+        size if size > 9999 => format!("{:>6}K", scaled(KIB)),
+        size => format!(" {:>6}", size),
+    }
+}
+
+fn render_xml(entries: &[ExplorerEntry]) -> String {
+    let mut xml = String::with_capacity(64 + entries.len() * 96);
+    xml.push_str("<?xml version=\"1.0\"?>\r\n<list>\r\n");
+
+    for entry in entries {
+        let mtime = DateTime::<Utc>::from(entry.mtime());
+        let mtime = mtime.format("%Y-%m-%dT%H:%M:%SZ");
+        let name = escape_html(entry.path());
+
+        let _ = match entry {
+            ExplorerEntry::File {
+                size,
+                hash: Some(hash),
+                ..
+            } => write!(
+                xml,
+                "<file mtime=\"{}\" size=\"{}\" hash=\"{}\">{}</file>\r\n",
+                mtime, size, hash, name
+            ),
+            ExplorerEntry::File { size, .. } => write!(
+                xml,
+                "<file mtime=\"{}\" size=\"{}\">{}</file>\r\n",
+                mtime, size, name
+            ),
+            ExplorerEntry::Directory { .. } => write!(
+                xml,
+                "<directory mtime=\"{}\">{}</directory>\r\n",
+                mtime, name
+            ),
+        };
+    }
+
+    xml.push_str("</list>\r\n");
+    xml
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes everything but unreserved characters,
+/// like nginx's `NGX_ESCAPE_URI_COMPONENT`. Slashes only occur in
+/// the relative paths of recursive listings and are kept.
+pub(crate) fn escape_uri(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                escaped.push(byte as char)
+            }
+            _ => {
+                let _ = write!(escaped, "%{:02X}", byte);
+            }
+        }
+    }
+    escaped
+}
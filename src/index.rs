@@ -0,0 +1,701 @@
+use rayon::prelude::*;
+use spdlog::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread;
+use std::time::{Instant, SystemTime};
+use thiserror::Error;
+
+use crate::crc32::Crc32;
+use crate::inotify::{self, Event, Inotify};
+use crate::page;
+use crate::{ExplorerEntry, Filter, Root, SymlinkPolicy};
+
+const MAGIC: &[u8; 8] = b"RINDEX\r\n";
+
+/// Bumped whenever the layout of the file changes. Indexes of other
+/// versions are rebuilt.
+const VERSION: u32 = 1;
+
+/// Flags of an indexed entry.
+const ENTRY_DIR: u8 = 1;
+const ENTRY_SYMLINK: u8 = 2;
+
+/// Device and inode of a directory, to tell when a symlink leads
+/// back into one of its ancestors.
+type Identity = (u64, u64);
+
+#[derive(Debug, Error)]
+pub enum IndexError {
+    #[error("Failed to access the index {0}: {1}")]
+    Io(String, #[source] io::Error),
+    #[error("Index is corrupt: {0}")]
+    Corrupt(&'static str),
+    #[error("Index has version {0} instead of {VERSION}")]
+    Version(u32),
+    #[error("Index was built for another root or symlink policy")]
+    OtherRoot,
+}
+
+/// Names, sizes and mtimes of everything below the root, kept on disk
+/// between runs and refreshed by re-reading the directories whose
+/// mtime has changed or that inotify saw change. Listings covering a
+/// directory changed since the last refresh, or one inotify can't
+/// watch, walk the filesystem. Without inotify at all, listings may
+/// lag the filesystem by up to `--index-refresh`.
+pub struct Index {
+    path: PathBuf,
+    tree: RwLock<Arc<Tree>>,
+    /// Set once the tree was refreshed since it was loaded.
+    ready: AtomicBool,
+    changes: Arc<Changes>,
+}
+
+/// Directories watched for the index, and those changed since the
+/// last refresh.
+struct Changes {
+    inotify: Option<Inotify>,
+    state: Mutex<ChangeState>,
+}
+
+#[derive(Default)]
+struct ChangeState {
+    /// Relative paths of each watch. A directory reached through
+    /// several paths has a single watch.
+    watches: HashMap<i32, Vec<String>>,
+    dirty: HashSet<String>,
+    /// Set when events were lost, so that any directory may have
+    /// changed.
+    overflowed: bool,
+    /// Changes taken by the refresh under way, which still count until
+    /// its tree replaces the one they make stale.
+    refreshing: HashSet<String>,
+    refreshing_overflowed: bool,
+    /// Indexed directories past the watch limit, whose changes go
+    /// unseen.
+    unwatched: HashSet<String>,
+}
+
+impl Index {
+    /// Loads the index at `path`, or starts an empty one if it is
+    /// missing, corrupt or made for another root. Until `refresh`
+    /// brings it up to date, listings walk the filesystem instead.
+    pub fn open(path: PathBuf, root: &Root) -> Result<Self, IndexError> {
+        let tree = match Tree::load(&path, root) {
+            Ok(tree) => tree,
+            Err(IndexError::Io(_, err)) if err.kind() == io::ErrorKind::NotFound => {
+                info!("Building the index at {}", path.display());
+                Tree::default()
+            }
+            Err(err) => {
+                warn!("{}, rebuilding it", err);
+                Tree::default()
+            }
+        };
+
+        let inotify = Inotify::new()
+            .inspect_err(|err| warn!("Failed to watch the index, checking mtimes: {}", err))
+            .ok();
+        let changes = Arc::new(Changes {
+            inotify,
+            state: Mutex::default(),
+        });
+        if changes.inotify.is_some() {
+            let events = changes.clone();
+            let spawned = thread::Builder::new()
+                .name("rindex-index-inotify".to_string())
+                .spawn(move || events.read_events());
+            if let Err(err) = spawned {
+                warn!("Failed to watch the index: {}", err);
+            }
+        }
+
+        Ok(Self {
+            path,
+            tree: RwLock::new(Arc::new(tree)),
+            ready: AtomicBool::new(false),
+            changes,
+        })
+    }
+
+    /// The index as last loaded or refreshed.
+    fn snapshot(&self) -> Arc<Tree> {
+        self.tree
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The index as of the last refresh, if `dir` is indexed and
+    /// nothing at or below it changed since.
+    pub(crate) fn current(&self, base: &Path, dir: &Path) -> Option<Arc<Tree>> {
+        if !self.ready.load(Ordering::Acquire) {
+            return None;
+        }
+        let relative = dir.strip_prefix(base).ok()?.to_string_lossy();
+        if self.changes.is_dirty(&relative) {
+            return None;
+        }
+
+        let tree = self.snapshot();
+        tree.entries(base, dir)?;
+        Some(tree)
+    }
+
+    /// Re-reads the directories changed since the last refresh, then
+    /// saves the index.
+    pub fn refresh(&self, root: &Root) -> Result<(), IndexError> {
+        // Events from now on are for the next refresh.
+        let (dirty, overflowed) = self.changes.take();
+        let tree = self.scan(root, &dirty, overflowed);
+        self.publish(root, tree)
+    }
+
+    /// A new tree from the last one, re-reading `dirty` directories or
+    /// every one if events were lost.
+    fn scan(&self, root: &Root, dirty: &HashSet<String>, overflowed: bool) -> Arc<Tree> {
+        let start_time = Instant::now();
+
+        let previous = self.snapshot();
+        let scan = Scan {
+            root,
+            previous: &previous,
+            dirty: (!overflowed).then_some(dirty),
+            reread: AtomicUsize::new(0),
+        };
+        let tree = Arc::new(Tree {
+            dirs: scan
+                .dir(root.base(), String::new(), &[])
+                .into_iter()
+                .collect(),
+        });
+
+        let elapsed = start_time.elapsed().as_micros() as f64 / 1000.0;
+        info!(
+            "Indexed {} directories, {} re-read, in {}ms",
+            tree.dirs.len(),
+            scan.reread.into_inner(),
+            elapsed
+        );
+        tree
+    }
+
+    /// Watches the directories of `tree` and serves it, then saves it.
+    /// The changes it was scanned for stop counting once it's served.
+    fn publish(&self, root: &Root, tree: Arc<Tree>) -> Result<(), IndexError> {
+        self.changes.watch(root.base(), &tree);
+        *self.tree.write().unwrap_or_else(PoisonError::into_inner) = tree.clone();
+        self.ready.store(true, Ordering::Release);
+        self.changes.published();
+        tree.save(&self.path, root)
+    }
+}
+
+impl Changes {
+    fn lock(&self) -> MutexGuard<'_, ChangeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether `relative` or a directory below it changed, or may have
+    /// changed unseen.
+    fn is_dirty(&self, relative: &str) -> bool {
+        let state = self.lock();
+        let below = |dirty: &String| {
+            relative.is_empty()
+                || dirty
+                    .strip_prefix(relative)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        state.overflowed
+            || state.refreshing_overflowed
+            || [&state.dirty, &state.refreshing, &state.unwatched]
+                .into_iter()
+                .flatten()
+                .any(below)
+    }
+
+    /// The directories changed so far, and whether events were lost,
+    /// starting over. They still count as changed until `published`.
+    fn take(&self) -> (HashSet<String>, bool) {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let dirty = mem::take(&mut state.dirty);
+        let overflowed = mem::take(&mut state.overflowed);
+        state.refreshing.extend(dirty.iter().cloned());
+        state.refreshing_overflowed |= overflowed;
+        (dirty, overflowed)
+    }
+
+    /// Drops the changes taken once the tree scanned for them is served.
+    fn published(&self) {
+        let mut state = self.lock();
+        state.refreshing.clear();
+        state.refreshing_overflowed = false;
+    }
+
+    /// Watches every directory of `tree`, as far as the kernel's
+    /// watch limit goes; the rest are only checked for a new mtime,
+    /// and listings covering them walk the filesystem.
+    fn watch(&self, base: &Path, tree: &Tree) {
+        let Some(inotify) = &self.inotify else {
+            return;
+        };
+
+        let mut watches = HashMap::<i32, Vec<String>>::new();
+        let mut unwatched = HashSet::new();
+        let mut dirs = tree.dirs.keys();
+        for relative in dirs.by_ref() {
+            match inotify.add_watch(&base.join(relative)) {
+                Ok(wd) => watches.entry(wd).or_default().push(relative.clone()),
+                Err(err) => {
+                    info!(
+                        "Watching {} of {} indexed directories: {}",
+                        watches.values().map(Vec::len).sum::<usize>(),
+                        tree.dirs.len(),
+                        err
+                    );
+                    unwatched.insert(relative.clone());
+                    break;
+                }
+            }
+        }
+        unwatched.extend(dirs.cloned());
+
+        let mut state = self.lock();
+        state.watches = watches;
+        state.unwatched = unwatched;
+    }
+
+    /// Marks directories dirty as their events arrive, for as long as
+    /// the server runs.
+    fn read_events(&self) {
+        let Some(inotify) = &self.inotify else {
+            return;
+        };
+        let mut buffer = vec![0; inotify::BUFFER_LEN];
+
+        loop {
+            let events = match inotify.read(&mut buffer) {
+                Ok(events) => events,
+                Err(err) => {
+                    warn!("Failed to read inotify events of the index: {}", err);
+                    return;
+                }
+            };
+
+            let mut state = self.lock();
+            let state = &mut *state;
+            for event in events {
+                match event {
+                    Event::Changed(wd) => {
+                        let paths = state.watches.get(&wd).into_iter().flatten();
+                        state.dirty.extend(paths.cloned());
+                    }
+                    Event::Overflow => state.overflowed = true,
+                }
+            }
+        }
+    }
+}
+
+/// Every directory below the root, keyed by its path relative to it.
+#[derive(Default)]
+pub(crate) struct Tree {
+    dirs: HashMap<String, Dir>,
+}
+
+struct Dir {
+    mtime: SystemTime,
+    entries: Arc<[Entry]>,
+}
+
+/// An entry the root permits, with the metadata of its target.
+pub(crate) struct Entry {
+    pub name: String,
+    flags: u8,
+    size: u64,
+    mtime: SystemTime,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.flags & ENTRY_DIR != 0
+    }
+
+    /// The listing entry, if it passes the metadata part of `filter`.
+    pub fn to_explorer(&self, filter: &Filter) -> Option<ExplorerEntry> {
+        let symlink = self.flags & ENTRY_SYMLINK != 0;
+        if !filter.accepts_attributes(self.is_dir(), symlink, self.size, self.mtime) {
+            return None;
+        }
+
+        Some(match self.is_dir() {
+            true => ExplorerEntry::Directory {
+                mtime: self.mtime,
+                name: self.name.clone(),
+                path: None,
+                children: None,
+                child_count: None,
+            },
+            false => ExplorerEntry::File {
+                mtime: self.mtime,
+                name: self.name.clone(),
+                path: None,
+                size: self.size,
+                hash: None,
+            },
+        })
+    }
+}
+
+impl Tree {
+    /// Entries of `dir`, a path below `base`, if it was indexed.
+    pub fn entries(&self, base: &Path, dir: &Path) -> Option<&[Entry]> {
+        let relative = dir.strip_prefix(base).ok()?.to_string_lossy();
+        self.dirs.get(relative.as_ref()).map(|dir| &*dir.entries)
+    }
+
+    fn load(path: &Path, root: &Root) -> Result<Self, IndexError> {
+        let bytes = fs::read(path).map_err(|err| io_error(path, err))?;
+
+        if bytes.len() < 4 {
+            return Err(IndexError::Corrupt("truncated"));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        let mut crc = Crc32::new();
+        crc.update(body);
+        if crc.finish().to_le_bytes() != checksum {
+            return Err(IndexError::Corrupt("checksum mismatch"));
+        }
+
+        let mut reader = Reader(body);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(IndexError::Corrupt("not an index"));
+        }
+        match reader.u32()? {
+            VERSION => {}
+            version => return Err(IndexError::Version(version)),
+        }
+        if reader.text()? != root.base().to_string_lossy() || reader.u8()? != policy(root) {
+            return Err(IndexError::OtherRoot);
+        }
+
+        let count = reader.count()?;
+        let mut dirs = HashMap::with_capacity(count);
+        for _ in 0..count {
+            let path = reader.text()?;
+            let mtime = reader.mtime()?;
+            let entries = (0..reader.count()?)
+                .map(|_| {
+                    Ok(Entry {
+                        flags: reader.u8()?,
+                        size: reader.u64()?,
+                        mtime: reader.mtime()?,
+                        name: reader.text()?,
+                    })
+                })
+                .collect::<Result<_, IndexError>>()?;
+            dirs.insert(path, Dir { mtime, entries });
+        }
+        if !reader.0.is_empty() {
+            return Err(IndexError::Corrupt("trailing bytes"));
+        }
+
+        Ok(Self { dirs })
+    }
+
+    /// Writes the index next to `path` and renames it over, so readers
+    /// never see half of it.
+    fn save(&self, path: &Path, root: &Root) -> Result<(), IndexError> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        let write = || -> io::Result<()> {
+            let file = File::create(&temporary)?;
+            let mut writer = Checksummed {
+                writer: BufWriter::new(file),
+                crc: Crc32::new(),
+            };
+
+            writer.write_all(MAGIC)?;
+            writer.write_all(&VERSION.to_le_bytes())?;
+            write_text(&mut writer, &root.base().to_string_lossy())?;
+            writer.write_all(&[policy(root)])?;
+            writer.write_all(&(self.dirs.len() as u64).to_le_bytes())?;
+
+            for (path, dir) in &self.dirs {
+                write_text(&mut writer, path)?;
+                writer.write_all(&page::to_nanos(dir.mtime).to_le_bytes())?;
+                writer.write_all(&(dir.entries.len() as u64).to_le_bytes())?;
+                for entry in dir.entries.iter() {
+                    writer.write_all(&[entry.flags])?;
+                    writer.write_all(&entry.size.to_le_bytes())?;
+                    writer.write_all(&page::to_nanos(entry.mtime).to_le_bytes())?;
+                    write_text(&mut writer, &entry.name)?;
+                }
+            }
+
+            let checksum = writer.crc.finish();
+            let mut writer = writer.writer;
+            writer.write_all(&checksum.to_le_bytes())?;
+            writer
+                .into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_all()
+        };
+
+        write()
+            .and_then(|_| fs::rename(&temporary, path))
+            .map_err(|err| io_error(&temporary, err))
+    }
+}
+
+/// One refresh of the index.
+struct Scan<'a> {
+    root: &'a Root,
+    previous: &'a Tree,
+    /// Directories to re-read whatever their mtime, or `None` for all.
+    dirty: Option<&'a HashSet<String>>,
+    reread: AtomicUsize,
+}
+
+impl Scan<'_> {
+    /// Indexes `path` and the directories below it in parallel. Entries
+    /// are reused while the directory's mtime is unchanged and inotify
+    /// saw no change. Without a watch, files rewritten in place don't
+    /// change the mtime, so their size and mtime only catch up when
+    /// something else in the directory does.
+    fn dir(&self, path: &Path, relative: String, ancestors: &[Identity]) -> Vec<(String, Dir)> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                warn!("Failed to index {}: {}", path.display(), err);
+                return Vec::new();
+            }
+        };
+
+        let identity = (metadata.dev(), metadata.ino());
+        if ancestors.contains(&identity) {
+            info!("Symlink loop at {}", path.display());
+            return Vec::new();
+        }
+        let ancestors = [ancestors, &[identity]].concat();
+
+        let mtime = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let entries = match self.previous.dirs.get(&relative) {
+            Some(dir)
+                if dir.mtime == mtime
+                    && self.dirty.is_some_and(|dirty| !dirty.contains(&relative)) =>
+            {
+                dir.entries.clone()
+            }
+            _ => {
+                self.reread.fetch_add(1, Ordering::Relaxed);
+                self.read(path)
+            }
+        };
+
+        let mut dirs = entries
+            .par_iter()
+            .filter(|entry| entry.is_dir())
+            .flat_map_iter(|entry| {
+                let relative = match relative.is_empty() {
+                    true => entry.name.clone(),
+                    false => format!("{}/{}", relative, entry.name),
+                };
+                self.dir(&path.join(&entry.name), relative, &ancestors)
+            })
+            .collect::<Vec<_>>();
+
+        dirs.push((relative, Dir { mtime, entries }));
+        dirs
+    }
+
+    /// Stats the entries of `path` the root permits.
+    fn read(&self, path: &Path) -> Arc<[Entry]> {
+        let read_dir = match fs::read_dir(path) {
+            Ok(read_dir) => read_dir,
+            Err(err) => {
+                warn!("Failed to index {}: {}", path.display(), err);
+                return Arc::new([]);
+            }
+        };
+
+        read_dir
+            .par_bridge()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let path = entry.path();
+                let file_type = entry.file_type().ok()?;
+                self.root.permits(&path, file_type).ok()?;
+
+                let metadata = match fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        info!("Failed to index {}: {}", path.display(), err);
+                        return None;
+                    }
+                };
+
+                let dir = if metadata.is_dir() { ENTRY_DIR } else { 0 };
+                let symlink = if file_type.is_symlink() {
+                    ENTRY_SYMLINK
+                } else {
+                    0
+                };
+                Some(Entry {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    flags: dir | symlink,
+                    size: metadata.len(),
+                    mtime: metadata.modified().ok()?,
+                })
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+/// The rules of `root` an index was built under, with dotfiles
+/// hidden in a bit of their own.
+fn policy(root: &Root) -> u8 {
+    let symlinks = match root.symlinks() {
+        SymlinkPolicy::Inside => 0,
+        SymlinkPolicy::All => 1,
+        SymlinkPolicy::Never => 2,
+    };
+    symlinks | u8::from(root.hide_dotfiles()) << 2
+}
+
+fn io_error(path: &Path, err: io::Error) -> IndexError {
+    IndexError::Io(path.to_string_lossy().into_owned(), err)
+}
+
+/// Passes writes through, keeping their CRC.
+struct Checksummed<W: Write> {
+    writer: W,
+    crc: Crc32,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(bytes)?;
+        self.crc.update(&bytes[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn write_text<W: Write>(writer: &mut W, text: &str) -> io::Result<()> {
+    writer.write_all(&(text.len() as u32).to_le_bytes())?;
+    writer.write_all(text.as_bytes())
+}
+
+/// Reads fields of a loaded index, failing once it runs short.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], IndexError> {
+        let (bytes, rest) = self
+            .0
+            .split_at_checked(len)
+            .ok_or(IndexError::Corrupt("truncated"))?;
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, IndexError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, IndexError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, IndexError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A number of items, each taking at least a byte.
+    fn count(&mut self) -> Result<usize, IndexError> {
+        match self.u64()? {
+            count if count <= self.0.len() as u64 => Ok(count as usize),
+            _ => Err(IndexError::Corrupt("truncated")),
+        }
+    }
+
+    fn mtime(&mut self) -> Result<SystemTime, IndexError> {
+        let nanos = i128::from_le_bytes(self.take(16)?.try_into().unwrap());
+        page::from_nanos(nanos).ok_or(IndexError::Corrupt("invalid mtime"))
+    }
+
+    fn text(&mut self) -> Result<String, IndexError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| IndexError::Corrupt("invalid name"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    use super::Index;
+    use crate::{Root, SymlinkPolicy};
+
+    #[test]
+    fn changes_count_until_the_refresh_is_served() {
+        let dir = std::env::temp_dir().join(format!("rindex-refreshing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        let root = Root::new(dir.join("root"), SymlinkPolicy::Inside, false).unwrap();
+        let index = Index::open(dir.join("index"), &root).unwrap();
+        let (base, sub) = (root.base(), root.base().join("sub"));
+        index.refresh(&root).unwrap();
+        assert!(index.current(base, &sub).is_some());
+
+        fs::write(sub.join("new.txt"), "new").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while index.current(base, &sub).is_some() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(index.current(base, &sub).is_none());
+
+        // Through the scan, the tree served is the one before the change.
+        let (dirty, overflowed) = index.changes.take();
+        assert!(index.current(base, &sub).is_none());
+        assert!(index.current(base, base).is_none());
+        let tree = index.scan(&root, &dirty, overflowed);
+        assert!(index.current(base, &sub).is_none());
+        index.publish(&root, tree).unwrap();
+        let tree = index.current(base, &sub).unwrap();
+        let entries = tree.entries(base, &sub).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "new.txt");
+
+        // As do lost events.
+        index.changes.lock().overflowed = true;
+        let (dirty, overflowed) = index.changes.take();
+        assert!(index.current(base, base).is_none());
+        let tree = index.scan(&root, &dirty, overflowed);
+        assert!(index.current(base, &sub).is_none());
+        index.publish(&root, tree).unwrap();
+        assert!(index.current(base, &sub).is_some());
+
+        // Directories past the watch limit are never current.
+        index.changes.lock().unwatched.insert("sub".to_string());
+        assert!(index.current(base, &sub).is_none());
+        assert!(index.current(base, base).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Changes to a directory that make what was read of it stale.
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF
+    | libc::IN_ONLYDIR;
+
+/// Size of `struct inotify_event` before its name.
+const EVENT_LEN: usize = 16;
+
+/// Room for a good batch of events.
+pub(crate) const BUFFER_LEN: usize = 64 * 1024;
+
+/// Watches on directories, whose events are read by a thread of
+/// their own.
+pub(crate) struct Inotify(File);
+
+/// What happened to a watched directory.
+pub(crate) enum Event {
+    /// Something changed in the directory with this watch.
+    Changed(i32),
+    /// Events were dropped, so anything may have changed.
+    Overflow,
+}
+
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        match unsafe { libc::inotify_init1(libc::IN_CLOEXEC) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Self(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))),
+        }
+    }
+
+    /// Watches `dir`, returning the watch it shares with any other
+    /// path to the same directory.
+    pub fn add_watch(&self, dir: &Path) -> io::Result<i32> {
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        match unsafe { libc::inotify_add_watch(self.0.as_raw_fd(), path.as_ptr(), WATCH_MASK) } {
+            -1 => Err(io::Error::last_os_error()),
+            wd => Ok(wd),
+        }
+    }
+
+    pub fn rm_watch(&self, wd: i32) {
+        unsafe { libc::inotify_rm_watch(self.0.as_raw_fd(), wd) };
+    }
+
+    /// Waits for the next batch of events, read into `buffer`.
+    pub fn read<'a>(&self, buffer: &'a mut [u8]) -> io::Result<impl Iterator<Item = Event> + 'a> {
+        let len = loop {
+            match (&self.0).read(buffer) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                read => break read?,
+            }
+        };
+
+        let mut events = &buffer[..len];
+        Ok(std::iter::from_fn(move || {
+            if events.len() < EVENT_LEN {
+                return None;
+            }
+            let field = |at: usize| events[at..at + 4].try_into().unwrap();
+            let wd = i32::from_ne_bytes(field(0));
+            let mask = u32::from_ne_bytes(field(4));
+            let name_len = u32::from_ne_bytes(field(12)) as usize;
+            events = events.get(EVENT_LEN + name_len..).unwrap_or_default();
+
+            Some(match mask & libc::IN_Q_OVERFLOW {
+                0 => Event::Changed(wd),
+                _ => Event::Overflow,
+            })
+        }))
+    }
+}
@@ -1,7 +1,48 @@
+mod archive;
+mod bundle;
+mod cache;
+mod collation;
+mod compress;
+mod config;
+mod crc32;
+mod digest;
 mod explorer;
+mod file;
+mod filter;
+mod format;
+mod index;
+mod inotify;
 mod log;
+mod options;
+mod page;
+mod pattern;
+mod request;
+mod root;
+mod search;
 mod service;
+mod sorting;
+mod stream;
+mod walk;
 
+pub use archive::{Archive, ArchiveError, ArchiveFormat};
+pub use bundle::{BundleCache, BundleError};
+pub use cache::ListingCache;
+pub use collation::Collation;
+pub use compress::{Compression, Encoding, LevelError, Variants};
+pub use config::{CacheControl, Config, EntryErrorPolicy, Handoff, HandoffHeader};
+pub use digest::{HashAlgorithm, HashCache};
 pub use explorer::ExplorerEntry;
+pub use file::Download;
+pub use filter::{EntryKind, Filter};
+pub use format::Format;
+pub use index::{Index, IndexError};
 pub use log::Log;
-pub use service::{QueryResult, Service};
+pub use options::{Layout, ListOptions, OptionError};
+pub use page::{Page, Pagination};
+pub use pattern::{Pattern, PatternError};
+pub use request::{RequestError, RequestPath};
+pub use root::{ResolveError, Root, SymlinkPolicy};
+pub use search::{Found, Query, SearchMode};
+pub use service::{Listing, QueryResult, Reply, Service};
+pub use sorting::{Sort, SortKey, SortOrder};
+pub use stream::Stream;
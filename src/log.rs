@@ -6,6 +6,7 @@ use std::sync::Arc;
 pub struct Log;
 
 impl Log {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(logdir: Option<PathBuf>, verbose: bool) -> Arc<Logger> {
         let mut logger: LoggerBuilder = Logger::builder();
         logger.sinks(spdlog::default_logger().sinks().to_owned());
@@ -25,7 +26,7 @@ impl Log {
             }
 
             let log_name = format!("{}.log", env!("CARGO_PKG_NAME"));
-            let logdir = PathBuf::from(logdir).join(log_name);
+            let logdir = logdir.join(log_name);
 
             let file_sink: Arc<RotatingFileSink> = Arc::new(
                 RotatingFileSink::builder()
@@ -1,11 +1,16 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use argh::FromArgs;
+use chrono::{FixedOffset, Local};
 use spdlog::prelude::*;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
-use rindex::{Log, Service};
+use rindex::{
+    BundleCache, CacheControl, Collation, Compression, Config, EntryErrorPolicy, Format, Handoff,
+    HashCache, Index, ListingCache, Log, Root, Service, SymlinkPolicy,
+};
 
 static LOGGER: OnceLock<Arc<Logger>> = OnceLock::new();
 
@@ -30,17 +35,208 @@ struct Args {
     #[argh(description = "directory of log files, empty for disable")]
     logdir: Option<PathBuf>,
 
+    #[argh(option, short = 's')]
+    #[argh(default = "SymlinkPolicy::Inside")]
+    #[argh(description = "symlinks to follow: inside, all or never")]
+    symlinks: SymlinkPolicy,
+
+    #[argh(switch)]
+    #[argh(description = "hide files and directories whose names start with a dot")]
+    hide_dotfiles: bool,
+
+    #[argh(option, short = 'e')]
+    #[argh(default = "EntryErrorPolicy::Skip")]
+    #[argh(description = "unreadable entries: skip or fail the listing")]
+    entry_errors: EntryErrorPolicy,
+
+    #[argh(option, short = 'm')]
+    #[argh(description = "maximum entries in a listing, empty for unlimited")]
+    max_entries: Option<usize>,
+
+    #[argh(option)]
+    #[argh(default = "16")]
+    #[argh(description = "deepest depth of a recursive listing")]
+    max_depth: usize,
+
+    #[argh(option)]
+    #[argh(default = "100_000")]
+    #[argh(description = "most entries a recursive listing may read")]
+    max_walk: usize,
+
+    #[argh(option)]
+    #[argh(default = "10")]
+    #[argh(description = "seconds a search may walk before answering")]
+    search_timeout: u64,
+
+    #[argh(option)]
+    #[argh(description = "file of the index of names, empty for none")]
+    index: Option<PathBuf>,
+
+    #[argh(option)]
+    #[argh(default = "300")]
+    #[argh(description = "seconds between refreshes of the index")]
+    index_refresh: u64,
+
+    #[argh(option)]
+    #[argh(default = "64")]
+    #[argh(description = "megabytes of cached listings, 0 for no cache")]
+    cache_size: usize,
+
+    #[argh(option)]
+    #[argh(description = "cache-control of paths with a prefix, as <prefix>=<value>")]
+    cache_control: Vec<CacheControl>,
+
+    #[argh(option)]
+    #[argh(default = "1024")]
+    #[argh(description = "smallest listing in bytes sent compressed")]
+    compress_min: usize,
+
+    #[argh(option)]
+    #[argh(default = "6")]
+    #[argh(description = "gzip level, 1 to 9")]
+    gzip_level: u32,
+
+    #[argh(option)]
+    #[argh(default = "5")]
+    #[argh(description = "brotli level, 0 to 11")]
+    brotli_level: u32,
+
+    #[argh(option)]
+    #[argh(default = "3")]
+    #[argh(description = "zstd level, 1 to 19")]
+    zstd_level: u32,
+
+    #[argh(switch)]
+    #[argh(description = "send files instead of only listing directories")]
+    serve_files: bool,
+
+    #[argh(option)]
+    #[argh(
+        description = "hand files to the server in front, as accel-redirect=<prefix> or sendfile=<prefix>"
+    )]
+    handoff: Option<Handoff>,
+
+    #[argh(option)]
+    #[argh(default = "4096")]
+    #[argh(description = "megabytes of files an archive download may hold")]
+    max_archive_size: u64,
+
+    #[argh(option)]
+    #[argh(default = "10_000")]
+    #[argh(description = "most files an archive download may hold")]
+    max_archive_files: usize,
+
+    #[argh(option)]
+    #[argh(default = "0")]
+    #[argh(description = "archives kept read for browsing inside, 0 for no browsing")]
+    bundle_cache: usize,
+
+    #[argh(option)]
+    #[argh(default = "65_536")]
+    #[argh(description = "file digests kept, 0 for no cache")]
+    hash_cache: usize,
+
+    #[argh(option)]
+    #[argh(default = "Format::Json")]
+    #[argh(description = "default listing format: html, xml, json or jsonp")]
+    format: Format,
+
+    #[argh(switch)]
+    #[argh(description = "show rounded sizes in html listings")]
+    human_size: bool,
+
+    #[argh(switch)]
+    #[argh(description = "show local times in html listings")]
+    localtime: bool,
+
+    #[argh(option)]
+    #[argh(description = "utc offset of local times, empty for the system's")]
+    utc_offset: Option<FixedOffset>,
+
+    #[argh(option)]
+    #[argh(default = "Collation::Bytes")]
+    #[argh(description = "name order: bytes, natural, nocase or unicode")]
+    collation: Collation,
+
     #[argh(switch, short = 'v')]
     #[argh(description = "will show logs in stdout")]
     verbose: bool,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Index(IndexCommand),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "index")]
+#[argh(description = "build or refresh the index given by --index, then exit")]
+struct IndexCommand {}
+
 fn main() -> Result<()> {
     let args: Args = argh::from_env();
     LOGGER.get_or_init(|| Log::new(args.logdir, args.verbose));
 
+    let compression = Compression::new(
+        args.compress_min,
+        args.gzip_level,
+        args.brotli_level,
+        args.zstd_level,
+    )?;
+
+    let root = Root::new(args.directory, args.symlinks, args.hide_dotfiles)?;
+    let index = match args.index {
+        Some(path) => Some(Index::open(path, &root)?),
+        None if args.command.is_some() => bail!("The index subcommand needs --index"),
+        None => None,
+    };
+    if let Some(Command::Index(_)) = args.command {
+        if let Some(index) = &index {
+            index.refresh(&root)?;
+        }
+        LOGGER.get().unwrap().flush();
+        return Ok(());
+    }
+
+    let cache_size = args
+        .cache_size
+        .checked_mul(1 << 20)
+        .context("The cache size is too large")?;
+    let max_archive_size = args
+        .max_archive_size
+        .checked_mul(1 << 20)
+        .context("The archive size limit is too large")?;
+
     let address = SocketAddr::from((args.address, args.port));
-    Service::new(address, args.directory.canonicalize()?)?;
+    let config = Config {
+        root,
+        entry_errors: args.entry_errors,
+        max_entries: args.max_entries,
+        max_depth: args.max_depth,
+        max_walk: args.max_walk,
+        search_timeout: Duration::from_secs(args.search_timeout),
+        index,
+        index_refresh: Duration::from_secs(args.index_refresh),
+        cache: (cache_size > 0).then(|| ListingCache::new(cache_size)),
+        cache_control: args.cache_control,
+        compression,
+        serve_files: args.serve_files,
+        handoff: args.handoff,
+        max_archive_size,
+        max_archive_files: args.max_archive_files,
+        bundles: (args.bundle_cache > 0).then(|| BundleCache::new(args.bundle_cache)),
+        hashes: (args.hash_cache > 0).then(|| HashCache::new(args.hash_cache)),
+        format: args.format,
+        exact_size: !args.human_size,
+        localtime: args.localtime,
+        utc_offset: args.utc_offset.unwrap_or_else(|| *Local::now().offset()),
+        collation: args.collation,
+    };
+    Service::new(address, config)?;
 
     LOGGER.get().unwrap().flush();
     Ok(())
@@ -0,0 +1,325 @@
+use chrono::FixedOffset;
+use snowboard::Request;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::archive::ArchiveFormat;
+use crate::digest::HashAlgorithm;
+use crate::filter::{self, Filter};
+use crate::format::{self, Format};
+use crate::page::{self, Pagination};
+use crate::pattern::{Pattern, PatternError};
+use crate::request::{self, RequestPath};
+use crate::search::{Query, SearchMode};
+use crate::sorting::{Sort, SortKey};
+use crate::Config;
+
+#[derive(Debug, Error)]
+pub enum OptionError {
+    #[error("Invalid value for {0}: {1}")]
+    InvalidValue(&'static str, String),
+    #[error("Invalid pattern for {0}: {1}: {2}")]
+    InvalidPattern(&'static str, String, #[source] PatternError),
+}
+
+/// Results of a search unless `limit` asks for another number.
+const SEARCH_LIMIT: usize = 100;
+
+/// Shape of a recursive listing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// One list of entries carrying their relative paths.
+    #[default]
+    Flat,
+    /// Directories nest their entries, JSON only.
+    Tree,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "flat" => Ok(Self::Flat),
+            "tree" => Ok(Self::Tree),
+            _ => Err(format!("Unknown layout: {}", value)),
+        }
+    }
+}
+
+/// Per-request listing options, taken from the query string and
+/// headers with the server configuration as fallback.
+pub struct ListOptions {
+    pub format: Format,
+    pub callback: Option<String>,
+    pub exact_size: bool,
+    /// Offset of the HTML listing's mtimes, UTC unless `localtime` is on.
+    pub utc_offset: FixedOffset,
+    pub sort: Sort,
+    pub pagination: Pagination,
+    pub filter: Filter,
+    /// Levels to list, more than one for a recursive listing.
+    pub depth: usize,
+    pub layout: Layout,
+    /// Wrap JSON listings in an object carrying the page metadata.
+    pub envelope: bool,
+    /// Give up walking at this time.
+    pub deadline: Option<Instant>,
+    /// Send the directory as an archive of this format instead.
+    pub archive: Option<ArchiveFormat>,
+    /// Send this digest of a file instead, or show it in listings for
+    /// the files it is known of.
+    pub hash: Option<HashAlgorithm>,
+}
+
+impl ListOptions {
+    pub fn new(config: &Config, req: &Request, request: &RequestPath) -> Result<Self, OptionError> {
+        let format = match request.param("format") {
+            Some(format) => parse("format", format)?,
+            None => Self::negotiate_format(config.format, req),
+        };
+
+        // An empty callback is no callback, and JSONP without one is
+        // plain JSON, labelled so, as nginx does.
+        let callback = match request
+            .param("callback")
+            .filter(|callback| !callback.is_empty())
+        {
+            Some(callback) if !format::is_valid_callback(callback) => {
+                return Err(OptionError::InvalidValue("callback", callback.to_string()));
+            }
+            callback => callback.map(str::to_string),
+        };
+        let format = match (format, &callback) {
+            (Format::Jsonp, None) => Format::Json,
+            (format, _) => format,
+        };
+
+        let exact_size = match request.param("exact_size") {
+            Some(exact_size) => parse_switch("exact_size", exact_size)?,
+            None => config.exact_size,
+        };
+
+        let localtime = match request.param("localtime") {
+            Some(localtime) => parse_switch("localtime", localtime)?,
+            None => config.localtime,
+        };
+
+        let utc_offset = if localtime {
+            config.utc_offset
+        } else {
+            FixedOffset::east_opt(0).unwrap()
+        };
+
+        // Apache's mod_autoindex spells these `C=M;O=D`. Its `C=D` sorts
+        // by description, which listings don't have, so it is refused.
+        let mut sort = Sort {
+            collation: config.collation,
+            ..Sort::default()
+        };
+        if let Some(key) = request.param("sort").or_else(|| request.param("C")) {
+            sort.key = parse("sort", key)?;
+        }
+        if let Some(order) = request.param("order").or_else(|| request.param("O")) {
+            sort.order = parse("order", order)?;
+        }
+        if let Some(dirs_first) = request.param("dirs_first") {
+            sort.dirs_first = parse_switch("dirs_first", dirs_first)?;
+        }
+        if let Some(collation) = request.param("collation") {
+            sort.collation = parse("collation", collation)?;
+        }
+
+        let mut pagination = Pagination::default();
+        if let Some(offset) = request.param("offset") {
+            pagination.offset = parse("offset", offset)?;
+        }
+        if let Some(limit) = request.param("limit") {
+            pagination.limit = Some(parse("limit", limit)?);
+        }
+        if let Some(cursor) = request.param("cursor") {
+            if sort.key == SortKey::None {
+                return Err(OptionError::InvalidValue("cursor", cursor.to_string()));
+            }
+            pagination.cursor = Some(
+                page::decode_cursor(cursor, &sort)
+                    .ok_or_else(|| OptionError::InvalidValue("cursor", cursor.to_string()))?,
+            );
+        }
+
+        // Globs match the whole name, regexes anywhere in it.
+        let mut filter = Filter {
+            include: patterns(request, "include", Pattern::glob)?
+                .chain(patterns(request, "include_regex", Pattern::regex)?)
+                .collect(),
+            exclude: patterns(request, "exclude", Pattern::glob)?
+                .chain(patterns(request, "exclude_regex", Pattern::regex)?)
+                .collect(),
+            ..Filter::default()
+        };
+        if let Some(kind) = request.param("type") {
+            filter.kind = Some(parse("type", kind)?);
+        }
+        if let Some(min_size) = request.param("min_size") {
+            filter.min_size = Some(parse_with("min_size", min_size, filter::parse_size)?);
+        }
+        if let Some(max_size) = request.param("max_size") {
+            filter.max_size = Some(parse_with("max_size", max_size, filter::parse_size)?);
+        }
+        if let Some(since) = request.param("modified_since") {
+            filter.modified_since = Some(parse_with("modified_since", since, filter::parse_date)?);
+        }
+        if let Some(before) = request.param("modified_before") {
+            filter.modified_before =
+                Some(parse_with("modified_before", before, filter::parse_date)?);
+        }
+
+        let archive = match request.param("archive") {
+            Some(archive) => Some(parse("archive", archive)?),
+            None => None,
+        };
+
+        let hash = match request.param("hash") {
+            Some(hash) => Some(parse("hash", hash)?),
+            None => None,
+        };
+
+        // Archives hold the whole tree unless asked otherwise.
+        let depth = match request.param("depth") {
+            Some(depth) => match parse("depth", depth)? {
+                levels @ 1.. if levels <= config.max_depth => levels,
+                _ => return Err(OptionError::InvalidValue("depth", depth.to_string())),
+            },
+            None if archive.is_some() => config.max_depth,
+            None => 1,
+        };
+
+        let layout = match request.param("layout") {
+            Some(layout) => parse("layout", layout)?,
+            None => Layout::default(),
+        };
+        let nests = !matches!(format, Format::Json | Format::Jsonp) || archive.is_some();
+        if layout == Layout::Tree && nests {
+            return Err(OptionError::InvalidValue("layout", "tree".to_string()));
+        }
+
+        let envelope = match request.param("envelope") {
+            Some(envelope) => parse_switch("envelope", envelope)?,
+            None => false,
+        };
+
+        Ok(Self {
+            format,
+            callback,
+            exact_size,
+            utc_offset,
+            sort,
+            pagination,
+            filter,
+            depth,
+            layout,
+            envelope,
+            deadline: None,
+            archive,
+            hash,
+        })
+    }
+
+    /// Options of a search: the listing options, walking the whole
+    /// tree for names matching `q` within the time limit.
+    pub fn search(
+        config: &Config,
+        req: &Request,
+        request: &RequestPath,
+    ) -> Result<Self, OptionError> {
+        let mut options = Self::new(config, req, request)?;
+
+        let query = match request.param("q") {
+            Some(query) if !query.is_empty() => query,
+            query => {
+                let query = query.unwrap_or_default().to_string();
+                return Err(OptionError::InvalidValue("q", query));
+            }
+        };
+        let mode = match request.param("mode") {
+            Some(mode) => parse("mode", mode)?,
+            None => SearchMode::default(),
+        };
+        options.filter.query = Some(
+            Query::new(query, mode)
+                .map_err(|err| OptionError::InvalidPattern("q", query.to_string(), err))?,
+        );
+
+        if let Some(cursor) = request.param("cursor") {
+            return Err(OptionError::InvalidValue("cursor", cursor.to_string()));
+        }
+        options.pagination.limit = options.pagination.limit.or(Some(SEARCH_LIMIT));
+
+        let timeout = match request.param("timeout") {
+            Some(timeout) => Duration::from_millis(parse("timeout", timeout)?),
+            None => config.search_timeout,
+        };
+        // Timeouts too long to count down from now mean none at all.
+        options.deadline = Instant::now().checked_add(timeout.min(config.search_timeout));
+
+        options.format = Format::Json;
+        options.depth = config.max_depth;
+        options.layout = Layout::Flat;
+        Ok(options)
+    }
+
+    /// Lets the `Accept` header pick a format, keeping the default
+    /// for wildcards and for clients that accept none of them.
+    fn negotiate_format(default: Format, req: &Request) -> Format {
+        let Some(accept) = request::header(req, "Accept") else {
+            return default;
+        };
+
+        let offers = std::iter::once(default)
+            .chain(Format::ALL)
+            .map(Format::content_type)
+            .collect::<Vec<_>>();
+
+        request::negotiate(accept, &offers)
+            .and_then(Format::from_content_type)
+            .unwrap_or(default)
+    }
+}
+
+fn parse<T: FromStr>(name: &'static str, value: &str) -> Result<T, OptionError> {
+    value
+        .parse()
+        .map_err(|_| OptionError::InvalidValue(name, value.to_string()))
+}
+
+fn parse_with<T>(
+    name: &'static str,
+    value: &str,
+    parser: fn(&str) -> Option<T>,
+) -> Result<T, OptionError> {
+    parser(value).ok_or_else(|| OptionError::InvalidValue(name, value.to_string()))
+}
+
+fn parse_switch(name: &'static str, value: &str) -> Result<bool, OptionError> {
+    match value {
+        "on" | "true" | "1" => Ok(true),
+        "off" | "false" | "0" => Ok(false),
+        _ => Err(OptionError::InvalidValue(name, value.to_string())),
+    }
+}
+
+fn patterns(
+    request: &RequestPath,
+    name: &'static str,
+    compile: fn(&str) -> Result<Pattern, PatternError>,
+) -> Result<std::vec::IntoIter<Pattern>, OptionError> {
+    request
+        .params(name)
+        .map(|pattern| {
+            compile(pattern)
+                .map_err(|err| OptionError::InvalidPattern(name, pattern.to_string(), err))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Vec::into_iter)
+}
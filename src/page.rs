@@ -0,0 +1,163 @@
+use std::cmp::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{ExplorerEntry, Sort};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Which part of a sorted listing to return. A cursor names the last
+/// entry already seen, so pages stay stable while the directory changes.
+#[derive(Default)]
+pub struct Pagination {
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub cursor: Option<ExplorerEntry>,
+}
+
+/// What the client needs to fetch the rest of the listing.
+#[derive(Clone)]
+pub struct Page {
+    pub total: usize,
+    pub next: Option<String>,
+}
+
+impl Pagination {
+    /// Cuts the page out of `entries`, which must be sorted by `sort`.
+    pub fn apply(&self, entries: &mut Vec<ExplorerEntry>, sort: &Sort) -> Page {
+        let total = entries.len();
+
+        let start = match &self.cursor {
+            Some(cursor) => entries.partition_point(|entry| sort.compare(entry, cursor).is_le()),
+            None => 0,
+        };
+        let start = start.saturating_add(self.offset).min(total);
+        let end = self
+            .limit
+            .map_or(total, |limit| start.saturating_add(limit).min(total));
+
+        entries.truncate(end);
+        entries.drain(..start);
+
+        let next = match entries.last() {
+            Some(last) if end < total => Some(encode_cursor(last, sort)),
+            _ => None,
+        };
+
+        Page { total, next }
+    }
+}
+
+/// Nanoseconds since the epoch, negative before it.
+pub(crate) fn to_nanos(time: SystemTime) -> i128 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i128,
+        Err(err) => -(err.duration().as_nanos() as i128),
+    }
+}
+
+pub(crate) fn from_nanos(nanos: i128) -> Option<SystemTime> {
+    let since = Duration::from_nanos(u64::try_from(nanos.unsigned_abs()).ok()?);
+    match nanos.cmp(&0) {
+        Ordering::Less => UNIX_EPOCH.checked_sub(since),
+        _ => UNIX_EPOCH.checked_add(since),
+    }
+}
+
+pub fn encode_cursor(entry: &ExplorerEntry, sort: &Sort) -> String {
+    let mtime = to_nanos(entry.mtime());
+    let size = entry
+        .size()
+        .map_or("-".to_string(), |size| size.to_string());
+
+    let text = format!(
+        "{}\n{}\n{}\n{}",
+        fingerprint(sort),
+        size,
+        mtime,
+        entry.path()
+    );
+    encode_base64(text.as_bytes())
+}
+
+/// Decodes a cursor made by `encode_cursor` under the same sort.
+pub fn decode_cursor(cursor: &str, sort: &Sort) -> Option<ExplorerEntry> {
+    let text = String::from_utf8(decode_base64(cursor)?).ok()?;
+    let mut fields = text.splitn(4, '\n');
+
+    if fields.next()? != fingerprint(sort) {
+        return None;
+    }
+
+    let size = match fields.next()? {
+        "-" => None,
+        size => Some(size.parse().ok()?),
+    };
+
+    let mtime = from_nanos(fields.next()?.parse().ok()?)?;
+
+    // Entries of recursive listings are identified by their path.
+    let path = fields.next()?.to_string();
+    let (name, path) = match path.rsplit_once('/') {
+        Some((_, name)) => (name.to_string(), Some(path)),
+        None => (path, None),
+    };
+
+    Some(match size {
+        Some(size) => ExplorerEntry::File {
+            mtime,
+            name,
+            path,
+            size,
+            hash: None,
+        },
+        None => ExplorerEntry::Directory {
+            mtime,
+            name,
+            path,
+            children: None,
+            child_count: None,
+        },
+    })
+}
+
+fn fingerprint(sort: &Sort) -> String {
+    format!(
+        "{:?}{:?}{}{:?}",
+        sort.key, sort.order, sort.dirs_first as u8, sort.collation
+    )
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (index, &byte)| {
+                group | (byte as u32) << (16 - 8 * index)
+            });
+        for index in 0..=chunk.len() {
+            text.push(BASE64[(group >> (18 - 6 * index) & 0x3f) as usize] as char);
+        }
+    }
+
+    text
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut group, mut bits) = (0u32, 0);
+
+    for char in text.bytes() {
+        let value = BASE64.iter().position(|&digit| digit == char)? as u32;
+        group = group << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
@@ -0,0 +1,525 @@
+use std::iter::Peekable;
+use std::str::Chars;
+use thiserror::Error;
+
+/// Upper bound on compiled instructions, which keeps counted
+/// repetitions like `a{1000}{1000}` from exhausting memory.
+const MAX_PROGRAM: usize = 10_000;
+
+/// Upper bound on pattern length, which also bounds the nesting
+/// depth of the recursive parsers.
+const MAX_PATTERN: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum PatternError {
+    #[error("unexpected end of pattern")]
+    UnexpectedEnd,
+    #[error("unterminated character class")]
+    UnterminatedClass,
+    #[error("unmatched parenthesis")]
+    UnmatchedParen,
+    #[error("unmatched brace")]
+    UnmatchedBrace,
+    #[error("nothing to repeat before '{0}'")]
+    NothingToRepeat(char),
+    #[error("invalid repetition count")]
+    InvalidRepetition,
+    #[error("invalid character range {0}-{1}")]
+    InvalidRange(char, char),
+    #[error("pattern is too large")]
+    TooLarge,
+}
+
+/// A set of characters, possibly negated.
+#[derive(Clone, Debug, Default)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn single(char: char, icase: bool) -> Self {
+        let mut class = Self::default();
+        class.push(char, char, icase);
+        class
+    }
+
+    fn any() -> Self {
+        Self {
+            ranges: Vec::new(),
+            negated: true,
+        }
+    }
+
+    fn push(&mut self, from: char, to: char, icase: bool) {
+        self.ranges.push((from, to));
+        if icase {
+            // ASCII letters only; the other case is one bit away.
+            let swap = |char: char| (char as u8 ^ 0x20) as char;
+            for (lower, upper) in [('a', 'z'), ('A', 'Z')] {
+                let (low, high) = (from.max(lower), to.min(upper));
+                if low <= high {
+                    self.ranges.push((swap(low), swap(high)));
+                }
+            }
+        }
+    }
+
+    fn matches(&self, char: char) -> bool {
+        let found = self
+            .ranges
+            .iter()
+            .any(|&(from, to)| from <= char && char <= to);
+        found != self.negated
+    }
+}
+
+enum Node {
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+enum Inst {
+    Class(Class),
+    Split(usize, usize),
+    Jump(usize),
+    Start,
+    End,
+    Match,
+}
+
+/// A compiled glob or regular expression, run as a Thompson NFA so
+/// that matching stays linear in the length of the name.
+pub struct Pattern {
+    program: Vec<Inst>,
+}
+
+impl Pattern {
+    /// Shell glob: `*`, `?`, `[a-z]`, `[!a-z]`, `{a,b}` and `\` escapes,
+    /// matched against the whole name.
+    pub fn glob(pattern: &str) -> Result<Self, PatternError> {
+        if pattern.len() > MAX_PATTERN {
+            return Err(PatternError::TooLarge);
+        }
+        let mut chars = pattern.chars().peekable();
+        let node = parse_glob(&mut chars, false)?;
+        let node = Node::Concat(vec![Node::Start, node, Node::End]);
+        Self::compile(&node)
+    }
+
+    /// Regular expression with the usual operators, classes,
+    /// `\d\w\s` escapes, anchors and a leading `(?i)` flag,
+    /// matched anywhere in the name.
+    pub fn regex(pattern: &str) -> Result<Self, PatternError> {
+        if pattern.len() > MAX_PATTERN {
+            return Err(PatternError::TooLarge);
+        }
+        let (pattern, icase) = match pattern.strip_prefix("(?i)") {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let mut parser = RegexParser {
+            chars: pattern.chars().peekable(),
+            icase,
+        };
+        let node = parser.alternate()?;
+        if parser.chars.next().is_some() {
+            return Err(PatternError::UnmatchedParen);
+        }
+        Self::compile(&node)
+    }
+
+    fn compile(node: &Node) -> Result<Self, PatternError> {
+        let mut program = Vec::new();
+        emit(node, &mut program)?;
+        program.push(Inst::Match);
+        Ok(Self { program })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let size = self.program.len();
+        let mut current = Threads::new(size);
+        let mut next = Threads::new(size);
+        let mut chars = text.chars().peekable();
+        let mut at_start = true;
+
+        loop {
+            let at_end = chars.peek().is_none();
+            if self.add_thread(&mut current, 0, at_start, at_end) {
+                return true;
+            }
+
+            let Some(char) = chars.next() else {
+                return false;
+            };
+            let at_end = chars.peek().is_none();
+
+            for index in 0..current.len {
+                let pc = current.dense[index];
+                if let Inst::Class(class) = &self.program[pc] {
+                    if class.matches(char) && self.add_thread(&mut next, pc + 1, false, at_end) {
+                        return true;
+                    }
+                }
+            }
+
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+            at_start = false;
+        }
+    }
+
+    /// Follows the epsilon transitions from `pc`, returning whether
+    /// a match was reached.
+    fn add_thread(&self, threads: &mut Threads, pc: usize, at_start: bool, at_end: bool) -> bool {
+        let mut stack = vec![pc];
+
+        while let Some(pc) = stack.pop() {
+            if !threads.insert(pc) {
+                continue;
+            }
+            match self.program[pc] {
+                Inst::Class(_) => {}
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Jump(target) => stack.push(target),
+                Inst::Start if at_start => stack.push(pc + 1),
+                Inst::End if at_end => stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                Inst::Match => return true,
+            }
+        }
+
+        false
+    }
+}
+
+/// Sparse set of program counters.
+struct Threads {
+    dense: Vec<usize>,
+    sparse: Vec<usize>,
+    len: usize,
+}
+
+impl Threads {
+    fn new(size: usize) -> Self {
+        Self {
+            dense: vec![0; size],
+            sparse: vec![0; size],
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, pc: usize) -> bool {
+        let index = self.sparse[pc];
+        if index < self.len && self.dense[index] == pc {
+            return false;
+        }
+        self.sparse[pc] = self.len;
+        self.dense[self.len] = pc;
+        self.len += 1;
+        true
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+fn emit(node: &Node, program: &mut Vec<Inst>) -> Result<(), PatternError> {
+    if program.len() > MAX_PROGRAM {
+        return Err(PatternError::TooLarge);
+    }
+
+    match node {
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Concat(nodes) => {
+            for node in nodes {
+                emit(node, program)?;
+            }
+        }
+        Node::Alternate(nodes) => {
+            let mut jumps = Vec::new();
+            for (index, node) in nodes.iter().enumerate() {
+                if index + 1 < nodes.len() {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    emit(node, program)?;
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    let next = program.len();
+                    program[split] = Inst::Split(split + 1, next);
+                } else {
+                    emit(node, program)?;
+                }
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat(node, min, max) => {
+            for _ in 0..*min {
+                emit(node, program)?;
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    emit(node, program)?;
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(0, 0));
+                        emit(node, program)?;
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_glob(chars: &mut Peekable<Chars>, nested: bool) -> Result<Node, PatternError> {
+    let mut alternatives = Vec::new();
+    let mut sequence = Vec::new();
+
+    while let Some(char) = chars.next() {
+        let node = match char {
+            '*' => Node::Repeat(Box::new(Node::Class(Class::any())), 0, None),
+            '?' => Node::Class(Class::any()),
+            '[' => Node::Class(parse_class(chars, true, false)?),
+            '\\' => Node::Class(Class::single(
+                chars.next().ok_or(PatternError::UnexpectedEnd)?,
+                false,
+            )),
+            '{' => parse_glob(chars, true)?,
+            ',' if nested => {
+                alternatives.push(Node::Concat(std::mem::take(&mut sequence)));
+                continue;
+            }
+            '}' if nested => {
+                alternatives.push(Node::Concat(sequence));
+                return Ok(Node::Alternate(alternatives));
+            }
+            _ => Node::Class(Class::single(char, false)),
+        };
+        sequence.push(node);
+    }
+
+    if nested {
+        return Err(PatternError::UnmatchedBrace);
+    }
+    Ok(Node::Concat(sequence))
+}
+
+/// Parses a bracket expression after its opening `[`.
+fn parse_class(
+    chars: &mut Peekable<Chars>,
+    glob: bool,
+    icase: bool,
+) -> Result<Class, PatternError> {
+    let mut class = Class::default();
+
+    if chars
+        .next_if(|&char| char == '^' || (glob && char == '!'))
+        .is_some()
+    {
+        class.negated = true;
+    }
+
+    let mut first = true;
+    loop {
+        let char = chars.next().ok_or(PatternError::UnterminatedClass)?;
+        let from = match char {
+            ']' if !first => return Ok(class),
+            '\\' => {
+                let escaped = chars.next().ok_or(PatternError::UnterminatedClass)?;
+                if !glob {
+                    if let Some(shorthand) = shorthand_class(escaped) {
+                        class.ranges.extend(shorthand.ranges);
+                        first = false;
+                        continue;
+                    }
+                }
+                escaped
+            }
+            _ => char,
+        };
+        first = false;
+
+        if chars.peek() == Some(&'-') {
+            let mut lookahead = chars.clone();
+            lookahead.next();
+            match lookahead.peek() {
+                Some(']') | None => {}
+                Some(_) => {
+                    chars.next();
+                    let to = match chars.next().ok_or(PatternError::UnterminatedClass)? {
+                        '\\' => chars.next().ok_or(PatternError::UnterminatedClass)?,
+                        to => to,
+                    };
+                    if to < from {
+                        return Err(PatternError::InvalidRange(from, to));
+                    }
+                    class.push(from, to, icase);
+                    continue;
+                }
+            }
+        }
+
+        class.push(from, from, icase);
+    }
+}
+
+/// `\d`, `\w` and `\s`; the uppercase forms are negated.
+fn shorthand_class(char: char) -> Option<Class> {
+    let ranges = match char.to_ascii_lowercase() {
+        'd' => vec![('0', '9')],
+        'w' => vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        's' => vec![(' ', ' '), ('\t', '\r')],
+        _ => return None,
+    };
+    Some(Class {
+        ranges,
+        negated: char.is_ascii_uppercase(),
+    })
+}
+
+struct RegexParser<'a> {
+    chars: Peekable<Chars<'a>>,
+    icase: bool,
+}
+
+impl RegexParser<'_> {
+    fn alternate(&mut self) -> Result<Node, PatternError> {
+        let mut alternatives = vec![self.concat()?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            alternatives.push(self.concat()?);
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives.pop().unwrap(),
+            _ => Node::Alternate(alternatives),
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, PatternError> {
+        let mut sequence = Vec::new();
+        while let Some(&char) = self.chars.peek() {
+            if char == '|' || char == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            sequence.push(self.quantifiers(atom)?);
+        }
+        Ok(Node::Concat(sequence))
+    }
+
+    fn atom(&mut self) -> Result<Node, PatternError> {
+        let char = self.chars.next().ok_or(PatternError::UnexpectedEnd)?;
+        Ok(match char {
+            '(' => {
+                if self.chars.next_if_eq(&'?').is_some() && self.chars.next() != Some(':') {
+                    return Err(PatternError::UnmatchedParen);
+                }
+                let node = self.alternate()?;
+                if self.chars.next() != Some(')') {
+                    return Err(PatternError::UnmatchedParen);
+                }
+                node
+            }
+            ')' => return Err(PatternError::UnmatchedParen),
+            '[' => Node::Class(parse_class(&mut self.chars, false, self.icase)?),
+            '.' => Node::Class(Class {
+                ranges: vec![('\n', '\n')],
+                negated: true,
+            }),
+            '^' => Node::Start,
+            '$' => Node::End,
+            '*' | '+' | '?' => return Err(PatternError::NothingToRepeat(char)),
+            '\\' => {
+                let escaped = self.chars.next().ok_or(PatternError::UnexpectedEnd)?;
+                match shorthand_class(escaped) {
+                    Some(class) => Node::Class(class),
+                    None => Node::Class(Class::single(escaped, self.icase)),
+                }
+            }
+            _ => Node::Class(Class::single(char, self.icase)),
+        })
+    }
+
+    fn quantifiers(&mut self, mut node: Node) -> Result<Node, PatternError> {
+        loop {
+            let (min, max) = match self.chars.peek() {
+                Some('{') => match self.counted()? {
+                    Some(bounds) => bounds,
+                    None => return Ok(node),
+                },
+                Some('*') => self.single((0, None)),
+                Some('+') => self.single((1, None)),
+                Some('?') => self.single((0, Some(1))),
+                _ => return Ok(node),
+            };
+            // Laziness doesn't change whether a name matches.
+            self.chars.next_if_eq(&'?');
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    fn single(&mut self, bounds: (u32, Option<u32>)) -> (u32, Option<u32>) {
+        self.chars.next();
+        bounds
+    }
+
+    /// Consumes `{m}`, `{m,}` or `{m,n}`. A brace that doesn't start a count is left alone
+    /// and matched literally.
+    fn counted(&mut self) -> Result<Option<(u32, Option<u32>)>, PatternError> {
+        let mut lookahead = self.chars.clone();
+        lookahead.next();
+        let mut body = String::new();
+        loop {
+            match lookahead.next() {
+                Some('}') => break,
+                Some(char) if char.is_ascii_digit() || char == ',' => body.push(char),
+                _ => return Ok(None),
+            }
+        }
+
+        let number = |text: &str| {
+            text.parse::<u32>()
+                .map_err(|_| PatternError::InvalidRepetition)
+        };
+        let bounds = match body.split_once(',') {
+            None => {
+                let count = number(&body)?;
+                (count, Some(count))
+            }
+            Some((min, "")) => (number(min)?, None),
+            Some((min, max)) => (number(min)?, Some(number(max)?)),
+        };
+
+        if bounds.1.is_some_and(|max| max < bounds.0) || bounds.0 > MAX_PROGRAM as u32 {
+            return Err(PatternError::InvalidRepetition);
+        }
+
+        self.chars = lookahead;
+        Ok(Some(bounds))
+    }
+}
@@ -0,0 +1,143 @@
+use snowboard::Request;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RequestError {
+    #[error("Malformed percent-encoding: {0}")]
+    MalformedEncoding(String),
+    #[error("Request path is not valid UTF-8: {0}")]
+    InvalidUtf8(String),
+}
+
+/// The request target split into a decoded path and its query parameters.
+#[derive(Debug, Default)]
+pub struct RequestPath {
+    pub path: String,
+    query: Vec<(String, String)>,
+}
+
+impl RequestPath {
+    pub fn parse(target: &str) -> Result<Self, RequestError> {
+        let target = target.split_once('#').map_or(target, |(target, _)| target);
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let path = percent_decode(path, false)?;
+
+        let query = query
+            .split(['&', ';'])
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Ok((percent_decode(key, true)?, percent_decode(value, true)?))
+            })
+            .collect::<Result<_, RequestError>>()?;
+
+        Ok(Self { path, query })
+    }
+
+    /// Returns the first value given for `key`.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value given for `key`, in order.
+    pub fn params<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.query
+            .iter()
+            .filter(move |(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn percent_decode(text: &str, plus_as_space: bool) -> Result<String, RequestError> {
+    let malformed = || RequestError::MalformedEncoding(text.to_string());
+
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut iter = text.bytes();
+
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let high = iter.next().and_then(hex_value).ok_or_else(malformed)?;
+                let low = iter.next().and_then(hex_value).ok_or_else(malformed)?;
+                bytes.push(high << 4 | low);
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| RequestError::InvalidUtf8(text.to_string()))
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Looks up a request header by name, ignoring case.
+pub fn header<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Picks the offer weighted highest by an `Accept`-style header,
+/// preferring earlier offers on ties. Returns `None` if the client
+/// accepts none of them.
+pub fn negotiate<'a>(accept: &str, offers: &[&'a str]) -> Option<&'a str> {
+    let ranges = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let name = parts.next()?.trim();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!name.is_empty()).then_some((name, quality))
+        })
+        .collect::<Vec<_>>();
+
+    let quality_of = |offer: &str| {
+        ranges
+            .iter()
+            .filter_map(|&(name, quality)| {
+                let specificity = if name.eq_ignore_ascii_case(offer) {
+                    2
+                } else if name == "*" || name == "*/*" {
+                    0
+                } else {
+                    let prefix = name.strip_suffix('*')?;
+                    let matches = prefix.ends_with('/')
+                        && offer.len() > prefix.len()
+                        && offer[..prefix.len()].eq_ignore_ascii_case(prefix);
+                    matches.then_some(1)?
+                };
+                Some((specificity, quality))
+            })
+            .max_by_key(|&(specificity, _)| specificity)
+            .map_or(0.0, |(_, quality)| quality)
+    };
+
+    offers
+        .iter()
+        .map(|&offer| (offer, quality_of(offer)))
+        .filter(|&(_, quality)| quality > 0.0)
+        .fold(
+            None,
+            |best: Option<(&str, f32)>, (offer, quality)| match best {
+                Some((_, best_quality)) if best_quality >= quality => best,
+                _ => Some((offer, quality)),
+            },
+        )
+        .map(|(offer, _)| offer)
+}
@@ -0,0 +1,149 @@
+use std::fs::{self, FileType};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Follow symlinks whose target stays inside the root.
+    Inside,
+    /// Follow every symlink, wherever it points.
+    All,
+    /// Never follow symlinks.
+    Never,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "inside" => Ok(Self::Inside),
+            "all" => Ok(Self::All),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("Unknown symlink policy: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("Path not found: {0}")]
+    NotFound(String),
+    #[error("Path escapes the served root: {0}")]
+    Escape(String),
+    #[error("Symlink not permitted: {0}")]
+    Symlink(String),
+    #[error("Hidden path: {0}")]
+    Hidden(String),
+}
+
+/// The served base directory together with the rules deciding
+/// which paths below it may be exposed.
+pub struct Root {
+    base: PathBuf,
+    symlinks: SymlinkPolicy,
+    /// Whether names starting with a dot are kept out of sight.
+    hide_dotfiles: bool,
+}
+
+impl Root {
+    pub fn new(
+        directory: PathBuf,
+        symlinks: SymlinkPolicy,
+        hide_dotfiles: bool,
+    ) -> io::Result<Self> {
+        let base = directory.canonicalize()?;
+        Ok(Self {
+            base,
+            symlinks,
+            hide_dotfiles,
+        })
+    }
+
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    pub fn symlinks(&self) -> SymlinkPolicy {
+        self.symlinks
+    }
+
+    pub fn hide_dotfiles(&self) -> bool {
+        self.hide_dotfiles
+    }
+
+    /// Maps a request path onto the filesystem, refusing `..` segments
+    /// that climb above the root, symlinks the policy doesn't allow and
+    /// dotfiles when they're hidden.
+    pub fn resolve(&self, request_path: &str) -> Result<PathBuf, ResolveError> {
+        let mut segments = Vec::new();
+
+        for segment in request_path.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => {
+                    if segments.pop().is_none() {
+                        return Err(ResolveError::Escape(request_path.to_string()));
+                    }
+                }
+                _ if segment.contains('\0') => {
+                    return Err(ResolveError::Escape(request_path.to_string()));
+                }
+                _ => segments.push(segment),
+            }
+        }
+
+        let mut full_path = self.base.clone();
+        for segment in segments {
+            if self.hide_dotfiles && segment.starts_with('.') {
+                return Err(ResolveError::Hidden(request_path.to_string()));
+            }
+            full_path.push(segment);
+
+            let metadata = fs::symlink_metadata(&full_path)
+                .map_err(|_| ResolveError::NotFound(full_path.to_string_lossy().into_owned()))?;
+
+            if metadata.file_type().is_symlink() {
+                self.check_symlink(&full_path)?;
+            }
+        }
+
+        Ok(full_path)
+    }
+
+    /// Checks a directory entry against the symlink policy and, when
+    /// they're hidden, against dotfiles.
+    pub fn permits(&self, path: &Path, file_type: FileType) -> Result<(), ResolveError> {
+        let is_dotfile = path
+            .file_name()
+            .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."));
+        if self.hide_dotfiles && is_dotfile {
+            return Err(ResolveError::Hidden(path.to_string_lossy().into_owned()));
+        }
+        if file_type.is_symlink() {
+            self.check_symlink(path)?;
+        }
+        Ok(())
+    }
+
+    fn check_symlink(&self, path: &Path) -> Result<(), ResolveError> {
+        let display = || path.to_string_lossy().into_owned();
+
+        match self.symlinks {
+            SymlinkPolicy::All => Ok(()),
+            SymlinkPolicy::Never => Err(ResolveError::Symlink(display())),
+            SymlinkPolicy::Inside => {
+                let target = path
+                    .canonicalize()
+                    .map_err(|_| ResolveError::NotFound(display()))?;
+                if target.starts_with(&self.base) {
+                    Ok(())
+                } else {
+                    Err(ResolveError::Escape(display()))
+                }
+            }
+        }
+    }
+}
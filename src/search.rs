@@ -0,0 +1,186 @@
+use std::cmp::Reverse;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::explorer::ExplorerError;
+use crate::pattern::{Pattern, PatternError};
+use crate::{stream, walk, Config, ExplorerEntry, ListOptions};
+
+/// Matches kept while searching, as a multiple of the limit, before
+/// the worst ones are dropped.
+const SLACK: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SearchMode {
+    /// Case-insensitive substring of the name.
+    #[default]
+    Substring,
+    /// Shell glob over the whole name.
+    Glob,
+    /// Case-insensitive subsequence of the name.
+    Fuzzy,
+}
+
+impl FromStr for SearchMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "substring" => Ok(Self::Substring),
+            "glob" => Ok(Self::Glob),
+            "fuzzy" => Ok(Self::Fuzzy),
+            _ => Err(format!("Unknown search mode: {}", value)),
+        }
+    }
+}
+
+/// A compiled search query. Higher scores are better matches.
+pub enum Query {
+    Substring(String),
+    Glob(Pattern),
+    Fuzzy(Vec<char>),
+}
+
+impl Query {
+    pub fn new(query: &str, mode: SearchMode) -> Result<Self, PatternError> {
+        Ok(match mode {
+            SearchMode::Substring => Self::Substring(query.to_lowercase()),
+            SearchMode::Glob => Self::Glob(Pattern::glob(query)?),
+            SearchMode::Fuzzy => Self::Fuzzy(query.to_lowercase().chars().collect()),
+        })
+    }
+
+    /// Scores `name`, or `None` if it doesn't match.
+    pub fn score(&self, name: &str) -> Option<i64> {
+        match self {
+            Self::Substring(query) => {
+                let name = name.to_lowercase();
+                let start = name.find(query.as_str())?;
+                let rank = if name.len() == query.len() {
+                    3000
+                } else if start == 0 {
+                    2000
+                } else if name[..start].ends_with(is_separator) {
+                    1000
+                } else {
+                    0
+                };
+                Some(rank - name.len() as i64)
+            }
+            Self::Glob(pattern) => pattern.is_match(name).then_some(0),
+            Self::Fuzzy(query) => fuzzy_score(query, name),
+        }
+    }
+}
+
+/// Greedy subsequence match, rewarding runs of consecutive characters
+/// and matches at word starts, and penalizing gaps.
+fn fuzzy_score(query: &[char], name: &str) -> Option<i64> {
+    let mut score = 0;
+    let mut previous: Option<usize> = None;
+    let mut chars = name.chars().flat_map(char::to_lowercase).enumerate();
+    let mut last = None;
+
+    for &wanted in query {
+        let index = loop {
+            let (index, char) = chars.next()?;
+            if char == wanted {
+                break index;
+            }
+            last = Some(char);
+        };
+
+        score += 16;
+        match previous {
+            Some(previous) if previous + 1 == index => score += 15,
+            Some(previous) => score -= (index - previous - 1).min(8) as i64,
+            None => score -= index.min(8) as i64,
+        }
+        if index == 0 || (previous != Some(index - 1) && last.is_some_and(is_separator)) {
+            score += 10;
+        }
+
+        previous = Some(index);
+        last = Some(wanted);
+    }
+
+    Some(score)
+}
+
+fn is_separator(char: char) -> bool {
+    matches!(char, ' ' | '-' | '_' | '.' | '+' | '(' | '[')
+}
+
+/// Best matches of a search, with how many there were in total.
+pub struct Found {
+    pub entries: Vec<ExplorerEntry>,
+    pub total: usize,
+    /// Whether the whole tree was searched, within the time and
+    /// entry limits.
+    pub complete: bool,
+}
+
+/// Walks `base` for entries whose names match the query in
+/// `options.filter`, keeping the `limit` best ranked.
+pub fn search(
+    config: &Config,
+    base: &Path,
+    options: &ListOptions,
+    limit: usize,
+) -> Result<Found, ExplorerError> {
+    let Some(query) = &options.filter.query else {
+        return Ok(Found {
+            entries: Vec::new(),
+            total: 0,
+            complete: true,
+        });
+    };
+
+    let (sender, receiver) = mpsc::sync_channel(1024);
+
+    let (mut ranked, total, walked) = thread::scope(|scope| {
+        let walker =
+            scope.spawn(|| stream::pool().install(|| walk::stream(config, base, options, sender)));
+
+        let mut ranked = Vec::new();
+        let mut total = 0;
+        for entry in receiver {
+            let score = query.score(entry.name()).unwrap_or(i64::MIN);
+            ranked.push((score, entry));
+            total += 1;
+            if ranked.len() >= limit.saturating_mul(SLACK).max(1024) {
+                rank(&mut ranked, limit);
+            }
+        }
+
+        (ranked, total, walker.join())
+    });
+
+    let complete = match walked {
+        Ok(Ok(())) => true,
+        Ok(Err(ExplorerError::WalkTooLarge(_) | ExplorerError::TimedOut)) => false,
+        Ok(Err(err)) => return Err(err),
+        Err(panic) => std::panic::resume_unwind(panic),
+    };
+
+    rank(&mut ranked, limit);
+
+    Ok(Found {
+        entries: ranked.into_iter().map(|(_, entry)| entry).collect(),
+        total,
+        complete,
+    })
+}
+
+/// Sorts by score, then shallower and shorter paths first, keeping
+/// the best `limit`.
+fn rank(ranked: &mut Vec<(i64, ExplorerEntry)>, limit: usize) {
+    ranked.sort_by_cached_key(|(score, entry)| {
+        let path = entry.path();
+        let depth = path.matches('/').count();
+        (Reverse(*score), depth, path.len(), path.to_string())
+    });
+    ranked.truncate(limit);
+}
@@ -1,84 +1,912 @@
 use anyhow::Result;
+use httpdate::HttpDate;
 use rayon::prelude::ParallelSliceMut;
-use rayon::prelude::{ParallelBridge, ParallelIterator};
-use snowboard::{headers, response, Request, Server};
+use serde::Serialize;
+use snowboard::DEFAULT_HTTP_VERSION;
+use snowboard::{headers, response, Headers, HttpVersion, Method, Request, Response, Server};
 use spdlog::prelude::*;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::time::Instant;
+use std::borrow::Cow;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::explorer::ExplorerError;
-use crate::ExplorerEntry;
+use crate::archive::{Archive, ArchiveError, ArchiveFormat};
+use crate::bundle::BundleError;
+use crate::compress::{Encoding, Variants};
+use crate::config::{Config, Handoff};
+use crate::digest::{self, HashAlgorithm};
+use crate::explorer::{ExplorerEntry, ExplorerError};
+use crate::file::{self, Download};
+use crate::format::{self, Format};
+use crate::options::{Layout, ListOptions, OptionError};
+use crate::page::Page;
+use crate::request::{self, RequestPath};
+use crate::root::ResolveError;
+use crate::stream::Stream;
+use crate::{search, walk};
+
+/// A rendered listing page.
+#[derive(Clone)]
+pub struct Listing {
+    pub body: String,
+    pub format: Format,
+    pub page: Page,
+    /// Whether a search covered the whole tree, `None` for listings.
+    pub complete: Option<bool>,
+    /// Quoted hash of the body.
+    pub etag: String,
+    /// When the directory or any listed entry last changed.
+    pub modified: Option<SystemTime>,
+    /// The body compressed so far.
+    pub variants: Arc<Variants>,
+}
+
+/// A response, or the head of one whose body is streamed.
+pub enum Reply {
+    Full(Response),
+    Stream(Response, Box<Stream>),
+    File(Response, Box<Download>),
+    Archive(Response, Box<Archive>),
+}
 
 pub enum QueryResult {
-    Success(String),
+    Success(Listing),
     PathNotFound,
     NotDirectory,
+    Forbidden,
+    MalformedRequest,
+    PermissionDenied,
+    TooLarge,
+    Internal,
+    InvalidParameter(String),
+    /// None of the requested ranges lies within the file's length.
+    RangeNotSatisfiable(u64),
+    ArchiveTooLarge,
+    /// An archive on disk that can't be read as one.
+    Corrupt,
+}
+
+impl From<io::Error> for QueryResult {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::PathNotFound,
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+            _ => Self::Internal,
+        }
+    }
+}
+
+impl From<OptionError> for QueryResult {
+    fn from(err: OptionError) -> Self {
+        Self::InvalidParameter(err.to_string())
+    }
+}
+
+impl From<ExplorerError> for QueryResult {
+    fn from(err: ExplorerError) -> Self {
+        match err {
+            ExplorerError::Io(_, err) => err.into(),
+            ExplorerError::TooManyEntries(_) | ExplorerError::WalkTooLarge(_) => Self::TooLarge,
+            _ => Self::Internal,
+        }
+    }
+}
+
+impl From<ArchiveError> for QueryResult {
+    fn from(err: ArchiveError) -> Self {
+        match err {
+            ArchiveError::Io(_, err) => err.into(),
+            ArchiveError::TooManyFiles(..) | ArchiveError::TooLarge(..) => Self::ArchiveTooLarge,
+        }
+    }
+}
+
+impl From<BundleError> for QueryResult {
+    fn from(err: BundleError) -> Self {
+        match err {
+            BundleError::Io(_, err) => err.into(),
+            BundleError::Corrupt(..) => Self::Corrupt,
+            BundleError::TooManyMembers(..) => Self::TooLarge,
+            BundleError::Unsupported(..) => Self::Forbidden,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HashBody<'a> {
+    name: &'a str,
+    size: u64,
+    algorithm: &'a str,
+    hash: &'a str,
 }
 
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    path: &'a str,
+}
+
+/// Where searches are served, outside of the listed paths.
+const SEARCH_PATH: &str = "/_search";
+
+/// How long a streamed listing waits on a client that stopped reading.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Responder = fn(Vec<u8>, Option<Headers>, HttpVersion) -> Response;
+
 pub struct Service;
 
 impl Service {
-    pub fn new(address: SocketAddr, directory: PathBuf) -> Result<Self> {
+    pub fn new(address: SocketAddr, config: Config) -> Result<Self> {
         info!("Server started at {}", address);
-        Server::new(address)?.run_async(move |req: Request| {
-            let directory = directory.clone();
-            Box::pin(async move {
-                let full_path = directory.join(&req.url.to_string()[1..]);
-                match Self::query_directory(full_path.clone()).unwrap() {
-                    QueryResult::Success(data_text) => {
-                        let headers = headers! { "Content-Type" => "application/json" };
-                        response!(ok, data_text, headers)
+        let config = Arc::new(config);
+
+        if config.index.is_some() {
+            let config = config.clone();
+            thread::Builder::new()
+                .name("rindex-index".to_string())
+                .spawn(move || Self::refresh_index(&config))?;
+        }
+
+        for (mut stream, req) in Server::new(address)? {
+            let config = config.clone();
+            async_std::task::spawn(async move {
+                // Streams last as long as the client reads, so they get
+                // a thread of their own and a stalled client is dropped.
+                let sent = match Self::handle(&config, &req) {
+                    Reply::Full(mut response) => response.send_to(&mut stream),
+                    Reply::Stream(head, body) => {
+                        Self::send_blocking(stream, head, move |stream| body.send(&config, stream))
+                            .await
+                    }
+                    Reply::File(head, body) => {
+                        Self::send_blocking(stream, head, move |stream| body.send(stream)).await
+                    }
+                    Reply::Archive(head, body) => {
+                        Self::send_blocking(stream, head, move |stream| body.send(&config, stream))
+                            .await
+                    }
+                };
+                if let Err(err) = sent {
+                    debug!("Failed to send response: {}", err);
+                }
+            });
+        }
+
+        Ok(Self)
+    }
+
+    /// Sends `head`, then the body written by `send`, on a thread that
+    /// may block on the client.
+    async fn send_blocking(
+        mut stream: TcpStream,
+        mut head: Response,
+        send: impl FnOnce(&mut TcpStream) -> io::Result<()> + Send + 'static,
+    ) -> io::Result<()> {
+        async_std::task::spawn_blocking(move || {
+            stream.set_write_timeout(Some(STREAM_TIMEOUT))?;
+            head.send_to(&mut stream)?;
+            send(&mut stream)
+        })
+        .await
+    }
+
+    /// Brings the index up to date, while listings walk the
+    /// filesystem, and keeps it so for as long as the server runs.
+    fn refresh_index(config: &Config) {
+        let Some(index) = &config.index else {
+            return;
+        };
+        loop {
+            if let Err(err) = index.refresh(&config.root) {
+                warn!("{}", err);
+            }
+            thread::sleep(config.index_refresh);
+        }
+    }
+
+    /// Answers `req`, with the head alone for `HEAD`, giving the
+    /// length of the body `GET` gets.
+    pub fn handle(config: &Config, req: &Request) -> Reply {
+        match Self::answer(config, req) {
+            Reply::Full(mut response)
+                if req.method == Method::HEAD && !response.bytes.is_empty() =>
+            {
+                response.set_content_length(response.bytes.len());
+                response.bytes.clear();
+                Reply::Full(response)
+            }
+            reply => reply,
+        }
+    }
+
+    fn answer(config: &Config, req: &Request) -> Reply {
+        let request = match RequestPath::parse(&req.url) {
+            Ok(request) => request,
+            Err(err) => {
+                info!("{}", err);
+                return Self::reply(config, req, &req.url, QueryResult::MalformedRequest);
+            }
+        };
+
+        if request.path == SEARCH_PATH {
+            let result = match ListOptions::search(config, req, &request) {
+                Ok(options) => Self::query_search(config, &request.path, &options),
+                Err(err) => err.into(),
+            };
+            return Self::reply(config, req, &request.path, result);
+        }
+
+        let options = match ListOptions::new(config, req, &request) {
+            Ok(options) => options,
+            Err(err) => return Self::reply(config, req, &request.path, err.into()),
+        };
+
+        if let Some((full_path, inner)) = Self::find_bundle(config, &request.path) {
+            let reply = Self::bundle(config, req, &request.path, &full_path, inner, &options);
+            return reply.unwrap_or_else(|result| Self::reply(config, req, &request.path, result));
+        }
+
+        let result = match config.root.resolve(&request.path) {
+            Ok(full_path) => match fs::metadata(&full_path) {
+                Ok(metadata) if metadata.is_file() && config.serves_files() => {
+                    let reply = match (options.hash, &config.handoff) {
+                        (Some(algorithm), _) => {
+                            Self::hash(config, req, &request.path, &full_path, algorithm)
+                        }
+                        (None, Some(handoff)) => {
+                            Self::handoff(config, handoff, &request.path, &full_path)
+                        }
+                        (None, None) => {
+                            Self::file(config, req, &request.path, &full_path, &metadata)
+                        }
+                    };
+                    match reply {
+                        Ok(reply) => return reply,
+                        Err(result) => result,
                     }
-                    QueryResult::PathNotFound => {
-                        const MESSAGE: &str = "Path not found!";
-                        warn!("{} {}", MESSAGE, full_path.display());
-                        response!(not_found, MESSAGE)
+                }
+                Ok(metadata) if !metadata.is_dir() => QueryResult::NotDirectory,
+                Ok(_) if options.archive.is_some() => {
+                    match Self::archive(config, req, &request.path, full_path, &options) {
+                        Ok(reply) => return reply,
+                        Err(result) => result,
                     }
-                    QueryResult::NotDirectory => {
-                        const MESSAGE: &str = "Not a directory!";
-                        warn!("{} {}", MESSAGE, full_path.display());
-                        response!(bad_request, MESSAGE)
+                }
+                Ok(_) if options.format == Format::Ndjson => {
+                    match Self::stream(config, req, &request.path, full_path, options) {
+                        Ok(reply) => return reply,
+                        Err(result) => result,
                     }
                 }
-            })
+                Ok(_) => Self::cached_directory(config, &full_path, req, &request.path, &options),
+                Err(err) => err.into(),
+            },
+            Err(ResolveError::NotFound(_) | ResolveError::Hidden(_)) => QueryResult::PathNotFound,
+            Err(err) => {
+                info!("{}", err);
+                QueryResult::Forbidden
+            }
+        };
+
+        Self::reply(config, req, &request.path, result)
+    }
+
+    /// Splits a path continuing into an archive on disk into the path
+    /// of the archive and the path inside it, empty for its top.
+    fn find_bundle<'a>(config: &Config, path: &'a str) -> Option<(PathBuf, &'a str)> {
+        config.bundles.as_ref()?;
+        path.match_indices('/').find_map(|(at, _)| {
+            let (outer, inner) = (&path[..at], &path[at + 1..]);
+            ArchiveFormat::of(outer)?;
+            let full_path = config.root.resolve(outer).ok()?;
+            let is_file = fs::metadata(&full_path).is_ok_and(|metadata| metadata.is_file());
+            is_file.then_some((full_path, inner))
         })
     }
 
-    fn query_directory(full_path: PathBuf) -> Result<QueryResult> {
-        if !full_path.exists() {
-            return Ok(QueryResult::PathNotFound);
+    /// Lists a directory of an archive on disk, or sends one of its
+    /// files where files are served. The server in front can't reach
+    /// those, so they're sent by rindex even with a handoff.
+    fn bundle(
+        config: &Config,
+        req: &Request,
+        path: &str,
+        full_path: &Path,
+        inner: &str,
+        options: &ListOptions,
+    ) -> Result<Reply, QueryResult> {
+        let (Some(bundles), Some(format)) = (
+            &config.bundles,
+            ArchiveFormat::of(&full_path.to_string_lossy()),
+        ) else {
+            return Err(QueryResult::Internal);
+        };
+        if options.archive.is_some() || options.hash.is_some() {
+            info!("Archives and digests aren't made inside archives: {}", path);
+            return Err(QueryResult::Forbidden);
+        }
+
+        let metadata = fs::metadata(full_path)?;
+        let bundle = bundles
+            .get(full_path, format, &metadata, config.max_walk)
+            .map_err(|err| {
+                warn!("{}", err);
+                QueryResult::from(err)
+            })?;
+        let index = bundle.find(inner).ok_or(QueryResult::PathNotFound)?;
+
+        if bundle.is_file(index) {
+            if inner.ends_with('/') || !config.serves_files() {
+                return Err(QueryResult::NotDirectory);
+            }
+            let download = bundle.open(full_path, index).map_err(|err| {
+                info!("{}", err);
+                QueryResult::from(err)
+            })?;
+            return Self::download(config, req, path, download);
+        }
+
+        let start_time = Instant::now();
+        let entries = bundle.list(index, options, config.max_entries)?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .map(|mtime| newest(mtime, &entries));
+        let count = entries.len();
+        let result = Self::listing(path, entries, modified, options);
+        let elapsed = start_time.elapsed().as_micros() as f64 / 1000.0;
+
+        debug!(
+            "Response: {} items in {}/{} tooks {}ms",
+            count,
+            full_path.display(),
+            inner,
+            elapsed
+        );
+
+        Ok(Self::reply(config, req, path, result))
+    }
+
+    /// Sends the directory as an archive of what its recursive listing
+    /// shows, where files may be downloaded at all.
+    fn archive(
+        config: &Config,
+        req: &Request,
+        path: &str,
+        full_path: PathBuf,
+        options: &ListOptions,
+    ) -> Result<Reply, QueryResult> {
+        let Some(format) = options.archive else {
+            return Err(QueryResult::Internal);
+        };
+        if !config.serves_files() {
+            info!("Archives need files to be served: {}", path);
+            return Err(QueryResult::Forbidden);
+        }
+
+        let entries = walk::list(config, &full_path, options).map_err(|err| {
+            warn!("{}", err);
+            QueryResult::from(err)
+        })?;
+        let archive = Archive::new(config, full_path, format, entries).map_err(|err| {
+            warn!("{}", err);
+            QueryResult::from(err)
+        })?;
+
+        let mut head = archive.head();
+        if let Some(cache_control) = config.cache_control(path) {
+            head.set_header("Cache-Control", cache_control.to_string());
+        }
+        if req.method == Method::HEAD {
+            return Ok(Reply::Full(head));
+        }
+        Ok(Reply::Archive(head, Box::new(archive)))
+    }
+
+    /// Streams the listing as NDJSON, or only its head for `HEAD`
+    /// without walking the directory.
+    fn stream(
+        config: &Config,
+        req: &Request,
+        path: &str,
+        full_path: PathBuf,
+        options: ListOptions,
+    ) -> Result<Reply, QueryResult> {
+        let body = Stream::new(full_path, options).map_err(|err| {
+            info!("Failed to read {}: {}", path, err);
+            QueryResult::from(err)
+        })?;
+
+        let mut head = body.head();
+        if let Some(cache_control) = config.cache_control(path) {
+            head.set_header("Cache-Control", cache_control.to_string());
+        }
+        if req.method == Method::HEAD {
+            return Ok(Reply::Full(head));
+        }
+        Ok(Reply::Stream(head, Box::new(body)))
+    }
+
+    /// Sends a digest of a file where files may be downloaded, as JSON
+    /// or as a checksum line that `sha256sum -c` and the like can check.
+    fn hash(
+        config: &Config,
+        req: &Request,
+        path: &str,
+        full_path: &Path,
+        algorithm: HashAlgorithm,
+    ) -> Result<Reply, QueryResult> {
+        let metadata = fs::metadata(full_path)?;
+        let hash = digest::file(full_path, algorithm, config.hashes.as_ref()).map_err(|err| {
+            warn!("Failed to hash {}: {}", full_path.display(), err);
+            QueryResult::from(err)
+        })?;
+
+        let name = full_path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let accept = request::header(req, "Accept").unwrap_or("*/*");
+        let (content_type, body) =
+            match request::negotiate(accept, &["application/json", "text/plain"]) {
+                Some("text/plain") => ("text/plain", digest::line(&hash, &name)),
+                _ => {
+                    let body = HashBody {
+                        name: &name,
+                        size: metadata.len(),
+                        algorithm: algorithm.name(),
+                        hash: &hash,
+                    };
+                    let body = sonic_rs::to_string(&body).map_err(|err| {
+                        error!("{}", err);
+                        QueryResult::Internal
+                    })?;
+                    ("application/json", body)
+                }
+            };
+
+        let mut headers = headers! {
+            "Content-Type" => content_type,
+            "Vary" => "Accept",
+        };
+        if let Ok(modified) = metadata.modified() {
+            headers.insert("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+        if let Some(cache_control) = config.cache_control(path) {
+            headers.insert("Cache-Control", cache_control.to_string());
+        }
+        Ok(Reply::Full(response!(ok, body, headers)))
+    }
+
+    /// Points the web server in front at a file that passed the same
+    /// checks as a download: inside the root, allowed by the symlink
+    /// policy and readable.
+    fn handoff(
+        config: &Config,
+        handoff: &Handoff,
+        path: &str,
+        full_path: &Path,
+    ) -> Result<Reply, QueryResult> {
+        File::open(full_path)?;
+
+        let relative = full_path
+            .strip_prefix(config.root.base())
+            .map_err(|_| QueryResult::Forbidden)?;
+        let target = relative
+            .to_str()
+            .and_then(|relative| handoff.target(relative))
+            .ok_or(QueryResult::Forbidden)?;
+        debug!("{}: {} {}", path, handoff.header.name(), target);
+
+        let mut headers = headers! {
+            "Content-Type" => file::content_type(full_path),
+            handoff.header.name() => target,
+        };
+        if let Some(cache_control) = config.cache_control(path) {
+            headers.insert("Cache-Control", cache_control.to_string());
+        }
+        Ok(Reply::Full(response!(ok, Vec::new(), headers)))
+    }
+
+    /// Sends a file below the root.
+    fn file(
+        config: &Config,
+        req: &Request,
+        path: &str,
+        full_path: &Path,
+        metadata: &Metadata,
+    ) -> Result<Reply, QueryResult> {
+        let download = Download::open(full_path, metadata)?;
+        Self::download(config, req, path, download)
+    }
+
+    /// Sends a file or the ranges it was asked for, or only its head for
+    /// `HEAD` and current copies.
+    fn download(
+        config: &Config,
+        req: &Request,
+        path: &str,
+        mut download: Download,
+    ) -> Result<Reply, QueryResult> {
+        let cache_control = config.cache_control(path);
+
+        if Self::is_fresh(req, &download.etag(), download.modified()) {
+            let mut headers = download.head().headers.unwrap_or_default();
+            headers.remove("Content-Type");
+            headers.remove("Content-Length");
+            if let Some(cache_control) = cache_control {
+                headers.insert("Cache-Control", cache_control.to_string());
+            }
+            return Ok(Reply::Full(Response::not_modified(
+                Vec::new(),
+                Some(headers),
+                DEFAULT_HTTP_VERSION,
+            )));
+        }
+
+        // Ranges of another version of the file would be spliced into
+        // the client's copy, so a stale `If-Range` gets all of it. Nor
+        // are ranges cut from what has to be inflated from the start.
+        let range = request::header(req, "Range").filter(|_| {
+            req.method == Method::GET
+                && download.is_seekable()
+                && request::header(req, "If-Range").is_none_or(|known| download.matches(known))
+        });
+        match range.and_then(|range| file::ranges(range, download.len())) {
+            Some(ranges) if ranges.is_empty() => {
+                return Err(QueryResult::RangeNotSatisfiable(download.len()));
+            }
+            Some(ranges) => download.select(ranges),
+            None => {}
+        }
+
+        let mut head = download.head();
+        if let Some(cache_control) = cache_control {
+            head.set_header("Cache-Control", cache_control.to_string());
+        }
+        if req.method == Method::HEAD {
+            return Ok(Reply::Full(head));
+        }
+        Ok(Reply::File(head, Box::new(download)))
+    }
+
+    fn reply(config: &Config, req: &Request, path: &str, result: QueryResult) -> Reply {
+        let content_range = match result {
+            QueryResult::RangeNotSatisfiable(len) => Some(format!("bytes */{}", len)),
+            _ => None,
+        };
+        let (code, message, respond): (_, Cow<str>, Responder) = match result {
+            QueryResult::Success(listing) => {
+                let accept = request::header(req, "Accept-Encoding");
+                let encoding = config.compression.negotiate(accept, listing.body.len());
+                let etag = match encoding {
+                    Some(encoding) => variant_etag(&listing.etag, encoding),
+                    None => listing.etag.clone(),
+                };
+
+                let mut headers = headers! {
+                    "ETag" => &etag,
+                    "Vary" => "Accept-Encoding",
+                };
+                if let Some(modified) = listing.modified {
+                    headers.insert("Last-Modified", httpdate::fmt_http_date(modified));
+                }
+                if let Some(cache_control) = config.cache_control(path) {
+                    headers.insert("Cache-Control", cache_control.to_string());
+                }
+                if Self::is_fresh(req, &etag, listing.modified) {
+                    return Reply::Full(Response::not_modified(
+                        Vec::new(),
+                        Some(headers),
+                        DEFAULT_HTTP_VERSION,
+                    ));
+                }
+
+                headers.insert("Content-Type", listing.format.content_type().to_string());
+                headers.insert("X-Total-Count", listing.page.total.to_string());
+                if let Some(next) = listing.page.next {
+                    headers.insert("X-Next-Cursor", next);
+                }
+                if let Some(complete) = listing.complete {
+                    headers.insert("X-Search-Complete", complete.to_string());
+                }
+
+                let body = match encoding {
+                    Some(encoding) => {
+                        headers.insert("Content-Encoding", encoding.name().to_string());
+                        let compress = || {
+                            config
+                                .compression
+                                .compress(listing.body.as_bytes(), encoding)
+                        };
+                        listing
+                            .variants
+                            .get_or_compress(encoding, compress)
+                            .to_vec()
+                    }
+                    None => listing.body.into_bytes(),
+                };
+                return Reply::Full(response!(ok, body, headers));
+            }
+            QueryResult::PathNotFound => (
+                "path_not_found",
+                "Path not found!".into(),
+                Response::not_found,
+            ),
+            QueryResult::NotDirectory => (
+                "not_directory",
+                "Not a directory!".into(),
+                Response::bad_request,
+            ),
+            QueryResult::Forbidden => ("forbidden", "Forbidden!".into(), Response::forbidden),
+            QueryResult::MalformedRequest => (
+                "malformed_request",
+                "Malformed request!".into(),
+                Response::bad_request,
+            ),
+            QueryResult::PermissionDenied => (
+                "permission_denied",
+                "Permission denied!".into(),
+                Response::forbidden,
+            ),
+            QueryResult::TooLarge => (
+                "too_large",
+                "Too many entries!".into(),
+                Response::payload_too_large,
+            ),
+            QueryResult::Internal => (
+                "internal",
+                "Internal error!".into(),
+                Response::internal_server_error,
+            ),
+            QueryResult::InvalidParameter(message) => {
+                ("invalid_parameter", message.into(), Response::bad_request)
+            }
+            QueryResult::ArchiveTooLarge => (
+                "archive_too_large",
+                "Archive too large!".into(),
+                Response::payload_too_large,
+            ),
+            QueryResult::RangeNotSatisfiable(_) => (
+                "range_not_satisfiable",
+                "Range not satisfiable!".into(),
+                Response::range_not_satisfiable,
+            ),
+            QueryResult::Corrupt => (
+                "corrupt_archive",
+                "Archive is corrupt!".into(),
+                Response::unprocessable_entity,
+            ),
+        };
+
+        warn!("{} {}", message, path);
+
+        let accept = request::header(req, "Accept").unwrap_or("*/*");
+        let (content_type, data_text) =
+            match request::negotiate(accept, &["application/json", "text/plain"]) {
+                Some("text/plain") => ("text/plain", message.into_owned()),
+                _ => {
+                    let body = ErrorBody {
+                        code,
+                        message: &message,
+                        path,
+                    };
+                    let data_text = sonic_rs::to_string(&body).unwrap_or_default();
+                    ("application/json", data_text)
+                }
+            };
+
+        let mut headers = headers! { "Content-Type" => content_type };
+        if let Some(content_range) = content_range {
+            headers.insert("Content-Range", content_range);
+        }
+        Reply::Full(respond(
+            data_text.into(),
+            Some(headers),
+            DEFAULT_HTTP_VERSION,
+        ))
+    }
+
+    /// Whether the client's copy of a listing or file is current, going by
+    /// `If-None-Match` or, without it, `If-Modified-Since`.
+    fn is_fresh(req: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+        if let Some(etags) = request::header(req, "If-None-Match") {
+            return etags
+                .split(',')
+                .map(str::trim)
+                .any(|known| known == "*" || known.strip_prefix("W/").unwrap_or(known) == etag);
+        }
+
+        let since = request::header(req, "If-Modified-Since")
+            .and_then(|since| httpdate::parse_http_date(since).ok());
+        match (since, modified) {
+            // Dates are sent in whole seconds.
+            (Some(since), Some(modified)) => SystemTime::from(HttpDate::from(modified)) <= since,
+            _ => false,
+        }
+    }
+
+    /// Serves listings of single directories from the cache while the
+    /// directory is unchanged.
+    fn cached_directory(
+        config: &Config,
+        full_path: &Path,
+        req: &Request,
+        uri: &str,
+        options: &ListOptions,
+    ) -> QueryResult {
+        let cache = match &config.cache {
+            // Digests become known after the listing is cached.
+            Some(cache)
+                if options.depth == 1
+                    && options.layout == Layout::Flat
+                    && options.hash.is_none() =>
+            {
+                cache
+            }
+            _ => return Self::query_directory(config, full_path, uri, options),
+        };
+
+        // The negotiated format and the query decide the rendering.
+        let key = format!("{:?} {}", options.format, req.url);
+        if let Some(listing) = cache.get(full_path, &key) {
+            return QueryResult::Success(listing);
         }
-        if !full_path.is_dir() {
-            return Ok(QueryResult::NotDirectory);
+
+        let ticket = cache.watch(full_path);
+        let result = Self::query_directory(config, full_path, uri, options);
+        if let QueryResult::Success(listing) = &result {
+            cache.insert(ticket, key, listing);
         }
+        result
+    }
 
+    fn query_directory(
+        config: &Config,
+        full_path: &Path,
+        uri: &str,
+        options: &ListOptions,
+    ) -> QueryResult {
         let start_time = Instant::now();
 
-        let mut file_list = std::fs::read_dir(&full_path)?
-            .par_bridge()
-            .filter_map(|entry| match ExplorerEntry::new(&entry.unwrap()) {
-                Ok(explorer_entry) => Some(Ok(explorer_entry)),
-                Err(ExplorerError::MissingSymlinkTarget(ref err)) => {
-                    info!("{}", err);
-                    None
-                },
-                Err(err) => Some(Err(err))
-            })
-            .collect::<Result<Vec<ExplorerEntry>, _>>()?;
-
-        file_list.par_sort();
-
-        let data_text = sonic_rs::to_string(&file_list)?;
+        let file_list = match walk::list(config, full_path, options) {
+            Ok(file_list) => file_list,
+            Err(err) => {
+                warn!("{}", err);
+                return err.into();
+            }
+        };
+
+        let modified = fs::metadata(full_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .map(|mtime| newest(mtime, &file_list));
+
+        let count = file_list.len();
+        let result = Self::listing(uri, file_list, modified, options);
         let elapsed = start_time.elapsed().as_micros() as f64 / 1000.0;
 
         debug!(
             "Response: {} items in {} tooks {}ms",
-            file_list.len(),
+            count,
             full_path.display(),
             elapsed
         );
 
-        Ok(QueryResult::Success(data_text))
+        result
+    }
+
+    /// Sorts, pages and renders the entries of a listing.
+    fn listing(
+        uri: &str,
+        mut file_list: Vec<ExplorerEntry>,
+        modified: Option<SystemTime>,
+        options: &ListOptions,
+    ) -> QueryResult {
+        file_list.par_sort_by(|a, b| options.sort.compare(a, b));
+        let page = options.pagination.apply(&mut file_list, &options.sort);
+
+        let data_text = match format::render(uri, &file_list, &page, options) {
+            Ok(data_text) => data_text,
+            Err(err) => {
+                error!("{}", err);
+                return QueryResult::Internal;
+            }
+        };
+
+        QueryResult::Success(Listing {
+            etag: etag(&data_text),
+            body: data_text,
+            format: options.format,
+            page,
+            complete: None,
+            modified,
+            variants: Arc::default(),
+        })
+    }
+
+    /// Searches the whole root, answering with the best matches found
+    /// before the walk ends or gives up.
+    fn query_search(config: &Config, uri: &str, options: &ListOptions) -> QueryResult {
+        let start_time = Instant::now();
+        let pagination = &options.pagination;
+        let limit = pagination
+            .offset
+            .saturating_add(pagination.limit.unwrap_or(usize::MAX));
+
+        let found = match search::search(config, config.root.base(), options, limit) {
+            Ok(found) => found,
+            Err(err) => {
+                warn!("{}", err);
+                return err.into();
+            }
+        };
+
+        let mut entries = found.entries;
+        entries.drain(..pagination.offset.min(entries.len()));
+        let page = Page {
+            total: found.total,
+            next: None,
+        };
+
+        let data_text = match format::render(uri, &entries, &page, options) {
+            Ok(data_text) => data_text,
+            Err(err) => {
+                error!("{}", err);
+                return QueryResult::Internal;
+            }
+        };
+        let elapsed = start_time.elapsed().as_micros() as f64 / 1000.0;
+
+        debug!(
+            "Search: {} of {} matches in {}ms{}",
+            entries.len(),
+            found.total,
+            elapsed,
+            if found.complete { "" } else { ", incomplete" }
+        );
+
+        QueryResult::Success(Listing {
+            etag: etag(&data_text),
+            body: data_text,
+            format: options.format,
+            page,
+            complete: Some(found.complete),
+            modified: None,
+            variants: Arc::default(),
+        })
     }
 }
+
+/// The latest of `mtime` and the mtimes of `entries`, nested ones
+/// included.
+fn newest(mtime: SystemTime, entries: &[ExplorerEntry]) -> SystemTime {
+    entries.iter().fold(mtime, |mtime, entry| match entry {
+        ExplorerEntry::Directory {
+            children: Some(children),
+            ..
+        } => newest(mtime.max(entry.mtime()), children),
+        _ => mtime.max(entry.mtime()),
+    })
+}
+
+/// A strong ETag from the 64-bit FNV-1a hash of `body`.
+fn etag(body: &str) -> String {
+    let hash = body.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("\"{:016x}\"", hash)
+}
+
+/// The ETag of `etag`'s body sent in `encoding`, which differs in its
+/// bytes.
+fn variant_etag(etag: &str, encoding: Encoding) -> String {
+    let hash = etag.trim_end_matches('"');
+    format!("{}-{}\"", hash, encoding)
+}
@@ -0,0 +1,109 @@
+use std::cmp::Ordering;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{Collation, ExplorerEntry};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+    /// Directories before files, then by extension.
+    Type,
+    /// Directory order, as read.
+    None,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "name" | "N" => Ok(Self::Name),
+            "size" | "S" => Ok(Self::Size),
+            "mtime" | "M" => Ok(Self::Mtime),
+            "type" => Ok(Self::Type),
+            "none" => Ok(Self::None),
+            _ => Err(format!("Unknown sort key: {}", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "asc" | "A" => Ok(Self::Asc),
+            "desc" | "D" => Ok(Self::Desc),
+            _ => Err(format!("Unknown sort order: {}", value)),
+        }
+    }
+}
+
+/// How a listing is ordered. The default matches the `Ord`
+/// implementation of `ExplorerEntry`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub order: SortOrder,
+    /// Keep directories ahead of files whatever the key and order.
+    pub dirs_first: bool,
+    pub collation: Collation,
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Self {
+            key: SortKey::default(),
+            order: SortOrder::default(),
+            dirs_first: true,
+            collation: Collation::default(),
+        }
+    }
+}
+
+impl Sort {
+    pub fn compare(&self, a: &ExplorerEntry, b: &ExplorerEntry) -> Ordering {
+        if self.key == SortKey::None {
+            return Ordering::Equal;
+        }
+
+        let kind = |entry: &ExplorerEntry| entry.size().is_some();
+
+        if self.dirs_first && kind(a) != kind(b) {
+            return kind(a).cmp(&kind(b));
+        }
+
+        let ordering = match self.key {
+            SortKey::Name | SortKey::None => Ordering::Equal,
+            SortKey::Size => a.size().cmp(&b.size()),
+            SortKey::Mtime => a.mtime().cmp(&b.mtime()),
+            SortKey::Type => kind(a)
+                .cmp(&kind(b))
+                .then_with(|| extension(a.name()).cmp(extension(b.name()))),
+        }
+        .then_with(|| self.collation.compare(a.path(), b.path()));
+
+        match self.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+}
+
+fn extension(name: &str) -> &str {
+    Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+}
@@ -0,0 +1,412 @@
+use rayon::prelude::ParallelSliceMut;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use snowboard::{headers, Response, DEFAULT_HTTP_VERSION};
+use spdlog::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::OnceLock;
+use std::thread;
+
+use crate::explorer::ExplorerError;
+use crate::format::Format;
+use crate::page;
+use crate::sorting::SortKey;
+use crate::{walk, Config, ExplorerEntry, ListOptions, Sort};
+
+/// Entries in flight between the walk and the connection.
+const CHANNEL_LEN: usize = 4096;
+
+/// Bytes buffered before a chunk is written.
+const CHUNK_LEN: usize = 16 * 1024;
+
+/// Entries sorted in memory before they are spilled to disk as a run.
+const RUN_LEN: usize = 64 * 1024;
+
+/// Threads walking streamed listings and searches. They block while a
+/// slow client drains its listing, so they're kept apart from the
+/// global pool.
+static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+/// The pool for walks whose entries are sent over a channel.
+pub(crate) fn pool() -> &'static ThreadPool {
+    POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .thread_name(|index| format!("rindex-stream-{}", index))
+            .build()
+            .expect("Failed to start the stream pool")
+    })
+}
+
+/// Spill files made so far, to name the next one.
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// A newline-delimited JSON listing, sent with chunked transfer
+/// encoding while the directory is read.
+pub struct Stream {
+    full_path: PathBuf,
+    options: ListOptions,
+}
+
+impl Stream {
+    /// Fails if the directory can't be read, while the status can
+    /// still tell why.
+    pub fn new(full_path: PathBuf, options: ListOptions) -> io::Result<Self> {
+        fs::read_dir(&full_path)?;
+        Ok(Self { full_path, options })
+    }
+
+    /// Status line and headers, sent before the listing is read.
+    pub fn head(&self) -> Response {
+        let headers = headers! {
+            "Content-Type" => Format::Ndjson.content_type(),
+            "Transfer-Encoding" => "chunked",
+        };
+        Response::new(DEFAULT_HTTP_VERSION, 200, "OK", Vec::new(), Some(headers))
+    }
+
+    /// Walks the directory and writes the body to `writer`. Errors after
+    /// the head has gone out leave the body without its last chunk, so
+    /// clients can tell the listing is incomplete.
+    pub fn send<W: Write>(self, config: &Config, writer: &mut W) -> io::Result<()> {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_LEN);
+        let mut chunked = Chunked::new(writer);
+
+        let (written, walked) = thread::scope(|scope| {
+            let walker = scope.spawn(|| {
+                pool().install(|| walk::stream(config, &self.full_path, &self.options, sender))
+            });
+            // Dropping the receiver makes the walk give up early.
+            let written = match self.options.sort.key {
+                SortKey::None => self.unsorted(receiver, &mut chunked),
+                _ => self.sorted(receiver, &mut chunked),
+            };
+            (written, walker.join())
+        });
+
+        match walked {
+            Ok(Ok(())) | Ok(Err(ExplorerError::Abandoned)) => {}
+            Ok(Err(err)) => {
+                warn!("{}", err);
+                return Err(io::Error::other(err));
+            }
+            Err(_) => return Err(io::Error::other("Listing walk panicked")),
+        }
+
+        written?;
+        chunked.finish()
+    }
+
+    /// Writes entries in the order they're read, flushing whenever the
+    /// walk falls behind so the first ones go out at once.
+    fn unsorted<W: Write>(
+        &self,
+        receiver: Receiver<ExplorerEntry>,
+        chunked: &mut Chunked<W>,
+    ) -> io::Result<()> {
+        let mut window = Window::new(&self.options);
+
+        loop {
+            let entry = match receiver.try_recv() {
+                Ok(entry) => entry,
+                Err(TryRecvError::Empty) => {
+                    chunked.flush()?;
+                    match receiver.recv() {
+                        Ok(entry) => entry,
+                        Err(_) => return Ok(()),
+                    }
+                }
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            };
+
+            match window.admit(&entry) {
+                Admit::Skip => {}
+                Admit::Take => chunked.push(&entry)?,
+                Admit::Done => return Ok(()),
+            }
+        }
+    }
+
+    /// External merge sort: runs of `RUN_LEN` entries are sorted and
+    /// spilled to unlinked temporary files, then merged.
+    fn sorted<W: Write>(
+        &self,
+        receiver: Receiver<ExplorerEntry>,
+        chunked: &mut Chunked<W>,
+    ) -> io::Result<()> {
+        let sort = &self.options.sort;
+        let mut runs = Vec::new();
+        let mut buffer = Vec::with_capacity(RUN_LEN);
+
+        for entry in receiver {
+            buffer.push(entry);
+            if buffer.len() == RUN_LEN {
+                buffer.par_sort_by(|a, b| sort.compare(a, b));
+                runs.push(Run::spill(&buffer)?);
+                buffer.clear();
+            }
+        }
+
+        buffer.par_sort_by(|a, b| sort.compare(a, b));
+        runs.push(Run::Memory(buffer.into_iter()));
+
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (index, run) in runs.iter_mut().enumerate() {
+            if let Some(entry) = run.next()? {
+                heap.push(Head {
+                    entry,
+                    run: index,
+                    sort,
+                });
+            }
+        }
+
+        let mut window = Window::new(&self.options);
+        while let Some(Head { entry, run, .. }) = heap.pop() {
+            match window.admit(&entry) {
+                Admit::Skip => {}
+                Admit::Take => chunked.push(&entry)?,
+                Admit::Done => break,
+            }
+            if let Some(entry) = runs[run].next()? {
+                heap.push(Head { entry, run, sort });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Chunked transfer encoding over `writer`.
+struct Chunked<'a, W: Write> {
+    writer: &'a mut W,
+    buffer: Vec<u8>,
+}
+
+impl<'a, W: Write> Chunked<'a, W> {
+    fn new(writer: &'a mut W) -> Self {
+        Self {
+            writer,
+            buffer: Vec::with_capacity(CHUNK_LEN + 512),
+        }
+    }
+
+    fn push(&mut self, entry: &ExplorerEntry) -> io::Result<()> {
+        sonic_rs::to_writer(&mut self.buffer, entry).map_err(io::Error::other)?;
+        self.buffer.push(b'\n');
+        if self.buffer.len() >= CHUNK_LEN {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        write!(self.writer, "{:x}\r\n", self.buffer.len())?;
+        self.writer.write_all(&self.buffer)?;
+        self.writer.write_all(b"\r\n")?;
+        self.buffer.clear();
+        self.writer.flush()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        self.writer.write_all(b"0\r\n\r\n")?;
+        self.writer.flush()
+    }
+}
+
+enum Admit {
+    Skip,
+    Take,
+    Done,
+}
+
+/// Offset, limit and cursor applied to entries as they go out.
+struct Window<'a> {
+    options: &'a ListOptions,
+    skipped: usize,
+    taken: usize,
+}
+
+impl<'a> Window<'a> {
+    fn new(options: &'a ListOptions) -> Self {
+        Self {
+            options,
+            skipped: 0,
+            taken: 0,
+        }
+    }
+
+    fn admit(&mut self, entry: &ExplorerEntry) -> Admit {
+        let pagination = &self.options.pagination;
+
+        if let Some(cursor) = &pagination.cursor {
+            if self.options.sort.compare(entry, cursor).is_le() {
+                return Admit::Skip;
+            }
+        }
+        if self.skipped < pagination.offset {
+            self.skipped += 1;
+            return Admit::Skip;
+        }
+        if pagination.limit.is_some_and(|limit| self.taken >= limit) {
+            return Admit::Done;
+        }
+
+        self.taken += 1;
+        Admit::Take
+    }
+}
+
+/// A sorted run of entries.
+enum Run {
+    Memory(std::vec::IntoIter<ExplorerEntry>),
+    Disk(BufReader<File>),
+}
+
+impl Run {
+    fn spill(entries: &[ExplorerEntry]) -> io::Result<Self> {
+        let index = SPILLS.fetch_add(1, atomic::Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("rindex-run-{}-{}", std::process::id(), index));
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Unlinked right away, so the run goes with the file handle.
+        fs::remove_file(&path)?;
+
+        let mut writer = BufWriter::new(&mut file);
+        for entry in entries {
+            write_entry(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        file.rewind()?;
+        Ok(Self::Disk(BufReader::new(file)))
+    }
+
+    fn next(&mut self) -> io::Result<Option<ExplorerEntry>> {
+        match self {
+            Self::Memory(entries) => Ok(entries.next()),
+            Self::Disk(reader) => read_entry(reader),
+        }
+    }
+}
+
+/// The smallest entry of a run, ordered for a min-heap.
+struct Head<'a> {
+    entry: ExplorerEntry,
+    run: usize,
+    sort: &'a Sort,
+}
+
+impl Ord for Head<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sort
+            .compare(&other.entry, &self.entry)
+            .then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for Head<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Head<'_> {}
+
+/// Flags of a spilled entry.
+const SPILL_FILE: u8 = 1;
+const SPILL_PATH: u8 = 2;
+const SPILL_HASH: u8 = 4;
+
+fn write_entry<W: Write>(writer: &mut W, entry: &ExplorerEntry) -> io::Result<()> {
+    let (path, size, hash) = match entry {
+        ExplorerEntry::Directory { path, .. } => (path, None, &None),
+        ExplorerEntry::File {
+            path, size, hash, ..
+        } => (path, Some(*size), hash),
+    };
+    let flags = size.map_or(0, |_| SPILL_FILE)
+        | path.as_ref().map_or(0, |_| SPILL_PATH)
+        | hash.as_ref().map_or(0, |_| SPILL_HASH);
+
+    writer.write_all(&[flags])?;
+    writer.write_all(&size.unwrap_or(0).to_le_bytes())?;
+    writer.write_all(&page::to_nanos(entry.mtime()).to_le_bytes())?;
+    let texts = std::iter::once(entry.name())
+        .chain(path.as_deref())
+        .chain(hash.as_deref());
+    for text in texts {
+        writer.write_all(&(text.len() as u32).to_le_bytes())?;
+        writer.write_all(text.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> io::Result<Option<ExplorerEntry>> {
+    let mut flags = [0];
+    if reader.read(&mut flags)? == 0 {
+        return Ok(None);
+    }
+
+    let mut size = [0; 8];
+    let mut mtime = [0; 16];
+    reader.read_exact(&mut size)?;
+    reader.read_exact(&mut mtime)?;
+    let mtime = page::from_nanos(i128::from_le_bytes(mtime))
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+
+    let mut text = || -> io::Result<String> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(io::Error::other)
+    };
+
+    let name = text()?;
+    let path = match flags[0] & SPILL_PATH {
+        0 => None,
+        _ => Some(text()?),
+    };
+    let hash = match flags[0] & SPILL_HASH {
+        0 => None,
+        _ => Some(text()?),
+    };
+
+    Ok(Some(match flags[0] & SPILL_FILE {
+        0 => ExplorerEntry::Directory {
+            mtime,
+            name,
+            path,
+            children: None,
+            child_count: None,
+        },
+        _ => ExplorerEntry::File {
+            mtime,
+            name,
+            path,
+            size: u64::from_le_bytes(size),
+            hash,
+        },
+    }))
+}
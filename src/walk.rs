@@ -0,0 +1,409 @@
+use rayon::prelude::*;
+use spdlog::prelude::*;
+use std::fs::{self, DirEntry};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::{Config, EntryErrorPolicy};
+use crate::digest::Sums;
+use crate::explorer::ExplorerError;
+use crate::index::Tree;
+use crate::{ExplorerEntry, Filter, Layout, ListOptions};
+
+/// Device and inode of a directory, to tell when a symlink leads
+/// back into one of its ancestors.
+type Identity = (u64, u64);
+
+/// Lists `full_path`, descending `options.depth - 1` levels into its
+/// subdirectories. Entries of a flat recursive listing carry their
+/// path relative to `full_path`; in a tree listing directories carry
+/// their sorted entries instead. Recursive listings read the index
+/// instead of the filesystem when there is one.
+pub fn list(
+    config: &Config,
+    full_path: &Path,
+    options: &ListOptions,
+) -> Result<Vec<ExplorerEntry>, ExplorerError> {
+    Walk::new(config, full_path, options, None).run(full_path)
+}
+
+/// Like `list` for flat listings, but sends entries to `sender` as they
+/// are read instead of collecting them. Gives up once the receiver is gone.
+pub fn stream(
+    config: &Config,
+    full_path: &Path,
+    options: &ListOptions,
+    sender: SyncSender<ExplorerEntry>,
+) -> Result<(), ExplorerError> {
+    Walk::new(config, full_path, options, Some(sender)).run(full_path)?;
+    Ok(())
+}
+
+struct Walk<'a> {
+    config: &'a Config,
+    options: &'a ListOptions,
+    tree: bool,
+    recursive: bool,
+    /// Entries read so far by a recursive listing.
+    read: AtomicUsize,
+    sender: Option<SyncSender<ExplorerEntry>>,
+    /// Read instead of the filesystem by recursive listings of indexed
+    /// directories.
+    index: Option<Arc<Tree>>,
+}
+
+/// A directory entry as read, before its subdirectory is walked.
+struct Scanned {
+    entry: Option<ExplorerEntry>,
+    /// Set for directories the walk may enter.
+    subdir: Option<PathBuf>,
+    path: Option<String>,
+}
+
+impl<'a> Walk<'a> {
+    fn new(
+        config: &'a Config,
+        full_path: &Path,
+        options: &'a ListOptions,
+        sender: Option<SyncSender<ExplorerEntry>>,
+    ) -> Self {
+        let tree = options.layout == Layout::Tree;
+        let recursive = tree || options.depth > 1;
+        let index = match &config.index {
+            Some(index) if recursive => index.current(config.root.base(), full_path),
+            _ => None,
+        };
+
+        Self {
+            config,
+            options,
+            tree,
+            recursive,
+            read: AtomicUsize::new(0),
+            sender,
+            index,
+        }
+    }
+
+    fn run(&self, full_path: &Path) -> Result<Vec<ExplorerEntry>, ExplorerError> {
+        let metadata = fs::metadata(full_path).map_err(|err| io_error(full_path, err))?;
+        let ancestors = [(metadata.dev(), metadata.ino())];
+        let prefix = (!self.tree && self.options.depth > 1).then_some("");
+
+        self.directory(full_path, prefix, self.options.depth, &ancestors)
+    }
+
+    /// Lists `dir` and, while `depth` allows, its subdirectories in
+    /// parallel. `prefix` is the relative path of `dir` in a flat
+    /// recursive listing, ending with a slash unless empty.
+    fn directory(
+        &self,
+        dir: &Path,
+        prefix: Option<&str>,
+        depth: usize,
+        ancestors: &[Identity],
+    ) -> Result<Vec<ExplorerEntry>, ExplorerError> {
+        let count = AtomicUsize::new(0);
+        let descend = self.tree || depth > 1;
+        let sums = self
+            .options
+            .hash
+            .and_then(|algorithm| Sums::read(dir, algorithm, &self.config.root));
+
+        let scanned = match &self.index {
+            Some(index) => {
+                let base = self.config.root.base();
+                let entries = index
+                    .entries(base, dir)
+                    .ok_or_else(|| io_error(dir, io::ErrorKind::NotFound.into()))?;
+
+                entries
+                    .par_iter()
+                    .filter_map(|entry| {
+                        let subdir = dir.join(&entry.name);
+                        let walkable =
+                            descend && entry.is_dir() && index.entries(base, &subdir).is_some();
+                        self.scan(
+                            &entry.name,
+                            walkable.then_some(subdir),
+                            prefix,
+                            &count,
+                            |filter| Ok(self.hashed(dir, sums.as_ref(), entry.to_explorer(filter))),
+                        )
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            None => fs::read_dir(dir)
+                .map_err(|err| io_error(dir, err))?
+                .par_bridge()
+                .filter_map(|entry| {
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(err) => return self.failed(io_error(dir, err)).map(Err),
+                    };
+
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    let walkable = descend && self.is_walkable(&entry);
+                    self.scan(
+                        &name,
+                        walkable.then(|| entry.path()),
+                        prefix,
+                        &count,
+                        |filter| {
+                            ExplorerEntry::new(&entry, &self.config.root, filter)
+                                .map(|found| self.hashed(dir, sums.as_ref(), found))
+                        },
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+
+        if self.tree {
+            return scanned
+                .into_par_iter()
+                .filter_map(|scanned| Some((scanned.entry?, scanned.subdir)))
+                .map(|(entry, subdir)| match subdir {
+                    Some(subdir) if depth > 1 => {
+                        let mut children =
+                            self.subdirectory(&subdir, None, depth - 1, ancestors)?;
+                        children.par_sort_by(|a, b| self.options.sort.compare(a, b));
+                        Ok(entry.with_contents(Some(children), None))
+                    }
+                    Some(subdir) => Ok(entry.with_contents(None, self.count_children(&subdir)?)),
+                    None => Ok(entry),
+                })
+                .collect();
+        }
+
+        let mut entries = Vec::with_capacity(scanned.len());
+        let mut subdirs = Vec::new();
+        for scanned in scanned {
+            entries.extend(scanned.entry);
+            if let (Some(subdir), Some(path)) = (scanned.subdir, scanned.path) {
+                subdirs.push((subdir, format!("{}/", path)));
+            }
+        }
+
+        let nested = subdirs
+            .into_par_iter()
+            .map(|(subdir, prefix)| self.subdirectory(&subdir, Some(&prefix), depth - 1, ancestors))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.extend(nested.into_iter().flatten());
+
+        Ok(entries)
+    }
+
+    fn subdirectory(
+        &self,
+        dir: &Path,
+        prefix: Option<&str>,
+        depth: usize,
+        ancestors: &[Identity],
+    ) -> Result<Vec<ExplorerEntry>, ExplorerError> {
+        // The index was built without loops.
+        if self.index.is_some() {
+            return self.directory(dir, prefix, depth, ancestors);
+        }
+
+        let metadata = match fs::metadata(dir) {
+            Ok(metadata) => metadata,
+            Err(err) => return self.failed(io_error(dir, err)).map_or(Ok(Vec::new()), Err),
+        };
+
+        let identity = (metadata.dev(), metadata.ino());
+        if ancestors.contains(&identity) {
+            info!("Symlink loop at {}", dir.display());
+            return Ok(Vec::new());
+        }
+
+        let ancestors = [ancestors, &[identity]].concat();
+
+        match self.directory(dir, prefix, depth, &ancestors) {
+            Err(err @ ExplorerError::Io(..)) => self.failed(err).map_or(Ok(Vec::new()), Err),
+            result => result,
+        }
+    }
+
+    /// Counts the entries of a collapsed directory that aren't hidden,
+    /// before any filter. `None` if the directory can't be read.
+    fn count_children(&self, dir: &Path) -> Result<Option<usize>, ExplorerError> {
+        if let Some(index) = &self.index {
+            let count = index.entries(self.config.root.base(), dir).map(<[_]>::len);
+            self.read(count.unwrap_or(0))?;
+            return Ok(count);
+        }
+
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            return Ok(None);
+        };
+
+        let mut count = 0;
+        for entry in read_dir.flatten() {
+            self.read(1)?;
+            let permitted = entry
+                .file_type()
+                .is_ok_and(|file_type| self.config.root.permits(&entry.path(), file_type).is_ok());
+            count += usize::from(permitted);
+        }
+
+        Ok(Some(count))
+    }
+
+    /// Turns an entry of a directory into what the walk needs of it,
+    /// sending it on right away when streaming. `subdir` is set for
+    /// directories the walk will enter.
+    fn scan(
+        &self,
+        name: &str,
+        subdir: Option<PathBuf>,
+        prefix: Option<&str>,
+        count: &AtomicUsize,
+        stat: impl FnOnce(&Filter) -> Result<Option<ExplorerEntry>, ExplorerError>,
+    ) -> Option<Result<Scanned, ExplorerError>> {
+        if let Err(err) = self.read(1) {
+            return Some(Err(err));
+        }
+
+        let path = prefix.map(|prefix| format!("{}{}", prefix, name));
+
+        // Trees show every directory, as the parent of what passes
+        // the filter, and count the entries of collapsed ones.
+        let unfiltered = self.tree && subdir.is_some();
+        let explorer_entry = match self.entry(name, count, unfiltered, stat) {
+            Ok(explorer_entry) => explorer_entry,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let mut scanned = Scanned {
+            entry: match &path {
+                Some(path) => explorer_entry.map(|entry| entry.with_path(path.clone())),
+                None => explorer_entry,
+            },
+            subdir,
+            path,
+        };
+
+        if let Some(sender) = &self.sender {
+            if let Some(entry) = scanned.entry.take() {
+                if sender.send(entry).is_err() {
+                    return Some(Err(ExplorerError::Abandoned));
+                }
+            }
+        }
+
+        // Streamed entries are gone, only subdirectories are left.
+        (self.sender.is_none() || scanned.subdir.is_some()).then_some(Ok(scanned))
+    }
+
+    /// Stats the entry with `stat` if its name passes the filter, leaving
+    /// out hidden entries and, depending on the policy, unreadable ones.
+    /// `unfiltered` entries skip the filter altogether.
+    fn entry(
+        &self,
+        name: &str,
+        count: &AtomicUsize,
+        unfiltered: bool,
+        stat: impl FnOnce(&Filter) -> Result<Option<ExplorerEntry>, ExplorerError>,
+    ) -> Result<Option<ExplorerEntry>, ExplorerError> {
+        let filter = match unfiltered {
+            true => &Filter::default(),
+            false => &self.options.filter,
+        };
+
+        if !filter.accepts_name(name) {
+            return Ok(None);
+        }
+
+        match stat(filter) {
+            Ok(None) => Ok(None),
+            Ok(Some(explorer_entry)) => match self.config.max_entries {
+                Some(max) if count.fetch_add(1, Ordering::Relaxed) >= max => {
+                    Err(ExplorerError::TooManyEntries(max))
+                }
+                _ => Ok(Some(explorer_entry)),
+            },
+            Err(ExplorerError::MissingSymlinkTarget(ref err)) => {
+                info!("{}", err);
+                Ok(None)
+            }
+            Err(ExplorerError::Hidden(ref err)) => {
+                info!("{}", err);
+                Ok(None)
+            }
+            Err(err) => self.failed(err).map_or(Ok(None), Err),
+        }
+    }
+
+    /// Fills in the digest asked for of a file in `dir`, from the digests
+    /// computed so far or else from the directory's checksum file.
+    fn hashed(
+        &self,
+        dir: &Path,
+        sums: Option<&Sums>,
+        entry: Option<ExplorerEntry>,
+    ) -> Option<ExplorerEntry> {
+        let entry = entry?;
+        let Some(algorithm) = self.options.hash.filter(|_| entry.size().is_some()) else {
+            return Some(entry);
+        };
+
+        let cached = self.config.hashes.as_ref().and_then(|cache| {
+            let metadata = fs::metadata(dir.join(entry.name())).ok()?;
+            cache.get(&metadata, algorithm)
+        });
+        let hash = cached.or_else(|| Some(sums?.get(entry.name())?.to_string()));
+        Some(entry.with_hash(hash))
+    }
+
+    /// Whether the walk may descend into `entry`.
+    fn is_walkable(&self, entry: &DirEntry) -> bool {
+        let Ok(file_type) = entry.file_type() else {
+            return false;
+        };
+        let path = entry.path();
+
+        if self.config.root.permits(&path, file_type).is_err() {
+            return false;
+        }
+
+        file_type.is_dir()
+            || file_type.is_symlink() && fs::metadata(&path).is_ok_and(|metadata| metadata.is_dir())
+    }
+
+    /// Counts entries read by a recursive listing against its limit,
+    /// and checks the deadline.
+    fn read(&self, entries: usize) -> Result<(), ExplorerError> {
+        if let Some(deadline) = self.options.deadline {
+            if Instant::now() >= deadline {
+                return Err(ExplorerError::TimedOut);
+            }
+        }
+
+        let max = self.config.max_walk;
+        if self.recursive && self.read.fetch_add(entries, Ordering::Relaxed) >= max {
+            return Err(ExplorerError::WalkTooLarge(max));
+        }
+        Ok(())
+    }
+
+    /// Applies the entry error policy, returning the error if it
+    /// should fail the listing.
+    fn failed(&self, err: ExplorerError) -> Option<ExplorerError> {
+        match self.config.entry_errors {
+            EntryErrorPolicy::Skip => {
+                warn!("{}", err);
+                None
+            }
+            EntryErrorPolicy::Fail => Some(err),
+        }
+    }
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> ExplorerError {
+    ExplorerError::Io(path.to_string_lossy().into_owned(), err)
+}
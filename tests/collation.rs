@@ -0,0 +1,122 @@
+use rindex::Collation;
+
+fn sorted(collation: Collation, names: &[&str]) -> Vec<String> {
+    let mut names = names
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    names.sort_by(|a, b| collation.compare(a, b));
+    names
+}
+
+fn assert_order(collation: Collation, expected: &[&str]) {
+    let reversed = expected.iter().rev().copied().collect::<Vec<_>>();
+    assert_eq!(sorted(collation, &reversed), expected);
+}
+
+#[test]
+fn natural_orders_kernel_versions() {
+    assert_order(
+        Collation::Natural,
+        &["linux-6.9", "linux-6.9-rc1", "linux-6.9.1", "linux-6.10"],
+    );
+}
+
+#[test]
+fn natural_orders_numbers_by_value() {
+    assert_order(
+        Collation::Natural,
+        &["file1.txt", "file2.txt", "file10.txt", "v2", "v10"],
+    );
+}
+
+#[test]
+fn natural_breaks_leading_zero_ties_by_bytes() {
+    assert_order(Collation::Natural, &["a001", "a01", "a1"]);
+}
+
+#[test]
+fn natural_ignores_archive_suffixes() {
+    assert_order(
+        Collation::Natural,
+        &["pkg-1.2.tar.gz", "pkg-1.10.tar.gz", "pkg-1.10.zip"],
+    );
+}
+
+#[test]
+fn natural_puts_tilde_prereleases_first() {
+    assert_order(
+        Collation::Natural,
+        &[
+            "foo-1.0~rc1",
+            "foo-1.0",
+            "foo-1.0.tar.gz",
+            "foo-1.0a",
+            "x~",
+            "x",
+        ],
+    );
+}
+
+#[test]
+fn natural_puts_hidden_names_first() {
+    assert_order(
+        Collation::Natural,
+        &[".", "..", ".hidden", "1.0", "1.0.0", "Zebra", "apple"],
+    );
+}
+
+#[test]
+fn nocase_ignores_case() {
+    assert_order(Collation::Nocase, &["apple", "Banana", "cherry", "Zebra"]);
+    assert_order(Collation::Nocase, &["README", "Readme", "readme"]);
+}
+
+#[test]
+fn unicode_ignores_accents_first() {
+    assert_order(
+        Collation::Unicode,
+        &["eagle", "Eclair", "école", "Ezra", "Straße", "strasse2"],
+    );
+    assert_order(Collation::Unicode, &["resume", "Resume", "résumé"]);
+}
+
+#[test]
+fn nocase_folds_other_scripts() {
+    assert_order(Collation::Nocase, &["Арбуз", "банан", "Вишня"]);
+    assert_order(Collation::Nocase, &["ΣΟΦΟΣ", "σοφος", "σοφοσ", "Ωμέγα"]);
+}
+
+#[test]
+fn unicode_ignores_greek_and_cyrillic_accents() {
+    assert_order(
+        Collation::Unicode,
+        &["αλφα", "Αλφα", "άλφα", "Άλφα", "βήτα", "Γάμμα"],
+    );
+    assert_order(Collation::Unicode, &["ёж", "елка", "Ёлочка", "есть"]);
+}
+
+#[test]
+fn unicode_ignores_vietnamese_and_combining_accents() {
+    assert_order(
+        Collation::Unicode,
+        &["Đà Lạt", "Hà Nội", "Huê", "Huế", "hues"],
+    );
+    assert_order(
+        Collation::Unicode,
+        &["ecole", "e\u{301}cole", "école", "ÉCOLE", "ecoles"],
+    );
+}
+
+#[test]
+fn unicode_keeps_code_point_order_without_case_or_accents() {
+    assert_order(Collation::Unicode, &["a", "中文", "日本", "한국"]);
+}
+
+#[test]
+fn bytes_keeps_byte_order() {
+    assert_order(
+        Collation::Bytes,
+        &["Zebra", "apple", "linux-6.10", "linux-6.9"],
+    );
+}
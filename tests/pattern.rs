@@ -0,0 +1,87 @@
+use rindex::{Pattern, PatternError};
+
+fn glob(pattern: &str) -> Pattern {
+    Pattern::glob(pattern).unwrap()
+}
+
+fn regex(pattern: &str) -> Pattern {
+    Pattern::regex(pattern).unwrap()
+}
+
+#[test]
+fn globs_match_the_whole_name() {
+    let pattern = glob("*.iso");
+    assert!(pattern.is_match("debian.iso"));
+    assert!(pattern.is_match(".iso"));
+    assert!(!pattern.is_match("debian.iso.sig"));
+    assert!(!pattern.is_match("debian.ISO"));
+
+    assert!(glob("file?.txt").is_match("file1.txt"));
+    assert!(!glob("file?.txt").is_match("file10.txt"));
+    assert!(glob("[a-c]*").is_match("beta"));
+    assert!(!glob("[!a-c]*").is_match("beta"));
+    assert!(glob("*.{tar.gz,zip}").is_match("src.tar.gz"));
+    assert!(glob("*.{tar.gz,zip}").is_match("src.zip"));
+    assert!(!glob("*.{tar.gz,zip}").is_match("src.tar"));
+    assert!(glob("\\*").is_match("*"));
+    assert!(!glob("\\*").is_match("a"));
+    assert!(glob("*").is_match("ünïcødé"));
+    assert!(glob("?").is_match("é"));
+}
+
+#[test]
+fn regexes_match_anywhere_in_the_name() {
+    assert!(regex("iso").is_match("debian.iso.sig"));
+    assert!(!regex("iso$").is_match("debian.iso.sig"));
+    assert!(regex("^deb").is_match("debian.iso"));
+    assert!(!regex("^ian").is_match("debian.iso"));
+    assert!(regex("-\\d+\\.\\d+").is_match("linux-6.10.tar.xz"));
+    assert!(regex("^(foo|bar)[0-9]{2,3}$").is_match("bar123"));
+    assert!(!regex("^(foo|bar)[0-9]{2,3}$").is_match("bar1234"));
+    assert!(regex("(?i)readme").is_match("README.md"));
+    assert!(!regex("readme").is_match("README.md"));
+    // `*` is a repetition in a regex and a wildcard in a glob.
+    assert!(regex("a*").is_match("bbb"));
+    assert!(!glob("a*").is_match("bbb"));
+    assert!(regex("x.iso").is_match("x-iso"));
+    assert!(!glob("x.iso").is_match("x-iso"));
+}
+
+#[test]
+fn matching_stays_linear() {
+    let pattern = regex("^(a+)+$");
+    let name = format!("{}b", "a".repeat(4096));
+    assert!(!pattern.is_match(&name));
+    assert!(regex("(a|aa)*c").is_match(&format!("{}c", "a".repeat(4096))));
+}
+
+#[test]
+fn bad_patterns_are_refused() {
+    for (pattern, expected) in [
+        ("[a-", "unterminated character class"),
+        ("{a,b", "unmatched brace"),
+        ("[z-a]", "invalid character range z-a"),
+        ("\\", "unexpected end of pattern"),
+    ] {
+        let err = Pattern::glob(pattern).err().expect(pattern);
+        assert_eq!(err.to_string(), expected, "{}", pattern);
+    }
+    for (pattern, expected) in [
+        ("(abc", "unmatched parenthesis"),
+        ("abc)", "unmatched parenthesis"),
+        ("*a", "nothing to repeat before '*'"),
+        ("a{3,1}", "invalid repetition count"),
+        ("[abc", "unterminated character class"),
+    ] {
+        let err = Pattern::regex(pattern).err().expect(pattern);
+        assert_eq!(err.to_string(), expected, "{}", pattern);
+    }
+    assert!(matches!(
+        Pattern::regex("a{1000}{1000}"),
+        Err(PatternError::TooLarge)
+    ));
+    assert!(matches!(
+        Pattern::glob(&"a".repeat(2000)),
+        Err(PatternError::TooLarge)
+    ));
+}
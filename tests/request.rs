@@ -0,0 +1,74 @@
+use rindex::{RequestError, RequestPath};
+
+fn path(target: &str) -> String {
+    RequestPath::parse(target).unwrap().path
+}
+
+#[test]
+fn escapes_are_decoded() {
+    assert_eq!(path("/my%20files/"), "/my files/");
+    assert_eq!(path("/price-%E2%82%AC.txt"), "/price-€.txt");
+    assert_eq!(path("/%e2%82%ac"), "/€");
+}
+
+#[test]
+fn malformed_escapes_are_refused() {
+    for target in ["/%zz", "/a%4", "/a%", "/%4g/"] {
+        assert!(
+            matches!(
+                RequestPath::parse(target),
+                Err(RequestError::MalformedEncoding(_))
+            ),
+            "{}",
+            target
+        );
+    }
+    assert!(matches!(
+        RequestPath::parse("/?q=%4"),
+        Err(RequestError::MalformedEncoding(_))
+    ));
+}
+
+#[test]
+fn invalid_utf8_is_refused() {
+    for target in ["/%FF", "/%E2%82", "/?q=%C3"] {
+        assert!(
+            matches!(
+                RequestPath::parse(target),
+                Err(RequestError::InvalidUtf8(_))
+            ),
+            "{}",
+            target
+        );
+    }
+}
+
+#[test]
+fn plus_is_a_space_only_in_the_query() {
+    let request = RequestPath::parse("/c++/a+b?q=a+b&r=a%2Bb").unwrap();
+    assert_eq!(request.path, "/c++/a+b");
+    assert_eq!(request.param("q"), Some("a b"));
+    assert_eq!(request.param("r"), Some("a+b"));
+}
+
+#[test]
+fn query_and_fragment_are_stripped() {
+    let request = RequestPath::parse("/docs/?sort=size#top").unwrap();
+    assert_eq!(request.path, "/docs/");
+    assert_eq!(request.param("sort"), Some("size"));
+
+    assert_eq!(path("/docs/#top?sort=size"), "/docs/");
+    assert_eq!(path("/docs/%3F%23"), "/docs/?#");
+    assert!(RequestPath::parse("/docs/#top?sort=size")
+        .unwrap()
+        .param("sort")
+        .is_none());
+}
+
+#[test]
+fn repeated_parameters_keep_their_order() {
+    let request = RequestPath::parse("/?ext=iso;ext=img&flag&=x").unwrap();
+    assert_eq!(request.params("ext").collect::<Vec<_>>(), ["iso", "img"]);
+    assert_eq!(request.param("flag"), Some(""));
+    assert_eq!(request.param("missing"), None);
+}
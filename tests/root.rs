@@ -0,0 +1,183 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+
+use rindex::{ResolveError, Root, SymlinkPolicy};
+
+/// A root holding `sub/inside.txt`, next to an `outside` directory, with
+/// `in` linking to the file inside and `out` to the one outside.
+fn scratch_root(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rindex-root-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("root/sub")).unwrap();
+    fs::create_dir_all(dir.join("outside")).unwrap();
+    fs::write(dir.join("root/sub/inside.txt"), "inside").unwrap();
+    fs::write(dir.join("outside/secret.txt"), "secret").unwrap();
+    symlink(dir.join("root/sub/inside.txt"), dir.join("root/in")).unwrap();
+    symlink(dir.join("outside"), dir.join("root/out")).unwrap();
+    dir
+}
+
+fn root(dir: &std::path::Path, symlinks: SymlinkPolicy) -> Root {
+    Root::new(dir.join("root"), symlinks, false).unwrap()
+}
+
+#[test]
+fn traversal_above_the_root_is_refused() {
+    let dir = scratch_root("traversal");
+    let root = root(&dir, SymlinkPolicy::Inside);
+
+    for path in [
+        "/..",
+        "/../outside/secret.txt",
+        "/sub/../../outside",
+        "/sub/../..",
+    ] {
+        assert!(
+            matches!(root.resolve(path), Err(ResolveError::Escape(_))),
+            "{}",
+            path
+        );
+    }
+    // Climbing back down stays inside.
+    assert_eq!(
+        root.resolve("/sub/../sub/inside.txt").unwrap(),
+        root.base().join("sub/inside.txt")
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn nul_bytes_are_refused() {
+    let dir = scratch_root("nul");
+    let root = root(&dir, SymlinkPolicy::Inside);
+
+    assert!(matches!(
+        root.resolve("/sub/inside.txt\0.png"),
+        Err(ResolveError::Escape(_))
+    ));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn absolute_paths_stay_below_the_root() {
+    let dir = scratch_root("absolute");
+    let root = root(&dir, SymlinkPolicy::Inside);
+    let secret = dir.join("outside/secret.txt");
+
+    for path in [
+        secret.to_string_lossy().into_owned(),
+        format!("/{}", secret.display()),
+    ] {
+        assert!(
+            matches!(root.resolve(&path), Err(ResolveError::NotFound(_))),
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        root.resolve("//sub//inside.txt").unwrap(),
+        root.base().join("sub/inside.txt")
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn symlink_policy_applies_to_requested_paths() {
+    let dir = scratch_root("policy");
+
+    let inside = root(&dir, SymlinkPolicy::Inside);
+    assert!(inside.resolve("/in").is_ok());
+    assert!(matches!(
+        inside.resolve("/out/secret.txt"),
+        Err(ResolveError::Escape(_))
+    ));
+
+    let all = root(&dir, SymlinkPolicy::All);
+    assert!(all.resolve("/in").is_ok());
+    assert_eq!(
+        all.resolve("/out/secret.txt").unwrap(),
+        all.base().join("out/secret.txt")
+    );
+
+    let never = root(&dir, SymlinkPolicy::Never);
+    for path in ["/in", "/out/secret.txt"] {
+        assert!(
+            matches!(never.resolve(path), Err(ResolveError::Symlink(_))),
+            "{}",
+            path
+        );
+    }
+    assert!(never.resolve("/sub/inside.txt").is_ok());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn symlink_policy_applies_to_entries() {
+    let dir = scratch_root("entries");
+
+    for (symlinks, permitted) in [
+        (SymlinkPolicy::Inside, [true, false]),
+        (SymlinkPolicy::All, [true, true]),
+        (SymlinkPolicy::Never, [false, false]),
+    ] {
+        let root = root(&dir, symlinks);
+        for (name, permitted) in ["in", "out"].into_iter().zip(permitted) {
+            let path = root.base().join(name);
+            let file_type = fs::symlink_metadata(&path).unwrap().file_type();
+            assert_eq!(
+                root.permits(&path, file_type).is_ok(),
+                permitted,
+                "{:?} {}",
+                symlinks,
+                name
+            );
+        }
+
+        let path = root.base().join("sub");
+        let file_type = fs::symlink_metadata(&path).unwrap().file_type();
+        assert!(root.permits(&path, file_type).is_ok());
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dotfiles_are_hidden_on_request() {
+    let dir = scratch_root("dotfiles");
+    fs::create_dir_all(dir.join("root/.git")).unwrap();
+    fs::write(dir.join("root/.git/config"), "secret").unwrap();
+    fs::write(dir.join("root/sub/.env"), "secret").unwrap();
+
+    let shown = Root::new(dir.join("root"), SymlinkPolicy::Inside, false).unwrap();
+    let hidden = Root::new(dir.join("root"), SymlinkPolicy::Inside, true).unwrap();
+    for path in ["/.git/config", "/sub/.env", "/.git/../sub/.env"] {
+        assert!(shown.resolve(path).is_ok(), "{}", path);
+        assert!(
+            matches!(hidden.resolve(path), Err(ResolveError::Hidden(_))),
+            "{}",
+            path
+        );
+    }
+    // Dot segments are steps, not names.
+    assert!(hidden.resolve("/./sub/../sub/inside.txt").is_ok());
+    assert!(hidden.resolve("/.git/../sub/inside.txt").is_ok());
+
+    for (name, dotfile) in [(".git", true), ("sub/.env", true), ("sub", false)] {
+        let path = hidden.base().join(name);
+        let file_type = fs::symlink_metadata(&path).unwrap().file_type();
+        assert!(shown.permits(&path, file_type).is_ok(), "{}", name);
+        assert_eq!(
+            hidden.permits(&path, file_type).is_err(),
+            dotfile,
+            "{}",
+            name
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
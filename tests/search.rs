@@ -0,0 +1,59 @@
+use rindex::{Query, SearchMode};
+
+fn ranked<'a>(query: &str, mode: SearchMode, names: &[&'a str]) -> Vec<&'a str> {
+    let query = Query::new(query, mode).unwrap();
+    let mut scored = names
+        .iter()
+        .filter_map(|&name| Some((query.score(name)?, name)))
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    scored.into_iter().map(|(_, name)| name).collect()
+}
+
+#[test]
+fn substring_ranks_exact_then_prefix_then_word_matches() {
+    assert_eq!(
+        ranked(
+            "linux",
+            SearchMode::Substring,
+            &[
+                "archlinux.iso",
+                "kali-linux.iso",
+                "linux",
+                "Linux-6.10.tar.xz",
+                "linux.tar",
+                "windows.iso",
+            ],
+        ),
+        [
+            "linux",
+            "linux.tar",
+            "Linux-6.10.tar.xz",
+            "kali-linux.iso",
+            "archlinux.iso"
+        ]
+    );
+}
+
+#[test]
+fn fuzzy_prefers_runs_and_word_starts() {
+    assert_eq!(
+        ranked(
+            "dbi",
+            SearchMode::Fuzzy,
+            &["dxxbxxi", "xxdbi", "d-b-i", "Dbi.txt", "bid"],
+        ),
+        ["Dbi.txt", "d-b-i", "xxdbi", "dxxbxxi"]
+    );
+    let query = Query::new("abc", SearchMode::Fuzzy).unwrap();
+    assert!(query.score("cba").is_none());
+    assert!(query.score("a-b-c").is_some());
+}
+
+#[test]
+fn glob_matches_whole_names_only() {
+    let query = Query::new("*.iso", SearchMode::Glob).unwrap();
+    assert_eq!(query.score("debian.iso"), Some(0));
+    assert_eq!(query.score("debian.iso.sig"), None);
+    assert!(Query::new("[a-", SearchMode::Glob).is_err());
+}
@@ -0,0 +1,2010 @@
+use chrono::FixedOffset;
+use std::fs::{self, File, Permissions};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rindex::{
+    BundleCache, Collation, Compression, Config, EntryErrorPolicy, Format, Handoff, HashCache,
+    Index, ListingCache, Reply, Root, Service, SymlinkPolicy,
+};
+use snowboard::{Request, Response};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rindex-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        root: Root::new(dir.to_path_buf(), SymlinkPolicy::Inside, false).unwrap(),
+        entry_errors: EntryErrorPolicy::Skip,
+        max_entries: None,
+        max_depth: 16,
+        max_walk: 100_000,
+        search_timeout: Duration::from_secs(10),
+        index: None,
+        index_refresh: Duration::from_secs(300),
+        cache: None,
+        cache_control: Vec::new(),
+        compression: Compression::new(1024, 6, 5, 3).unwrap(),
+        serve_files: false,
+        handoff: None,
+        max_archive_size: 1 << 30,
+        max_archive_files: 10_000,
+        bundles: None,
+        hashes: None,
+        format: Format::Json,
+        exact_size: true,
+        localtime: false,
+        utc_offset: FixedOffset::east_opt(0).unwrap(),
+        collation: Collation::Bytes,
+    }
+}
+
+fn request(target: &str, headers: &[&str]) -> Request {
+    request_with("GET", target, headers)
+}
+
+fn request_with(method: &str, target: &str, headers: &[&str]) -> Request {
+    let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, target);
+    for header in headers {
+        raw.push_str(&format!("{}\r\n", header));
+    }
+    raw.push_str("\r\n");
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    Request::new(raw.as_bytes(), address).unwrap()
+}
+
+fn get(config: &Config, target: &str) -> Response {
+    get_with(config, target, &[])
+}
+
+/// Fetches `target` with `headers`, returning only the head of streamed
+/// replies.
+fn get_with(config: &Config, target: &str, headers: &[&str]) -> Response {
+    match Service::handle(config, &request(target, headers)) {
+        Reply::Full(response) => response,
+        Reply::Stream(head, _) | Reply::File(head, _) | Reply::Archive(head, _) => head,
+    }
+}
+
+/// Fetches a file with `headers`, returning its head and body.
+fn download(config: &Config, target: &str, headers: &[&str]) -> (Response, Vec<u8>) {
+    match Service::handle(config, &request(target, headers)) {
+        Reply::Full(response) => {
+            let body = response.bytes.clone();
+            (response, body)
+        }
+        Reply::File(head, body) => {
+            let mut sent = Vec::new();
+            body.send(&mut sent).unwrap();
+            (head, sent)
+        }
+        Reply::Stream(..) | Reply::Archive(..) => panic!("Not a file: {}", target),
+    }
+}
+
+/// Sets the mtime of a file or directory.
+fn touch(path: &Path, mtime: SystemTime) {
+    File::open(path).unwrap().set_modified(mtime).unwrap();
+}
+
+/// Fetches a streamed listing, returning its head and its body with
+/// the chunked encoding taken off.
+fn stream(config: &Config, target: &str) -> (Response, String) {
+    let Reply::Stream(head, body) = Service::handle(config, &request(target, &[])) else {
+        panic!("Not a stream: {}", target);
+    };
+    let mut sent = Vec::new();
+    body.send(config, &mut sent).unwrap();
+
+    let (mut rest, mut listing) = (&sent[..], Vec::new());
+    loop {
+        let end = rest.windows(2).position(|pair| pair == b"\r\n").unwrap();
+        let len = std::str::from_utf8(&rest[..end]).unwrap();
+        let len = usize::from_str_radix(len, 16).unwrap();
+        rest = &rest[end + 2..];
+        if len == 0 {
+            assert_eq!(rest, b"\r\n");
+            break;
+        }
+        listing.extend_from_slice(&rest[..len]);
+        assert_eq!(&rest[len..len + 2], b"\r\n");
+        rest = &rest[len + 2..];
+    }
+    (head, String::from_utf8(listing).unwrap())
+}
+
+fn body(response: &Response) -> String {
+    String::from_utf8(response.bytes.to_vec()).unwrap()
+}
+
+/// Names of the entries of a JSON listing, in order.
+fn names(listing: &str) -> Vec<&str> {
+    fields(listing, "name")
+}
+
+/// Relative paths of the entries of a recursive JSON listing, in order.
+fn paths(listing: &str) -> Vec<&str> {
+    fields(listing, "path")
+}
+
+fn fields<'a>(listing: &'a str, key: &str) -> Vec<&'a str> {
+    listing
+        .split(&format!("\"{}\":\"", key))
+        .skip(1)
+        .map(|rest| rest.split_once('"').unwrap().0)
+        .collect()
+}
+
+fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response.headers.as_ref()?.get(name).map(String::as_str)
+}
+
+#[test]
+fn unreadable_directory_is_answered() {
+    let dir = scratch_dir("unreadable");
+    let locked = dir.join("locked");
+    fs::create_dir(&locked).unwrap();
+    fs::write(dir.join("file.txt"), "x").unwrap();
+    fs::set_permissions(&locked, Permissions::from_mode(0o000)).unwrap();
+
+    let config = config(&dir);
+    // Privileged users read the directory regardless of its mode.
+    let expected = if fs::read_dir(&locked).is_ok() {
+        200
+    } else {
+        403
+    };
+    assert_eq!(get(&config, "/locked/").status, expected);
+    assert_eq!(get(&config, "/").status, 200);
+
+    fs::set_permissions(&locked, Permissions::from_mode(0o755)).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn oversized_listing_is_refused() {
+    let dir = scratch_dir("oversized");
+    for index in 0..8 {
+        fs::write(dir.join(index.to_string()), "x").unwrap();
+    }
+
+    let mut config = config(&dir);
+    config.max_entries = Some(4);
+    assert_eq!(get(&config, "/").status, 413);
+
+    config.max_entries = Some(8);
+    assert_eq!(get(&config, "/").status, 200);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn file_ranges_are_served() {
+    let dir = scratch_dir("ranges");
+    fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+    let mut config = config(&dir);
+    config.serve_files = true;
+
+    let (head, body) = download(&config, "/digits.txt", &[]);
+    assert_eq!(head.status, 200);
+    assert_eq!(header(&head, "Accept-Ranges"), Some("bytes"));
+    assert_eq!(body, b"0123456789");
+
+    for (range, content_range, expected) in [
+        ("bytes=2-4", "bytes 2-4/10", "234"),
+        ("bytes=7-", "bytes 7-9/10", "789"),
+        ("bytes=-3", "bytes 7-9/10", "789"),
+        ("bytes=8-99", "bytes 8-9/10", "89"),
+        ("bytes=0-3,2-5", "bytes 0-5/10", "012345"),
+    ] {
+        let (head, body) = download(&config, "/digits.txt", &[&format!("Range: {}", range)]);
+        assert_eq!(head.status, 206, "{}", range);
+        assert_eq!(header(&head, "Content-Range"), Some(content_range));
+        let len = expected.len().to_string();
+        assert_eq!(header(&head, "Content-Length"), Some(len.as_str()));
+        assert_eq!(body, expected.as_bytes());
+    }
+
+    let (head, body) = download(&config, "/digits.txt", &["Range: bytes=0-1,-2"]);
+    assert_eq!(head.status, 206);
+    let boundary = header(&head, "Content-Type")
+        .and_then(|content_type| content_type.strip_prefix("multipart/byteranges; boundary="))
+        .unwrap();
+    let part = |range: &str, data: &str| {
+        format!(
+            "\r\n--{}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Range: bytes {}/10\r\n\r\n{}",
+            boundary, range, data
+        )
+    };
+    let expected = format!(
+        "{}{}\r\n--{}--\r\n",
+        part("0-1", "01"),
+        part("8-9", "89"),
+        boundary
+    );
+    let len = expected.len().to_string();
+    assert_eq!(header(&head, "Content-Length"), Some(len.as_str()));
+    assert_eq!(String::from_utf8(body).unwrap(), expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn out_of_bounds_range_is_refused() {
+    let dir = scratch_dir("bounds");
+    fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+    let mut config = config(&dir);
+    config.serve_files = true;
+
+    for range in ["bytes=10-", "bytes=20-30", "bytes=-0", "bytes=12-14,10-"] {
+        let (head, _) = download(&config, "/digits.txt", &[&format!("Range: {}", range)]);
+        assert_eq!(head.status, 416, "{}", range);
+        assert_eq!(header(&head, "Content-Range"), Some("bytes */10"));
+    }
+
+    // Ranges that can't be read, or are of another version of the file,
+    // get all of it.
+    for headers in [
+        &["Range: bytes=5-2"][..],
+        &["Range: lines=0-1"],
+        &["Range: bytes=a-b"],
+        &["Range: bytes=0-1", "If-Range: \"stale\""],
+    ] {
+        let (head, body) = download(&config, "/digits.txt", headers);
+        assert_eq!(head.status, 200, "{:?}", headers);
+        assert_eq!(body, b"0123456789");
+    }
+
+    let (head, _) = download(&config, "/digits.txt", &[]);
+    let if_range = format!("If-Range: {}", header(&head, "ETag").unwrap());
+    let (head, body) = download(&config, "/digits.txt", &["Range: bytes=0-1", &if_range]);
+    assert_eq!(head.status, 206);
+    assert_eq!(body, b"01");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn archive_members_are_browsed() {
+    let dir = scratch_dir("bundles");
+    fs::create_dir_all(dir.join("src/nested")).unwrap();
+    fs::write(dir.join("src/nested/digits.txt"), "0123456789").unwrap();
+    let mut config = config(&dir);
+    config.serve_files = true;
+    config.bundles = Some(BundleCache::new(4));
+
+    for format in ["zip", "tar"] {
+        let Reply::Archive(_, body) =
+            Service::handle(&config, &request(&format!("/src/?archive={}", format), &[]))
+        else {
+            panic!("Not an archive: {}", format);
+        };
+        let mut archive = Vec::new();
+        body.send(&config, &mut archive).unwrap();
+        let name = format!("src.{}", format);
+        fs::write(dir.join(&name), archive).unwrap();
+
+        let listing = get(&config, &format!("/{}/src/nested/", name));
+        assert_eq!(listing.status, 200, "{}", name);
+        let listing = String::from_utf8(listing.bytes.to_vec()).unwrap();
+        assert!(
+            listing.contains("\"name\":\"digits.txt\",\"size\":10"),
+            "{}",
+            listing
+        );
+
+        let target = format!("/{}/src/nested/digits.txt", name);
+        let (head, body) = download(&config, &target, &["Range: bytes=2-4"]);
+        assert_eq!(head.status, 206, "{}", name);
+        assert_eq!(body, b"234");
+
+        assert_eq!(get(&config, &format!("/{}/src/missing/", name)).status, 404);
+        assert_eq!(get(&config, &format!("{}/", target)).status, 400);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn files_are_hashed_and_listed_with_digests() {
+    let dir = scratch_dir("hashes");
+    fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+    fs::write(dir.join("letters.txt"), "abc").unwrap();
+    fs::write(
+        dir.join("MD5SUMS"),
+        "900150983cd24fb0d6963f7d28e17f72 *letters.txt\n",
+    )
+    .unwrap();
+    let mut config = config(&dir);
+    config.serve_files = true;
+    config.hashes = Some(HashCache::new(16));
+
+    let sha256 = "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882";
+    let (head, body) = download(&config, "/digits.txt?hash=sha256", &["Accept: text/plain"]);
+    assert_eq!(head.status, 200);
+    assert_eq!(body, format!("{}  digits.txt\n", sha256).as_bytes());
+
+    let listing = get(&config, "/?hash=sha256");
+    let listing = String::from_utf8(listing.bytes.to_vec()).unwrap();
+    assert!(listing.contains(&format!("\"size\":10,\"hash\":\"{}\"", sha256)));
+
+    let listing = get(&config, "/?hash=md5&include=*.txt");
+    let listing = String::from_utf8(listing.bytes.to_vec()).unwrap();
+    assert!(listing.contains("\"size\":3,\"hash\":\"900150983cd24fb0d6963f7d28e17f72\""));
+    assert!(!listing.contains("\"size\":10,\"hash\""), "{}", listing);
+
+    assert_eq!(get(&config, "/digits.txt?hash=sha512").status, 400);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn escapes_from_the_root_are_refused() {
+    let dir = scratch_dir("escapes");
+    fs::create_dir(dir.join("sub")).unwrap();
+    let config = config(&dir);
+
+    for target in [
+        "/../",
+        "/%2e%2e/",
+        "/sub/%2E%2E/%2e%2e/",
+        "/sub/..%2f..%2f",
+        "/sub/%00/",
+    ] {
+        assert_eq!(get(&config, target).status, 403, "{}", target);
+    }
+    assert_eq!(get(&config, "/etc/").status, 404);
+    assert_eq!(get(&config, "/sub/%2e%2e/").status, 200);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn symlinks_are_listed_by_policy() {
+    let dir = scratch_dir("symlinks");
+    let outside = scratch_dir("symlinks-outside");
+    fs::write(outside.join("secret.txt"), "secret").unwrap();
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/inside.txt"), "inside").unwrap();
+    symlink(dir.join("sub/inside.txt"), dir.join("in")).unwrap();
+    symlink(&outside, dir.join("out")).unwrap();
+    symlink(&outside, dir.join("sub/away")).unwrap();
+
+    for (symlinks, listed, secret, collapsed) in [
+        (SymlinkPolicy::Inside, &["in", "sub"][..], 403, 1),
+        (SymlinkPolicy::All, &["in", "out", "sub"], 200, 2),
+        (SymlinkPolicy::Never, &["sub"], 403, 1),
+    ] {
+        let mut config = config(&dir);
+        config.root = Root::new(dir.clone(), symlinks, false).unwrap();
+        config.serve_files = true;
+
+        let listing = body(&get(&config, "/?sort=name"));
+        let names = ["in", "out", "sub"]
+            .into_iter()
+            .filter(|name| listing.contains(&format!("\"name\":\"{}\"", name)))
+            .collect::<Vec<_>>();
+        assert_eq!(names, listed, "{:?}", symlinks);
+
+        let recursive = body(&get(&config, "/?depth=3"));
+        assert_eq!(
+            recursive.contains("secret.txt"),
+            secret == 200,
+            "{:?} {}",
+            symlinks,
+            recursive
+        );
+        assert_eq!(get(&config, "/out/secret.txt").status, secret);
+
+        let tree = body(&get(&config, "/?layout=tree&depth=1"));
+        let count = format!("\"name\":\"sub\",\"child_count\":{}", collapsed);
+        assert!(tree.contains(&count), "{:?} {}", symlinks, tree);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&outside).unwrap();
+}
+
+#[test]
+fn errors_follow_accept() {
+    let dir = scratch_dir("errors");
+    fs::write(dir.join("file.txt"), "x").unwrap();
+    let config = config(&dir);
+
+    for accept in [
+        None,
+        Some("*/*"),
+        Some("application/json"),
+        Some("text/*;q=0.5, */*"),
+    ] {
+        let headers = accept.map(|accept| format!("Accept: {}", accept));
+        let headers = headers.iter().map(String::as_str).collect::<Vec<_>>();
+        let resp = get_with(&config, "/missing/", &headers);
+        assert_eq!(resp.status, 404);
+        assert_eq!(header(&resp, "Content-Type"), Some("application/json"));
+        assert_eq!(
+            body(&resp),
+            r#"{"code":"path_not_found","message":"Path not found!","path":"/missing/"}"#,
+            "{:?}",
+            accept
+        );
+    }
+
+    for accept in ["text/plain", "text/*", "application/json;q=0.1, text/plain"] {
+        let resp = get_with(&config, "/file.txt/", &[&format!("Accept: {}", accept)]);
+        assert_eq!(resp.status, 400);
+        assert_eq!(header(&resp, "Content-Type"), Some("text/plain"));
+        assert_eq!(body(&resp), "Not a directory!", "{}", accept);
+    }
+
+    let resp = get_with(&config, "/?sort=color", &["Accept: text/plain"]);
+    assert_eq!(resp.status, 400);
+    assert!(body(&resp).contains("color"));
+    let resp = get(&config, "/%zz/");
+    assert_eq!(resp.status, 400);
+    assert!(body(&resp).starts_with(r#"{"code":"malformed_request","#));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn listings_match_nginx_byte_for_byte() {
+    let dir = scratch_dir("nginx");
+    let (d49, d50, f60) = ("d".repeat(49), "d".repeat(50), "f".repeat(60));
+    for name in [&d49, &d50, "docs"] {
+        fs::create_dir(dir.join(name)).unwrap();
+    }
+    fs::write(dir.join("a&b <c>.txt"), "0123456789").unwrap();
+    fs::write(dir.join(&f60), [0; 2048]).unwrap();
+    fs::write(dir.join("é.txt"), "").unwrap();
+    // 14-Nov-2023 22:13:20 UTC
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    for name in [&d49, &d50, "docs", "a&b <c>.txt", &f60, "é.txt"] {
+        touch(&dir.join(name), mtime);
+    }
+    let config = config(&dir);
+
+    let html = get(&config, "/?format=html");
+    assert_eq!(header(&html, "Content-Type"), Some("text/html"));
+    let expected = [
+        "<html>\r\n<head><title>Index of /</title></head>\r\n<body>\r\n".to_string(),
+        "<h1>Index of /</h1><hr><pre><a href=\"../\">../</a>\r\n".to_string(),
+        format!("<a href=\"{d49}/\">{d49}/</a> 14-Nov-2023 22:13                   -\r\n"),
+        format!("<a href=\"{d50}/\">{d50}</a> 14-Nov-2023 22:13                   -\r\n"),
+        "<a href=\"docs/\">docs/</a>                                              \
+         14-Nov-2023 22:13                   -\r\n"
+            .to_string(),
+        "<a href=\"a%26b%20%3Cc%3E.txt\">a&amp;b &lt;c&gt;.txt</a>                                        \
+         14-Nov-2023 22:13                  10\r\n"
+            .to_string(),
+        format!(
+            "<a href=\"{f60}\">{}..&gt;</a> 14-Nov-2023 22:13                2048\r\n",
+            &f60[..47]
+        ),
+        "<a href=\"%C3%A9.txt\">é.txt</a>                                              \
+         14-Nov-2023 22:13                   0\r\n"
+            .to_string(),
+        "</pre><hr></body>\r\n</html>\r\n".to_string(),
+    ];
+    assert_eq!(body(&html), expected.concat());
+
+    let xml = get(&config, "/?format=xml");
+    assert_eq!(header(&xml, "Content-Type"), Some("text/xml"));
+    let mtime = "mtime=\"2023-11-14T22:13:20Z\"";
+    let expected = [
+        "<?xml version=\"1.0\"?>\r\n<list>\r\n".to_string(),
+        format!("<directory {mtime}>{d49}</directory>\r\n"),
+        format!("<directory {mtime}>{d50}</directory>\r\n"),
+        format!("<directory {mtime}>docs</directory>\r\n"),
+        format!("<file {mtime} size=\"10\">a&amp;b &lt;c&gt;.txt</file>\r\n"),
+        format!("<file {mtime} size=\"2048\">{f60}</file>\r\n"),
+        format!("<file {mtime} size=\"0\">é.txt</file>\r\n"),
+        "</list>\r\n".to_string(),
+    ];
+    assert_eq!(body(&xml), expected.concat());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn jsonp_wraps_listings_in_valid_callbacks() {
+    let dir = scratch_dir("jsonp");
+    fs::write(dir.join("file.txt"), "x").unwrap();
+    let config = config(&dir);
+
+    let json = body(&get(&config, "/?format=json"));
+    let jsonp = get(&config, "/?format=jsonp&callback=jQuery_1.done");
+    assert_eq!(jsonp.status, 200);
+    assert_eq!(
+        header(&jsonp, "Content-Type"),
+        Some("application/javascript")
+    );
+    assert_eq!(body(&jsonp), format!("jQuery_1.done({});", json));
+
+    // Without a callback, or with an empty one, nginx sends plain JSON.
+    for target in ["/?format=jsonp", "/?format=jsonp&callback="] {
+        let resp = get(&config, target);
+        assert_eq!(resp.status, 200, "{}", target);
+        assert_eq!(header(&resp, "Content-Type"), Some("application/json"));
+        assert_eq!(body(&resp), json, "{}", target);
+    }
+
+    for callback in ["alert(1)", "a%3Bb", "%3Cscript%3E"] {
+        let resp = get(&config, &format!("/?format=jsonp&callback={}", callback));
+        assert_eq!(resp.status, 400, "{}", callback);
+        assert!(body(&resp).contains("invalid_parameter"));
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn html_sizes_round_like_nginx() {
+    let dir = scratch_dir("human");
+    let sizes = [
+        (0, "      0"),
+        (9999, "   9999"),
+        (10000, "    10K"),
+        (10751, "    10K"),
+        (10752, "    11K"),
+        ((1 << 20) - 1, "  1024K"),
+        (1 << 20, "     1M"),
+        ((3 << 19) - 1, "     1M"),
+        (3 << 19, "     2M"),
+        ((3 << 30) - 1, "     3G"),
+        (80 << 30, "    80G"),
+    ];
+    for (size, _) in sizes {
+        File::create(dir.join(format!("s{:014}", size)))
+            .unwrap()
+            .set_len(size)
+            .unwrap();
+    }
+    fs::create_dir(dir.join("sub")).unwrap();
+    let config = config(&dir);
+
+    // Sizes take the last seven columns, after a space.
+    let columns = |html: &str, width: usize| {
+        html.lines()
+            .filter(|line| line.starts_with("<a href=\"s"))
+            .map(|line| {
+                let (rest, column) = line.split_at(line.len() - width);
+                assert!(rest.ends_with(' '), "{}", line);
+                column.to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let html = body(&get(&config, "/?format=html&exact_size=off&dirs_first=off"));
+    let expected = sizes.map(|(_, column)| column.to_string());
+    let mut expected = expected.to_vec();
+    expected.push("      -".to_string());
+    assert_eq!(columns(&html, 7), expected);
+
+    let html = body(&get(&config, "/?format=html&exact_size=on&dirs_first=off"));
+    let mut expected = sizes.map(|(size, _)| format!("{:>19}", size)).to_vec();
+    expected.push(format!("{:>19}", "-"));
+    assert_eq!(columns(&html, 19), expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn html_mtimes_follow_localtime() {
+    let dir = scratch_dir("localtime");
+    fs::write(dir.join("file.txt"), "x").unwrap();
+    touch(
+        &dir.join("file.txt"),
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+    );
+    let mut config = config(&dir);
+    config.utc_offset = FixedOffset::east_opt(8 * 3600).unwrap();
+
+    let utc = body(&get(&config, "/?format=html"));
+    assert!(utc.contains(" 14-Nov-2023 22:13 "));
+    let local = body(&get(&config, "/?format=html&localtime=on"));
+    assert!(local.contains(" 15-Nov-2023 06:13 "));
+
+    config.localtime = true;
+    assert_eq!(body(&get(&config, "/?format=html")), local);
+    assert_eq!(body(&get(&config, "/?format=html&localtime=off")), utc);
+    // XML and JSON dates are always UTC.
+    let xml = body(&get(&config, "/?format=xml"));
+    assert!(xml.contains("mtime=\"2023-11-14T22:13:20Z\""));
+    assert_eq!(get(&config, "/?localtime=maybe").status, 400);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn listings_are_sorted_on_request() {
+    let dir = scratch_dir("sorting");
+    fs::create_dir(dir.join("b")).unwrap();
+    fs::create_dir(dir.join("y")).unwrap();
+    for (name, size, secs) in [
+        ("a.txt", 30, 300),
+        ("c.iso", 10, 100),
+        ("x.img", 20, 400),
+        ("z.txt", 40, 200),
+    ] {
+        fs::write(dir.join(name), vec![0; size]).unwrap();
+        touch(&dir.join(name), UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    touch(&dir.join("b"), UNIX_EPOCH + Duration::from_secs(500));
+    touch(&dir.join("y"), UNIX_EPOCH + Duration::from_secs(50));
+    let config = config(&dir);
+
+    for (query, expected) in [
+        ("", &["b", "y", "a.txt", "c.iso", "x.img", "z.txt"][..]),
+        (
+            "sort=name&order=desc",
+            &["y", "b", "z.txt", "x.img", "c.iso", "a.txt"],
+        ),
+        ("sort=size", &["b", "y", "c.iso", "x.img", "a.txt", "z.txt"]),
+        (
+            "sort=mtime",
+            &["y", "b", "c.iso", "z.txt", "a.txt", "x.img"],
+        ),
+        (
+            "sort=mtime&dirs_first=off",
+            &["y", "c.iso", "z.txt", "a.txt", "x.img", "b"],
+        ),
+        (
+            "sort=mtime&order=desc&dirs_first=off",
+            &["b", "x.img", "a.txt", "z.txt", "c.iso", "y"],
+        ),
+        ("sort=type", &["b", "y", "x.img", "c.iso", "a.txt", "z.txt"]),
+        (
+            "sort=type&order=desc&dirs_first=off",
+            &["z.txt", "a.txt", "c.iso", "x.img", "y", "b"],
+        ),
+        (
+            "sort=name&dirs_first=off",
+            &["a.txt", "b", "c.iso", "x.img", "y", "z.txt"],
+        ),
+        // Apache's spelling.
+        ("C=S&O=D", &["y", "b", "z.txt", "a.txt", "x.img", "c.iso"]),
+        ("C=M;O=A", &["y", "b", "c.iso", "z.txt", "a.txt", "x.img"]),
+        (
+            "C=N&O=D&dirs_first=0",
+            &["z.txt", "y", "x.img", "c.iso", "b", "a.txt"],
+        ),
+    ] {
+        let listing = body(&get(&config, &format!("/?{}", query)));
+        assert_eq!(names(&listing), expected, "{}", query);
+    }
+
+    let unsorted = body(&get(&config, "/?sort=none"));
+    let mut unsorted = names(&unsorted);
+    unsorted.sort();
+    assert_eq!(unsorted, ["a.txt", "b", "c.iso", "x.img", "y", "z.txt"]);
+
+    // Apache's `C=D` is by description, which listings lack.
+    for query in [
+        "C=D",
+        "sort=D",
+        "sort=color",
+        "order=up",
+        "O=X",
+        "dirs_first=maybe",
+    ] {
+        let resp = get(&config, &format!("/?{}", query));
+        assert_eq!(resp.status, 400, "{}", query);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cursors_continue_where_the_last_page_stopped() {
+    let dir = scratch_dir("cursors");
+    for index in 0..10 {
+        fs::write(dir.join(format!("f{}", index)), vec![0; 10 - index]).unwrap();
+    }
+    let config = config(&dir);
+
+    for (query, expected) in [
+        (
+            "sort=name",
+            ["f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8", "f9"],
+        ),
+        (
+            "sort=size",
+            ["f9", "f8", "f7", "f6", "f5", "f4", "f3", "f2", "f1", "f0"],
+        ),
+    ] {
+        let (mut seen, mut pages) = (Vec::new(), 0);
+        let mut target = format!("/?{}&limit=3", query);
+        loop {
+            let resp = get(&config, &target);
+            assert_eq!(resp.status, 200);
+            assert_eq!(header(&resp, "X-Total-Count"), Some("10"));
+            let listing = body(&resp);
+            seen.extend(names(&listing).into_iter().map(str::to_string));
+            pages += 1;
+            match header(&resp, "X-Next-Cursor") {
+                Some(cursor) => target = format!("/?{}&limit=3&cursor={}", query, cursor),
+                None => break,
+            }
+        }
+        assert_eq!(seen, expected, "{}", query);
+        assert_eq!(pages, 4);
+    }
+
+    // Changes before the cursor don't shift the next page.
+    let first = get(&config, "/?sort=name&limit=3");
+    let cursor = header(&first, "X-Next-Cursor").unwrap().to_string();
+    fs::remove_file(dir.join("f0")).unwrap();
+    fs::write(dir.join("f00"), "x").unwrap();
+    let next = body(&get(
+        &config,
+        &format!("/?sort=name&limit=3&cursor={}", cursor),
+    ));
+    assert_eq!(names(&next), ["f3", "f4", "f5"]);
+    let skipped = body(&get(
+        &config,
+        &format!("/?sort=name&limit=3&offset=2&cursor={}", cursor),
+    ));
+    assert_eq!(names(&skipped), ["f5", "f6", "f7"]);
+
+    let envelope = body(&get(&config, "/?sort=name&limit=9&envelope=on"));
+    assert!(envelope.starts_with("{\"total\":10,\"next\":\""));
+    let last = body(&get(&config, "/?sort=name&offset=9&envelope=on"));
+    assert!(last.starts_with("{\"total\":10,\"next\":null,"));
+
+    // Cursors only hold under the sort they were made for.
+    for query in [
+        format!("sort=size&cursor={}", cursor),
+        format!("sort=name&order=desc&cursor={}", cursor),
+        format!("sort=none&cursor={}", cursor),
+        "sort=name&cursor=not*base64".to_string(),
+        "sort=name&cursor=Zm9v".to_string(),
+    ] {
+        assert_eq!(
+            get(&config, &format!("/?{}", query)).status,
+            400,
+            "{}",
+            query
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn listings_are_filtered_by_name() {
+    let dir = scratch_dir("patterns");
+    for name in ["a.iso", "a.iso.sig", "b.img", "README", "readme.txt"] {
+        fs::write(dir.join(name), "x").unwrap();
+    }
+    fs::create_dir(dir.join("iso")).unwrap();
+    let config = config(&dir);
+
+    for (query, expected) in [
+        ("include=*.iso", &["a.iso"][..]),
+        ("include=*.iso&include=*.img", &["a.iso", "b.img"]),
+        ("include=iso", &["iso"]),
+        ("include_regex=iso", &["iso", "a.iso", "a.iso.sig"]),
+        ("include_regex=%5Ea%5C.", &["a.iso", "a.iso.sig"]),
+        ("include_regex=(%3Fi)%5Eread", &["README", "readme.txt"]),
+        (
+            "exclude=*.sig&exclude=iso",
+            &["README", "a.iso", "b.img", "readme.txt"],
+        ),
+        ("include=*.iso*&exclude_regex=sig%24", &["a.iso"]),
+        ("include=%7Ba%2Cb%7D.*", &["a.iso", "a.iso.sig", "b.img"]),
+    ] {
+        let listing = body(&get(&config, &format!("/?{}", query)));
+        assert_eq!(names(&listing), expected, "{}", query);
+    }
+
+    for query in [
+        "include=%5Ba-",
+        "exclude=%7Ba",
+        "include_regex=(iso",
+        "exclude_regex=*iso",
+    ] {
+        let resp = get(&config, &format!("/?{}", query));
+        assert_eq!(resp.status, 400, "{}", query);
+        assert!(body(&resp).contains("Invalid pattern"), "{}", query);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn listings_are_filtered_by_type_size_and_mtime() {
+    let dir = scratch_dir("bounds");
+    // 14-Nov-2023 22:13:20 UTC, then hourly.
+    let hour = |hours: u64| UNIX_EPOCH + Duration::from_secs(1_700_000_000 + hours * 3600);
+    for (name, size, hours) in [
+        ("small.txt", 100, 0),
+        ("mid.bin", 2048, 1),
+        ("big.bin", 2 << 20, 2),
+    ] {
+        fs::write(dir.join(name), vec![0; size]).unwrap();
+        touch(&dir.join(name), hour(hours));
+    }
+    symlink(dir.join("big.bin"), dir.join("link")).unwrap();
+    fs::create_dir(dir.join("sub")).unwrap();
+    touch(&dir.join("sub"), hour(3));
+    let config = config(&dir);
+
+    for (query, expected) in [
+        (
+            "type=file",
+            &["big.bin", "link", "mid.bin", "small.txt"][..],
+        ),
+        ("type=dir", &["sub"]),
+        ("type=symlink", &["link"]),
+        ("min_size=2K", &["big.bin", "link", "mid.bin"]),
+        ("max_size=2KiB", &["mid.bin", "small.txt"]),
+        ("min_size=101&max_size=1M", &["mid.bin"]),
+        ("min_size=2m&max_size=2MB", &["big.bin", "link"]),
+        ("max_size=0", &[]),
+        (
+            "modified_since=2023-11-14T23:13:20Z",
+            &["sub", "big.bin", "link", "mid.bin"],
+        ),
+        (
+            "modified_before=Tue,%2014%20Nov%202023%2023:13:20%20GMT",
+            &["small.txt"],
+        ),
+        (
+            "modified_since=2023-11-14T23:13:20%2B00:00&modified_before=2023-11-15T00:13:20Z",
+            &["mid.bin"],
+        ),
+        (
+            "modified_since=2023-11-15T07:13:20%2B08:00&type=file",
+            &["big.bin", "link", "mid.bin"],
+        ),
+    ] {
+        let listing = body(&get(&config, &format!("/?{}", query)));
+        assert_eq!(names(&listing), expected, "{}", query);
+    }
+
+    for query in [
+        "type=socket",
+        "min_size=1Q",
+        "min_size=-1",
+        "max_size=99999999999T",
+        "modified_since=yesterday",
+        "modified_before=2023-13-01T00:00:00Z",
+    ] {
+        let resp = get(&config, &format!("/?{}", query));
+        assert_eq!(resp.status, 400, "{}", query);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recursive_listings_stop_at_the_depth_and_at_loops() {
+    let dir = scratch_dir("recursive");
+    fs::create_dir_all(dir.join("a/sub/deeper")).unwrap();
+    fs::write(dir.join("a/sub/deep.txt"), "x").unwrap();
+    fs::write(dir.join("a/sub/deeper/deepest.txt"), "x").unwrap();
+    fs::write(dir.join("a/x.txt"), "x").unwrap();
+    fs::write(dir.join("top.txt"), "x").unwrap();
+    symlink(&dir, dir.join("a/loop")).unwrap();
+    let mut config = config(&dir);
+    config.max_depth = 3;
+
+    for (depth, expected) in [
+        (1, &["a", "top.txt"][..]),
+        (2, &["a", "a/loop", "a/sub", "a/x.txt", "top.txt"]),
+        (
+            3,
+            &[
+                "a",
+                "a/loop",
+                "a/sub",
+                "a/sub/deeper",
+                "a/sub/deep.txt",
+                "a/x.txt",
+                "top.txt",
+            ],
+        ),
+    ] {
+        let listing = body(&get(&config, &format!("/?depth={}", depth)));
+        let listed = match depth {
+            1 => names(&listing),
+            _ => paths(&listing),
+        };
+        assert_eq!(listed, expected, "{}", depth);
+    }
+
+    // The link back to the root is listed but not entered.
+    config.max_depth = 16;
+    let listing = body(&get(&config, "/?depth=16&type=file"));
+    assert_eq!(
+        paths(&listing),
+        [
+            "a/sub/deep.txt",
+            "a/sub/deeper/deepest.txt",
+            "a/x.txt",
+            "top.txt"
+        ]
+    );
+
+    for depth in ["0", "17", "-1", "deep"] {
+        let resp = get(&config, &format!("/?depth={}", depth));
+        assert_eq!(resp.status, 400, "{}", depth);
+    }
+
+    config.max_walk = 4;
+    assert_eq!(get(&config, "/?depth=16").status, 413);
+    assert_eq!(get(&config, "/a/?depth=1").status, 200);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tree_listings_nest_json_only() {
+    let dir = scratch_dir("tree");
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    fs::write(dir.join("a/b/c.txt"), "x").unwrap();
+    fs::write(dir.join("a/a.txt"), "x").unwrap();
+    fs::write(dir.join("top.txt"), "x").unwrap();
+    symlink(&dir, dir.join("loop")).unwrap();
+    let config = config(&dir);
+
+    // Without mtimes, which change as the test runs.
+    let tree = |query: &str| {
+        let listing = body(&get(&config, &format!("/?layout=tree&{}", query)));
+        let mut parts = listing.split("\"mtime\":\"");
+        let mut stripped = parts.next().unwrap().to_string();
+        for part in parts {
+            stripped.push_str(part.split_once("\",").unwrap().1);
+        }
+        stripped
+    };
+
+    assert_eq!(
+        tree("depth=1"),
+        r#"[{"type":"directory","name":"a","child_count":2},"#.to_string()
+            + r#"{"type":"directory","name":"loop","child_count":3},"#
+            + r#"{"type":"file","name":"top.txt","size":1}]"#
+    );
+    assert_eq!(
+        tree("depth=2&sort=name&order=desc"),
+        r#"[{"type":"directory","name":"loop","children":[]},"#.to_string()
+            + r#"{"type":"directory","name":"a","children":["#
+            + r#"{"type":"directory","name":"b","child_count":1},"#
+            + r#"{"type":"file","name":"a.txt","size":1}]},"#
+            + r#"{"type":"file","name":"top.txt","size":1}]"#
+    );
+    // Directories stay as the parents of what passes the filter.
+    assert_eq!(
+        tree("depth=3&include=c.*"),
+        r#"[{"type":"directory","name":"a","children":["#.to_string()
+            + r#"{"type":"directory","name":"b","children":["#
+            + r#"{"type":"file","name":"c.txt","size":1}]}]},"#
+            + r#"{"type":"directory","name":"loop","children":[]}]"#
+    );
+
+    assert!(tree("format=jsonp&callback=cb").starts_with("cb([{"));
+    for query in ["format=html", "format=xml", "format=ndjson", "archive=tar"] {
+        let resp = get(&config, &format!("/?layout=tree&{}", query));
+        assert_eq!(resp.status, 400, "{}", query);
+    }
+    let resp = get_with(&config, "/?layout=tree", &["Accept: text/html"]);
+    assert_eq!(resp.status, 400);
+    assert_eq!(get(&config, "/?layout=nested").status, 400);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ndjson_streams_beyond_one_sorted_run() {
+    let dir = scratch_dir("ndjson");
+    // More than one in-memory run of the external sort.
+    let count = 70_000;
+    for index in 0..count {
+        File::create(dir.join(format!("f{:05}", index))).unwrap();
+    }
+    fs::create_dir(dir.join("sub")).unwrap();
+    let config = config(&dir);
+
+    let expected = (0..count)
+        .map(|index| format!("f{:05}", index))
+        .collect::<Vec<_>>();
+
+    let (head, listing) = stream(&config, "/?format=ndjson");
+    assert_eq!(head.status, 200);
+    assert_eq!(header(&head, "Content-Type"), Some("application/x-ndjson"));
+    assert_eq!(header(&head, "Transfer-Encoding"), Some("chunked"));
+    let lines = listing.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), count + 1);
+    assert!(lines[0].starts_with(r#"{"type":"directory","#));
+    assert!(lines
+        .iter()
+        .all(|line| line.starts_with('{') && line.ends_with('}')));
+    assert_eq!(names(&listing)[1..], expected);
+
+    let (_, listing) = stream(
+        &config,
+        "/?format=ndjson&sort=name&order=desc&dirs_first=off",
+    );
+    let mut reversed = expected.clone();
+    reversed.push("sub".to_string());
+    reversed.reverse();
+    assert_eq!(names(&listing), reversed);
+
+    // The window spans the runs.
+    let (_, listing) = stream(&config, "/?format=ndjson&offset=65530&limit=10");
+    assert_eq!(names(&listing), expected[65529..65539]);
+
+    let (_, listing) = stream(&config, "/?format=ndjson&sort=none");
+    let mut unsorted = names(&listing);
+    assert_eq!(unsorted.len(), count + 1);
+    unsorted.sort();
+    assert_eq!(unsorted[..count], expected);
+    assert_eq!(unsorted[count], "sub");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ndjson_errors_and_head_come_before_the_walk() {
+    let dir = scratch_dir("ndjson-head");
+    let locked = dir.join("locked");
+    fs::create_dir(&locked).unwrap();
+    fs::write(dir.join("file.txt"), "x").unwrap();
+    fs::set_permissions(&locked, Permissions::from_mode(0o000)).unwrap();
+    let config = config(&dir);
+
+    // Privileged users read the directory regardless of its mode.
+    if fs::read_dir(&locked).is_err() {
+        let resp = get(&config, "/locked/?format=ndjson");
+        assert_eq!(resp.status, 403);
+        assert!(body(&resp).contains("permission_denied"));
+    }
+    assert_eq!(get(&config, "/missing/?format=ndjson").status, 404);
+    assert_eq!(get(&config, "/file.txt/?format=ndjson").status, 400);
+
+    let request = request_with("HEAD", "/?format=ndjson", &[]);
+    let Reply::Full(head) = Service::handle(&config, &request) else {
+        panic!("HEAD walked the listing");
+    };
+    assert_eq!(head.status, 200);
+    assert_eq!(header(&head, "Content-Type"), Some("application/x-ndjson"));
+    assert!(head.bytes.is_empty());
+
+    fs::set_permissions(&locked, Permissions::from_mode(0o755)).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn searches_rank_matches_and_report_partial_walks() {
+    let dir = scratch_dir("search");
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    for name in [
+        "linux",
+        "a/linux",
+        "a/b/linux.tar",
+        "archlinux.iso",
+        "a/kali-linux.iso",
+        "windows.iso",
+    ] {
+        fs::write(dir.join(name), "x").unwrap();
+    }
+    let mut config = config(&dir);
+    // Long enough not to fit in an `Instant`.
+    config.search_timeout = Duration::MAX;
+
+    let resp = get(&config, "/_search?q=linux");
+    assert_eq!(resp.status, 200);
+    assert_eq!(header(&resp, "X-Search-Complete"), Some("true"));
+    assert_eq!(header(&resp, "X-Total-Count"), Some("5"));
+    assert_eq!(
+        paths(&body(&resp)),
+        [
+            "linux",
+            "a/linux",
+            "a/b/linux.tar",
+            "a/kali-linux.iso",
+            "archlinux.iso"
+        ]
+    );
+
+    let resp = get(&config, "/_search?q=linux&limit=2&offset=1");
+    assert_eq!(paths(&body(&resp)), ["a/linux", "a/b/linux.tar"]);
+    assert_eq!(header(&resp, "X-Total-Count"), Some("5"));
+    let resp = get(&config, "/_search?q=*.iso&mode=glob");
+    assert_eq!(
+        paths(&body(&resp)),
+        ["windows.iso", "archlinux.iso", "a/kali-linux.iso"]
+    );
+
+    // A walk that gives up still answers with what it found.
+    let resp = get(&config, "/_search?q=linux&timeout=0");
+    assert_eq!(resp.status, 200);
+    assert_eq!(header(&resp, "X-Search-Complete"), Some("false"));
+    config.max_walk = 3;
+    let resp = get(&config, "/_search?q=linux");
+    assert_eq!(resp.status, 200);
+    assert_eq!(header(&resp, "X-Search-Complete"), Some("false"));
+
+    for query in [
+        "",
+        "?q=",
+        "?q=x&mode=exact",
+        "?q=x&cursor=abc",
+        "?q=[a-&mode=glob",
+    ] {
+        let resp = get(&config, &format!("/_search{}", query));
+        assert_eq!(resp.status, 400, "{}", query);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Bitwise CRC-32, to forge index checksums.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => crc >> 1 ^ 0xedb8_8320,
+            };
+        }
+    }
+    !crc
+}
+
+#[test]
+fn broken_indexes_are_rebuilt() {
+    let dir = scratch_dir("index-rebuild");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/a.txt"), "x").unwrap();
+    let path = dir.with_extension("index");
+    let root = Root::new(dir.clone(), SymlinkPolicy::Inside, false).unwrap();
+
+    Index::open(path.clone(), &root)
+        .unwrap()
+        .refresh(&root)
+        .unwrap();
+    let saved = fs::read(&path).unwrap();
+    assert!(saved.starts_with(b"RINDEX\r\n"));
+
+    // Another version, with a checksum to match.
+    let mut other = saved.clone();
+    other[8..12].copy_from_slice(&2u32.to_le_bytes());
+    let end = other.len() - 4;
+    let checksum = crc32(&other[..end]);
+    other[end..].copy_from_slice(&checksum.to_le_bytes());
+
+    let mut corrupt = saved.clone();
+    corrupt[20] ^= 1;
+
+    for broken in [other, corrupt, b"RINDEX".to_vec()] {
+        fs::write(&path, &broken).unwrap();
+        fs::write(dir.join("sub/b.txt"), "x").unwrap();
+
+        let mut config = config(&dir);
+        config.index = Some(Index::open(path.clone(), &root).unwrap());
+        config.index.as_ref().unwrap().refresh(&root).unwrap();
+        let rebuilt = fs::read(&path).unwrap();
+        assert!(rebuilt.starts_with(b"RINDEX\r\n\x01\0\0\0"));
+        let end = rebuilt.len() - 4;
+        assert_eq!(rebuilt[end..], crc32(&rebuilt[..end]).to_le_bytes());
+        let listing = body(&get(&config, "/?depth=2&sort=name"));
+        assert_eq!(paths(&listing), ["sub", "sub/a.txt", "sub/b.txt"]);
+
+        fs::remove_file(dir.join("sub/b.txt")).unwrap();
+    }
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn indexes_are_only_served_while_current() {
+    let dir = scratch_dir("index-current");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/a.txt"), "x").unwrap();
+    let path = dir.with_extension("index");
+    let root = Root::new(dir.clone(), SymlinkPolicy::Inside, false).unwrap();
+    Index::open(path.clone(), &root)
+        .unwrap()
+        .refresh(&root)
+        .unwrap();
+
+    // Until the loaded index is refreshed, listings walk.
+    fs::write(dir.join("sub/b.txt"), "x").unwrap();
+    let mut config = config(&dir);
+    config.index = Some(Index::open(path.clone(), &root).unwrap());
+    let listing = body(&get(&config, "/?depth=2"));
+    assert_eq!(paths(&listing), ["sub", "sub/a.txt", "sub/b.txt"]);
+
+    // Rewriting a file leaves the mtime of its directory alone; only
+    // inotify tells.
+    config.index.as_ref().unwrap().refresh(&root).unwrap();
+    fs::write(dir.join("sub/a.txt"), "xyz").unwrap();
+    let rewritten =
+        |config: &Config, target: &str| body(&get(config, target)).contains(r#"a.txt","size":3"#);
+    let start = SystemTime::now();
+    while !rewritten(&config, "/sub/?depth=2") {
+        assert!(start.elapsed().unwrap() < Duration::from_secs(2));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    // Changes below a directory stale the listings above it too.
+    assert!(rewritten(&config, "/?depth=2"));
+
+    config.index.as_ref().unwrap().refresh(&root).unwrap();
+    assert!(rewritten(&config, "/?depth=2"));
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn changes_invalidate_cached_listings() {
+    let dir = scratch_dir("cache");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/a.txt"), "x").unwrap();
+    let mut config = config(&dir);
+    config.cache = Some(ListingCache::new(1 << 20));
+
+    let listed = |target: &str, expected: &str| {
+        let start = SystemTime::now();
+        while !body(&get(&config, target)).contains(expected) {
+            assert!(
+                start.elapsed().unwrap() < Duration::from_secs(2),
+                "{}",
+                expected
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+    assert_eq!(names(&body(&get(&config, "/sub/"))), ["a.txt"]);
+    assert_eq!(names(&body(&get(&config, "/sub/?sort=size"))), ["a.txt"]);
+
+    fs::write(dir.join("sub/b.txt"), "x").unwrap();
+    listed("/sub/", r#""name":"b.txt""#);
+    listed("/sub/?sort=size", r#""name":"b.txt""#);
+
+    // Rewriting a file leaves the mtime of its directory alone.
+    fs::write(dir.join("sub/a.txt"), "xyz").unwrap();
+    listed("/sub/", r#""name":"a.txt","size":3"#);
+
+    fs::remove_file(dir.join("sub/b.txt")).unwrap();
+    listed("/sub/", r#""name":"a.txt","size":3}]"#);
+    assert_eq!(names(&body(&get(&config, "/sub/"))), ["a.txt"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn etags_answer_conditional_requests() {
+    let dir = scratch_dir("etag");
+    // Listings were last modified by their newest entry or the
+    // directory itself.
+    let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    for index in 0..40 {
+        let path = dir.join(format!("file-{:02}.txt", index));
+        fs::write(&path, "x").unwrap();
+        touch(&path, modified - Duration::from_secs(index));
+    }
+    touch(&dir, modified - Duration::from_secs(60));
+    let config = config(&dir);
+
+    let plain = get(&config, "/");
+    assert!(plain.bytes.len() > 1024);
+    let etag = header(&plain, "ETag").unwrap().to_string();
+    assert_eq!(header(&plain, "Vary"), Some("Accept-Encoding"));
+    assert_eq!(
+        header(&plain, "Last-Modified"),
+        Some("Tue, 14 Nov 2023 22:13:20 GMT")
+    );
+    assert_eq!(header(&get(&config, "/"), "ETag"), Some(&*etag));
+
+    // Each encoding is a variant with bytes, and so a tag, of its own.
+    let mut etags = vec![etag.clone()];
+    for encoding in ["gzip", "br", "zstd"] {
+        let accept = format!("Accept-Encoding: {}", encoding);
+        let resp = get_with(&config, "/", &[&accept]);
+        assert_eq!(header(&resp, "Content-Encoding"), Some(encoding));
+        let variant = header(&resp, "ETag").unwrap().to_string();
+        assert_eq!(
+            variant,
+            format!("{}-{}\"", etag.trim_end_matches('"'), encoding)
+        );
+
+        let matching = format!("If-None-Match: {}", variant);
+        let resp = get_with(&config, "/", &[&accept, &matching]);
+        assert_eq!(resp.status, 304, "{}", encoding);
+        assert_eq!(get_with(&config, "/", &[&matching]).status, 200);
+        etags.push(variant);
+    }
+    let all = format!("If-None-Match: {}", etags.join(", "));
+    assert_eq!(get_with(&config, "/", &[&all]).status, 304);
+
+    for matching in [
+        etag.clone(),
+        format!("W/{}", etag),
+        format!("\"other\", {}", etag),
+        "*".to_string(),
+    ] {
+        let resp = get_with(&config, "/", &[&format!("If-None-Match: {}", matching)]);
+        assert_eq!(resp.status, 304, "{}", matching);
+        assert!(resp.bytes.is_empty());
+        assert_eq!(header(&resp, "ETag"), Some(&*etag));
+        assert_eq!(header(&resp, "Content-Type"), None);
+    }
+    let resp = get_with(&config, "/", &["If-None-Match: \"other\""]);
+    assert_eq!(resp.status, 200);
+
+    // Dates only count without tags.
+    for (since, status) in [
+        ("Tue, 14 Nov 2023 22:13:20 GMT", 304),
+        ("Wed, 15 Nov 2023 00:00:00 GMT", 304),
+        ("Tue, 14 Nov 2023 22:13:19 GMT", 200),
+        ("yesterday", 200),
+    ] {
+        let since = format!("If-Modified-Since: {}", since);
+        assert_eq!(
+            get_with(&config, "/", &[&since]).status,
+            status,
+            "{}",
+            since
+        );
+    }
+    let headers = [
+        "If-None-Match: \"other\"",
+        "If-Modified-Since: Wed, 15 Nov 2023 00:00:00 GMT",
+    ];
+    assert_eq!(get_with(&config, "/", &headers).status, 200);
+
+    // A listing that changes changes its tag.
+    fs::write(dir.join("new.txt"), "x").unwrap();
+    let resp = get_with(&config, "/", &[&format!("If-None-Match: {}", etag)]);
+    assert_eq!(resp.status, 200);
+    assert_ne!(header(&resp, "ETag"), Some(&*etag));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn encodings_follow_accept_encoding_weights() {
+    let dir = scratch_dir("accept-encoding");
+    fs::create_dir(dir.join("big")).unwrap();
+    for index in 0..40 {
+        fs::write(dir.join(format!("big/file-{:02}.txt", index)), "x").unwrap();
+    }
+    let config = config(&dir);
+
+    for (accept, encoding) in [
+        ("gzip", Some("gzip")),
+        ("GZIP", Some("gzip")),
+        ("gzip, deflate, br", Some("br")),
+        ("gzip, zstd", Some("zstd")),
+        ("gzip;q=1, br;q=0.5, zstd;q=0.8", Some("gzip")),
+        ("br;q=0.2, gzip;q=0.1", Some("br")),
+        ("br;q=0, *", Some("zstd")),
+        ("*;q=0.5, gzip", Some("gzip")),
+        ("gzip ; q=0.9 , zstd;q=0.95", Some("zstd")),
+        ("gzip;q=oops", Some("gzip")),
+        ("identity", None),
+        ("deflate", None),
+        ("br;q=0, zstd;q=0, gzip;q=0", None),
+        ("*;q=0", None),
+        ("", None),
+    ] {
+        let resp = get_with(&config, "/big/", &[&format!("Accept-Encoding: {}", accept)]);
+        assert_eq!(resp.status, 200);
+        assert_eq!(header(&resp, "Content-Encoding"), encoding, "{}", accept);
+        assert_eq!(header(&resp, "Vary"), Some("Accept-Encoding"));
+    }
+
+    // Bodies below the threshold are sent as they are.
+    let resp = get_with(&config, "/", &["Accept-Encoding: gzip"]);
+    assert_eq!(header(&resp, "Content-Encoding"), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn files_are_served_and_heads_have_no_body() {
+    let dir = scratch_dir("head");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/notes.txt"), "some notes").unwrap();
+    fs::write(dir.join("image.iso"), vec![0; 2048]).unwrap();
+    touch(
+        &dir.join("sub/notes.txt"),
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+    );
+    let mut config = config(&dir);
+    assert_eq!(get(&config, "/sub/notes.txt").status, 400);
+    config.serve_files = true;
+
+    let (head, sent) = download(&config, "/sub/notes.txt", &[]);
+    assert_eq!(head.status, 200);
+    assert_eq!(sent, b"some notes");
+    assert_eq!(
+        header(&head, "Content-Type"),
+        Some("text/plain; charset=utf-8")
+    );
+    assert_eq!(header(&head, "Content-Length"), Some("10"));
+    assert_eq!(
+        header(&head, "Last-Modified"),
+        Some("Tue, 14 Nov 2023 22:13:20 GMT")
+    );
+    let head = get(&config, "/image.iso");
+    assert_eq!(
+        header(&head, "Content-Type"),
+        Some("application/x-iso9660-image")
+    );
+    assert_eq!(header(&head, "Content-Length"), Some("2048"));
+
+    for target in [
+        "/sub/notes.txt",
+        "/image.iso",
+        "/",
+        "/?format=html",
+        "/?format=ndjson",
+        "/sub/?archive=tar",
+        "/sub/notes.txt?hash=sha256",
+        "/missing",
+        "/?sort=nothing",
+    ] {
+        let Reply::Full(head) = Service::handle(&config, &request_with("HEAD", target, &[])) else {
+            panic!("HEAD sent a body: {}", target);
+        };
+        assert!(head.bytes.is_empty(), "{}", target);
+
+        let get = get(&config, target);
+        assert_eq!(head.status, get.status, "{}", target);
+        for name in ["Content-Type", "ETag", "Last-Modified"] {
+            assert_eq!(
+                header(&head, name),
+                header(&get, name),
+                "{} {}",
+                target,
+                name
+            );
+        }
+        let len = match get.bytes.len() {
+            0 => header(&get, "Content-Length").map(str::to_string),
+            len => Some(len.to_string()),
+        };
+        assert_eq!(
+            header(&head, "Content-Length"),
+            len.as_deref(),
+            "{}",
+            target
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dotfiles_are_hidden_on_request() {
+    let dir = scratch_dir("dotfiles");
+    fs::create_dir_all(dir.join(".git")).unwrap();
+    fs::create_dir_all(dir.join("docs")).unwrap();
+    fs::write(dir.join(".git/config"), "secret").unwrap();
+    fs::write(dir.join(".env"), "secret").unwrap();
+    fs::write(dir.join("docs/.draft.txt"), "secret").unwrap();
+    fs::write(dir.join("docs/readme.txt"), "readme").unwrap();
+    let mut config = config(&dir);
+    config.serve_files = true;
+
+    assert_eq!(names(&body(&get(&config, "/"))), [".git", "docs", ".env"]);
+    assert_eq!(get(&config, "/.env").status, 200);
+
+    config.root = Root::new(dir.clone(), SymlinkPolicy::Inside, true).unwrap();
+    assert_eq!(names(&body(&get(&config, "/"))), ["docs"]);
+    assert_eq!(
+        paths(&body(&get(&config, "/?depth=3"))),
+        ["docs", "docs/readme.txt"]
+    );
+    let tree = body(&get(&config, "/?layout=tree&depth=1"));
+    assert!(tree.contains("\"child_count\":1"), "{}", tree);
+    for target in [
+        "/.env",
+        "/.git/",
+        "/.git/config",
+        "/docs/.draft.txt",
+        "/docs/.draft.txt?hash=sha256",
+        "/.git/?archive=tar",
+    ] {
+        assert_eq!(get(&config, target).status, 404, "{}", target);
+    }
+
+    let resp = get(&config, "/_search?q=txt");
+    assert_eq!(paths(&body(&resp)), ["docs/readme.txt"]);
+    let resp = get(&config, "/_search?q=config");
+    assert_eq!(header(&resp, "X-Total-Count"), Some("0"));
+
+    let Reply::Archive(_, archive) = Service::handle(&config, &request("/?archive=tar", &[]))
+    else {
+        panic!("Not an archive");
+    };
+    let mut sent = Vec::new();
+    archive.send(&config, &mut sent).unwrap();
+    let sent = String::from_utf8_lossy(&sent);
+    assert!(sent.contains("readme.txt"));
+    assert!(!sent.contains(".draft.txt") && !sent.contains(".env") && !sent.contains(".git"));
+
+    config.handoff = Some("accel-redirect=/internal/".parse().unwrap());
+    assert_eq!(get(&config, "/.env").status, 404);
+    assert_eq!(
+        header(&get(&config, "/docs/readme.txt"), "X-Accel-Redirect"),
+        Some("/internal/docs/readme.txt")
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn handoffs_point_the_server_in_front_at_files() {
+    let dir = scratch_dir("handoff");
+    fs::create_dir_all(dir.join("my files")).unwrap();
+    fs::write(dir.join("my files/a b+€.txt"), "some notes").unwrap();
+    fs::write(dir.join("bell\x07.txt"), "ding").unwrap();
+    let mut config = config(&dir);
+
+    for prefix in ["/internal", "/internal/", "/internal//"] {
+        config.handoff = Some(format!("accel-redirect={}", prefix).parse().unwrap());
+        let resp = get(&config, "/my%20files/a%20b%2B%E2%82%AC.txt");
+        assert_eq!(resp.status, 200);
+        assert!(resp.bytes.is_empty());
+        assert_eq!(
+            header(&resp, "X-Accel-Redirect"),
+            Some("/internal/my%20files/a%20b%2B%E2%82%AC.txt"),
+            "{}",
+            prefix
+        );
+        assert_eq!(
+            header(&resp, "Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(header(&resp, "X-Sendfile"), None);
+    }
+    config.handoff = Some("accel-redirect=/".parse().unwrap());
+    assert_eq!(
+        header(&get(&config, "/bell%07.txt"), "X-Accel-Redirect"),
+        Some("/bell%07.txt")
+    );
+
+    config.handoff = Some("sendfile=/srv/files/".parse().unwrap());
+    let resp = get(&config, "/my%20files/a%20b%2B%E2%82%AC.txt");
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        header(&resp, "X-Sendfile"),
+        Some("/srv/files/my files/a b+€.txt")
+    );
+    assert_eq!(header(&resp, "X-Accel-Redirect"), None);
+    assert_eq!(get(&config, "/bell%07.txt").status, 403);
+
+    // Listings, digests and missing files are still answered here.
+    let resp = get(&config, "/my%20files/");
+    assert_eq!(resp.status, 200);
+    assert_eq!(header(&resp, "X-Sendfile"), None);
+    let resp = get(&config, "/my%20files/a%20b%2B%E2%82%AC.txt?hash=sha256");
+    assert_eq!(resp.status, 200);
+    assert_eq!(header(&resp, "X-Sendfile"), None);
+    assert_eq!(get(&config, "/missing.txt").status, 404);
+
+    for value in [
+        "accel-redirect",
+        "accel-redirect=internal/",
+        "x-sendfile=/srv/",
+        "sendfile=",
+    ] {
+        assert!(value.parse::<Handoff>().is_err(), "{}", value);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Fetches an archive download, returning its head and body.
+fn archive(config: &Config, target: &str) -> (Response, Vec<u8>) {
+    let Reply::Archive(head, body) = Service::handle(config, &request(target, &[])) else {
+        panic!("Not an archive: {}", target);
+    };
+    let mut sent = Vec::new();
+    body.send(config, &mut sent).unwrap();
+    (head, sent)
+}
+
+/// Paths and contents of the members of a tar archive, taking pax
+/// paths over ustar ones.
+fn tar_members(tar: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let field = |block: &[u8], range: std::ops::Range<usize>| {
+        let field = &block[range];
+        let end = field
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(field.len());
+        String::from_utf8(field[..end].to_vec()).unwrap()
+    };
+    let (mut members, mut at, mut pax_path) = (Vec::new(), 0, None);
+    loop {
+        let block = &tar[at..at + 512];
+        if block.iter().all(|&byte| byte == 0) {
+            assert!(tar[at..].iter().all(|&byte| byte == 0));
+            assert_eq!(tar.len() - at, 1024);
+            return members;
+        }
+        let size = u64::from_str_radix(&field(block, 124..136), 8).unwrap() as usize;
+        let data = tar[at + 512..at + 512 + size].to_vec();
+        at += 512 + size.div_ceil(512) * 512;
+        if block[156] == b'x' {
+            let records = String::from_utf8(data).unwrap();
+            pax_path = records
+                .lines()
+                .find_map(|record| record.split_once(" path="))
+                .map(|(_, path)| path.to_string());
+            continue;
+        }
+        let path = match (field(block, 345..500), field(block, 0..100)) {
+            (prefix, name) if prefix.is_empty() => name,
+            (prefix, name) => format!("{}/{}", prefix, name),
+        };
+        members.push((pax_path.take().unwrap_or(path), data));
+    }
+}
+
+/// Paths and contents of the members of a zip archive, going by its
+/// central directory and checking each member's CRC-32.
+fn zip_members(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let u16_at = |at: usize| u16::from_le_bytes(zip[at..at + 2].try_into().unwrap()) as usize;
+    let u32_at = |at: usize| u32::from_le_bytes(zip[at..at + 4].try_into().unwrap());
+    let end = zip.len() - 22;
+    assert_eq!(u32_at(end), 0x0605_4b50);
+    let (count, size, offset) = (u16_at(end + 10), u32_at(end + 12), u32_at(end + 16));
+    assert_eq!((offset + size) as usize, end);
+
+    let (mut members, mut at) = (Vec::new(), offset as usize);
+    for _ in 0..count {
+        assert_eq!(u32_at(at), 0x0201_4b50);
+        let (crc, len) = (u32_at(at + 16), u32_at(at + 24) as usize);
+        let (name_len, extra_len) = (u16_at(at + 28), u16_at(at + 30));
+        let comment_len = u16_at(at + 32);
+        let path = String::from_utf8(zip[at + 46..at + 46 + name_len].to_vec()).unwrap();
+        let local = u32_at(at + 42) as usize;
+        assert_eq!(u32_at(local), 0x0403_4b50);
+        let start = local + 30 + u16_at(local + 26) + u16_at(local + 28);
+        let data = zip[start..start + len].to_vec();
+        assert_eq!(crc32(&data), crc, "{}", path);
+        members.push((path, data));
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    members
+}
+
+#[test]
+fn archives_have_exact_lengths_and_limits() {
+    let dir = scratch_dir("archive-lengths");
+    let long = format!("{}/{}", "d".repeat(120), "n".repeat(110));
+    fs::create_dir_all(dir.join("src/sub")).unwrap();
+    fs::create_dir_all(dir.join("src").join(&long).parent().unwrap()).unwrap();
+    fs::write(dir.join("src/a.txt"), "abc").unwrap();
+    fs::write(dir.join("src/sub/b.bin"), vec![7; 1000]).unwrap();
+    fs::write(dir.join("src").join(&long), "long").unwrap();
+    let mut config = config(&dir);
+    config.serve_files = true;
+    config.bundles = Some(BundleCache::new(4));
+
+    let expected = [
+        ("src/a.txt", &b"abc"[..]),
+        ("src/sub/b.bin", &[7; 1000][..]),
+        (&format!("src/{}", long), &b"long"[..]),
+    ];
+    let files = |members: Vec<(String, Vec<u8>)>| {
+        let mut files = members
+            .into_iter()
+            .filter(|(path, _)| !path.ends_with('/'))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let mut sorted = expected
+        .iter()
+        .map(|(path, data)| (path.to_string(), data.to_vec()))
+        .collect::<Vec<_>>();
+    sorted.sort();
+
+    let (head, tar) = archive(&config, "/src/?archive=tar");
+    assert_eq!(header(&head, "Content-Type"), Some("application/x-tar"));
+    assert_eq!(
+        header(&head, "Content-Length"),
+        Some(&*tar.len().to_string())
+    );
+    assert_eq!(files(tar_members(&tar)), sorted);
+
+    let (head, zip) = archive(&config, "/src/?archive=zip");
+    assert_eq!(header(&head, "Content-Type"), Some("application/zip"));
+    assert_eq!(
+        header(&head, "Content-Length"),
+        Some(&*zip.len().to_string())
+    );
+    assert_eq!(files(zip_members(&zip)), sorted);
+
+    // Compressed archives have no length before they're sent, and read
+    // back as the same tar.
+    let (head, tar_gz) = archive(&config, "/src/?archive=tar.gz");
+    assert_eq!(header(&head, "Content-Type"), Some("application/gzip"));
+    assert_eq!(header(&head, "Content-Length"), None);
+    assert_eq!(tar_gz[..2], [0x1f, 0x8b]);
+    fs::write(dir.join("src.tar.gz"), &tar_gz).unwrap();
+    for (path, data) in expected {
+        let (head, sent) = download(&config, &format!("/src.tar.gz/{}", path), &[]);
+        assert_eq!(head.status, 200, "{}", path);
+        assert_eq!(sent, data, "{}", path);
+    }
+
+    // Files that change between the head and the body are cut or filled
+    // with zeros, keeping the length announced.
+    for format in ["tar", "zip"] {
+        let target = format!("/src/?archive={}", format);
+        let Reply::Archive(head, body) = Service::handle(&config, &request(&target, &[])) else {
+            panic!("Not an archive: {}", format);
+        };
+        fs::write(dir.join("src/a.txt"), "a").unwrap();
+        fs::write(dir.join("src/sub/b.bin"), vec![8; 2000]).unwrap();
+        fs::remove_file(dir.join("src").join(&long)).unwrap();
+
+        let mut sent = Vec::new();
+        body.send(&config, &mut sent).unwrap();
+        assert_eq!(
+            header(&head, "Content-Length"),
+            Some(&*sent.len().to_string())
+        );
+        let members = match format {
+            "tar" => tar_members(&sent),
+            _ => zip_members(&sent),
+        };
+        let mut changed = vec![
+            ("src/a.txt".to_string(), b"a\0\0".to_vec()),
+            ("src/sub/b.bin".to_string(), vec![8; 1000]),
+            (format!("src/{}", long), vec![0; 4]),
+        ];
+        changed.sort();
+        assert_eq!(files(members), changed, "{}", format);
+
+        fs::write(dir.join("src/a.txt"), "abc").unwrap();
+        fs::write(dir.join("src/sub/b.bin"), vec![7; 1000]).unwrap();
+        fs::write(dir.join("src").join(&long), "long").unwrap();
+    }
+
+    // Limits are on files and their bytes, met exactly or refused.
+    config.max_archive_files = 3;
+    config.max_archive_size = 1007;
+    assert_eq!(get(&config, "/src/?archive=tar").status, 200);
+    for (files, size) in [(2, 1007), (3, 1006)] {
+        config.max_archive_files = files;
+        config.max_archive_size = size;
+        for format in ["tar", "tar.gz", "zip"] {
+            let resp = get(&config, &format!("/src/?archive={}", format));
+            assert_eq!(resp.status, 413, "{} {} {}", files, size, format);
+            assert!(body(&resp).contains("\"archive_too_large\""));
+        }
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A ustar header for a member of `size` bytes of `kind`.
+fn tar_header(path: &str, size: usize, kind: u8) -> Vec<u8> {
+    let mut header = vec![0; 512];
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(b"14000000000\0");
+    header[156] = kind;
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[148..156].fill(b' ');
+    let checksum = header.iter().map(|&byte| byte as u32).sum::<u32>();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+/// A tar of regular files, with the blocks ending an archive.
+fn tar_of(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut tar = Vec::new();
+    for (path, data) in members {
+        tar.extend(tar_header(path, data.len(), b'0'));
+        tar.extend(*data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+    tar.extend([0; 1024]);
+    tar
+}
+
+/// A zip of one stored file whose sizes and offset are all in zip64
+/// fields, as are the counts and offsets of the central directory.
+fn zip64_of(path: &str, data: &[u8]) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let push =
+        |zip: &mut Vec<u8>, fields: &[&[u8]]| fields.iter().for_each(|field| zip.extend(*field));
+    let (crc, len, name_len) = (crc32(data), data.len() as u64, path.len() as u16);
+    let date = ((1 << 5) | 1_u16).to_le_bytes();
+
+    push(
+        &mut zip,
+        &[
+            &0x0403_4b50_u32.to_le_bytes(),
+            &45_u16.to_le_bytes(),
+            &0x0800_u16.to_le_bytes(),
+            &[0; 4],
+            &date,
+            &crc.to_le_bytes(),
+            &[0xff; 8],
+            &name_len.to_le_bytes(),
+            &20_u16.to_le_bytes(),
+            path.as_bytes(),
+            &1_u16.to_le_bytes(),
+            &16_u16.to_le_bytes(),
+            &len.to_le_bytes(),
+            &len.to_le_bytes(),
+            data,
+        ],
+    );
+
+    let central = zip.len() as u64;
+    push(
+        &mut zip,
+        &[
+            &0x0201_4b50_u32.to_le_bytes(),
+            &(0x0300_u16 | 45).to_le_bytes(),
+            &45_u16.to_le_bytes(),
+            &0x0800_u16.to_le_bytes(),
+            &[0; 4],
+            &date,
+            &crc.to_le_bytes(),
+            &[0xff; 8],
+            &name_len.to_le_bytes(),
+            &28_u16.to_le_bytes(),
+            &[0; 6],
+            &(0o100_644_u32 << 16).to_le_bytes(),
+            &[0xff; 4],
+            path.as_bytes(),
+            &1_u16.to_le_bytes(),
+            &24_u16.to_le_bytes(),
+            &len.to_le_bytes(),
+            &len.to_le_bytes(),
+            &0_u64.to_le_bytes(),
+        ],
+    );
+
+    let end = zip.len() as u64;
+    push(
+        &mut zip,
+        &[
+            &0x0606_4b50_u32.to_le_bytes(),
+            &44_u64.to_le_bytes(),
+            &(0x0300_u16 | 45).to_le_bytes(),
+            &45_u16.to_le_bytes(),
+            &[0; 8],
+            &1_u64.to_le_bytes(),
+            &1_u64.to_le_bytes(),
+            &(end - central).to_le_bytes(),
+            &central.to_le_bytes(),
+            &0x0706_4b50_u32.to_le_bytes(),
+            &0_u32.to_le_bytes(),
+            &end.to_le_bytes(),
+            &1_u32.to_le_bytes(),
+            &0x0605_4b50_u32.to_le_bytes(),
+            &[0; 4],
+            &[0xff; 12],
+            &[0; 2],
+        ],
+    );
+    zip
+}
+
+#[test]
+fn malformed_archives_are_refused() {
+    let dir = scratch_dir("malformed");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    let text = (0..2_000)
+        .map(|line| format!("line {}\n", line))
+        .collect::<String>();
+    fs::write(dir.join("src/lines.txt"), &text).unwrap();
+    let mut config = config(&dir);
+    config.serve_files = true;
+    config.bundles = Some(BundleCache::new(16));
+    let corrupt = |config: &Config, name: &str, archive: &[u8]| {
+        fs::write(dir.join(name), archive).unwrap();
+        let resp = get(config, &format!("/{}/", name));
+        assert_eq!(resp.status, 422, "{}", name);
+        assert!(body(&resp).contains("\"corrupt_archive\""), "{}", name);
+    };
+
+    // Members climbing out of the archive are left out.
+    let tar = tar_of(&[
+        ("ok.txt", b"fine"),
+        ("./dot/ok.txt", b"fine"),
+        ("../evil.txt", b"evil"),
+        ("dot/../../evil.txt", b"evil"),
+        ("/../evil.txt", b"evil"),
+    ]);
+    fs::write(dir.join("dots.tar"), &tar).unwrap();
+    assert_eq!(names(&body(&get(&config, "/dots.tar/"))), ["dot", "ok.txt"]);
+    assert_eq!(names(&body(&get(&config, "/dots.tar/dot/"))), ["ok.txt"]);
+    assert_eq!(get(&config, "/dots.tar/evil.txt").status, 404);
+
+    // Cut in a header, cut in a member's data, and a bad checksum.
+    corrupt(&config, "header.tar", &tar[..300]);
+    corrupt(&config, "data.tar", &tar[..514]);
+    let mut flipped = tar.clone();
+    flipped[0] ^= 1;
+    corrupt(&config, "checksum.tar", &flipped);
+
+    // Extended headers past their limit or not in records.
+    let mut pax = tar_header("././@PaxHeader", (1 << 20) + 1, b'x');
+    pax.resize(512 + (1 << 20) + 512, b'a');
+    pax.extend(tar_of(&[("ok.txt", b"fine")]));
+    corrupt(&config, "pax.tar", &pax);
+    let mut pax = tar_header("././@PaxHeader", 10, b'x');
+    pax.extend(b"99 path=a\n");
+    pax.resize(1024, 0);
+    pax.extend(tar_of(&[("ok.txt", b"fine")]));
+    corrupt(&config, "record.tar", &pax);
+
+    // Inflated members are sent whole, as ranges of them can't be
+    // read without inflating all before.
+    let (_, tar_gz) = archive(&config, "/src/?archive=tar.gz");
+    fs::write(dir.join("src.tar.gz"), &tar_gz).unwrap();
+    let (head, sent) = download(&config, "/src.tar.gz/src/lines.txt", &["Range: bytes=2-4"]);
+    assert_eq!(head.status, 200);
+    assert_eq!(sent, text.as_bytes());
+    assert_eq!(header(&head, "Accept-Ranges"), Some("none"));
+    assert_eq!(header(&head, "Content-Range"), None);
+    let (head, sent) = download(&config, "/dots.tar/ok.txt", &["Range: bytes=1-2"]);
+    assert_eq!(head.status, 206);
+    assert_eq!(sent, b"in");
+    assert_eq!(header(&head, "Accept-Ranges"), Some("bytes"));
+    corrupt(&config, "cut.tar.gz", &tar_gz[..tar_gz.len() / 2]);
+
+    let (_, zip) = archive(&config, "/src/?archive=zip");
+    let end = zip.len() - 22;
+    let mut outside = zip.clone();
+    outside[end + 16..end + 20].copy_from_slice(&0xffff_fff0_u32.to_le_bytes());
+    corrupt(&config, "outside.zip", &outside);
+    corrupt(&config, "tail.zip", &zip[..zip.len() - 30]);
+
+    // Encrypted members are listed but not sent.
+    let mut encrypted = zip.clone();
+    let central = u32::from_le_bytes(zip[end + 16..end + 20].try_into().unwrap()) as usize;
+    for at in (central..end).filter(|&at| zip[at..at + 4] == [0x50, 0x4b, 0x01, 0x02]) {
+        encrypted[at + 8] |= 1;
+    }
+    fs::write(dir.join("encrypted.zip"), &encrypted).unwrap();
+    let listing = body(&get(&config, "/encrypted.zip/src/"));
+    assert_eq!(names(&listing), ["lines.txt"]);
+    let resp = get(&config, "/encrypted.zip/src/lines.txt");
+    assert_eq!(resp.status, 403);
+    assert!(body(&resp).contains("\"forbidden\""));
+
+    fs::write(dir.join("wide.zip"), zip64_of("wide.txt", b"zip64")).unwrap();
+    let listing = body(&get(&config, "/wide.zip/"));
+    assert!(
+        listing.contains("\"name\":\"wide.txt\",\"size\":5"),
+        "{}",
+        listing
+    );
+    let (head, sent) = download(&config, "/wide.zip/wide.txt", &[]);
+    assert_eq!(head.status, 200);
+    assert_eq!(sent, b"zip64");
+
+    // Nothing inside archives is read unless asked for.
+    config.bundles = None;
+    assert_eq!(get(&config, "/dots.tar/ok.txt").status, 404);
+
+    fs::remove_dir_all(&dir).unwrap();
+}